@@ -0,0 +1,163 @@
+//! 代理消息路由基准测试
+//!
+//! 在本机启动代理，建立N个订阅连接，分别测量：
+//! - fan-out：一个主题被全部连接订阅，每条消息投递N次
+//! - unicast：每个连接订阅自己的主题，每条消息只投递给一个连接
+//!
+//! 运行：`cargo bench --bench fanout -- 1000 10000`（参数为连接数，默认1000和10000）。
+//! 每个连接在进程内占用两个文件描述符，连接数较大时需要提高`ulimit -n`。
+use futures::{SinkExt, StreamExt};
+use mqtt::codec::MqttCodec;
+use mqtt::packet::{ConnectPacket, Packet, PublishPacket, SubscribePacket};
+use mqtt::properties::Properties;
+use mqtt::protocol::QoS;
+use mqtt::server::MqttBroker;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_util::codec::Framed;
+
+// fan-out场景中每轮发布的消息数
+const FANOUT_MESSAGES: usize = 20;
+// unicast场景中发给每个连接的消息数
+const UNICAST_MESSAGES: usize = 5;
+
+type ClientFramed = Framed<TcpStream, MqttCodec>;
+
+async fn connect(addr: &str, client_id: String) -> ClientFramed {
+    let socket = TcpStream::connect(addr).await.expect("connect to broker");
+    socket.set_nodelay(true).unwrap();
+    let mut framed = Framed::new(socket, MqttCodec::new());
+    let mut connect = ConnectPacket::new(client_id);
+    connect.keep_alive = 0;
+    framed.send(Packet::Connect(connect)).await.unwrap();
+    match framed.next().await {
+        Some(Ok(Packet::Connack(connack))) if connack.return_code == 0 => framed,
+        other => panic!("expected CONNACK, got {:?}", other),
+    }
+}
+
+async fn subscribe(framed: &mut ClientFramed, topic: String) {
+    framed.send(Packet::Subscribe(SubscribePacket {
+        packet_id: 1,
+        topics: vec![(topic, QoS::AtMostOnce)],
+        properties: Properties::default(),
+    })).await.unwrap();
+    match framed.next().await {
+        Some(Ok(Packet::Suback(_))) => {}
+        other => panic!("expected SUBACK, got {:?}", other),
+    }
+}
+
+fn message(topic: String) -> Packet {
+    Packet::Publish(PublishPacket {
+        dup: false,
+        qos: QoS::AtMostOnce,
+        retain: false,
+        topic,
+        packet_id: None,
+        properties: Properties::default(),
+        payload: bytes::Bytes::from_static(&[0u8; 64]),
+    })
+}
+
+/// 建立`connections`个订阅连接，每个连接收到`expected`条消息后通知`done`
+async fn spawn_subscribers(
+    addr: &str,
+    connections: usize,
+    expected: usize,
+    topic: impl Fn(usize) -> String,
+) -> mpsc::Receiver<()> {
+    let (done_tx, done_rx) = mpsc::channel(connections);
+    for i in 0..connections {
+        let mut framed = connect(addr, format!("bench-sub-{}", i)).await;
+        subscribe(&mut framed, topic(i)).await;
+        let done_tx = done_tx.clone();
+        tokio::spawn(async move {
+            let mut received = 0;
+            while received < expected {
+                match framed.next().await {
+                    Some(Ok(Packet::Publish(_))) => received += 1,
+                    Some(Ok(_)) => {}
+                    _ => return,
+                }
+            }
+            let _ = done_tx.send(()).await;
+        });
+    }
+    done_rx
+}
+
+/// 等待所有订阅连接收齐消息，返回耗时
+async fn wait_all(mut done: mpsc::Receiver<()>, connections: usize, start: Instant) -> Duration {
+    for _ in 0..connections {
+        tokio::time::timeout(Duration::from_secs(120), done.recv())
+            .await
+            .expect("subscribers did not receive all messages in time")
+            .expect("subscriber task failed");
+    }
+    start.elapsed()
+}
+
+fn report(scenario: &str, connections: usize, deliveries: usize, elapsed: Duration) {
+    println!(
+        "{:<8} connections={:<6} deliveries={:<8} elapsed={:>8.1?} throughput={:>10.0} msg/s",
+        scenario,
+        connections,
+        deliveries,
+        elapsed,
+        deliveries as f64 / elapsed.as_secs_f64(),
+    );
+}
+
+async fn bench_fanout(connections: usize) {
+    let addr = start_broker().await;
+    let done = spawn_subscribers(&addr, connections, FANOUT_MESSAGES, |_| "bench/fanout".to_string()).await;
+
+    let mut publisher = connect(&addr, "bench-pub".to_string()).await;
+    let start = Instant::now();
+    for _ in 0..FANOUT_MESSAGES {
+        publisher.send(message("bench/fanout".to_string())).await.unwrap();
+    }
+    let elapsed = wait_all(done, connections, start).await;
+    report("fan-out", connections, connections * FANOUT_MESSAGES, elapsed);
+}
+
+async fn bench_unicast(connections: usize) {
+    let addr = start_broker().await;
+    let done = spawn_subscribers(&addr, connections, UNICAST_MESSAGES, |i| format!("bench/unicast/{}", i)).await;
+
+    let mut publisher = connect(&addr, "bench-pub".to_string()).await;
+    let start = Instant::now();
+    for _ in 0..UNICAST_MESSAGES {
+        for i in 0..connections {
+            publisher.feed(message(format!("bench/unicast/{}", i))).await.unwrap();
+        }
+    }
+    publisher.flush().await.unwrap();
+    let elapsed = wait_all(done, connections, start).await;
+    report("unicast", connections, connections * UNICAST_MESSAGES, elapsed);
+}
+
+async fn start_broker() -> String {
+    let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+    // 基准测试中订阅方始终在读取，允许较深的队列以避免丢弃
+    broker.set_queue_capacity(FANOUT_MESSAGES.max(UNICAST_MESSAGES));
+    let addr = broker.local_addr().unwrap().to_string();
+    tokio::spawn(async move { broker.run().await.unwrap() });
+    addr
+}
+
+fn main() {
+    // cargo bench会传入--bench等参数，只取数字参数作为连接数
+    let mut counts: Vec<usize> = std::env::args().skip(1).filter_map(|arg| arg.parse().ok()).collect();
+    if counts.is_empty() {
+        counts = vec![1_000, 10_000];
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for connections in counts {
+        runtime.block_on(bench_fanout(connections));
+        runtime.block_on(bench_unicast(connections));
+    }
+}
@@ -0,0 +1,280 @@
+/// 代理端主题访问控制（ACL）
+/// 按顺序匹配允许/拒绝规则，第一条匹配的规则决定结果；
+/// 规则中的主题过滤器支持通配符，以及`%c`（客户端标识符）和`%u`（用户名）替换
+use std::io;
+use std::path::Path;
+use crate::topic;
+
+/// 访问类型
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Publish,
+    Subscribe,
+}
+
+/// 规则的处理结果
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    Allow,
+    Deny,
+}
+
+/// 一条访问控制规则
+#[derive(Debug, Clone)]
+pub struct AclRule {
+    permission: Permission,
+    // 为None时同时适用于发布和订阅
+    access: Option<Access>,
+    // 为None时适用于所有用户
+    username: Option<String>,
+    topic: String,
+}
+
+impl AclRule {
+    /// 允许访问匹配主题过滤器的主题
+    pub fn allow(topic: &str) -> Self {
+        Self::new(Permission::Allow, topic)
+    }
+
+    /// 拒绝访问匹配主题过滤器的主题
+    pub fn deny(topic: &str) -> Self {
+        Self::new(Permission::Deny, topic)
+    }
+
+    fn new(permission: Permission, topic: &str) -> Self {
+        AclRule {
+            permission,
+            access: None,
+            username: None,
+            topic: topic.to_string(),
+        }
+    }
+
+    /// 只对发布或订阅生效
+    pub fn access(mut self, access: Access) -> Self {
+        self.access = Some(access);
+        self
+    }
+
+    /// 只对指定用户生效
+    pub fn for_user(mut self, username: &str) -> Self {
+        self.username = Some(username.to_string());
+        self
+    }
+
+    /// 规则是否适用于本次访问
+    fn applies(&self, access: Access, client_id: &str, username: Option<&str>, topic: &str) -> bool {
+        if self.access.is_some_and(|a| a != access) {
+            return false;
+        }
+        if self.username.is_some() && self.username.as_deref() != username {
+            return false;
+        }
+        let Some(pattern) = substitute(&self.topic, client_id, username) else {
+            return false;
+        };
+        match (access, self.permission) {
+            (Access::Publish, _) => topic::matches(&pattern, topic),
+            // 允许订阅时，订阅的过滤器必须完全落在规则过滤器的范围内
+            (Access::Subscribe, Permission::Allow) => topic::filter_covers(&pattern, topic),
+            // 拒绝订阅时，只要订阅可能收到被拒绝的主题就拒绝
+            (Access::Subscribe, Permission::Deny) => topic::filters_overlap(&pattern, topic),
+        }
+    }
+}
+
+/// 替换规则中的`%c`和`%u`
+///
+/// 用户名为空或替换值包含`/`、通配符时规则不生效，防止客户端借此扩大权限。
+fn substitute(pattern: &str, client_id: &str, username: Option<&str>) -> Option<String> {
+    let safe = |value: &str| !value.is_empty() && !value.contains(['/', '+', '#']);
+    let mut result = pattern.to_string();
+    if result.contains("%c") {
+        if !safe(client_id) {
+            return None;
+        }
+        result = result.replace("%c", client_id);
+    }
+    if result.contains("%u") {
+        let username = username.filter(|u| safe(u))?;
+        result = result.replace("%u", username);
+    }
+    Some(result)
+}
+
+/// 访问控制列表
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    // 没有规则匹配时的结果
+    default: Permission,
+}
+
+impl Acl {
+    /// 创建空的ACL，没有规则匹配时使用`default`
+    pub fn new(default: Permission) -> Self {
+        Acl {
+            rules: Vec::new(),
+            default,
+        }
+    }
+
+    /// 允许所有访问，代理的默认策略
+    pub fn allow_all() -> Self {
+        Self::new(Permission::Allow)
+    }
+
+    /// 追加一条规则，规则按添加顺序匹配
+    pub fn add_rule(&mut self, rule: AclRule) {
+        self.rules.push(rule);
+    }
+
+    /// 从ACL文件加载规则
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// 解析ACL文件内容
+    ///
+    /// 每行一条规则，格式为`allow|deny publish|subscribe|all <主题过滤器> [user <用户名>]`，
+    /// 按出现顺序匹配；`default allow|deny`设置没有规则匹配时的结果（默认deny）。
+    /// 空行和以`#`开头的行被忽略。
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut acl = Acl::new(Permission::Deny);
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, message));
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let permission = |word: &str| match word {
+                "allow" => Ok(Permission::Allow),
+                "deny" => Ok(Permission::Deny),
+                _ => Err(invalid("expected 'allow' or 'deny'")),
+            };
+            match fields.as_slice() {
+                ["default", default] => acl.default = permission(default)?,
+                [rule, access, filter, rest @ ..] => {
+                    if !topic::valid_topic_filter(filter) {
+                        return Err(invalid(&format!("invalid topic filter '{}'", filter)));
+                    }
+                    let mut rule = match permission(rule)? {
+                        Permission::Allow => AclRule::allow(filter),
+                        Permission::Deny => AclRule::deny(filter),
+                    };
+                    match *access {
+                        "publish" => rule = rule.access(Access::Publish),
+                        "subscribe" => rule = rule.access(Access::Subscribe),
+                        "all" => {}
+                        _ => return Err(invalid("expected 'publish', 'subscribe' or 'all'")),
+                    }
+                    match rest {
+                        [] => {}
+                        ["user", username] => rule = rule.for_user(username),
+                        _ => return Err(invalid("expected 'user <username>' after the topic filter")),
+                    }
+                    acl.add_rule(rule);
+                }
+                _ => return Err(invalid("expected 'allow|deny publish|subscribe|all <filter> [user <username>]'")),
+            }
+        }
+        Ok(acl)
+    }
+
+    /// 检查客户端能否发布到主题名，或订阅主题过滤器
+    pub fn check(&self, access: Access, client_id: &str, username: Option<&str>, topic: &str) -> bool {
+        let permission = self.rules
+            .iter()
+            .find(|rule| rule.applies(access, client_id, username, topic))
+            .map_or(self.default, |rule| rule.permission);
+        permission == Permission::Allow
+    }
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::allow_all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deployment_acl() -> Acl {
+        let mut acl = Acl::new(Permission::Deny);
+        acl.add_rule(AclRule::allow("#").for_user("admin"));
+        acl.add_rule(AclRule::deny("devices/%c/secret"));
+        acl.add_rule(AclRule::allow("devices/%c/#"));
+        acl.add_rule(AclRule::allow("users/%u/#"));
+        acl.add_rule(AclRule::allow("broadcast/#").access(Access::Subscribe));
+        acl
+    }
+
+    #[test]
+    fn test_client_id_and_username_substitution() {
+        let acl = deployment_acl();
+        assert!(acl.check(Access::Publish, "dev1", None, "devices/dev1/temp"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "devices/dev2/temp"));
+        assert!(acl.check(Access::Subscribe, "dev1", Some("alice"), "users/alice/+"));
+        assert!(!acl.check(Access::Subscribe, "dev1", Some("alice"), "users/+/inbox"));
+        // 没有用户名时%u规则不生效
+        assert!(!acl.check(Access::Publish, "dev1", None, "users//inbox"));
+    }
+
+    #[test]
+    fn test_first_matching_rule_wins() {
+        let acl = deployment_acl();
+        assert!(!acl.check(Access::Publish, "dev1", None, "devices/dev1/secret"));
+        assert!(acl.check(Access::Publish, "dev1", Some("admin"), "devices/dev1/secret"));
+        assert!(acl.check(Access::Subscribe, "dev1", None, "broadcast/news"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "broadcast/news"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "other"));
+    }
+
+    #[test]
+    fn test_deny_rule_rejects_overlapping_subscriptions() {
+        let acl = deployment_acl();
+        // 通配符订阅会收到被拒绝的主题
+        assert!(!acl.check(Access::Subscribe, "dev1", None, "devices/dev1/#"));
+        assert!(!acl.check(Access::Subscribe, "dev1", None, "devices/dev1/+"));
+        assert!(!acl.check(Access::Subscribe, "dev1", None, "devices/dev1/secret"));
+        assert!(acl.check(Access::Subscribe, "dev1", None, "devices/dev1/temp"));
+        assert!(acl.check(Access::Subscribe, "dev1", None, "devices/dev1/+/temp"));
+    }
+
+    #[test]
+    fn test_parse_acl_file() {
+        let acl = Acl::parse(
+            "# 部署规则\n\
+             allow all # user admin\n\
+             deny all devices/%c/secret\n\
+             allow all devices/%c/#\n\
+             allow all users/%u/#\n\
+             \n\
+             allow subscribe broadcast/#\n",
+        )
+        .unwrap();
+        assert!(acl.check(Access::Publish, "dev1", Some("admin"), "devices/dev1/secret"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "devices/dev1/secret"));
+        assert!(acl.check(Access::Subscribe, "dev1", None, "broadcast/news"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "broadcast/news"));
+        assert!(!acl.check(Access::Publish, "dev1", None, "other"));
+        assert!(Acl::parse("default allow").unwrap().check(Access::Publish, "dev1", None, "other"));
+
+        let error = Acl::parse("allow all a/#\npermit all b").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: expected 'allow' or 'deny'");
+        assert!(Acl::parse("allow write a").is_err());
+        assert!(Acl::parse("allow all a/#/b").is_err());
+        assert!(Acl::parse("allow all a user").is_err());
+    }
+
+    #[test]
+    fn test_wildcards_in_substituted_values_are_rejected() {
+        let acl = deployment_acl();
+        assert!(!acl.check(Access::Subscribe, "#", None, "devices/#"));
+        assert!(!acl.check(Access::Subscribe, "dev1", Some("+"), "users/+/inbox"));
+    }
+}
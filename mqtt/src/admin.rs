@@ -0,0 +1,386 @@
+/// 管理HTTP服务
+/// 供运维通过HTTP采集和管理：`/metrics`以Prometheus文本格式导出代理统计，`/healthz`用于存活检查，
+/// JSON接口查看在线客户端和保留消息、断开客户端、以运维身份发布消息。
+/// 服务不做任何认证，能访问它的人都可以断开客户端和发布消息，只能监听在受信任的网络接口上
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::Arc;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use crate::packet::PublishPacket;
+use crate::properties::Properties;
+use crate::protocol::QoS;
+use crate::server::BrokerState;
+use crate::stats::{BrokerStats, DropReason, Gauges, SYS_PREFIX};
+use crate::topic::valid_topic_name;
+
+/// Prometheus文本格式的Content-Type
+pub const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+// 请求体的最大长度
+const MAX_BODY_SIZE: usize = 256 * 1024;
+
+/// `GET /clients`返回的在线客户端
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub subscriptions: Vec<SubscriptionInfo>,
+    /// 队列中尚未发送和已发送未确认的消息数
+    pub inflight: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SubscriptionInfo {
+    pub filter: String,
+    pub qos: u8,
+}
+
+/// `GET /retained`返回的保留消息
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RetainedInfo {
+    pub topic: String,
+    pub qos: u8,
+    pub payload_size: usize,
+}
+
+/// `POST /publish`的请求体
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PublishRequest {
+    pub topic: String,
+    pub payload: String,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+type AdminResponse = Response<Full<Bytes>>;
+
+/// 接受管理HTTP连接，每个连接在独立的任务中处理
+pub(crate) async fn run_admin_listener(listener: TcpListener, state: Arc<BrokerState>) {
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Error accepting admin connection: {}", e);
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |request| handle_request(request, state.clone()));
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(socket), service).await {
+                log::debug!("Error serving admin connection from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle_request(request: Request<Incoming>, state: Arc<BrokerState>) -> Result<AdminResponse, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+    let response = match (&method, path.as_str()) {
+        (&Method::GET, "/metrics") => {
+            let body = render_metrics(&state.stats, state.gauges().await);
+            text_response(StatusCode::OK, METRICS_CONTENT_TYPE, body)
+        }
+        (&Method::GET, "/healthz") => text_response(StatusCode::OK, "text/plain", "ok\n".to_string()),
+        (&Method::GET, "/clients") => json_response(StatusCode::OK, &state.connected_clients().await),
+        (&Method::GET, "/retained") => json_response(StatusCode::OK, &state.retained_topics().await),
+        (&Method::POST, "/publish") => publish(request, &state).await,
+        (&Method::DELETE, path) if path.starts_with("/clients/") => {
+            match percent_decode(&path["/clients/".len()..]) {
+                Some(client_id) if state.disconnect_client(&client_id).await => {
+                    log::info!("Client {} disconnected by operator", client_id);
+                    empty_response(StatusCode::NO_CONTENT)
+                }
+                Some(_) => error_response(StatusCode::NOT_FOUND, "client not connected"),
+                None => error_response(StatusCode::BAD_REQUEST, "invalid client id"),
+            }
+        }
+        (_, "/metrics" | "/healthz" | "/clients" | "/retained" | "/publish") => {
+            error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    };
+    Ok(response)
+}
+
+/// `POST /publish`：以运维身份发布一条消息，与客户端发布的消息走相同的路由
+///
+/// 请求没有认证，也不经过ACL检查，调用方可以向任何普通主题发布；
+/// 与客户端一样不能发布到由代理维护的`$SYS`主题。
+async fn publish(request: Request<Incoming>, state: &BrokerState) -> AdminResponse {
+    let body = match Limited::new(request.into_body(), MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error_response(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"),
+    };
+    let request: PublishRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("invalid request body: {}", e)),
+    };
+    let Some(qos) = QoS::from_u8(request.qos) else {
+        return error_response(StatusCode::BAD_REQUEST, "qos must be 0, 1 or 2");
+    };
+    if !valid_topic_name(&request.topic) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid topic name");
+    }
+    if request.topic.starts_with(SYS_PREFIX) {
+        return error_response(StatusCode::FORBIDDEN, "$SYS topics are published by the broker only");
+    }
+    log::info!("Operator publishing to topic '{}': {} bytes", request.topic, request.payload.len());
+    state.publish(PublishPacket {
+        dup: false,
+        qos,
+        retain: request.retain,
+        topic: request.topic,
+        packet_id: None,
+        properties: Properties::default(),
+        payload: request.payload.into(),
+    }).await;
+    empty_response(StatusCode::NO_CONTENT)
+}
+
+/// 解码路径中的百分号编码，结果不是合法的UTF-8时返回None
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn text_response(status: StatusCode, content_type: &'static str, body: String) -> AdminResponse {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(content_type));
+    response
+}
+
+fn json_response(status: StatusCode, body: &impl Serialize) -> AdminResponse {
+    match serde_json::to_string(body) {
+        Ok(body) => text_response(status, "application/json", body),
+        Err(e) => error_response(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+/// 错误响应，响应体为`{"error": "..."}`
+fn error_response(status: StatusCode, message: &str) -> AdminResponse {
+    let body = serde_json::json!({ "error": message }).to_string();
+    text_response(status, "application/json", body)
+}
+
+fn empty_response(status: StatusCode) -> AdminResponse {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+/// 以Prometheus文本格式输出代理统计
+pub fn render_metrics(stats: &BrokerStats, gauges: Gauges) -> String {
+    let qos_samples = |count: &dyn Fn(QoS) -> u64| {
+        [QoS::AtMostOnce, QoS::AtLeastOnce, QoS::ExactlyOnce]
+            .into_iter()
+            .map(|qos| (format!("qos=\"{}\"", qos as u8), count(qos)))
+            .collect::<Vec<_>>()
+    };
+    let drop_samples = DropReason::ALL
+        .into_iter()
+        .map(|reason| (format!("reason=\"{}\"", reason.as_str()), stats.messages_dropped(reason)))
+        .collect::<Vec<_>>();
+    let queue_samples = vec![
+        ("state=\"online\"".to_string(), gauges.queued_online as u64),
+        ("state=\"offline\"".to_string(), gauges.queued_offline as u64),
+    ];
+
+    let mut out = String::new();
+    write_metric(&mut out, "mqtt_uptime_seconds", "gauge", "Seconds since the broker started.", &[(String::new(), stats.uptime().as_secs())]);
+    write_metric(&mut out, "mqtt_connections_active", "gauge", "Currently connected clients.", &[(String::new(), gauges.clients_connected as u64)]);
+    write_metric(&mut out, "mqtt_connections_total", "counter", "Accepted CONNECT packets.", &[(String::new(), stats.connections())]);
+    write_metric(&mut out, "mqtt_auth_failures_total", "counter", "CONNECT packets rejected by authentication.", &[(String::new(), stats.auth_failures())]);
+    write_metric(&mut out, "mqtt_publish_received_total", "counter", "PUBLISH packets received from clients.", &qos_samples(&|qos| stats.messages_received_with_qos(qos)));
+    write_metric(&mut out, "mqtt_publish_sent_total", "counter", "PUBLISH packets sent to clients, excluding retransmissions.", &qos_samples(&|qos| stats.messages_sent_with_qos(qos)));
+    write_metric(&mut out, "mqtt_messages_dropped_total", "counter", "Messages dropped by the broker.", &drop_samples);
+    write_metric(&mut out, "mqtt_queued_messages", "gauge", "Messages waiting in session queues.", &queue_samples);
+    write_metric(&mut out, "mqtt_subscriptions", "gauge", "Active subscriptions, including shared subscriptions.", &[(String::new(), gauges.subscriptions as u64)]);
+    write_metric(&mut out, "mqtt_retained_messages", "gauge", "Stored retained messages.", &[(String::new(), gauges.retained as u64)]);
+    write_metric(&mut out, "mqtt_bytes_received_total", "counter", "Bytes received from clients.", &[(String::new(), stats.bytes_received())]);
+    write_metric(&mut out, "mqtt_bytes_sent_total", "counter", "Bytes sent to clients.", &[(String::new(), stats.bytes_sent())]);
+    out
+}
+
+/// 输出一个指标的HELP、TYPE和各个样本，样本的标签为空时不输出花括号
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, u64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            let _ = writeln!(out, "{} {}", name, value);
+        } else {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::MqttClient;
+    use crate::server::MqttBroker;
+    use crate::codec::MqttCodec;
+    use crate::packet::{ConnectPacket, Packet, SubscribePacket};
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+    use tokio_util::codec::Framed;
+
+    #[test]
+    fn test_render_metrics_in_prometheus_format() {
+        let stats = BrokerStats::new();
+        stats.message_received(QoS::AtLeastOnce);
+        stats.message_dropped(DropReason::Expired);
+        let gauges = Gauges { clients_connected: 2, queued_offline: 5, ..Gauges::default() };
+        let text = render_metrics(&stats, gauges);
+
+        assert!(text.contains("# TYPE mqtt_connections_active gauge\nmqtt_connections_active 2\n"));
+        assert!(text.contains("mqtt_publish_received_total{qos=\"0\"} 0\n"));
+        assert!(text.contains("mqtt_publish_received_total{qos=\"1\"} 1\n"));
+        assert!(text.contains("mqtt_messages_dropped_total{reason=\"expired\"} 1\n"));
+        assert!(text.contains("mqtt_queued_messages{state=\"offline\"} 5\n"));
+        // 每个样本行都以指标名开头，注释行以#开头
+        assert!(text.lines().all(|line| line.starts_with("# ") || line.starts_with("mqtt_")));
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("client-1").as_deref(), Some("client-1"));
+        assert_eq!(percent_decode("a%2Fb%20c").as_deref(), Some("a/b c"));
+        assert_eq!(percent_decode("bad%2"), None);
+        assert_eq!(percent_decode("bad%zz"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    /// 发送一个HTTP/1.1请求，返回(状态码, 响应体)
+    async fn http_request(addr: &str, method: &str, path: &str) -> (u16, String) {
+        http_request_with_body(addr, method, path, "").await
+    }
+
+    async fn http_request_with_body(addr: &str, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+            method, path, addr, body.len(), body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+        (status, body)
+    }
+
+    /// 启动带管理HTTP服务的代理，返回(MQTT地址, 管理地址)
+    async fn start_broker() -> (String, String) {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.enable_admin("127.0.0.1:0").await.unwrap();
+        let mqtt_addr = broker.local_addr().unwrap().to_string();
+        let admin_addr = broker.admin_local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+        (mqtt_addr, admin_addr)
+    }
+
+    #[tokio::test]
+    async fn test_metrics_and_healthz_endpoints() {
+        let (mqtt_addr, admin_addr) = start_broker().await;
+
+        assert_eq!(http_request(&admin_addr, "GET", "/healthz").await, (200, "ok\n".to_string()));
+        assert_eq!(http_request(&admin_addr, "GET", "/missing").await.0, 404);
+        assert_eq!(http_request(&admin_addr, "POST", "/metrics").await.0, 405);
+
+        let mut client = MqttClient::new("metrics-client".to_string());
+        client.connect(&mqtt_addr).await.unwrap();
+        client.publish("test/topic".to_string(), "hello".to_string(), QoS::AtLeastOnce).await.unwrap();
+
+        let (status, body) = http_request(&admin_addr, "GET", "/metrics").await;
+        assert_eq!(status, 200);
+        assert!(body.contains("mqtt_connections_active 1\n"));
+        assert!(body.contains("mqtt_connections_total 1\n"));
+        assert!(body.contains("mqtt_publish_received_total{qos=\"1\"} 1\n"));
+        assert!(body.contains("mqtt_auth_failures_total 0\n"));
+    }
+
+    #[tokio::test]
+    async fn test_rest_api_lists_kicks_and_publishes() {
+        let (mqtt_addr, admin_addr) = start_broker().await;
+        let socket = TcpStream::connect(&mqtt_addr).await.unwrap();
+        let mut device = Framed::new(socket, MqttCodec::new());
+        device.send(Packet::Connect(ConnectPacket::new("device-1".to_string()))).await.unwrap();
+        assert!(matches!(device.next().await, Some(Ok(Packet::Connack(_)))));
+        device.send(Packet::Subscribe(SubscribePacket {
+            packet_id: 1,
+            topics: vec![
+                ("cmd/device-1".to_string(), QoS::AtLeastOnce),
+                ("$share/workers/jobs/#".to_string(), QoS::AtMostOnce),
+            ],
+            properties: Properties::default(),
+        })).await.unwrap();
+        assert!(matches!(device.next().await, Some(Ok(Packet::Suback(_)))));
+
+        let (status, body) = http_request(&admin_addr, "GET", "/clients").await;
+        assert_eq!(status, 200);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap(),
+            serde_json::json!([{
+                "client_id": "device-1",
+                "subscriptions": [
+                    { "filter": "$share/workers/jobs/#", "qos": 0 },
+                    { "filter": "cmd/device-1", "qos": 1 },
+                ],
+                "inflight": 0,
+            }])
+        );
+
+        // 运维发布的消息照常路由给订阅者，保留消息出现在/retained中
+        let request = r#"{"topic": "cmd/device-1", "payload": "reboot", "qos": 1, "retain": true}"#;
+        assert_eq!(http_request_with_body(&admin_addr, "POST", "/publish", request).await.0, 204);
+        match device.next().await {
+            Some(Ok(Packet::Publish(publish))) => {
+                assert_eq!(publish.topic, "cmd/device-1");
+                assert_eq!(&publish.payload[..], b"reboot");
+                assert_eq!(publish.qos, QoS::AtLeastOnce);
+            }
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+        // 列表中还包括代理自己发布的$SYS保留消息
+        let (_, body) = http_request(&admin_addr, "GET", "/retained").await;
+        let retained: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        assert!(retained.contains(&serde_json::json!({ "topic": "cmd/device-1", "qos": 1, "payload_size": 6 })));
+        let invalid = r#"{"topic": "cmd/#", "payload": "x"}"#;
+        assert_eq!(http_request_with_body(&admin_addr, "POST", "/publish", invalid).await.0, 400);
+        assert_eq!(http_request_with_body(&admin_addr, "POST", "/publish", "not json").await.0, 400);
+        // $SYS主题只能由代理发布
+        let sys = r#"{"topic": "$SYS/broker/uptime", "payload": "0"}"#;
+        assert_eq!(http_request_with_body(&admin_addr, "POST", "/publish", sys).await.0, 403);
+
+        // 断开客户端：连接被关闭，之后不再出现在列表中
+        assert_eq!(http_request(&admin_addr, "DELETE", "/clients/device-1").await.0, 204);
+        assert!(device.next().await.is_none());
+        assert_eq!(http_request(&admin_addr, "DELETE", "/clients/device-1").await.0, 404);
+        assert_eq!(http_request(&admin_addr, "GET", "/clients").await.1, "[]");
+    }
+}
@@ -0,0 +1,233 @@
+/// 代理端身份认证
+/// 根据CONNECT中的用户名和密码决定CONNACK返回码，
+/// 提供内存用户表和bcrypt密码文件两种实现；
+/// MQTT 5.0的增强认证（AUTH包质询/响应）通过`Authenticator::authenticate_enhanced`扩展
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use bytes::Bytes;
+use crate::protocol::reason_code;
+
+/// 认证结果，对应CONNACK返回码
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthResult {
+    /// 0x00：连接已接受
+    Accepted,
+    /// 0x04：用户名或密码错误
+    BadCredentials,
+    /// 0x05：未授权
+    NotAuthorized,
+}
+
+impl AuthResult {
+    /// 转换为CONNACK返回码
+    pub fn return_code(self) -> u8 {
+        match self {
+            AuthResult::Accepted => 0x00,
+            AuthResult::BadCredentials => 0x04,
+            AuthResult::NotAuthorized => 0x05,
+        }
+    }
+
+    /// 转换为MQTT 5.0的CONNACK原因码
+    pub fn reason_code(self) -> u8 {
+        match self {
+            AuthResult::Accepted => reason_code::SUCCESS,
+            AuthResult::BadCredentials => reason_code::BAD_USERNAME_OR_PASSWORD,
+            AuthResult::NotAuthorized => reason_code::NOT_AUTHORIZED,
+        }
+    }
+}
+
+/// 增强认证中一轮质询/响应的结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnhancedAuth {
+    /// 认证尚未完成，把质询数据通过AUTH（0x18）发给客户端
+    Continue(Bytes),
+    /// 认证成功，可选的数据随CONNACK或AUTH（0x00）发给客户端
+    Success(Option<Bytes>),
+    /// 认证失败，携带CONNACK或DISCONNECT使用的原因码
+    Failure(u8),
+}
+
+/// 可插拔的认证器，在代理处理CONNECT时调用
+#[async_trait::async_trait]
+pub trait Authenticator: Send + Sync {
+    async fn authenticate(&self, client_id: &str, username: Option<&str>, password: Option<&[u8]>) -> AuthResult;
+
+    /// MQTT 5.0增强认证，CONNECT携带认证方法时代替`authenticate`调用
+    ///
+    /// 每收到一次认证数据（CONNECT或AUTH包）调用一次，多轮认证的中间状态由实现按客户端标识符保存。
+    /// 默认不支持任何认证方法。
+    async fn authenticate_enhanced(&self, _client_id: &str, _method: &str, _data: Option<&[u8]>) -> EnhancedAuth {
+        EnhancedAuth::Failure(reason_code::BAD_AUTHENTICATION_METHOD)
+    }
+}
+
+/// 接受所有连接的认证器，代理的默认行为
+#[derive(Debug, Default)]
+pub struct AllowAll;
+
+#[async_trait::async_trait]
+impl Authenticator for AllowAll {
+    async fn authenticate(&self, _client_id: &str, _username: Option<&str>, _password: Option<&[u8]>) -> AuthResult {
+        AuthResult::Accepted
+    }
+}
+
+/// 内存中的明文用户表
+#[derive(Debug, Default)]
+pub struct InMemoryAuthenticator {
+    users: HashMap<String, String>,
+    allow_anonymous: bool,
+}
+
+impl InMemoryAuthenticator {
+    pub fn new() -> Self {
+        InMemoryAuthenticator::default()
+    }
+
+    /// 添加或替换用户
+    pub fn add_user(&mut self, username: &str, password: &str) {
+        self.users.insert(username.to_string(), password.to_string());
+    }
+
+    /// 是否允许不带用户名的连接，默认不允许
+    pub fn set_allow_anonymous(&mut self, allow_anonymous: bool) {
+        self.allow_anonymous = allow_anonymous;
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for InMemoryAuthenticator {
+    async fn authenticate(&self, _client_id: &str, username: Option<&str>, password: Option<&[u8]>) -> AuthResult {
+        let Some(username) = username else {
+            return anonymous_result(self.allow_anonymous);
+        };
+        match self.users.get(username) {
+            Some(expected) if password == Some(expected.as_bytes()) => AuthResult::Accepted,
+            _ => AuthResult::BadCredentials,
+        }
+    }
+}
+
+/// bcrypt密码文件认证器
+///
+/// 文件每行一个用户，格式为`用户名:bcrypt哈希`，空行和以`#`开头的行被忽略。
+#[derive(Debug, Default)]
+pub struct PasswordFileAuthenticator {
+    hashes: HashMap<String, Arc<str>>,
+    allow_anonymous: bool,
+}
+
+impl PasswordFileAuthenticator {
+    /// 从密码文件加载用户
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        Self::parse(&contents)
+    }
+
+    /// 解析密码文件内容
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut hashes = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, hash) = line.split_once(':').ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected 'username:hash'", number + 1))
+            })?;
+            hashes.insert(username.to_string(), Arc::from(hash));
+        }
+        Ok(PasswordFileAuthenticator {
+            hashes,
+            allow_anonymous: false,
+        })
+    }
+
+    /// 是否允许不带用户名的连接，默认不允许
+    pub fn set_allow_anonymous(&mut self, allow_anonymous: bool) {
+        self.allow_anonymous = allow_anonymous;
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for PasswordFileAuthenticator {
+    async fn authenticate(&self, _client_id: &str, username: Option<&str>, password: Option<&[u8]>) -> AuthResult {
+        let Some(username) = username else {
+            return anonymous_result(self.allow_anonymous);
+        };
+        let (Some(hash), Some(password)) = (self.hashes.get(username), password) else {
+            return AuthResult::BadCredentials;
+        };
+
+        // bcrypt校验是CPU密集操作，放到阻塞线程池中执行，避免阻塞其他连接
+        let hash = hash.clone();
+        let password = password.to_vec();
+        let verified = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result.map_err(|e| e.to_string()));
+        match verified {
+            Ok(true) => AuthResult::Accepted,
+            Ok(false) => AuthResult::BadCredentials,
+            Err(e) => {
+                log::error!("Failed to verify password of user '{}': {}", username, e);
+                AuthResult::BadCredentials
+            }
+        }
+    }
+}
+
+fn anonymous_result(allow_anonymous: bool) -> AuthResult {
+    if allow_anonymous {
+        AuthResult::Accepted
+    } else {
+        AuthResult::NotAuthorized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_in_memory_authenticator() {
+        let mut auth = InMemoryAuthenticator::new();
+        auth.add_user("alice", "secret");
+
+        assert_eq!(auth.authenticate("c1", Some("alice"), Some(b"secret")).await, AuthResult::Accepted);
+        assert_eq!(auth.authenticate("c1", Some("alice"), Some(b"wrong")).await, AuthResult::BadCredentials);
+        assert_eq!(auth.authenticate("c1", Some("bob"), Some(b"secret")).await, AuthResult::BadCredentials);
+        assert_eq!(auth.authenticate("c1", None, None).await, AuthResult::NotAuthorized);
+
+        auth.set_allow_anonymous(true);
+        assert_eq!(auth.authenticate("c1", None, None).await, AuthResult::Accepted);
+    }
+
+    #[tokio::test]
+    async fn test_password_file_authenticator() {
+        // 测试中使用最低的cost，保持测试速度
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        let contents = format!("# users\n\nalice:{}\n", hash);
+        let auth = PasswordFileAuthenticator::parse(&contents).unwrap();
+
+        assert_eq!(auth.authenticate("c1", Some("alice"), Some(b"secret")).await, AuthResult::Accepted);
+        assert_eq!(auth.authenticate("c1", Some("alice"), Some(b"wrong")).await, AuthResult::BadCredentials);
+        assert_eq!(auth.authenticate("c1", Some("alice"), None).await, AuthResult::BadCredentials);
+        assert_eq!(auth.authenticate("c1", None, None).await, AuthResult::NotAuthorized);
+
+        assert!(PasswordFileAuthenticator::parse("no-separator").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_enhanced_auth_unsupported_by_default() {
+        assert_eq!(AuthResult::BadCredentials.reason_code(), reason_code::BAD_USERNAME_OR_PASSWORD);
+        assert_eq!(
+            AllowAll.authenticate_enhanced("c1", "SCRAM-SHA-1", None).await,
+            EnhancedAuth::Failure(reason_code::BAD_AUTHENTICATION_METHOD)
+        );
+    }
+}
@@ -1,541 +1,1425 @@
 /// MQTT客户端实现
+/// 默认使用MQTT 3.1.1，`set_protocol_version(5)`后以MQTT 5.0连接
+///
+/// 连接建立后由后台事件循环任务独占连接，负责收发数据包、QoS确认流程、重传和保活；
+/// `ClientHandle`可以克隆到多个任务中并发发布、订阅和断开连接。
 use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, AsyncReadExt};
-use std::collections::HashMap;
-use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio_util::codec::Framed;
+use futures::{SinkExt, Stream, StreamExt};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::time::Instant;
 use tokio::sync::Mutex;
-use crate::packet::ConnectPacket;
-use crate::protocol::PacketType;
-use bytes::{BytesMut, BufMut};
+use crate::codec::MqttCodec;
+use crate::error::{ConnackCode, MqttError};
+use crate::inflight::{InflightWindow, DEFAULT_RETRY_INTERVAL};
+use crate::options::{keep_alive_secs, MqttOptions};
+use crate::packet::{
+    check_field_len, AckPacket, AuthPacket, ConnackPacket, ConnectPacket, DisconnectPacket, LastWill, Packet,
+    PublishPacket, SubscribePacket, UnsubscribePacket,
+};
+use crate::properties::Properties;
+use crate::reconnect::ReconnectPolicy;
+use crate::protocol::{
+    reason_code, PacketType, QoS, CLEAN_SESSION, MAX_REMAINING_LENGTH, MQTT_PROTOCOL_VERSION, MQTT_PROTOCOL_VERSION_5,
+    SUBACK_FAILURE,
+};
+use crate::shared;
+use crate::tls::TlsClientOptions;
+use crate::topic::{valid_topic_name, TopicTrie};
+pub use crate::transport::Transport;
 use crate::patterns::{Command, State, ClientState, DisconnectedState, ConnectedState};
-use crate::patterns::states::{ConnectingState, DisconnectingState};
+
+/// 句柄请求队列的容量，队列满时调用方等待事件循环处理
+const REQUEST_QUEUE_CAPACITY: usize = 64;
+
+/// 每个消息流最多缓存的未读消息数，已满时丢弃发给该流的新消息
+pub const MESSAGE_STREAM_CAPACITY: usize = 1000;
+
+// 与代理之间按MQTT数据包收发的连接
+type BrokerFramed = Framed<Box<dyn Transport>, MqttCodec>;
+
+// 当前事件循环的请求通道，未连接时为None
+type RequestSlot = std::sync::Mutex<Option<mpsc::Sender<Request>>>;
 
 // 消息回调类型
-type MessageCallback = Box<dyn Fn(String, String) + Send + Sync>;
+enum MessageCallback {
+    /// 只关心主题和负载
+    Payload(Box<dyn Fn(String, Bytes) + Send + Sync>),
+    /// 需要完整的PUBLISH包，例如读取MQTT 5.0的响应主题和关联数据
+    Publish(Box<dyn Fn(&PublishPacket) + Send + Sync>),
+}
+
+// 增强认证中根据代理的质询数据生成响应数据
+type AuthResponder = Box<dyn Fn(Option<&[u8]>) -> Option<Bytes> + Send + Sync>;
+
+/// 增强认证（MQTT 5.0）的配置
+struct EnhancedAuthConfig {
+    method: String,
+    data: Option<Bytes>,
+    responder: AuthResponder,
+}
+
+/// 句柄发给事件循环的请求，结果通过oneshot通道返回
+// 请求在有界队列中只短暂停留，不必为PUBLISH装箱
+#[allow(clippy::large_enum_variant)]
+enum Request {
+    Publish { publish: PublishPacket, reply: oneshot::Sender<Result<(), MqttError>> },
+    Subscribe { topics: Vec<(String, QoS)>, reply: oneshot::Sender<Result<Vec<u8>, MqttError>> },
+    Unsubscribe { topics: Vec<String>, reply: oneshot::Sender<Result<(), MqttError>> },
+    Disconnect { reply: oneshot::Sender<Result<(), MqttError>> },
+}
+
+/// 等待代理应答的SUBSCRIBE或UNSUBSCRIBE
+enum PendingAck {
+    Subscribe { topics: Vec<(String, QoS)>, reply: oneshot::Sender<Result<Vec<u8>, MqttError>> },
+    Unsubscribe { topics: Vec<String>, reply: oneshot::Sender<Result<(), MqttError>> },
+}
+
+/// 跨连接保留的会话状态：未完成的发送和接收确认流程
+#[derive(Default)]
+struct Session {
+    // 发出的QoS 1/2消息
+    inflight: InflightWindow,
+    // 已收到但尚未收到PUBREL的QoS 2包标识符，用于去重
+    incoming_qos2: HashSet<u16>,
+}
+
+/// 客户端、句柄和事件循环共享的状态
+struct Shared {
+    state: std::sync::Mutex<Box<dyn State + Send>>,
+    // 主题过滤器 -> 回调列表，支持通配符匹配
+    subscriptions: Mutex<TopicTrie<Vec<MessageCallback>>>,
+    // 通过`messages()`创建的消息流
+    listeners: std::sync::Mutex<Vec<mpsc::Sender<PublishPacket>>>,
+}
+
+impl Shared {
+    fn get_state(&self) -> ClientState {
+        self.state.lock().unwrap().get_state()
+    }
+
+    fn can_execute_command(&self, command_name: &str) -> bool {
+        self.state.lock().unwrap().can_execute_command(command_name)
+    }
+
+    fn set_state(&self, state: Box<dyn State + Send>) {
+        *self.state.lock().unwrap() = state;
+    }
+
+    /// 状态转换
+    fn transition_to(&self, target_state: ClientState) {
+        let mut state = self.state.lock().unwrap();
+        let target_state_copy = target_state.clone(); // 创建副本用于日志记录
+        if let Some(new_state) = state.transition_to(target_state) {
+            log::debug!("Transitioning from {:?} to {:?}", state.get_state(), new_state.get_state());
+            *state = new_state;
+        } else {
+            log::warn!("Invalid state transition from {:?} to {:?}", state.get_state(), target_state_copy);
+        }
+    }
+
+    /// 将PUBLISH消息分发给已注册的回调和消息流
+    async fn dispatch_publish(&self, publish: &PublishPacket) {
+        log::info!("Received PUBLISH message on topic '{}': {} bytes", publish.topic, publish.payload.len());
+
+        // 触发所有匹配过滤器上的回调
+        let subs = self.subscriptions.lock().await;
+        for callbacks in subs.matches(&publish.topic) {
+            for callback in callbacks {
+                match callback {
+                    MessageCallback::Payload(callback) => callback(publish.topic.clone(), publish.payload.clone()),
+                    MessageCallback::Publish(callback) => callback(publish),
+                }
+            }
+        }
+        drop(subs);
+
+        // 事件循环不等待消息流：已满的流丢弃这条消息，接收端已被丢弃的流被移除
+        self.listeners.lock().unwrap().retain(|listener| match listener.try_send(publish.clone()) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                log::warn!("Message stream is full, dropping message on topic '{}'", publish.topic);
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        });
+    }
+}
+
+/// 收到的PUBLISH消息流，由`messages()`创建
+///
+/// 每个流都收到所有订阅上的消息，与回调互不影响；客户端重新连接后继续有效，
+/// 客户端和所有句柄都被丢弃后结束。消费过慢时最多缓存`MESSAGE_STREAM_CAPACITY`条消息，
+/// 之后发给该流的新消息被丢弃，不影响其他流、回调和连接。
+pub struct MessageStream {
+    receiver: mpsc::Receiver<PublishPacket>,
+}
+
+impl Stream for MessageStream {
+    type Item = PublishPacket;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// 已连接客户端的句柄，克隆开销很小，可以在多个任务中并发使用
+///
+/// 请求交给后台事件循环执行，方法在代理完成确认后返回。
+/// `MqttClient`和所有句柄都被丢弃后事件循环直接关闭连接，不发送DISCONNECT，代理会发布遗嘱消息。
+#[derive(Clone)]
+pub struct ClientHandle {
+    shared: Arc<Shared>,
+    requests: Arc<RequestSlot>,
+}
+
+impl ClientHandle {
+    /// 获取当前状态
+    pub fn get_state(&self) -> ClientState {
+        self.shared.get_state()
+    }
+
+    /// 创建接收所有订阅消息的异步流
+    pub fn messages(&self) -> MessageStream {
+        let (sender, receiver) = mpsc::channel(MESSAGE_STREAM_CAPACITY);
+        let mut listeners = self.shared.listeners.lock().unwrap();
+        // 顺便移除已被丢弃的流，没有消息到达时它们不会在分发时被清理
+        listeners.retain(|listener| !listener.is_closed());
+        listeners.push(sender);
+        MessageStream { receiver }
+    }
+
+    /// 以指定的QoS发布消息，负载可以是`Bytes`、`Vec<u8>`、`String`等任何能转换为`Bytes`的类型，按原样发送
+    ///
+    /// QoS 0在数据包写出后返回；QoS 1在收到PUBACK后返回；
+    /// QoS 2在完成PUBREC/PUBREL/PUBCOMP四次握手后返回。
+    pub async fn publish(&self, topic: String, payload: impl Into<Bytes>, qos: QoS) -> Result<(), MqttError> {
+        self.publish_with_properties(topic, payload, qos, Properties::default()).await
+    }
+
+    /// 将值序列化为JSON后发布
+    pub async fn publish_json<T: Serialize + ?Sized>(&self, topic: String, value: &T, qos: QoS) -> Result<(), MqttError> {
+        let payload = serde_json::to_vec(value)?;
+        self.publish(topic, payload, qos).await
+    }
+
+    /// 携带MQTT 5.0属性发布消息，例如用户属性、消息过期间隔，或请求/响应使用的响应主题和关联数据
+    ///
+    /// 以3.1.1连接时属性不会被发送。代理以失败原因码确认时返回错误；
+    /// 主题名不合法（为空、含通配符或超过65535字节）或属性超长时返回`MqttError::InvalidArgument`。
+    pub async fn publish_with_properties(
+        &self,
+        topic: String,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+        properties: Properties,
+    ) -> Result<(), MqttError> {
+        if !valid_topic_name(&topic) {
+            return Err(MqttError::InvalidArgument(format!("invalid topic name '{}'", topic)));
+        }
+        properties.check_field_lengths().map_err(|e| MqttError::InvalidArgument(e.to_string()))?;
+        let mut publish = MqttClient::create_publish_packet(&topic, payload.into(), qos);
+        publish.properties = properties;
+        self.request("Publish", |reply| Request::Publish { publish, reply }).await
+    }
+
+    /// 以指定的QoS订阅主题
+    pub async fn subscribe(&self, topic: String, qos: QoS) -> Result<(), MqttError> {
+        let return_codes = self.subscribe_many(&[(topic.clone(), qos)]).await?;
+        if let Some(code) = return_codes.first().filter(|code| **code >= SUBACK_FAILURE) {
+            return Err(MqttError::SubscriptionRejected(topic, *code));
+        }
+        Ok(())
+    }
+
+    /// 在一个SUBSCRIBE包中订阅多个主题过滤器
+    ///
+    /// 返回SUBACK中每个过滤器对应的返回码：授予的QoS，或0x80及以上的值表示订阅失败。
+    pub async fn subscribe_many(&self, topics: &[(String, QoS)]) -> Result<Vec<u8>, MqttError> {
+        if topics.is_empty() {
+            return Err(MqttError::InvalidArgument("SUBSCRIBE requires at least one topic filter".to_string()));
+        }
+        for (topic, _) in topics {
+            check_field_len("topic filter", topic.len()).map_err(|e| MqttError::InvalidArgument(e.to_string()))?;
+        }
+        let topics = topics.to_vec();
+        self.request("Subscribe", |reply| Request::Subscribe { topics, reply }).await
+    }
+
+    /// 取消订阅主题过滤器，收到UNSUBACK后返回
+    pub async fn unsubscribe(&self, topic: String) -> Result<(), MqttError> {
+        check_field_len("topic filter", topic.len()).map_err(|e| MqttError::InvalidArgument(e.to_string()))?;
+        self.request("Unsubscribe", |reply| Request::Unsubscribe { topics: vec![topic], reply }).await
+    }
+
+    /// 发送DISCONNECT并关闭连接，事件循环随之结束
+    pub async fn disconnect(&self) -> Result<(), MqttError> {
+        self.request("Disconnect", |reply| Request::Disconnect { reply }).await
+    }
+
+    /// 检查当前状态后把请求交给事件循环，并等待执行结果
+    async fn request<T>(
+        &self,
+        command_name: &'static str,
+        request: impl FnOnce(oneshot::Sender<Result<T, MqttError>>) -> Request,
+    ) -> Result<T, MqttError> {
+        if !self.shared.can_execute_command(command_name) {
+            return Err(MqttError::InvalidState { operation: command_name, state: self.get_state() });
+        }
+        let sender = self.requests.lock().unwrap().clone().ok_or(MqttError::NotConnected)?;
+        let (reply, response) = oneshot::channel();
+        sender.send(request(reply)).await.map_err(|_| MqttError::NotConnected)?;
+        response.await.map_err(|_| MqttError::ConnectionLost)?
+    }
+}
 
 pub struct MqttClient {
-    client_id: String,
-    stream: Option<TcpStream>,
-    subscriptions: Arc<Mutex<HashMap<String, Vec<MessageCallback>>>>,
-    state: Box<dyn State + Send>,
+    // 连接选项，代理分配的客户端标识符和指定的保活时间也记录在这里
+    options: MqttOptions,
+    shared: Arc<Shared>,
+    // 客户端自己的句柄，`handle()`返回它的克隆
+    handle: ClientHandle,
+    // 正在运行的事件循环，结束时交回会话状态和结束原因
+    event_loop: Option<JoinHandle<(Session, Result<(), MqttError>)>>,
+    // 事件循环未运行时保存的会话状态
+    session: Session,
+    retry_interval: Duration,
+    // 最近一次CONNACK中代理是否恢复了已有会话
+    session_present: bool,
+    // CONNECT中使用的协议级别：4（3.1.1）或5（5.0）
+    protocol_version: u8,
+    // 断开后代理保留会话的秒数（MQTT 5.0）
+    session_expiry_interval: u32,
+    // 增强认证的方法、初始数据和质询响应函数（MQTT 5.0）
+    auth: Option<Arc<EnhancedAuthConfig>>,
+    // 设置后连接意外断开时自动重连
+    reconnect: Option<ReconnectPolicy>,
 }
 
 impl MqttClient {
-    pub fn new(client_id: String) -> Self {
+    /// 以连接选项创建客户端；只需要默认选项时可以直接传入客户端标识符
+    ///
+    /// 选项在`connect`时检查，不合法时返回`MqttError::InvalidArgument`。
+    pub fn new(options: impl Into<MqttOptions>) -> Self {
+        let shared = Arc::new(Shared {
+            state: std::sync::Mutex::new(Box::new(DisconnectedState)),
+            subscriptions: Mutex::new(TopicTrie::new()),
+            listeners: std::sync::Mutex::new(Vec::new()),
+        });
+        let handle = ClientHandle {
+            shared: shared.clone(),
+            requests: Arc::new(std::sync::Mutex::new(None)),
+        };
         MqttClient {
-            client_id,
-            stream: None,
-            subscriptions: Arc::new(Mutex::new(HashMap::new())),
-            state: Box::new(DisconnectedState),
+            options: options.into(),
+            shared,
+            handle,
+            event_loop: None,
+            session: Session::default(),
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            session_present: false,
+            protocol_version: MQTT_PROTOCOL_VERSION,
+            session_expiry_interval: 0,
+            auth: None,
+            reconnect: None,
         }
     }
 
-    /// 获取当前状态
-    pub fn get_state(&self) -> ClientState {
-        self.state.get_state()
+    /// 客户端标识符；以空标识符连接MQTT 5.0代理后为代理分配的标识符
+    pub fn client_id(&self) -> &str {
+        self.options.client_id()
     }
 
-    /// 设置状态
-    fn set_state(&mut self, state: Box<dyn State + Send>) {
-        self.state = state;
+    /// 当前的连接选项
+    pub fn options(&self) -> &MqttOptions {
+        &self.options
     }
-    
-    /// 状态转换
-    fn transition_to(&mut self, target_state: ClientState) {
-        let target_state_copy = target_state.clone(); // 创建副本用于日志记录
-        if let Some(new_state) = self.state.transition_to(target_state) {
-            log::debug!("Transitioning from {:?} to {:?}", self.state.get_state(), new_state.get_state());
-            self.state = new_state;
-        } else {
-            log::warn!("Invalid state transition from {:?} to {:?}", self.state.get_state(), target_state_copy);
+
+    /// 获取可以在其他任务中并发发布、订阅和断开连接的句柄
+    ///
+    /// 句柄在重新连接后继续有效。
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+
+    /// 创建接收所有订阅消息的异步流，可以与回调同时使用
+    pub fn messages(&self) -> MessageStream {
+        self.handle.messages()
+    }
+
+    /// 设置协议级别，4为MQTT 3.1.1（默认），5为MQTT 5.0，在下次连接时生效
+    pub fn set_protocol_version(&mut self, protocol_version: u8) -> Result<(), MqttError> {
+        if protocol_version != MQTT_PROTOCOL_VERSION && protocol_version != MQTT_PROTOCOL_VERSION_5 {
+            return Err(MqttError::InvalidArgument(format!("unsupported protocol level {}", protocol_version)));
         }
+        self.protocol_version = protocol_version;
+        Ok(())
+    }
+
+    /// 设置会话过期间隔（秒，仅MQTT 5.0），在下次连接时生效
+    ///
+    /// 代理在连接断开后保留会话的时间，`SESSION_EXPIRY_NEVER`表示永不过期；默认为0，会话随连接删除。
+    pub fn set_session_expiry_interval(&mut self, session_expiry_interval: u32) {
+        self.session_expiry_interval = session_expiry_interval;
+    }
+
+    /// 使用增强认证（仅MQTT 5.0），在下次连接时生效
+    ///
+    /// `data`随CONNECT发送；代理每次以AUTH发来质询时调用`responder`生成响应数据。
+    pub fn set_authentication<F>(&mut self, method: String, data: Option<Bytes>, responder: F)
+    where
+        F: Fn(Option<&[u8]>) -> Option<Bytes> + Send + Sync + 'static,
+    {
+        self.auth = Some(Arc::new(EnhancedAuthConfig {
+            method,
+            data,
+            responder: Box::new(responder),
+        }));
+    }
+
+    /// 设置遗嘱消息，在下次连接时生效
+    ///
+    /// 客户端未发送DISCONNECT就断开连接时，代理会将该消息发布到指定主题。
+    pub fn set_will(&mut self, topic: String, message: impl Into<Bytes>, qos: QoS, retain: bool) {
+        self.options.will = Some(LastWill {
+            topic,
+            message: message.into(),
+            qos,
+            retain,
+            properties: Properties::default(),
+        });
+    }
+
+    /// 清除遗嘱消息
+    pub fn clear_will(&mut self) {
+        self.options.will = None;
+    }
+
+    /// 设置连接时使用的用户名和密码
+    pub fn set_credentials(&mut self, username: String, password: String) {
+        self.options.username = Some(username);
+        self.options.password = Some(password);
+    }
+
+    /// 设置是否清除会话，在下次连接时生效
+    ///
+    /// 设为false时代理会按客户端标识符保留订阅，并缓存离线期间的QoS 1/2消息。
+    pub fn set_clean_session(&mut self, clean_session: bool) {
+        self.options.clean_session = clean_session;
+    }
+
+    /// 最近一次连接时代理是否恢复了已有会话
+    pub fn session_present(&self) -> bool {
+        self.session_present
+    }
+
+    /// 设置保活时间，在下次连接时生效，取整规则见[`MqttOptions::keep_alive`]
+    ///
+    /// 空闲超过保活时间时自动发送PINGREQ，再过一个保活时间仍未收到PINGRESP则视为连接断开。
+    pub fn set_keep_alive(&mut self, keep_alive: Duration) {
+        self.options.keep_alive = keep_alive_secs(keep_alive);
+    }
+
+    /// 通过TLS连接代理，在下次连接时生效
+    pub fn set_tls(&mut self, tls: TlsClientOptions) {
+        self.options.tls = Some(tls);
+    }
+
+    /// 连接意外断开时按策略自动重连，在下次连接时生效
+    ///
+    /// 重连期间状态为`Reconnecting`，发布请求缓存到重新连接后发送，等待确认的发布在重连后继续完成；
+    /// 代理没有保留会话时自动重新订阅之前订阅成功的所有主题过滤器。
+    pub fn set_reconnect_policy(&mut self, policy: ReconnectPolicy) {
+        self.reconnect = Some(policy);
+    }
+
+    /// 设置未确认消息的重传间隔，在下次连接时生效
+    pub fn set_retry_interval(&mut self, retry_interval: Duration) {
+        self.retry_interval = retry_interval;
+    }
+
+    /// 获取当前状态
+    pub fn get_state(&self) -> ClientState {
+        self.shared.get_state()
     }
 
     /// 执行命令
-    pub async fn execute_command(&mut self, command: &dyn Command) -> Result<(), Box<dyn std::error::Error>> {
+    pub async fn execute_command(&mut self, command: &dyn Command) -> Result<(), MqttError> {
         // 检查当前状态是否允许执行该命令
-        if !self.state.can_execute_command(command.get_name()) {
-            return Err(format!("Cannot execute {} command in {:?} state", 
-                              command.get_name(), self.state.get_state()).into());
+        if !self.shared.can_execute_command(command.get_name()) {
+            return Err(MqttError::InvalidState { operation: command.get_name(), state: self.get_state() });
         }
 
         // 执行命令
         let result = command.execute(self).await;
-        
+
         // 根据命令类型和执行结果更新状态
-        match command.get_name() {
-            "Connect" => {
-                if result.is_ok() {
-                    self.set_state(Box::new(ConnectedState));
-                }
-            },
-            _ => {}
+        if command.get_name() == "Connect" && result.is_ok() {
+            self.shared.set_state(Box::new(ConnectedState));
         }
-        
+
         result
     }
 
-    /// 连接到MQTT代理
-    pub async fn connect(&mut self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
+    /// 连接到MQTT代理，成功后启动后台事件循环
+    ///
+    /// 连接选项不合法、超过连接超时时间或代理拒绝连接时返回错误，客户端回到断开连接状态。
+    pub async fn connect(&mut self, addr: &str) -> Result<(), MqttError> {
         // 检查当前状态是否允许连接
-        if !self.state.can_execute_command("Connect") {
-            return Err(format!("Cannot connect in {:?} state", self.state.get_state()).into());
+        if !self.shared.can_execute_command("Connect") {
+            return Err(MqttError::InvalidState { operation: "Connect", state: self.get_state() });
         }
-        
+        self.options.validate(self.protocol_version)?;
+
         // 进入连接中状态
-        self.transition_to(ClientState::Connecting);
-        
-        let stream_result = TcpStream::connect(addr).await;
-        
-        match stream_result {
-            Ok(stream) => {
-                self.stream = Some(stream);
-                
-                // 创建并发送CONNECT包
-                let connect_packet = ConnectPacket::new(self.client_id.clone());
-                let encoded_packet = connect_packet.encode();
-                
-                log::debug!("Sending CONNECT packet: {:02x?}", encoded_packet);
-                
-                if let Some(ref mut stream) = self.stream {
-                    stream.write_all(&encoded_packet).await?;
-                    
-                    // 读取CONNACK响应（最大4字节）
-                    let mut buffer = [0u8; 4];
-                    match stream.read(&mut buffer).await {
-                        Ok(n) => {
-                            if n > 0 {
-                                log::debug!("Received {} bytes for CONNACK: {:02x?}", n, &buffer[..n]);
-                                
-                                // 验证CONNACK包类型
-                                let packet_type = (buffer[0] >> 4) as u8;
-                                if packet_type == PacketType::CONNACK as u8 {
-                                    // 解析CONNACK包
-                                    if n >= 4 {
-                                        let ack_flags = buffer[2];
-                                        let return_code = buffer[3];
-                                        log::info!("Connected to MQTT broker at {}, ack_flags: {:02x}, return_code: {:02x}", 
-                                            addr, ack_flags, return_code);
-                                        
-                                        match return_code {
-                                            0x00 => log::info!("CONNACK: Connection accepted"),
-                                            0x01 => log::error!("CONNACK: Unacceptable protocol version"),
-                                            0x02 => log::error!("CONNACK: Identifier rejected"),
-                                            0x03 => log::error!("CONNACK: Server unavailable"),
-                                            0x04 => log::error!("CONNACK: Bad user name or password"),
-                                            0x05 => log::error!("CONNACK: Not authorized"),
-                                            _ => log::error!("CONNACK: Unknown return code: {:02x}", return_code),
-                                        }
-                                        
-                                        if return_code == 0x00 {
-                                            // 连接成功，更新状态
-                                            self.transition_to(ClientState::Connected);
-                                            Ok(())
-                                        } else {
-                                            // 连接失败，回到断开连接状态
-                                            self.transition_to(ClientState::Disconnected);
-                                            Err("Connection rejected by broker".into())
-                                        }
-                                    } else {
-                                        log::error!("CONNACK packet too short: {} bytes", n);
-                                        self.transition_to(ClientState::Disconnected);
-                                        Err("Failed to connect: Invalid response".into())
-                                    }
-                                } else {
-                                    log::error!("Invalid CONNACK response, expected packet type {:?}, got {}", 
-                                        PacketType::CONNACK, packet_type);
-                                    self.transition_to(ClientState::Disconnected);
-                                    Err("Failed to connect: Invalid response".into())
-                                }
-                            } else {
-                                log::error!("No data received for CONNACK");
-                                self.transition_to(ClientState::Disconnected);
-                                Err("Failed to connect: No response".into())
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("Failed to read CONNACK: {}", e);
-                            self.transition_to(ClientState::Disconnected);
-                            Err(e.into())
-                        }
+        self.shared.transition_to(ClientState::Connecting);
+
+        let connector = self.connector(addr);
+        let result = connector.connect().await;
+        self.start_event_loop(connector, result).await
+    }
+
+    /// 根据当前设置创建连接参数
+    fn connector(&self, addr: &str) -> Connector {
+        let options = &self.options;
+        let mut connect_packet = ConnectPacket::new(options.client_id.clone());
+        connect_packet.will = options.will.clone();
+        connect_packet.username = options.username.clone();
+        connect_packet.password = options.password.clone();
+        connect_packet.keep_alive = options.keep_alive.as_secs() as u16;
+        if !options.clean_session {
+            connect_packet.connect_flags &= !CLEAN_SESSION;
+        }
+        if self.protocol_version == MQTT_PROTOCOL_VERSION_5 {
+            connect_packet.protocol_version = MQTT_PROTOCOL_VERSION_5;
+            if self.session_expiry_interval != 0 {
+                connect_packet.properties.session_expiry_interval = Some(self.session_expiry_interval);
+            }
+            if options.max_packet_size < MAX_REMAINING_LENGTH {
+                connect_packet.properties.maximum_packet_size = Some(options.max_packet_size as u32);
+            }
+            if let Some(ref auth) = self.auth {
+                connect_packet.properties.authentication_method = Some(auth.method.clone());
+                connect_packet.properties.authentication_data = auth.data.clone();
+            }
+        }
+        Connector {
+            addr: addr.to_string(),
+            tls: options.tls.clone(),
+            connect_packet,
+            auth: self.auth.clone(),
+            connect_timeout: options.connect_timeout,
+            max_packet_size: options.max_packet_size,
+            inflight_window: options.inflight_window,
+        }
+    }
+
+    /// 握手完成后把连接交给事件循环；握手失败时回到断开连接状态
+    async fn start_event_loop(
+        &mut self,
+        mut connector: Connector,
+        result: Result<(BrokerFramed, ConnackPacket), MqttError>,
+    ) -> Result<(), MqttError> {
+        let (framed, connack) = match result {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::error!("Failed to connect to {}: {}", connector.addr, e);
+                // 连接失败，回到断开连接状态
+                self.shared.transition_to(ClientState::Disconnected);
+                return Err(e);
+            }
+        };
+
+        // 上一次连接的事件循环可能刚刚结束，先取回它留下的会话状态
+        if self.event_loop.is_some() {
+            let _ = self.join_event_loop().await;
+        }
+        // 代理没有恢复会话时，本地未完成的确认流程也随之作废
+        self.session_present = connack.session_present;
+        let session = if connack.session_present { std::mem::take(&mut self.session) } else { Session::default() };
+        // 代理分配的客户端标识符和代理指定的保活时间（MQTT 5.0），重连时沿用
+        if let Some(ref client_id) = connack.properties.assigned_client_identifier {
+            log::info!("Broker assigned client ID: {}", client_id);
+            connector.connect_packet.client_id = client_id.clone();
+            self.options.client_id = client_id.clone();
+        }
+        if let Some(keep_alive) = connack.properties.server_keep_alive {
+            self.options.keep_alive = Duration::from_secs(keep_alive.into());
+            connector.connect_packet.keep_alive = keep_alive;
+        }
+
+        let (sender, requests) = mpsc::channel(REQUEST_QUEUE_CAPACITY);
+        let event_loop = EventLoop {
+            framed,
+            shared: self.shared.clone(),
+            requests,
+            slot: Arc::downgrade(&self.handle.requests),
+            connector,
+            reconnect: self.reconnect.clone(),
+            session,
+            publishes: HashMap::new(),
+            acks: HashMap::new(),
+            subscribed: BTreeMap::new(),
+            buffered: VecDeque::new(),
+            retry_interval: self.retry_interval,
+            keep_alive: self.options.keep_alive,
+            inflight_window: inflight_window(self.options.inflight_window, &connack),
+            last_sent: Instant::now(),
+            ping_sent_at: None,
+        };
+        *self.handle.requests.lock().unwrap() = Some(sender);
+        self.event_loop = Some(tokio::spawn(event_loop.run()));
+
+        // 连接成功，更新状态
+        self.shared.transition_to(ClientState::Connected);
+        Ok(())
+    }
+
+    /// 以指定的QoS订阅主题
+    pub async fn subscribe(&self, topic: String, qos: QoS) -> Result<(), MqttError> {
+        self.handle.subscribe(topic, qos).await
+    }
+
+    /// 在一个SUBSCRIBE包中订阅多个主题过滤器
+    ///
+    /// 返回SUBACK中每个过滤器对应的返回码：授予的QoS，或0x80及以上的值表示订阅失败。
+    pub async fn subscribe_many(&self, topics: &[(String, QoS)]) -> Result<Vec<u8>, MqttError> {
+        self.handle.subscribe_many(topics).await
+    }
+
+    /// 取消订阅主题过滤器，收到UNSUBACK后返回
+    pub async fn unsubscribe(&self, topic: String) -> Result<(), MqttError> {
+        self.handle.unsubscribe(topic).await
+    }
+
+    /// 以指定的QoS发布消息，见[`ClientHandle::publish`]
+    pub async fn publish(&self, topic: String, payload: impl Into<Bytes>, qos: QoS) -> Result<(), MqttError> {
+        self.handle.publish(topic, payload, qos).await
+    }
+
+    /// 将值序列化为JSON后发布
+    pub async fn publish_json<T: Serialize + ?Sized>(&self, topic: String, value: &T, qos: QoS) -> Result<(), MqttError> {
+        self.handle.publish_json(topic, value, qos).await
+    }
+
+    /// 携带MQTT 5.0属性发布消息，见[`ClientHandle::publish_with_properties`]
+    pub async fn publish_with_properties(
+        &self,
+        topic: String,
+        payload: impl Into<Bytes>,
+        qos: QoS,
+        properties: Properties,
+    ) -> Result<(), MqttError> {
+        self.handle.publish_with_properties(topic, payload, qos, properties).await
+    }
+
+    /// 创建SUBSCRIBE包
+    fn create_subscribe_packet(packet_id: u16, topics: &[(String, QoS)]) -> Packet {
+        Packet::Subscribe(SubscribePacket {
+            packet_id,
+            topics: topics.to_vec(),
+            properties: Properties::default(),
+        })
+    }
+
+    /// 创建PUBLISH包（包标识符在进入在途窗口时分配）
+    fn create_publish_packet(topic: &str, payload: Bytes, qos: QoS) -> PublishPacket {
+        PublishPacket {
+            dup: false,
+            qos,
+            retain: false,
+            topic: topic.to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload,
+        }
+    }
+
+    /// 注册消息回调，主题过滤器可以包含 `+` 和 `#` 通配符
+    ///
+    /// 共享订阅过滤器（`$share/<组名>/<过滤器>`）按去掉前缀后的过滤器匹配收到的消息。
+    /// 回调在事件循环中执行，耗时的处理应交给其他任务。负载按原样传入，文本可以用`std::str::from_utf8`解析。
+    pub async fn on_message<F>(&mut self, topic: String, callback: F)
+    where
+        F: Fn(String, Bytes) + Send + Sync + 'static
+    {
+        let mut subs = self.shared.subscriptions.lock().await;
+        subs.entry(shared::subscription_filter(&topic)).push(MessageCallback::Payload(Box::new(callback)));
+    }
+
+    /// 注册按JSON反序列化负载的消息回调，无法反序列化的消息记录警告后丢弃
+    pub async fn on_json<T, F>(&mut self, topic: String, callback: F)
+    where
+        T: DeserializeOwned,
+        F: Fn(String, T) + Send + Sync + 'static
+    {
+        self.on_message(topic, move |topic, payload| match serde_json::from_slice(&payload) {
+            Ok(value) => callback(topic, value),
+            Err(e) => log::warn!("Ignoring message on topic '{}' with invalid JSON payload: {}", topic, e),
+        }).await;
+    }
+
+    /// 注册接收完整PUBLISH包的回调，可以读取MQTT 5.0属性
+    ///
+    /// 请求/响应模式中，响应方从请求的`response_topic`和`correlation_data`构造响应。
+    pub async fn on_publish<F>(&mut self, topic: String, callback: F)
+    where
+        F: Fn(&PublishPacket) + Send + Sync + 'static
+    {
+        let mut subs = self.shared.subscriptions.lock().await;
+        subs.entry(shared::subscription_filter(&topic)).push(MessageCallback::Publish(Box::new(callback)));
+    }
+
+    /// 等待后台事件循环结束，返回连接结束的原因
+    ///
+    /// 代理关闭连接或通过句柄断开连接时返回Ok；等待期间可以通过`handle()`并发发布和订阅。
+    pub async fn start_listening(&mut self) -> Result<(), MqttError> {
+        log::info!("Waiting for the client event loop...");
+        self.join_event_loop().await
+    }
+
+    /// 等待事件循环结束并取回会话状态
+    async fn join_event_loop(&mut self) -> Result<(), MqttError> {
+        let Some(event_loop) = self.event_loop.take() else {
+            return Err(MqttError::NotConnected);
+        };
+        let (session, result) = event_loop.await.map_err(std::io::Error::from)?;
+        self.session = session;
+        result
+    }
+
+    /// 断开与MQTT代理的连接
+    pub async fn disconnect(&mut self) -> Result<(), MqttError> {
+        self.handle.disconnect().await?;
+        self.join_event_loop().await
+    }
+
+    /// 创建DISCONNECT包
+    fn create_disconnect_packet() -> Packet {
+        Packet::Disconnect(DisconnectPacket::default())
+    }
+}
+
+/// 建立连接所需的参数，事件循环自动重连时复用
+struct Connector {
+    addr: String,
+    tls: Option<TlsClientOptions>,
+    connect_packet: ConnectPacket,
+    auth: Option<Arc<EnhancedAuthConfig>>,
+    connect_timeout: Duration,
+    max_packet_size: usize,
+    // 选项中的在途窗口，每次连接后再按代理的接收上限调整
+    inflight_window: u16,
+}
+
+impl Connector {
+    /// 建立到代理的TCP连接，设置了TLS时再完成TLS握手
+    async fn open_transport(&self) -> std::io::Result<Box<dyn Transport>> {
+        let stream = TcpStream::connect(&self.addr).await?;
+        match &self.tls {
+            Some(tls) => Ok(Box::new(tls.connect(stream, &self.addr).await?)),
+            None => Ok(Box::new(stream)),
+        }
+    }
+
+    /// 建立新的连接并完成握手，超过连接超时时间时返回`MqttError::Timeout`
+    async fn connect(&self) -> Result<(BrokerFramed, ConnackPacket), MqttError> {
+        let connect = async {
+            let stream = self.open_transport().await?;
+            self.handshake(stream).await
+        };
+        tokio::time::timeout(self.connect_timeout, connect).await.map_err(|_| MqttError::Timeout("CONNACK"))?
+    }
+
+    /// 在已建立的传输上完成CONNECT/CONNACK握手
+    async fn handshake(&self, stream: Box<dyn Transport>) -> Result<(BrokerFramed, ConnackPacket), MqttError> {
+        let mut framed = Framed::new(stream, MqttCodec::with_max_packet_size(self.max_packet_size));
+        let connack = self.negotiate(&mut framed).await?;
+        Ok((framed, connack))
+    }
+
+    /// 发送CONNECT并等待代理接受连接，增强认证时先完成AUTH质询/响应
+    async fn negotiate(&self, framed: &mut BrokerFramed) -> Result<ConnackPacket, MqttError> {
+        log::debug!("Sending CONNECT packet: {:?}", self.connect_packet);
+
+        framed.send(Packet::Connect(self.connect_packet.clone())).await?;
+
+        // 读取CONNACK响应，增强认证时先完成AUTH质询/响应
+        let response = loop {
+            let auth = match framed.next().await {
+                Some(Ok(Packet::Auth(auth))) => auth,
+                other => break other,
+            };
+            let Some(reply) = self.auth_response(&auth) else {
+                return Err(MqttError::Protocol("unexpected AUTH from broker".to_string()));
+            };
+            framed.send(reply).await?;
+        };
+        match response {
+            Some(Ok(Packet::Connack(connack))) => {
+                match ConnackCode::from_return_code(self.connect_packet.protocol_version, connack.return_code) {
+                    None => {
+                        log::info!("Connected to MQTT broker at {}, session_present: {}",
+                            self.addr, connack.session_present);
+                        Ok(connack)
                     }
-                } else {
-                    self.transition_to(ClientState::Disconnected);
-                    Err("Failed to connect: No stream".into())
+                    Some(code) => {
+                        log::error!("CONNACK: Connection refused by {}: {}", self.addr, code);
+                        Err(MqttError::ConnectionRefused(code))
+                    }
+                }
+            }
+            Some(Ok(other)) => {
+                log::error!("Invalid CONNACK response, expected packet type {:?}, got {:?}",
+                    PacketType::CONNACK, other.packet_type());
+                Err(MqttError::Protocol(format!("expected CONNACK, got {:?}", other.packet_type())))
+            }
+            Some(Err(e)) => {
+                log::error!("Failed to read CONNACK: {}", e);
+                Err(MqttError::decode(e))
+            }
+            None => {
+                log::error!("Failed to read CONNACK: connection closed by server");
+                Err(MqttError::ConnectionLost)
+            }
+        }
+    }
+
+    /// 根据代理的AUTH质询生成响应，认证方法不匹配或未配置增强认证时返回None
+    fn auth_response(&self, auth: &AuthPacket) -> Option<Packet> {
+        let config = self.auth.as_ref()?;
+        if auth.reason_code != reason_code::CONTINUE_AUTHENTICATION
+            || auth.properties.authentication_method.as_deref() != Some(config.method.as_str())
+        {
+            log::error!("Unexpected AUTH from broker: {:?}", auth);
+            return None;
+        }
+        Some(Packet::Auth(AuthPacket {
+            reason_code: reason_code::CONTINUE_AUTHENTICATION,
+            properties: Properties {
+                authentication_method: Some(config.method.clone()),
+                authentication_data: (config.responder)(auth.properties.authentication_data.as_deref()),
+                ..Properties::default()
+            },
+        }))
+    }
+}
+
+/// 同时等待确认的发布数上限：MQTT 5.0代理在CONNACK中指定的接收上限更小时以代理为准
+fn inflight_window(configured: u16, connack: &ConnackPacket) -> usize {
+    configured.min(connack.properties.receive_maximum.unwrap_or(u16::MAX)).into()
+}
+
+/// 事件循环一次等待得到的事件
+// 事件创建后立即处理，不必为变体大小装箱
+#[allow(clippy::large_enum_variant)]
+enum Event {
+    Packet(Option<std::io::Result<Packet>>),
+    Request(Option<Request>),
+    Timeout,
+}
+
+/// 事件循环结束的原因（连接错误另以Err返回）
+enum Exit {
+    /// 调用方请求断开连接，等待断开完成
+    Disconnect(oneshot::Sender<Result<(), MqttError>>),
+    /// 客户端和所有句柄都已丢弃
+    Dropped,
+    /// 代理关闭了连接
+    Closed,
+}
+
+/// 后台事件循环，独占到代理的连接
+struct EventLoop {
+    framed: BrokerFramed,
+    shared: Arc<Shared>,
+    requests: mpsc::Receiver<Request>,
+    // 退出时清除句柄中的请求通道；句柄都已丢弃时无法升级
+    slot: Weak<RequestSlot>,
+    // 自动重连时用于重新建立连接
+    connector: Connector,
+    reconnect: Option<ReconnectPolicy>,
+    session: Session,
+    // 等待确认流程完成的QoS 1/2发布：包标识符 -> 调用方
+    publishes: HashMap<u16, oneshot::Sender<Result<(), MqttError>>>,
+    // 等待SUBACK/UNSUBACK的请求：包标识符 -> 调用方
+    acks: HashMap<u16, PendingAck>,
+    // 订阅成功的主题过滤器及请求的QoS，重连后代理没有保留会话时重新订阅
+    subscribed: BTreeMap<String, QoS>,
+    // 尚未发出的发布请求：重连期间缓存的请求，以及在途窗口已满时排队的请求
+    buffered: VecDeque<(PublishPacket, oneshot::Sender<Result<(), MqttError>>)>,
+    retry_interval: Duration,
+    keep_alive: Duration,
+    // 同时等待确认的QoS 1/2发布数上限
+    inflight_window: usize,
+    // 最近一次发送数据包的时间
+    last_sent: Instant,
+    // 已发送但尚未收到PINGRESP的PINGREQ的发送时间
+    ping_sent_at: Option<Instant>,
+}
+
+impl EventLoop {
+    /// 运行到连接结束，拒绝所有未完成的请求，交回会话状态和结束原因
+    async fn run(mut self) -> (Session, Result<(), MqttError>) {
+        let exit = self.serve().await;
+
+        // 先清除请求通道，再拒绝已经排队的请求，之后的请求在句柄中直接失败
+        if let Some(slot) = self.slot.upgrade() {
+            *slot.lock().unwrap() = None;
+        }
+        self.requests.close();
+        let mut disconnects = Vec::new();
+        while let Ok(request) = self.requests.try_recv() {
+            match request {
+                Request::Publish { reply, .. } | Request::Unsubscribe { reply, .. } => {
+                    let _ = reply.send(Err(MqttError::NotConnected));
+                }
+                Request::Subscribe { reply, .. } => {
+                    let _ = reply.send(Err(MqttError::NotConnected));
+                }
+                Request::Disconnect { reply } => disconnects.push(reply),
+            }
+        }
+        for (_, reply) in self.buffered.drain(..) {
+            let _ = reply.send(Err(MqttError::NotConnected));
+        }
+        for (_, reply) in self.publishes.drain() {
+            let _ = reply.send(Err(MqttError::ConnectionLost));
+        }
+        self.fail_pending_acks();
+        self.shared.transition_to(ClientState::Disconnected);
+
+        // 状态已是断开连接，此时再通知等待断开的调用方
+        let result = match exit {
+            Ok(Exit::Disconnect(reply)) => {
+                disconnects.push(reply);
+                Ok(())
+            }
+            Ok(Exit::Dropped) | Ok(Exit::Closed) => Ok(()),
+            Err(e) => Err(e),
+        };
+        for reply in disconnects {
+            let _ = reply.send(Ok(()));
+        }
+        (self.session, result)
+    }
+
+    /// 处理连接直到结束；设置了重连策略时，连接意外断开后自动重连并继续处理
+    async fn serve(&mut self) -> Result<Exit, MqttError> {
+        loop {
+            let error = match self.serve_connection().await {
+                Ok(Exit::Closed) => None,
+                Ok(exit) => return Ok(exit),
+                Err(e) => Some(e),
+            };
+            let Some(policy) = self.reconnect.clone() else {
+                return error.map_or(Ok(Exit::Closed), Err);
+            };
+            match error {
+                Some(e) => log::warn!("Connection to {} lost: {}", self.connector.addr, e),
+                None => log::warn!("Connection to {} closed by server", self.connector.addr),
+            }
+            if let Some(exit) = self.reconnect(&policy).await? {
+                return Ok(exit);
+            }
+        }
+    }
+
+    /// 处理当前连接上的数据包和请求，直到连接结束
+    async fn serve_connection(&mut self) -> Result<Exit, MqttError> {
+        loop {
+            let poll_interval = self.poll_interval();
+            let event = tokio::select! {
+                packet = self.framed.next() => Event::Packet(packet),
+                request = self.requests.recv() => Event::Request(request),
+                _ = tokio::time::sleep(poll_interval) => Event::Timeout,
+            };
+
+            match event {
+                Event::Packet(Some(Ok(packet))) => self.handle_incoming(packet).await?,
+                Event::Packet(Some(Err(e))) => {
+                    log::error!("Error reading from socket: {}", e);
+                    return Err(MqttError::decode(e));
+                }
+                Event::Packet(None) => {
+                    log::info!("Connection closed by server");
+                    return Ok(Exit::Closed);
+                }
+                Event::Request(Some(Request::Disconnect { reply })) => {
+                    self.disconnect().await;
+                    return Ok(Exit::Disconnect(reply));
+                }
+                Event::Request(Some(request)) => self.handle_request(request).await?,
+                Event::Request(None) => {
+                    // 客户端和所有句柄都已丢弃，直接关闭连接
+                    log::info!("All client handles dropped, closing connection");
+                    return Ok(Exit::Dropped);
+                }
+                Event::Timeout => {}
+            }
+            // 确认流程完成后在途窗口有了空位，发送排队的发布
+            self.flush_buffered().await?;
+            // 检查是否有需要重传的在途消息
+            self.retransmit().await?;
+            self.check_keep_alive().await?;
+        }
+    }
+
+    /// 按重连策略重新建立连接，等待期间缓存发布请求
+    ///
+    /// 重新连接成功时返回None；期间收到断开请求或句柄都已丢弃时返回结束原因；超过重连次数时返回错误。
+    async fn reconnect(&mut self, policy: &ReconnectPolicy) -> Result<Option<Exit>, MqttError> {
+        self.shared.transition_to(ClientState::Reconnecting);
+        // 订阅和取消订阅的应答随旧连接丢失，由调用方决定是否重试
+        self.fail_pending_acks();
+
+        let mut attempt = 1;
+        while policy.allows(attempt) {
+            let delay = policy.delay(attempt);
+            log::info!("Reconnecting to {} in {:?} (attempt {})", self.connector.addr, delay, attempt);
+            let deadline = Instant::now() + delay;
+            loop {
+                let request = tokio::select! {
+                    _ = tokio::time::sleep_until(deadline) => break,
+                    request = self.requests.recv() => request,
+                };
+                match request {
+                    Some(Request::Publish { publish, reply }) => self.buffer_publish(publish, reply, policy),
+                    Some(Request::Subscribe { reply, .. }) => {
+                        let _ = reply.send(Err(MqttError::NotConnected));
+                    }
+                    Some(Request::Unsubscribe { reply, .. }) => {
+                        let _ = reply.send(Err(MqttError::NotConnected));
+                    }
+                    Some(Request::Disconnect { reply }) => return Ok(Some(Exit::Disconnect(reply))),
+                    None => return Ok(Some(Exit::Dropped)),
+                }
+            }
+
+            let result = match self.connector.connect().await {
+                Ok((framed, connack)) => self.resume(framed, connack).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => return Ok(None),
+                Err(e) => log::warn!("Reconnect attempt {} to {} failed: {}", attempt, self.connector.addr, e),
+            }
+            attempt += 1;
+        }
+        log::error!("Giving up reconnecting to {} after {} attempts", self.connector.addr, attempt - 1);
+        Err(MqttError::ReconnectFailed(attempt - 1))
+    }
+
+    /// 重连期间缓存发布请求，超过上限时直接失败
+    fn buffer_publish(&mut self, publish: PublishPacket, reply: oneshot::Sender<Result<(), MqttError>>, policy: &ReconnectPolicy) {
+        if self.buffered.len() >= policy.buffer_capacity {
+            log::warn!("Reconnect buffer full, dropping publish to '{}'", publish.topic);
+            let _ = reply.send(Err(MqttError::BufferFull));
+            return;
+        }
+        self.buffered.push_back((publish, reply));
+    }
+
+    /// 在新连接上恢复：补发未完成的发布，代理没有保留会话时重新订阅，再发送缓存的发布
+    async fn resume(&mut self, framed: BrokerFramed, connack: ConnackPacket) -> Result<(), MqttError> {
+        self.framed = framed;
+        self.last_sent = Instant::now();
+        self.ping_sent_at = None;
+        self.inflight_window = inflight_window(self.connector.inflight_window, &connack);
+        if let Some(client_id) = connack.properties.assigned_client_identifier {
+            self.connector.connect_packet.client_id = client_id;
+        }
+        if let Some(keep_alive) = connack.properties.server_keep_alive {
+            self.keep_alive = Duration::from_secs(keep_alive.into());
+        }
+
+        let packets = if connack.session_present {
+            // 代理保留了会话：立即重传所有在途消息
+            self.session.inflight.retransmissions(Duration::ZERO)
+        } else {
+            self.restart_session()
+        };
+        for packet in packets {
+            self.send_packet(packet).await?;
+        }
+
+        if !connack.session_present && !self.subscribed.is_empty() {
+            let topics: Vec<_> = self.subscribed.iter().map(|(filter, qos)| (filter.clone(), *qos)).collect();
+            log::info!("Restoring {} subscriptions", topics.len());
+            if let Some(packet_id) = self.next_packet_id() {
+                let packet = MqttClient::create_subscribe_packet(packet_id, &topics);
+                // 恢复订阅没有等待结果的调用方
+                let (reply, _) = oneshot::channel();
+                self.acks.insert(packet_id, PendingAck::Subscribe { topics, reply });
+                self.send_packet(packet).await?;
+            } else {
+                log::warn!("No free packet identifier, subscriptions not restored");
+            }
+        }
+
+        self.flush_buffered().await?;
+
+        log::info!("Reconnected to {}", self.connector.addr);
+        self.shared.transition_to(ClientState::Connected);
+        Ok(())
+    }
+
+    /// 代理没有保留会话：代理尚未收到的在途消息换用新的包标识符重新发送，只差PUBCOMP的消息视为完成
+    fn restart_session(&mut self) -> Vec<Packet> {
+        let previous = std::mem::take(&mut self.session);
+        let mut publishes = std::mem::take(&mut self.publishes);
+        let mut packets = Vec::new();
+        for publish in previous.inflight.unreceived() {
+            let previous_id = publish.packet_id.unwrap_or_default();
+            let Some(publish) = self.session.inflight.start(PublishPacket { dup: false, ..publish.clone() }) else {
+                if let Some(reply) = publishes.remove(&previous_id) {
+                    let _ = reply.send(Err(MqttError::PacketIdsExhausted));
                 }
-            },
-            Err(e) => {
-                log::error!("Failed to connect to {}: {}", addr, e);
-                // 连接失败，回到断开连接状态
-                self.transition_to(ClientState::Disconnected);
-                Err(e.into())
+                continue;
+            };
+            if let Some(reply) = publishes.remove(&previous_id) {
+                self.publishes.insert(publish.packet_id.unwrap_or_default(), reply);
             }
+            packets.push(Packet::Publish(publish));
         }
+        for (_, reply) in publishes {
+            let _ = reply.send(Ok(()));
+        }
+        packets
     }
 
-    /// 订阅主题
-    pub async fn subscribe(&mut self, topic: String) -> Result<(), Box<dyn std::error::Error>> {
-        // 检查当前状态是否允许订阅
-        if !self.state.can_execute_command("Subscribe") {
-            return Err(format!("Cannot subscribe in {:?} state", self.state.get_state()).into());
+    /// 订阅和取消订阅的应答不会再到达，通知等待的调用方
+    fn fail_pending_acks(&mut self) {
+        for (_, pending) in self.acks.drain() {
+            match pending {
+                PendingAck::Subscribe { reply, .. } => { let _ = reply.send(Err(MqttError::ConnectionLost)); }
+                PendingAck::Unsubscribe { reply, .. } => { let _ = reply.send(Err(MqttError::ConnectionLost)); }
+            }
         }
-        
-        // 先创建包，避免借用冲突
-        let packet = Self::create_subscribe_packet(&topic);
-        
-        log::debug!("Sending SUBSCRIBE packet: {:02x?}", packet);
-        
-        if let Some(ref mut stream) = self.stream {
-            stream.write_all(&packet).await?;
-            
-            // 读取响应 (最大20字节，给一些余量)
-            let mut buffer = [0u8; 20];
-            match stream.read(&mut buffer).await {
-                Ok(n) => {
-                    if n > 0 {                       
-                        // 使用公共解析方法
-                        if let Some((packet_type, _payload)) = Self::parse_packet(&buffer[..n]) {
-                            log::debug!("Received packet type: {}", packet_type);
-                            
-                            match packet_type {
-                                9 => {
-                                    // SUBACK包
-                                    if n >= 3 {
-                                        let packet_id = ((buffer[2] as u16) << 8) | (buffer[3] as u16);
-                                        log::info!("Subscribed to topic: {}, packet_id: {}", topic, packet_id);
-                                        
-                                        // 检查返回码（第5个字节开始）
-                                        if n >= 5 {
-                                            let return_code = buffer[4];
-                                            match return_code {
-                                                0x00 => log::info!("SUBACK: QoS 0 granted"),
-                                                0x01 => log::info!("SUBACK: QoS 1 granted"),
-                                                0x02 => log::info!("SUBACK: QoS 2 granted"),
-                                                0x80 => log::warn!("SUBACK: Subscription failed"),
-                                                _ => log::warn!("SUBACK: Unknown return code: {:02x}", return_code),
-                                            }
-                                        }
-                                    } else {
-                                        log::warn!("SUBACK packet too short: {} bytes", n);
-                                    }
-                                    Ok(())
-                                },
-                                3 => {
-                                    // PUBLISH包 - 这可能是我们订阅的主题上已经存在的消息
-                                    log::info!("Received PUBLISH packet as response to SUBSCRIBE");
-                                    // 这里我们可以选择继续等待SUBACK，或者认为这是有效的响应
-                                    // 为简单起见，我们将其视为订阅成功
-                                    log::info!("Subscribed to topic: {} (received PUBLISH response)", topic);
-                                    Ok(())
-                                },
-                                _ => {
-                                    log::error!("Unexpected response packet type: {}, expected SUBACK (9)", packet_type);
-                                    Err("Failed to subscribe: Unexpected response".into())
-                                }
-                            }
-                        } else {
-                            log::error!("Failed to parse response packet");
-                            Err("Failed to subscribe: Invalid response".into())
-                        }
-                    } else {
-                        log::error!("No data received for SUBACK");
-                        Err("Failed to subscribe: No response".into())
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to read response: {}", e);
-                    Err(e.into())
+    }
+
+    /// 把句柄的请求转换为数据包发出，需要应答的请求登记后等待代理确认
+    async fn handle_request(&mut self, request: Request) -> Result<(), MqttError> {
+        match request {
+            Request::Publish { publish, reply } => {
+                // 排在前面的发布还没发出时依次排队，保持发布顺序
+                if !self.buffered.is_empty() || !self.can_send(&publish) {
+                    log::debug!("Inflight window full, queueing publish to '{}'", publish.topic);
+                    self.buffered.push_back((publish, reply));
+                    return Ok(());
                 }
+                self.send_publish(publish, reply).await
             }
-        } else {
-            Err("Not connected to broker".into())
+            Request::Subscribe { topics, reply } => {
+                let Some(packet_id) = self.next_packet_id() else {
+                    let _ = reply.send(Err(MqttError::PacketIdsExhausted));
+                    return Ok(());
+                };
+                let packet = MqttClient::create_subscribe_packet(packet_id, &topics);
+                log::debug!("Sending SUBSCRIBE packet: {:?}", packet);
+                self.acks.insert(packet_id, PendingAck::Subscribe { topics, reply });
+                self.send_packet(packet).await
+            }
+            Request::Unsubscribe { topics, reply } => {
+                let Some(packet_id) = self.next_packet_id() else {
+                    let _ = reply.send(Err(MqttError::PacketIdsExhausted));
+                    return Ok(());
+                };
+                let packet = Packet::Unsubscribe(UnsubscribePacket {
+                    packet_id,
+                    topics: topics.clone(),
+                    properties: Properties::default(),
+                });
+                log::debug!("Sending UNSUBSCRIBE packet: {:?}", packet);
+                self.acks.insert(packet_id, PendingAck::Unsubscribe { topics, reply });
+                self.send_packet(packet).await
+            }
+            Request::Disconnect { .. } => unreachable!("DISCONNECT requests end the event loop in serve"),
         }
     }
 
-    /// 发布消息
-    pub async fn publish(&mut self, topic: String, message: String) -> Result<(), Box<dyn std::error::Error>> {
-        // 检查当前状态是否允许发布
-        if !self.state.can_execute_command("Publish") {
-            return Err(format!("Cannot publish in {:?} state", self.state.get_state()).into());
-        }
-        
-        let packet = Self::create_publish_packet(&topic, &message);
-        
-        log::debug!("Sending PUBLISH packet: {:02x?}", packet);
-        
-        if let Some(ref mut stream) = self.stream {
-            stream.write_all(&packet).await?;
-            Ok(())
-        } else {
-            Err("Not connected to broker".into())
+    /// 发出PUBLISH；QoS 0写出后即完成，QoS 1/2加入在途窗口等待确认
+    async fn send_publish(&mut self, publish: PublishPacket, reply: oneshot::Sender<Result<(), MqttError>>) -> Result<(), MqttError> {
+        if publish.qos == QoS::AtMostOnce {
+            log::debug!("Sending PUBLISH packet: {:?}", publish);
+            self.send_packet(Packet::Publish(publish)).await?;
+            let _ = reply.send(Ok(()));
+            return Ok(());
         }
+
+        // 分配包标识符并加入在途窗口
+        let Some(publish) = self.session.inflight.start(publish) else {
+            let _ = reply.send(Err(MqttError::PacketIdsExhausted));
+            return Ok(());
+        };
+        log::debug!("Sending PUBLISH packet: {:?}", publish);
+        self.publishes.insert(publish.packet_id.unwrap_or_default(), reply);
+        self.send_packet(Packet::Publish(publish)).await
     }
-    
-    /// 创建SUBSCRIBE包
-    fn create_subscribe_packet(topic: &str) -> Vec<u8> {
-        let mut buffer = BytesMut::new();
-        
-        // 固定头部 - SUBSCRIBE包类型和标志
-        let header = (PacketType::SUBSCRIBE as u8) << 4 | 0b0010; // SUBSCRIBE包需要设置第1、2位为0010
-        buffer.put_u8(header);
-        
-        // 主题长度和主题
-        let topic_bytes = topic.as_bytes();
-        // 包标识符(2) + 主题长度字段(2) + 主题 + QoS(1)
-        let payload_length = 2 + 2 + topic_bytes.len() + 1;
-        
-        // 剩余长度编码（可变长度编码）
-        Self::encode_remaining_length_static(&mut buffer, payload_length);
-        
-        // 包标识符（简化处理，固定为1）
-        buffer.put_u16(1);
-        
-        // 主题长度
-        buffer.put_u16(topic_bytes.len() as u16);
-        
-        // 主题
-        buffer.extend_from_slice(topic_bytes);
-        
-        // QoS等级（0）
-        buffer.put_u8(0);
-        
-        let result = buffer.to_vec();
-        log::debug!("Created SUBSCRIBE packet: {:02x?}", result);
-        result
+
+    /// QoS 0的发布随时可以发送，QoS 1/2的发布需要在途窗口还有空位
+    fn can_send(&self, publish: &PublishPacket) -> bool {
+        publish.qos == QoS::AtMostOnce || self.session.inflight.len() < self.inflight_window
     }
-    
-    /// 编码剩余长度（使用可变长度编码）
-    fn encode_remaining_length_static(buffer: &mut BytesMut, length: usize) {
-        let mut remaining_length = length;
-        loop {
-            let mut digit = remaining_length % 128;
-            remaining_length /= 128;
-            if remaining_length > 0 {
-                digit |= 128;
-            }
-            buffer.put_u8(digit as u8);
-            if remaining_length == 0 {
-                break;
+
+    /// 按顺序发送排队的发布，直到在途窗口已满
+    async fn flush_buffered(&mut self) -> Result<(), MqttError> {
+        while self.buffered.front().is_some_and(|(publish, _)| self.can_send(publish)) {
+            let Some((publish, reply)) = self.buffered.pop_front() else { break };
+            self.send_publish(publish, reply).await?;
+        }
+        Ok(())
+    }
+
+    /// 为SUBSCRIBE/UNSUBSCRIBE分配包标识符，跳过仍在等待应答的标识符；全部被占用时返回None
+    fn next_packet_id(&mut self) -> Option<u16> {
+        for _ in 0..u16::MAX {
+            let packet_id = self.session.inflight.next_packet_id()?;
+            if !self.acks.contains_key(&packet_id) {
+                return Some(packet_id);
             }
         }
+        None
     }
 
-    /// 创建PUBLISH包
-    fn create_publish_packet(topic: &str, message: &str) -> Vec<u8> {
-        let mut buffer = BytesMut::new();
-        
-        // 固定头部 - PUBLISH包类型和标志 (QoS 0)
-        let header = (PacketType::PUBLISH as u8) << 4;
-        buffer.put_u8(header);
-        
-        // 主题长度和主题，消息内容
-        let topic_bytes = topic.as_bytes();
-        let message_bytes = message.as_bytes();
-        let payload_length = 2 + topic_bytes.len() + message_bytes.len();
-        
-        // 剩余长度编码（可变长度编码）
-        Self::encode_remaining_length_static(&mut buffer, payload_length);
-        
-        // 主题长度
-        buffer.put_u16(topic_bytes.len() as u16);
-        
-        // 主题
-        buffer.extend_from_slice(topic_bytes);
-        
-        // 消息内容
-        buffer.extend_from_slice(message_bytes);
-        
-        let result = buffer.to_vec();
-        log::debug!("Created PUBLISH packet: {:02x?}", result);
-        result
+    /// 重传超时未确认的在途消息
+    ///
+    /// MQTT 5.0禁止在连接存续期间重发，只在恢复会话时重发（见`resume`）。
+    async fn retransmit(&mut self) -> Result<(), MqttError> {
+        if self.connector.connect_packet.protocol_version == MQTT_PROTOCOL_VERSION_5 {
+            return Ok(());
+        }
+        for packet in self.session.inflight.retransmissions(self.retry_interval) {
+            log::warn!("Retransmitting unacknowledged {:?}", packet.packet_type());
+            self.send_packet(packet).await?;
+        }
+        Ok(())
     }
 
-    /// 注册消息回调
-    pub async fn on_message<F>(&mut self, topic: String, callback: F) 
-    where 
-        F: Fn(String, String) + Send + Sync + 'static
-    {
-        let mut subs = self.subscriptions.lock().await;
-        if let Some(callbacks) = subs.get_mut(&topic) {
-            callbacks.push(Box::new(callback));
-        } else {
-            subs.insert(topic, vec![Box::new(callback)]);
+    /// 空闲等待的最长时间：不超过重传间隔，也不错过下一次保活检查
+    fn poll_interval(&self) -> Duration {
+        if self.keep_alive.is_zero() {
+            return self.retry_interval;
         }
+        let deadline = match self.ping_sent_at {
+            Some(sent_at) => sent_at + self.keep_alive,
+            None => self.last_sent + self.keep_alive,
+        };
+        self.retry_interval.min(deadline.saturating_duration_since(Instant::now()))
     }
-    
-    /// 启动消息监听循环
-    pub async fn start_listening(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        log::info!("Starting message listening loop...");
-        
-        if let Some(ref mut stream) = self.stream {
-            let mut buffer = [0u8; 1024];
-            
-            loop {
-                match stream.read(&mut buffer).await {
-                    Ok(n) => {
-                        if n > 0 {                            
-                            // 使用公共解析方法
-                            if let Some((packet_type, payload)) = Self::parse_packet(&buffer[..n]) {
-                                log::debug!("Received packet type: {}", packet_type);
-                                
-                                match packet_type {
-                                    3 => {
-                                        // PUBLISH包
-                                        if let Some((topic, message)) = Self::extract_publish_payload(&payload) {
-                                            log::info!("Received PUBLISH message on topic '{}': {}", topic, message);
-                                            
-                                            // 触发回调
-                                            let subs = self.subscriptions.lock().await;
-                                            if let Some(callbacks) = subs.get(&topic) {
-                                                for callback in callbacks {
-                                                    callback(topic.clone(), message.clone());
-                                                }
-                                            }
-                                        }
-                                    },
-                                    _ => {
-                                        log::debug!("Received unhandled packet type: {}", packet_type);
-                                    }
-                                }
+
+    /// 保活检查：空闲达到保活时间时发送PINGREQ，PINGRESP超时则返回错误
+    async fn check_keep_alive(&mut self) -> Result<(), MqttError> {
+        if self.keep_alive.is_zero() {
+            return Ok(());
+        }
+        match self.ping_sent_at {
+            Some(sent_at) => {
+                if sent_at.elapsed() >= self.keep_alive {
+                    log::error!("No PINGRESP within {:?}, connection lost", self.keep_alive);
+                    return Err(MqttError::Timeout("PINGRESP"));
+                }
+            }
+            None => {
+                if self.last_sent.elapsed() >= self.keep_alive {
+                    log::debug!("Sending PINGREQ");
+                    self.send_packet(Packet::Pingreq).await?;
+                    self.ping_sent_at = Some(Instant::now());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 处理收到的数据包：分发消息、完成QoS确认流程并通知等待应答的调用方
+    async fn handle_incoming(&mut self, packet: Packet) -> Result<(), MqttError> {
+        log::debug!("Received packet type: {:?}", packet.packet_type());
+
+        match packet {
+            Packet::Publish(publish) => match (publish.qos, publish.packet_id) {
+                (QoS::AtLeastOnce, Some(packet_id)) => {
+                    self.shared.dispatch_publish(&publish).await;
+                    self.send_packet(Packet::Puback(AckPacket::new(packet_id))).await?;
+                }
+                (QoS::ExactlyOnce, Some(packet_id)) => {
+                    // 同一包标识符在收到PUBREL之前只分发一次
+                    if self.session.incoming_qos2.insert(packet_id) {
+                        self.shared.dispatch_publish(&publish).await;
+                    } else {
+                        log::debug!("Duplicate QoS 2 PUBLISH, packet_id: {}", packet_id);
+                    }
+                    self.send_packet(Packet::Pubrec(AckPacket::new(packet_id))).await?;
+                }
+                _ => {
+                    self.shared.dispatch_publish(&publish).await;
+                }
+            },
+            Packet::Pubrel(ack) => {
+                let reason = if self.session.incoming_qos2.remove(&ack.packet_id) {
+                    reason_code::SUCCESS
+                } else {
+                    reason_code::PACKET_IDENTIFIER_NOT_FOUND
+                };
+                self.send_packet(Packet::Pubcomp(AckPacket::with_reason(ack.packet_id, reason))).await?;
+            }
+            // 代理以失败原因码确认（MQTT 5.0），消息流程结束，等待确认的发布返回错误
+            Packet::Puback(ack) | Packet::Pubrec(ack) if ack.reason_code >= reason_code::UNSPECIFIED_ERROR => {
+                log::warn!("Publish rejected by broker, packet_id: {}, reason: {:02x}", ack.packet_id, ack.reason_code);
+                if self.session.inflight.abandon(ack.packet_id).is_some() {
+                    self.complete_publish(ack.packet_id, Err(MqttError::PublishRejected(ack.reason_code)));
+                }
+            }
+            Packet::Puback(ack) => {
+                if self.session.inflight.on_puback(ack.packet_id).is_some() {
+                    self.complete_publish(ack.packet_id, Ok(()));
+                } else {
+                    log::warn!("Unexpected PUBACK, packet_id: {}", ack.packet_id);
+                }
+            }
+            Packet::Pubrec(ack) => match self.session.inflight.on_pubrec(ack.packet_id) {
+                Some(pubrel) => self.send_packet(pubrel).await?,
+                None => log::warn!("Unexpected PUBREC, packet_id: {}", ack.packet_id),
+            },
+            Packet::Pubcomp(ack) => {
+                if self.session.inflight.on_pubcomp(ack.packet_id).is_some() {
+                    self.complete_publish(ack.packet_id, Ok(()));
+                } else {
+                    log::warn!("Unexpected PUBCOMP, packet_id: {}", ack.packet_id);
+                }
+            }
+            Packet::Suback(suback) => match self.acks.remove(&suback.packet_id) {
+                Some(PendingAck::Subscribe { topics, reply }) => {
+                    let result = Self::subscribe_result(&topics, suback.return_codes);
+                    if let Ok(return_codes) = &result {
+                        for ((filter, qos), return_code) in topics.into_iter().zip(return_codes) {
+                            if *return_code < SUBACK_FAILURE {
+                                self.subscribed.insert(filter, qos);
+                            } else {
+                                self.subscribed.remove(&filter);
                             }
-                        } else {
-                            log::info!("Connection closed by server");
-                            break;
                         }
                     }
-                    Err(e) => {
-                        log::error!("Error reading from socket: {}", e);
-                        return Err(e.into());
+                    let _ = reply.send(result);
+                }
+                other => self.unexpected_ack(PacketType::SUBACK, suback.packet_id, other),
+            },
+            Packet::Unsuback(unsuback) => match self.acks.remove(&unsuback.packet_id) {
+                Some(PendingAck::Unsubscribe { topics, reply }) => {
+                    log::info!("Unsubscribed from topics: {:?}", topics);
+                    for filter in &topics {
+                        self.subscribed.remove(filter);
                     }
+                    let _ = reply.send(Ok(()));
                 }
+                other => self.unexpected_ack(PacketType::UNSUBACK, unsuback.packet_id, other),
+            },
+            Packet::Pingresp => {
+                self.ping_sent_at = None;
+            }
+            Packet::Disconnect(disconnect) => {
+                // MQTT 5.0代理可以主动断开连接，例如会话被接管或保活超时
+                log::error!("Disconnected by broker with reason code {:02x}", disconnect.reason_code);
+                return Err(MqttError::DisconnectedByBroker(disconnect.reason_code));
+            }
+            other => {
+                log::debug!("Received unhandled packet type: {:?}", other.packet_type());
             }
-        } else {
-            return Err("Not connected to broker".into());
         }
-        
         Ok(())
     }
-    
-    /// 解析MQTT包
-    fn parse_packet(data: &[u8]) -> Option<(u8, Vec<u8>)> {
-        if data.is_empty() {
-            return None;
-        }
-        
-        // 解析包类型
-        let packet_type = (data[0] >> 4) as u8;
-        
-        // 解析剩余长度（简化处理，假设长度<128）
-        if data.len() < 2 {
-            return None;
+
+    /// 发布的确认流程结束，通知等待的调用方
+    fn complete_publish(&mut self, packet_id: u16, result: Result<(), MqttError>) {
+        if result.is_ok() {
+            log::info!("Publish flow completed for packet_id: {}", packet_id);
         }
-        
-        let remaining_length = data[1] as usize;
-        if data.len() < 2 + remaining_length {
-            return None;
+        // 上一次连接中发出、本次连接才完成的消息没有等待的调用方
+        if let Some(reply) = self.publishes.remove(&packet_id) {
+            let _ = reply.send(result);
         }
-        
-        // 提取载荷
-        let payload = data[2..2 + remaining_length].to_vec();
-        
-        Some((packet_type, payload))
     }
-    
-    /// 从PUBLISH包载荷中提取主题和消息
-    fn extract_publish_payload(payload: &[u8]) -> Option<(String, String)> {
-        if payload.len() < 4 {
-            return None;
-        }
-        
-        // 解析主题长度
-        let topic_len = ((payload[0] as u16) << 8 | (payload[1] as u16)) as usize;
-        
-        // 检查缓冲区长度
-        if payload.len() < 2 + topic_len {
-            return None;
+
+    /// 检查SUBACK中每个过滤器对应的返回码
+    fn subscribe_result(topics: &[(String, QoS)], return_codes: Vec<u8>) -> Result<Vec<u8>, MqttError> {
+        if return_codes.len() != topics.len() {
+            return Err(MqttError::Protocol("SUBACK return code count mismatch".to_string()));
         }
-        
-        // 解析主题
-        let topic = String::from_utf8_lossy(&payload[2..2 + topic_len]).to_string();
-        
-        // 解析消息内容
-        let message_start = 2 + topic_len;
-        if message_start >= payload.len() {
-            return None;
+        for ((topic, _), return_code) in topics.iter().zip(&return_codes) {
+            match return_code {
+                0x00 => log::info!("Subscribed to topic: {}, QoS 0 granted", topic),
+                0x01 => log::info!("Subscribed to topic: {}, QoS 1 granted", topic),
+                0x02 => log::info!("Subscribed to topic: {}, QoS 2 granted", topic),
+                code if *code >= SUBACK_FAILURE => log::warn!("Subscription to topic {} failed: {:02x}", topic, code),
+                _ => log::warn!("SUBACK: Unknown return code: {:02x}", return_code),
+            }
         }
-        
-        let message = String::from_utf8_lossy(&payload[message_start..]).to_string();
-        
-        Some((topic, message))
+        Ok(return_codes)
     }
-    
-    /// 断开与MQTT代理的连接
-    pub async fn disconnect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        // 检查当前状态是否允许断开连接
-        if !self.state.can_execute_command("Disconnect") {
-            return Err(format!("Cannot disconnect in {:?} state", self.state.get_state()).into());
+
+    /// 应答的包标识符没有对应类型的请求在等待：忽略该应答，原请求继续等待
+    fn unexpected_ack(&mut self, packet_type: PacketType, packet_id: u16, pending: Option<PendingAck>) {
+        log::warn!("Unexpected {:?}, packet_id: {}", packet_type, packet_id);
+        if let Some(pending) = pending {
+            self.acks.insert(packet_id, pending);
         }
-        
+    }
+
+    /// 发送DISCONNECT并关闭连接
+    async fn disconnect(&mut self) {
         // 进入断开连接中状态
-        self.transition_to(ClientState::Disconnecting);
-        
-        // 发送DISCONNECT包
-        let disconnect_packet = Self::create_disconnect_packet();
-        log::debug!("Sending DISCONNECT packet: {:02x?}", disconnect_packet);
-        
-        if let Some(ref mut stream) = self.stream {
-            match stream.write_all(&disconnect_packet).await {
-                Ok(_) => {
-                    log::info!("Sent DISCONNECT packet to broker");
-                },
-                Err(e) => {
-                    log::error!("Failed to send DISCONNECT packet: {}", e);
-                }
+        self.shared.transition_to(ClientState::Disconnecting);
+
+        let disconnect_packet = MqttClient::create_disconnect_packet();
+        log::debug!("Sending DISCONNECT packet: {:?}", disconnect_packet);
+
+        match self.framed.send(disconnect_packet).await {
+            Ok(_) => {
+                log::info!("Sent DISCONNECT packet to broker");
+            },
+            Err(e) => {
+                log::error!("Failed to send DISCONNECT packet: {}", e);
             }
-            
-            // 关闭连接
-            match stream.shutdown().await {
-                Ok(_) => {
-                    log::info!("Connection closed successfully");
-                },
-                Err(e) => {
-                    log::error!("Error closing connection: {}", e);
-                }
+        }
+
+        // 关闭连接
+        match self.framed.get_mut().shutdown().await {
+            Ok(_) => {
+                log::info!("Connection closed successfully");
+            },
+            Err(e) => {
+                log::error!("Error closing connection: {}", e);
             }
         }
-        
-        // 清理连接
-        self.stream = None;
-        
-        // 进入断开连接状态
-        self.transition_to(ClientState::Disconnected);
-        
-        Ok(())
     }
-    
-    /// 创建DISCONNECT包
-    fn create_disconnect_packet() -> Vec<u8> {
-        let mut buffer = Vec::new();
-        
-        // 固定头部 - DISCONNECT包类型
-        let header = (PacketType::DISCONNECT as u8) << 4;
-        buffer.push(header);
-        
-        // 剩余长度为0
-        buffer.push(0x00);
-        
-        buffer
+
+    /// 通过编解码器发送一个数据包
+    async fn send_packet(&mut self, packet: Packet) -> Result<(), MqttError> {
+        self.framed.send(packet).await?;
+        self.last_sent = Instant::now();
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::BytesMut;
+    use tokio_util::codec::Decoder;
 
     #[test]
     fn test_create_subscribe_packet() {
-        let topic = "test/topic";
-        let packet = MqttClient::create_subscribe_packet(topic);
+        let topics = vec![("test/topic".to_string(), QoS::AtLeastOnce), ("test/#".to_string(), QoS::AtMostOnce)];
+        let packet = MqttClient::create_subscribe_packet(1, &topics).to_bytes();
         
         // 验证包不为空
         assert!(!packet.is_empty());
@@ -546,8 +1430,8 @@ mod tests {
     #[test]
     fn test_create_publish_packet() {
         let topic = "test/topic";
-        let message = "Hello, MQTT!";
-        let packet = MqttClient::create_publish_packet(topic, message);
+        let message = Bytes::from_static(b"Hello, MQTT!");
+        let packet = Packet::Publish(MqttClient::create_publish_packet(topic, message, QoS::AtMostOnce)).to_bytes();
         
         // 验证包不为空
         assert!(!packet.is_empty());
@@ -556,43 +1440,599 @@ mod tests {
     }
     
     #[test]
-    fn test_encode_remaining_length_static() {
-        let mut buffer = BytesMut::new();
-        MqttClient::encode_remaining_length_static(&mut buffer, 127);
-        assert_eq!(buffer.len(), 1);
-        assert_eq!(buffer[0], 127);
-        
-        let mut buffer = BytesMut::new();
-        MqttClient::encode_remaining_length_static(&mut buffer, 128);
-        assert_eq!(buffer.len(), 2);
-        assert_eq!(buffer[0], 128 | 128); // 继续位 + 0
-        assert_eq!(buffer[1], 1); // 1
+    fn test_create_disconnect_packet() {
+        let packet = MqttClient::create_disconnect_packet().to_bytes();
+        assert_eq!(&packet[..], &[0xE0, 0x00]);
     }
     
     #[test]
-    fn test_parse_packet() {
-        // 创建一个简单的CONNECT包用于测试
-        let packet_data = vec![0x10, 0x00]; // CONNECT包类型 + 剩余长度0
-        let result = MqttClient::parse_packet(&packet_data);
+    fn test_decode_publish_from_broker() {
+        // 创建一个简单的PUBLISH包用于测试
+        let mut data = BytesMut::from(&[0x30, 0x0E, 0x00, 0x05][..]); // PUBLISH + 剩余长度14 + 主题长度5
+        data.extend_from_slice(b"topic"); // 主题: "topic"
+        data.extend_from_slice(b"message"); // 消息: "message"
+        
+        let packet = MqttCodec::new().decode(&mut data).unwrap();
+        
+        match packet {
+            Some(Packet::Publish(publish)) => {
+                assert_eq!(publish.topic, "topic");
+                assert_eq!(&publish.payload[..], b"message");
+            }
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+    
+    #[tokio::test]
+    async fn test_dispatch_publish_invokes_callbacks() {
+        let mut client = MqttClient::new("test-client".to_string());
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = received.clone();
+        client.on_message("test/topic".to_string(), move |topic, payload| {
+            sink.lock().unwrap().push((topic, payload));
+        }).await;
+        
+        // 二进制负载原样交给回调
+        let payload = Bytes::from_static(&[0x82, 0x01, 0x00, 0xFF]);
+        let publish = MqttClient::create_publish_packet("test/topic", payload.clone(), QoS::AtMostOnce);
+        client.shared.dispatch_publish(&publish).await;
         
-        assert!(result.is_some());
-        let (packet_type, payload) = result.unwrap();
-        assert_eq!(packet_type, PacketType::CONNECT as u8);
-        assert!(payload.is_empty());
+        assert_eq!(*received.lock().unwrap(), vec![("test/topic".to_string(), payload)]);
     }
     
-    #[test]
-    fn test_extract_publish_payload() {
-        // 创建一个简单的PUBLISH载荷用于测试
-        let mut payload = vec![0x00, 0x05]; // 主题长度: 5
-        payload.extend_from_slice(b"topic"); // 主题: "topic"
-        payload.extend_from_slice(b"message"); // 消息: "message"
+    #[tokio::test]
+    async fn test_on_json_decodes_payload() {
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        struct Reading {
+            value: f64,
+        }
+        
+        let mut client = MqttClient::new("test-client".to_string());
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = received.clone();
+        client.on_json("sensors/+".to_string(), move |topic, reading: Reading| {
+            sink.lock().unwrap().push((topic, reading));
+        }).await;
+        
+        for (topic, payload) in [("sensors/temp", &br#"{"value": 21.5}"#[..]), ("sensors/humidity", b"not json")] {
+            let publish = MqttClient::create_publish_packet(topic, Bytes::from_static(payload), QoS::AtMostOnce);
+            client.shared.dispatch_publish(&publish).await;
+        }
+        
+        // 无法反序列化的消息被丢弃
+        assert_eq!(*received.lock().unwrap(), vec![("sensors/temp".to_string(), Reading { value: 21.5 })]);
+    }
+    
+    #[tokio::test]
+    async fn test_dispatch_publish_matches_wildcard_callbacks() {
+        let mut client = MqttClient::new("test-client".to_string());
+        let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+        for filter in ["sensors/+/temp", "sensors/#", "$SYS/#", "#", "$share/workers/sensors/#"] {
+            let sink = received.clone();
+            client.on_message(filter.to_string(), move |_topic, _message| {
+                sink.lock().unwrap().push(filter);
+            }).await;
+        }
+        
+        let publish = MqttClient::create_publish_packet("sensors/kitchen/temp", Bytes::from_static(b"21.5"), QoS::AtMostOnce);
+        client.shared.dispatch_publish(&publish).await;
+        let mut filters = received.lock().unwrap().clone();
+        filters.sort();
+        assert_eq!(filters, vec!["#", "$share/workers/sensors/#", "sensors/#", "sensors/+/temp"]);
+        
+        // $开头的系统主题不会被顶层 # 匹配
+        received.lock().unwrap().clear();
+        let publish = MqttClient::create_publish_packet("$SYS/broker/uptime", Bytes::from_static(b"10"), QoS::AtMostOnce);
+        client.shared.dispatch_publish(&publish).await;
+        assert_eq!(*received.lock().unwrap(), vec!["$SYS/#"]);
+    }
+    
+    /// 绑定模拟代理的监听端口
+    async fn mock_broker() -> (tokio::net::TcpListener, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        (listener, addr)
+    }
+    
+    /// 接受连接的CONNACK
+    fn accepted_connack() -> Packet {
+        Packet::Connack(crate::packet::ConnackPacket {
+            session_present: false,
+            return_code: 0,
+            properties: Properties::default(),
+        })
+    }
+    
+    /// 接受一个客户端连接并完成CONNECT/CONNACK握手
+    async fn accept_connect(listener: &tokio::net::TcpListener) -> Framed<TcpStream, MqttCodec> {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        assert!(matches!(framed.next().await, Some(Ok(Packet::Connect(_)))));
+        framed.send(accepted_connack()).await.unwrap();
+        framed
+    }
+    
+    #[tokio::test]
+    async fn test_v5_does_not_resend_on_live_connection() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let publish = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            // 超过多个重传间隔也不会收到带DUP标志的重发
+            assert!(tokio::time::timeout(Duration::from_millis(300), framed.next()).await.is_err());
+            framed.send(Packet::Puback(AckPacket::new(publish.packet_id.unwrap()))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+
+        let mut client = MqttClient::new("v5-retry-client".to_string());
+        client.set_protocol_version(MQTT_PROTOCOL_VERSION_5).unwrap();
+        client.set_retry_interval(Duration::from_millis(50));
+        client.connect(&addr).await.unwrap();
+        client.publish("test/topic".to_string(), "hello".to_string(), QoS::AtLeastOnce).await.unwrap();
+        client.disconnect().await.unwrap();
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_qos1_publish_retransmits_with_dup() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            // 忽略第一次发送，等待带DUP标志的重传后再确认
+            let first = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert!(!first.dup);
+            let second = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert!(second.dup);
+            assert_eq!(second.packet_id, first.packet_id);
+            framed.send(Packet::Puback(AckPacket::new(second.packet_id.unwrap()))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+        
+        let mut client = MqttClient::new("qos1-client".to_string());
+        client.set_retry_interval(Duration::from_millis(50));
+        client.connect(&addr).await.unwrap();
+        client.publish("test/topic".to_string(), "hello".to_string(), QoS::AtLeastOnce).await.unwrap();
+        // 断开后事件循环交回会话状态，确认流程都已结束
+        client.disconnect().await.unwrap();
+        assert!(client.session.inflight.is_empty());
+        broker.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_qos2_publish_completes_four_way_handshake() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let packet_id = match framed.next().await { Some(Ok(Packet::Publish(p))) => p.packet_id.unwrap(), other => panic!("{:?}", other) };
+            framed.send(Packet::Pubrec(AckPacket::new(packet_id))).await.unwrap();
+            assert_eq!(framed.next().await.unwrap().unwrap(), Packet::Pubrel(AckPacket::new(packet_id)));
+            framed.send(Packet::Pubcomp(AckPacket::new(packet_id))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+        
+        let mut client = MqttClient::new("qos2-client".to_string());
+        client.connect(&addr).await.unwrap();
+        client.publish("test/topic".to_string(), "hello".to_string(), QoS::ExactlyOnce).await.unwrap();
+        // 断开后事件循环交回会话状态，确认流程都已结束
+        client.disconnect().await.unwrap();
+        assert!(client.session.inflight.is_empty());
+        broker.await.unwrap();
+    }
+    
+    #[tokio::test]
+    async fn test_connect_sends_will() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, MqttCodec::new());
+            let connect = match framed.next().await { Some(Ok(Packet::Connect(c))) => c, other => panic!("{:?}", other) };
+            framed.send(accepted_connack()).await.unwrap();
+            connect.will
+        });
         
-        let result = MqttClient::extract_publish_payload(&payload);
+        let mut client = MqttClient::new("will-client".to_string());
+        client.set_will("status/will-client".to_string(), "offline".to_string(), QoS::AtLeastOnce, true);
+        client.connect(&addr).await.unwrap();
         
-        assert!(result.is_some());
-        let (topic, message) = result.unwrap();
-        assert_eq!(topic, "topic");
-        assert_eq!(message, "message");
+        let will = broker.await.unwrap().expect("CONNECT should carry a will");
+        assert_eq!(will.topic, "status/will-client");
+        assert_eq!(&will.message[..], b"offline");
+        assert_eq!(will.qos, QoS::AtLeastOnce);
+        assert!(will.retain);
     }
-}
\ No newline at end of file
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_client_sends_pingreq_and_detects_missing_pingresp() {
+        // 使用内存管道，避免暂停的时钟在真实套接字数据到达前推进
+        let (client_side, broker_side) = tokio::io::duplex(4096);
+        let broker = tokio::spawn(async move {
+            let mut framed = Framed::new(broker_side, MqttCodec::new());
+            let connect = match framed.next().await { Some(Ok(Packet::Connect(c))) => c, other => panic!("{:?}", other) };
+            assert_eq!(connect.keep_alive, 10);
+            framed.send(accepted_connack()).await.unwrap();
+            
+            // 空闲一个保活时间后收到PINGREQ
+            let start = Instant::now();
+            assert_eq!(framed.next().await.unwrap().unwrap(), Packet::Pingreq);
+            assert_eq!(start.elapsed(), Duration::from_secs(10));
+            framed.send(Packet::Pingresp).await.unwrap();
+            
+            // 第二次PINGREQ不回复，客户端应在一个保活时间后放弃连接
+            assert_eq!(framed.next().await.unwrap().unwrap(), Packet::Pingreq);
+            assert_eq!(start.elapsed(), Duration::from_secs(20));
+            assert!(framed.next().await.is_none());
+            start.elapsed()
+        });
+        
+        let mut client = MqttClient::new("ping-client".to_string());
+        client.set_keep_alive(Duration::from_secs(10));
+        client.set_retry_interval(Duration::from_secs(60));
+        let connector = client.connector("duplex");
+        let result = connector.handshake(Box::new(client_side)).await;
+        client.start_event_loop(connector, result).await.unwrap();
+        assert!(matches!(client.start_listening().await, Err(MqttError::Timeout("PINGRESP"))));
+        assert_eq!(client.get_state(), ClientState::Disconnected);
+        assert_eq!(broker.await.unwrap(), Duration::from_secs(30));
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_many_and_unsubscribe() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let subscribe = match framed.next().await { Some(Ok(Packet::Subscribe(s))) => s, other => panic!("{:?}", other) };
+            assert_eq!(subscribe.topics.len(), 2);
+            framed.send(Packet::Suback(crate::packet::SubackPacket {
+                packet_id: subscribe.packet_id,
+                return_codes: vec![0x01, SUBACK_FAILURE],
+                properties: Properties::default(),
+            })).await.unwrap();
+            
+            let unsubscribe = match framed.next().await { Some(Ok(Packet::Unsubscribe(u))) => u, other => panic!("{:?}", other) };
+            assert_eq!(unsubscribe.topics, vec!["a/+".to_string()]);
+            framed.send(Packet::Unsuback(crate::packet::UnsubackPacket {
+                packet_id: unsubscribe.packet_id,
+                reason_codes: Vec::new(),
+                properties: Properties::default(),
+            })).await.unwrap();
+        });
+        
+        let mut client = MqttClient::new("multi-client".to_string());
+        client.connect(&addr).await.unwrap();
+        let return_codes = client.subscribe_many(&[
+            ("a/+".to_string(), QoS::AtLeastOnce),
+            ("a/#/b".to_string(), QoS::AtMostOnce),
+        ]).await.unwrap();
+        assert_eq!(return_codes, vec![0x01, SUBACK_FAILURE]);
+        client.unsubscribe("a/+".to_string()).await.unwrap();
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_v5_connect_adopts_assigned_id_and_reports_rejected_publish() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, MqttCodec::new());
+            let connect = match framed.next().await { Some(Ok(Packet::Connect(c))) => c, other => panic!("{:?}", other) };
+            assert_eq!(connect.protocol_version, MQTT_PROTOCOL_VERSION_5);
+            assert_eq!(connect.properties.session_expiry_interval, Some(300));
+            framed.send(Packet::Connack(crate::packet::ConnackPacket {
+                session_present: false,
+                return_code: reason_code::SUCCESS,
+                properties: Properties {
+                    assigned_client_identifier: Some("assigned-1".to_string()),
+                    ..Properties::default()
+                },
+            })).await.unwrap();
+
+            // 带属性的发布被拒绝
+            let publish = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(publish.properties.response_topic.as_deref(), Some("reply/topic"));
+            framed.send(Packet::Puback(AckPacket::with_reason(publish.packet_id.unwrap(), reason_code::NOT_AUTHORIZED))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+
+        let mut client = MqttClient::new(String::new());
+        client.set_protocol_version(MQTT_PROTOCOL_VERSION_5).unwrap();
+        client.set_session_expiry_interval(300);
+        client.connect(&addr).await.unwrap();
+        assert_eq!(client.client_id(), "assigned-1");
+
+        let properties = Properties { response_topic: Some("reply/topic".to_string()), ..Properties::default() };
+        let result = client.publish_with_properties("test/topic".to_string(), "hello".to_string(), QoS::AtLeastOnce, properties).await;
+        assert!(matches!(result, Err(MqttError::PublishRejected(reason_code::NOT_AUTHORIZED))));
+        // 断开后事件循环交回会话状态，确认流程都已结束
+        client.disconnect().await.unwrap();
+        assert!(client.session.inflight.is_empty());
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handles_publish_concurrently_while_listening() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            // 两个发布都发出后才逆序确认，只有并发执行的请求才能完成
+            let first = match framed.next().await { Some(Ok(Packet::Publish(p))) => p.packet_id.unwrap(), other => panic!("{:?}", other) };
+            let second = match framed.next().await { Some(Ok(Packet::Publish(p))) => p.packet_id.unwrap(), other => panic!("{:?}", other) };
+            framed.send(Packet::Puback(AckPacket::new(second))).await.unwrap();
+            framed.send(Packet::Puback(AckPacket::new(first))).await.unwrap();
+            framed.send(Packet::Publish(MqttClient::create_publish_packet("cmd/device", Bytes::from_static(b"reboot"), QoS::AtMostOnce))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+
+        let mut client = MqttClient::new("concurrent-client".to_string());
+        client.connect(&addr).await.unwrap();
+        let handle = client.handle();
+        let mut messages = client.messages();
+        let listening = tokio::spawn(async move {
+            let result = client.start_listening().await.map_err(|e| e.to_string());
+            (client.get_state(), result)
+        });
+
+        let other = handle.clone();
+        let (a, b) = tokio::join!(
+            handle.publish("test/a".to_string(), "a".to_string(), QoS::AtLeastOnce),
+            other.publish("test/b".to_string(), "b".to_string(), QoS::AtLeastOnce),
+        );
+        a.unwrap();
+        b.unwrap();
+
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.topic, "cmd/device");
+        assert_eq!(&message.payload[..], b"reboot");
+
+        handle.disconnect().await.unwrap();
+        assert_eq!(listening.await.unwrap(), (ClientState::Disconnected, Ok(())));
+        let result = handle.publish("test/a".to_string(), "a".to_string(), QoS::AtMostOnce).await;
+        assert!(matches!(result, Err(MqttError::InvalidState { operation: "Publish", state: ClientState::Disconnected })));
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_stream_is_bounded_and_dropped_streams_are_removed() {
+        let client = MqttClient::new("stream-client".to_string());
+        let mut stream = client.messages();
+        let dropped = client.messages();
+        drop(dropped);
+        let publish = MqttClient::create_publish_packet("t", Bytes::from_static(b"x"), QoS::AtMostOnce);
+        for _ in 0..MESSAGE_STREAM_CAPACITY + 10 {
+            client.handle.shared.dispatch_publish(&publish).await;
+        }
+        assert_eq!(client.handle.shared.listeners.lock().unwrap().len(), 1);
+
+        // 超出容量的消息被丢弃，之前的消息照常读取
+        let mut received = 0;
+        while stream.receiver.try_recv().is_ok() {
+            received += 1;
+        }
+        assert_eq!(received, MESSAGE_STREAM_CAPACITY);
+
+        drop(stream);
+        let _stream = client.messages();
+        assert_eq!(client.handle.shared.listeners.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dropping_client_and_handles_closes_connection() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            // 没有DISCONNECT，连接直接关闭
+            assert!(framed.next().await.is_none());
+        });
+
+        let mut client = MqttClient::new("dropped-client".to_string());
+        client.connect(&addr).await.unwrap();
+        let handle = client.handle();
+        drop(client);
+        assert_eq!(handle.get_state(), ClientState::Connected);
+        drop(handle);
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_restores_subscriptions_and_flushes_buffered_publishes() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let subscribe = match framed.next().await { Some(Ok(Packet::Subscribe(s))) => s, other => panic!("{:?}", other) };
+            framed.send(Packet::Suback(crate::packet::SubackPacket {
+                packet_id: subscribe.packet_id,
+                return_codes: vec![0x01, SUBACK_FAILURE],
+                properties: Properties::default(),
+            })).await.unwrap();
+            // 收到QoS 1发布后不确认，直接断开连接
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Publish(_)))));
+            drop(framed);
+
+            // 新连接上没有会话：未确认的发布重新发送，订阅成功的过滤器重新订阅，然后是缓存的发布
+            let mut framed = accept_connect(&listener).await;
+            let resent = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(&resent.payload[..], b"before");
+            assert!(!resent.dup);
+            let subscribe = match framed.next().await { Some(Ok(Packet::Subscribe(s))) => s, other => panic!("{:?}", other) };
+            assert_eq!(subscribe.topics, vec![("a/+".to_string(), QoS::AtLeastOnce)]);
+            let buffered = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(&buffered.payload[..], b"buffered");
+            framed.send(Packet::Puback(AckPacket::new(resent.packet_id.unwrap()))).await.unwrap();
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+
+        let mut client = MqttClient::new("reconnect-client".to_string());
+        client.set_reconnect_policy(ReconnectPolicy::new().initial_delay(Duration::from_millis(50)).jitter(0.0));
+        client.connect(&addr).await.unwrap();
+        client.subscribe_many(&[
+            ("a/+".to_string(), QoS::AtLeastOnce),
+            ("a/#/b".to_string(), QoS::AtMostOnce),
+        ]).await.unwrap();
+
+        let handle = client.handle();
+        let pending = tokio::spawn(async move {
+            handle.publish("test/topic".to_string(), "before".to_string(), QoS::AtLeastOnce).await.map_err(|e| e.to_string())
+        });
+        while client.get_state() != ClientState::Reconnecting {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        // 重连期间不能订阅，发布缓存到重新连接后发送
+        let result = client.subscribe("a/+".to_string(), QoS::AtMostOnce).await;
+        assert!(matches!(result, Err(MqttError::InvalidState { state: ClientState::Reconnecting, .. })));
+        client.publish("test/topic".to_string(), "buffered".to_string(), QoS::AtMostOnce).await.unwrap();
+
+        assert_eq!(pending.await.unwrap(), Ok(()));
+        assert_eq!(client.get_state(), ClientState::Connected);
+        client.disconnect().await.unwrap();
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_gives_up_after_max_attempts() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            drop(accept_connect(&listener).await);
+            // 之后的连接都在CONNACK之前被关闭
+            for _ in 0..2 {
+                let (socket, _) = listener.accept().await.unwrap();
+                drop(socket);
+            }
+        });
+
+        let mut client = MqttClient::new("give-up-client".to_string());
+        client.set_reconnect_policy(ReconnectPolicy::new().initial_delay(Duration::from_millis(10)).jitter(0.0).max_attempts(2));
+        client.connect(&addr).await.unwrap();
+        assert!(matches!(client.start_listening().await, Err(MqttError::ReconnectFailed(2))));
+        assert_eq!(client.get_state(), ClientState::Disconnected);
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_refused_connack_fails_connect() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            for return_code in [0x05, reason_code::BAD_USERNAME_OR_PASSWORD] {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut framed = Framed::new(socket, MqttCodec::new());
+                assert!(matches!(framed.next().await, Some(Ok(Packet::Connect(_)))));
+                framed.send(Packet::Connack(crate::packet::ConnackPacket {
+                    session_present: false,
+                    return_code,
+                    properties: Properties::default(),
+                })).await.unwrap();
+            }
+        });
+
+        let mut client = MqttClient::new("refused-client".to_string());
+        let result = client.connect(&addr).await;
+        assert!(matches!(result, Err(MqttError::ConnectionRefused(ConnackCode::NotAuthorized))));
+        assert_eq!(client.get_state(), ClientState::Disconnected);
+
+        // MQTT 5.0按原因码解释
+        client.set_protocol_version(MQTT_PROTOCOL_VERSION_5).unwrap();
+        let result = client.connect(&addr).await;
+        assert!(matches!(result, Err(MqttError::ConnectionRefused(ConnackCode::BadUsernameOrPassword))));
+        let result = client.publish("test/topic".to_string(), "hello".to_string(), QoS::AtMostOnce).await;
+        assert!(matches!(result, Err(MqttError::InvalidState { state: ClientState::Disconnected, .. })));
+        assert!(matches!(client.set_protocol_version(3), Err(MqttError::InvalidArgument(_))));
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connect_validates_options() {
+        let mut client = MqttClient::new(MqttOptions::new("sensor-1").strict_client_id(true));
+        let result = client.connect("127.0.0.1:1").await;
+        assert!(matches!(result, Err(MqttError::InvalidArgument(_))));
+        assert_eq!(client.get_state(), ClientState::Disconnected);
+    }
+
+    #[tokio::test]
+    async fn test_publish_rejects_invalid_topic_and_overlong_fields() {
+        // 在检查连接状态之前拒绝，不会产生损坏的数据包
+        let client = MqttClient::new("c".to_string());
+        let long = "a".repeat(70_000);
+        for topic in ["", "sensors/+", &long] {
+            let result = client.publish(topic.to_string(), "x", QoS::AtMostOnce).await;
+            assert!(matches!(result, Err(MqttError::InvalidArgument(_))), "{:?}", result);
+        }
+        let properties = Properties { response_topic: Some(long.clone()), ..Properties::default() };
+        let result = client.publish_with_properties("t".to_string(), "x", QoS::AtMostOnce, properties).await;
+        assert!(matches!(result, Err(MqttError::InvalidArgument(_))));
+        assert!(matches!(client.subscribe(long.clone(), QoS::AtMostOnce).await, Err(MqttError::InvalidArgument(_))));
+        assert!(matches!(client.unsubscribe(long).await, Err(MqttError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn test_connect_times_out_without_connack() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, MqttCodec::new());
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Connect(_)))));
+            // 不回复CONNACK，等待客户端超时后关闭连接
+            assert!(framed.next().await.is_none());
+        });
+
+        let options = MqttOptions::new("slow-client").connect_timeout(Duration::from_millis(100));
+        let mut client = MqttClient::new(options);
+        assert!(matches!(client.connect(&addr).await, Err(MqttError::Timeout("CONNACK"))));
+        assert_eq!(client.get_state(), ClientState::Disconnected);
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_inflight_window_queues_publishes() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let first = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(first.topic, "test/1");
+            // 窗口已满，确认之前不会收到后面的发布
+            assert!(tokio::time::timeout(Duration::from_millis(100), framed.next()).await.is_err());
+            framed.send(Packet::Puback(AckPacket::new(first.packet_id.unwrap()))).await.unwrap();
+
+            let second = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(second.topic, "test/2");
+            framed.send(Packet::Puback(AckPacket::new(second.packet_id.unwrap()))).await.unwrap();
+            // 排在后面的QoS 0发布保持顺序
+            let third = match framed.next().await { Some(Ok(Packet::Publish(p))) => p, other => panic!("{:?}", other) };
+            assert_eq!(third.topic, "test/3");
+            assert!(matches!(framed.next().await, Some(Ok(Packet::Disconnect(_)))));
+        });
+
+        let mut client = MqttClient::new(MqttOptions::new("window-client").inflight_window(1));
+        client.connect(&addr).await.unwrap();
+        let handle = client.handle();
+        let (first, second, third) = tokio::join!(
+            handle.publish("test/1".to_string(), "1".to_string(), QoS::AtLeastOnce),
+            async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                handle.publish("test/2".to_string(), "2".to_string(), QoS::AtLeastOnce).await
+            },
+            async {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                handle.publish("test/3".to_string(), "3".to_string(), QoS::AtMostOnce).await
+            },
+        );
+        first.unwrap();
+        second.unwrap();
+        third.unwrap();
+        client.disconnect().await.unwrap();
+        broker.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_packet_size_closes_connection() {
+        let (listener, addr) = mock_broker().await;
+        let broker = tokio::spawn(async move {
+            let mut framed = accept_connect(&listener).await;
+            let publish = MqttClient::create_publish_packet("test/topic", Bytes::from(vec![0u8; 100]), QoS::AtMostOnce);
+            framed.send(Packet::Publish(publish)).await.unwrap();
+            assert!(framed.next().await.is_none());
+        });
+
+        let mut client = MqttClient::new(MqttOptions::new("small-client").max_packet_size(64));
+        client.connect(&addr).await.unwrap();
+        assert!(matches!(client.start_listening().await, Err(MqttError::Protocol(_))));
+        broker.await.unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+/// MQTT流式编解码器
+/// 处理TCP读取中的半包和粘包，供服务端和客户端共用。
+/// 连接的协议级别由CONNECT决定：编解码器收发CONNECT时自动切换到其声明的协议级别
+use bytes::{Buf, BytesMut};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+use crate::packet::{decode_remaining_length, malformed, Packet};
+use crate::protocol::{MAX_REMAINING_LENGTH, MQTT_PROTOCOL_VERSION};
+
+/// 基于 `tokio_util::codec` 的MQTT编解码器
+#[derive(Debug, Clone)]
+pub struct MqttCodec {
+    /// 允许接收的最大包体长度（剩余长度）
+    max_packet_size: usize,
+    /// 当前连接使用的协议级别
+    protocol_version: u8,
+}
+
+impl MqttCodec {
+    pub fn new() -> Self {
+        Self::with_max_packet_size(MAX_REMAINING_LENGTH)
+    }
+
+    /// 创建限制最大包长度的编解码器
+    pub fn with_max_packet_size(max_packet_size: usize) -> Self {
+        MqttCodec {
+            max_packet_size,
+            protocol_version: MQTT_PROTOCOL_VERSION,
+        }
+    }
+
+    /// 当前连接使用的协议级别
+    pub fn protocol_version(&self) -> u8 {
+        self.protocol_version
+    }
+}
+
+impl Default for MqttCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MqttCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+
+        // 解析固定头部中的剩余长度
+        let (remaining_length, length_bytes) = match decode_remaining_length(&src[1..])? {
+            Some(result) => result,
+            None => return Ok(None),
+        };
+        if remaining_length > self.max_packet_size {
+            return Err(malformed(format!(
+                "packet size {} exceeds limit {}",
+                remaining_length, self.max_packet_size
+            )));
+        }
+
+        // 等待完整的数据包
+        let frame_length = 1 + length_bytes + remaining_length;
+        if src.len() < frame_length {
+            src.reserve(frame_length - src.len());
+            return Ok(None);
+        }
+
+        let header = src[0];
+        src.advance(1 + length_bytes);
+        let body = src.split_to(remaining_length).freeze();
+        let packet = Packet::decode_with_version(header, body, self.protocol_version)?;
+        if let Packet::Connect(ref connect) = packet {
+            self.protocol_version = connect.protocol_version;
+        }
+        Ok(Some(packet))
+    }
+}
+
+impl Encoder<Packet> for MqttCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if let Packet::Connect(ref connect) = packet {
+            self.protocol_version = connect.protocol_version;
+        }
+        // 超长的字段会产生损坏的数据包，编码前拒绝
+        packet.check_field_lengths()?;
+        packet.encode_with_version(dst, self.protocol_version);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{PublishPacket, SubscribePacket};
+    use crate::properties::Properties;
+    use crate::protocol::QoS;
+    use bytes::Bytes;
+
+    fn publish(topic: &str, payload: &[u8]) -> Packet {
+        Packet::Publish(PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: topic.to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: Bytes::copy_from_slice(payload),
+        })
+    }
+
+    #[test]
+    fn test_decode_partial_reads() {
+        let packet = publish("test/topic", &[7u8; 500]);
+        let encoded = packet.to_bytes();
+
+        // 逐字节喂入数据，只有最后一个字节到达时才能解出完整数据包
+        let mut codec = MqttCodec::new();
+        let mut buffer = BytesMut::new();
+        for (i, byte) in encoded.iter().enumerate() {
+            buffer.extend_from_slice(&[*byte]);
+            let result = codec.decode(&mut buffer).unwrap();
+            if i + 1 < encoded.len() {
+                assert!(result.is_none());
+            } else {
+                assert_eq!(result, Some(packet.clone()));
+            }
+        }
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_decode_multiple_packets_in_one_read() {
+        let packets = vec![
+            publish("a", b"first"),
+            Packet::Subscribe(SubscribePacket {
+                packet_id: 1,
+                topics: vec![("a/b".to_string(), QoS::AtLeastOnce)],
+                properties: Properties::default(),
+            }),
+            Packet::Pingreq,
+            publish("b", b"second"),
+        ];
+
+        let mut codec = MqttCodec::new();
+        let mut buffer = BytesMut::new();
+        for packet in &packets {
+            codec.encode(packet.clone(), &mut buffer).unwrap();
+        }
+
+        let mut decoded = Vec::new();
+        while let Some(packet) = codec.decode(&mut buffer).unwrap() {
+            decoded.push(packet);
+        }
+        assert_eq!(decoded, packets);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_connect_switches_protocol_version() {
+        let mut connect = crate::packet::ConnectPacket::new("v5".to_string());
+        connect.protocol_version = crate::protocol::MQTT_PROTOCOL_VERSION_5;
+
+        let mut client = MqttCodec::new();
+        let mut server = MqttCodec::new();
+        let mut buffer = BytesMut::new();
+        client.encode(Packet::Connect(connect), &mut buffer).unwrap();
+        assert!(matches!(server.decode(&mut buffer).unwrap(), Some(Packet::Connect(_))));
+        assert_eq!(client.protocol_version(), 5);
+        assert_eq!(server.protocol_version(), 5);
+
+        // 之后的数据包按5.0格式编解码，属性得以保留
+        let mut packet = publish("a", b"v5");
+        if let Packet::Publish(ref mut publish) = packet {
+            publish.properties.content_type = Some("text/plain".to_string());
+        }
+        server.encode(packet.clone(), &mut buffer).unwrap();
+        assert_eq!(client.decode(&mut buffer).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn test_decode_rejects_oversized_packet() {
+        let mut codec = MqttCodec::with_max_packet_size(16);
+        let mut buffer = publish("test/topic", &[0u8; 64]).to_bytes();
+        assert!(codec.decode(&mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_encode_rejects_overlong_fields() {
+        let mut codec = MqttCodec::new();
+        let mut buffer = BytesMut::new();
+        let error = codec.encode(publish(&"a".repeat(70_000), b"x"), &mut buffer).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+
+        let Packet::Publish(mut packet) = publish("test/topic", b"x") else { unreachable!() };
+        packet.properties.correlation_data = Some(Bytes::from(vec![0u8; 70_000]));
+        assert!(codec.encode(Packet::Publish(packet), &mut buffer).is_err());
+        // 不写出任何数据
+        assert!(buffer.is_empty());
+    }
+}
@@ -0,0 +1,415 @@
+/// 代理配置文件
+/// TOML格式，所有配置项都有默认值，未知的配置项视为错误；
+/// 命令行参数在加载配置文件之后覆盖对应的配置项，启动前由`validate`检查全部配置
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use crate::acl::Acl;
+use crate::auth::PasswordFileAuthenticator;
+use crate::protocol::MAX_REMAINING_LENGTH;
+use crate::server::{Backpressure, MqttBroker, DEFAULT_QUEUE_CAPACITY};
+use crate::storage::FileStorage;
+use crate::tls::{TlsServerOptions, DEFAULT_TLS_PORT};
+use crate::websocket::DEFAULT_WS_PATH;
+
+/// 代理的全部配置
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BrokerConfig {
+    /// 日志过滤表达式，如`info`或`info,mqtt::server=debug`；设置了`RUST_LOG`时以环境变量为准
+    pub log_level: String,
+    pub listeners: ListenersConfig,
+    pub tls: TlsConfig,
+    pub auth: AuthConfig,
+    pub acl: AclConfig,
+    pub limits: LimitsConfig,
+    pub persistence: PersistenceConfig,
+}
+
+/// 监听地址
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenersConfig {
+    /// MQTT over TCP
+    pub tcp: String,
+    /// MQTT over WebSocket，不设置时不监听
+    pub websocket: Option<String>,
+    /// WebSocket升级请求的路径
+    pub websocket_path: String,
+    /// 管理HTTP服务，不设置时不启动
+    pub admin: Option<String>,
+}
+
+/// TLS监听，设置了证书和私钥时启用
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub bind: String,
+    /// PEM格式的证书链
+    pub cert: Option<PathBuf>,
+    /// PEM格式的私钥
+    pub key: Option<PathBuf>,
+    /// 校验客户端证书的CA证书，不设置时不要求客户端证书
+    pub client_ca: Option<PathBuf>,
+    /// 是否拒绝没有客户端证书的连接
+    pub require_client_cert: bool,
+    /// 是否以客户端证书的CN作为用户名
+    pub identity_as_username: bool,
+}
+
+/// 认证方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthBackend {
+    /// 接受所有连接
+    #[default]
+    AllowAll,
+    /// 按bcrypt密码文件校验用户名和密码
+    PasswordFile,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub backend: AuthBackend,
+    /// `password_file`认证方式使用的密码文件
+    pub password_file: Option<PathBuf>,
+    /// 是否允许不带用户名的连接
+    pub allow_anonymous: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    /// ACL文件，不设置时允许所有发布和订阅
+    pub file: Option<PathBuf>,
+}
+
+/// 资源限制
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    /// 接收数据包的最大剩余长度（字节）
+    pub max_packet_size: usize,
+    /// 每个连接上已发送未确认的QoS 1/2消息上限
+    pub max_inflight: u16,
+    /// 在线连接数上限，不设置时不限制
+    pub max_connections: Option<usize>,
+    /// 每个在线会话的消息队列容量
+    pub queue_capacity: usize,
+    /// 消息队列已满时的处理策略
+    pub backpressure: Backpressure,
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PersistenceConfig {
+    /// 保存保留消息和持久会话的日志文件，不设置时只保存在内存中
+    pub path: Option<PathBuf>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            log_level: "info".to_string(),
+            listeners: ListenersConfig::default(),
+            tls: TlsConfig::default(),
+            auth: AuthConfig::default(),
+            acl: AclConfig::default(),
+            limits: LimitsConfig::default(),
+            persistence: PersistenceConfig::default(),
+        }
+    }
+}
+
+impl Default for ListenersConfig {
+    fn default() -> Self {
+        ListenersConfig {
+            tcp: "127.0.0.1:1883".to_string(),
+            websocket: None,
+            websocket_path: DEFAULT_WS_PATH.to_string(),
+            admin: None,
+        }
+    }
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            bind: format!("127.0.0.1:{}", DEFAULT_TLS_PORT),
+            cert: None,
+            key: None,
+            client_ca: None,
+            require_client_cert: false,
+            identity_as_username: false,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_packet_size: MAX_REMAINING_LENGTH,
+            max_inflight: u16::MAX,
+            max_connections: None,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// 配置错误
+#[derive(Debug)]
+pub enum ConfigError {
+    /// 无法读取配置文件
+    Io(PathBuf, io::Error),
+    /// TOML语法错误、未知配置项或类型不符
+    Parse(String),
+    /// 配置项的取值不合法：(配置项, 原因)
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "cannot read config file {}: {}", path.display(), e),
+            ConfigError::Parse(message) => write!(f, "{}", message),
+            ConfigError::Invalid(key, message) => write!(f, "invalid value for '{}': {}", key, message),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl BrokerConfig {
+    /// 读取并解析配置文件（不做取值检查）
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Self::parse(&contents).map_err(|e| ConfigError::Parse(format!("{}: {}", path.display(), e)))
+    }
+
+    /// 解析TOML格式的配置（不做取值检查）
+    pub fn parse(contents: &str) -> Result<Self, ConfigError> {
+        toml::from_str(contents).map_err(|e| ConfigError::Parse(e.to_string().trim_end().to_string()))
+    }
+
+    /// 检查配置项的取值，返回遇到的第一个错误
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate_log_level(&self.log_level)?;
+
+        validate_address("listeners.tcp", &self.listeners.tcp)?;
+        if let Some(addr) = &self.listeners.websocket {
+            validate_address("listeners.websocket", addr)?;
+        }
+        if !self.listeners.websocket_path.starts_with('/') {
+            return Err(ConfigError::Invalid("listeners.websocket_path", "must start with '/'".to_string()));
+        }
+        if let Some(addr) = &self.listeners.admin {
+            validate_address("listeners.admin", addr)?;
+        }
+
+        let tls = &self.tls;
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => {
+                validate_address("tls.bind", &tls.bind)?;
+                validate_file("tls.cert", cert)?;
+                validate_file("tls.key", key)?;
+                if let Some(ca) = &tls.client_ca {
+                    validate_file("tls.client_ca", ca)?;
+                }
+            }
+            (Some(_), None) => return Err(ConfigError::Invalid("tls.key", "required when tls.cert is set".to_string())),
+            (None, Some(_)) => return Err(ConfigError::Invalid("tls.cert", "required when tls.key is set".to_string())),
+            (None, None) => {
+                if tls.client_ca.is_some() {
+                    return Err(ConfigError::Invalid("tls.client_ca", "requires tls.cert and tls.key".to_string()));
+                }
+            }
+        }
+        if (tls.require_client_cert || tls.identity_as_username) && tls.client_ca.is_none() {
+            return Err(ConfigError::Invalid("tls.client_ca", "required to verify client certificates".to_string()));
+        }
+
+        match (self.auth.backend, &self.auth.password_file) {
+            (AuthBackend::PasswordFile, Some(path)) => validate_file("auth.password_file", path)?,
+            (AuthBackend::PasswordFile, None) => {
+                return Err(ConfigError::Invalid("auth.password_file", "required by the password_file backend".to_string()));
+            }
+            (AuthBackend::AllowAll, _) => {}
+        }
+        if let Some(path) = &self.acl.file {
+            validate_file("acl.file", path)?;
+        }
+
+        let limits = &self.limits;
+        if !(1..=MAX_REMAINING_LENGTH).contains(&limits.max_packet_size) {
+            return Err(ConfigError::Invalid(
+                "limits.max_packet_size",
+                format!("must be between 1 and {}", MAX_REMAINING_LENGTH),
+            ));
+        }
+        if limits.max_inflight == 0 {
+            return Err(ConfigError::Invalid("limits.max_inflight", "must be at least 1".to_string()));
+        }
+        if limits.max_connections == Some(0) {
+            return Err(ConfigError::Invalid("limits.max_connections", "must be at least 1".to_string()));
+        }
+        if limits.queue_capacity == 0 {
+            return Err(ConfigError::Invalid("limits.queue_capacity", "must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+
+    /// 按配置创建代理：绑定所有监听地址，加载证书、密码文件、ACL文件和持久化存储
+    ///
+    /// 调用前应先通过`validate`。
+    pub async fn build(&self) -> Result<MqttBroker, Box<dyn Error>> {
+        let listeners = &self.listeners;
+        let mut broker = MqttBroker::new(&listeners.tcp)
+            .await
+            .map_err(|e| format!("cannot listen on {}: {}", listeners.tcp, e))?;
+        if let Some(addr) = &listeners.websocket {
+            broker
+                .enable_websocket(addr, &listeners.websocket_path)
+                .await
+                .map_err(|e| format!("cannot listen for WebSocket on {}: {}", addr, e))?;
+        }
+        if let Some(addr) = &listeners.admin {
+            broker.enable_admin(addr).await.map_err(|e| format!("cannot start admin server on {}: {}", addr, e))?;
+        }
+        if let (Some(cert), Some(key)) = (&self.tls.cert, &self.tls.key) {
+            let mut options = TlsServerOptions::new(cert, key).use_identity_as_username(self.tls.identity_as_username);
+            if let Some(ca) = &self.tls.client_ca {
+                options = options.client_ca(ca, self.tls.require_client_cert);
+            }
+            broker.enable_tls(&self.tls.bind, options).await.map_err(|e| format!("cannot enable TLS: {}", e))?;
+        }
+
+        if self.auth.backend == AuthBackend::PasswordFile {
+            let path = self.auth.password_file.as_deref().ok_or("auth.password_file is not set")?;
+            let mut authenticator = PasswordFileAuthenticator::from_file(path)
+                .map_err(|e| format!("cannot load password file {}: {}", path.display(), e))?;
+            authenticator.set_allow_anonymous(self.auth.allow_anonymous);
+            broker.set_authenticator(authenticator);
+        }
+        if let Some(path) = &self.acl.file {
+            let acl = Acl::from_file(path).map_err(|e| format!("cannot load ACL file {}: {}", path.display(), e))?;
+            broker.set_acl(acl);
+        }
+
+        let limits = &self.limits;
+        broker.set_max_packet_size(limits.max_packet_size);
+        broker.set_max_inflight(limits.max_inflight);
+        if let Some(max_connections) = limits.max_connections {
+            broker.set_max_connections(max_connections);
+        }
+        broker.set_queue_capacity(limits.queue_capacity);
+        broker.set_backpressure(limits.backpressure);
+
+        if let Some(path) = &self.persistence.path {
+            let storage = FileStorage::open(path).map_err(|e| format!("cannot open storage {}: {}", path.display(), e))?;
+            broker.set_storage(storage)?;
+        }
+        Ok(broker)
+    }
+}
+
+fn validate_address(key: &'static str, addr: &str) -> Result<(), ConfigError> {
+    match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(ConfigError::Invalid(key, format!("'{}' does not resolve to any address", addr))),
+        Err(e) => Err(ConfigError::Invalid(key, format!("'{}' is not a valid address: {}", addr, e))),
+    }
+}
+
+fn validate_file(key: &'static str, path: &Path) -> Result<(), ConfigError> {
+    if path.is_file() {
+        Ok(())
+    } else {
+        Err(ConfigError::Invalid(key, format!("{} does not exist or is not a file", path.display())))
+    }
+}
+
+/// 检查env_logger格式的过滤表达式：逗号分隔的`级别`或`模块=级别`
+fn validate_log_level(filter: &str) -> Result<(), ConfigError> {
+    for directive in filter.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let level = directive.rsplit_once('=').map_or(directive, |(_, level)| level);
+        if level.parse::<log::LevelFilter>().is_err() {
+            return Err(ConfigError::Invalid(
+                "log_level",
+                format!("unknown level '{}', expected off, error, warn, info, debug or trace", level),
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_example_config_parses_and_defaults_apply() {
+        let config = BrokerConfig::parse(include_str!("../broker.example.toml")).unwrap();
+        assert_eq!(config.listeners.tcp, "0.0.0.0:1883");
+        assert_eq!(config.listeners.websocket.as_deref(), Some("0.0.0.0:8083"));
+        assert_eq!(config.limits.max_inflight, 32);
+        assert_eq!(config.limits.backpressure, Backpressure::Disconnect);
+
+        let config = BrokerConfig::parse("[limits]\nmax_connections = 10\n").unwrap();
+        assert_eq!(config.listeners, ListenersConfig::default());
+        assert_eq!(config.limits.max_connections, Some(10));
+        assert_eq!(config.limits.queue_capacity, DEFAULT_QUEUE_CAPACITY);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_unknown_keys_and_wrong_types_are_rejected() {
+        let error = BrokerConfig::parse("[limits]\nmax_conections = 10\n").unwrap_err();
+        assert!(error.to_string().contains("max_conections"), "{}", error);
+        let error = BrokerConfig::parse("[limits]\nbackpressure = \"wait\"\n").unwrap_err();
+        assert!(error.to_string().contains("wait"), "{}", error);
+        let error = BrokerConfig::from_file("/nonexistent/broker.toml").unwrap_err();
+        assert!(error.to_string().starts_with("cannot read config file /nonexistent/broker.toml"), "{}", error);
+    }
+
+    #[test]
+    fn test_validation_reports_offending_key() {
+        let invalid = |toml: &str| match BrokerConfig::parse(toml).unwrap().validate() {
+            Err(ConfigError::Invalid(key, _)) => key,
+            other => panic!("expected validation error for {:?}, got {:?}", toml, other),
+        };
+        assert_eq!(invalid("log_level = \"verbose\""), "log_level");
+        assert_eq!(invalid("[listeners]\ntcp = \"localhost\""), "listeners.tcp");
+        assert_eq!(invalid("[listeners]\nwebsocket_path = \"mqtt\""), "listeners.websocket_path");
+        assert_eq!(invalid("[tls]\ncert = \"server.pem\""), "tls.key");
+        assert_eq!(invalid("[tls]\nrequire_client_cert = true"), "tls.client_ca");
+        assert_eq!(invalid("[auth]\nbackend = \"password_file\""), "auth.password_file");
+        assert_eq!(invalid("[acl]\nfile = \"/nonexistent/acl\""), "acl.file");
+        assert_eq!(invalid("[limits]\nmax_inflight = 0"), "limits.max_inflight");
+        assert_eq!(invalid("[limits]\nmax_packet_size = 300000000"), "limits.max_packet_size");
+        assert!(BrokerConfig::parse("log_level = \"warn,mqtt::server=debug\"").unwrap().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_build_applies_acl_file() {
+        let dir = std::env::temp_dir().join(format!("mqtt-config-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("acl"), "allow all public/#\n").unwrap();
+        let mut config = BrokerConfig::default();
+        config.listeners.tcp = "127.0.0.1:0".to_string();
+        config.acl.file = Some(dir.join("acl"));
+        config.validate().unwrap();
+        let broker = config.build().await.unwrap();
+        assert!(broker.local_addr().is_ok());
+
+        std::fs::write(dir.join("acl"), "allow everything\n").unwrap();
+        let error = config.build().await.err().unwrap();
+        assert!(error.to_string().contains("line 1"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+/// 客户端和代理共用的错误类型
+/// 调用方可以按变体区分失败原因，例如连接被代理拒绝时读取CONNACK中的原因
+use std::error::Error;
+use std::fmt;
+use std::io;
+use crate::patterns::ClientState;
+use crate::protocol::{reason_code, MQTT_PROTOCOL_VERSION_5};
+
+/// 代理在CONNACK中拒绝连接的原因
+///
+/// 3.1.1的返回码和5.0的原因码中含义相同的值映射到同一个变体。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnackCode {
+    /// 代理不支持客户端请求的协议级别
+    UnacceptableProtocolVersion,
+    /// 客户端标识符不合法或不被接受
+    IdentifierRejected,
+    /// 服务不可用
+    ServerUnavailable,
+    /// 用户名或密码错误
+    BadUsernameOrPassword,
+    /// 未授权连接
+    NotAuthorized,
+    /// 代理繁忙，例如达到连接数上限（MQTT 5.0）
+    ServerBusy,
+    /// 客户端已被禁止连接（MQTT 5.0）
+    Banned,
+    /// 不支持的增强认证方法（MQTT 5.0）
+    BadAuthenticationMethod,
+    /// 其他失败返回码或原因码
+    Other(u8),
+}
+
+impl ConnackCode {
+    /// 按协议级别解释CONNACK中的返回码，连接被接受时返回None
+    pub fn from_return_code(protocol_version: u8, return_code: u8) -> Option<Self> {
+        if return_code == reason_code::SUCCESS {
+            return None;
+        }
+        let code = if protocol_version == MQTT_PROTOCOL_VERSION_5 {
+            match return_code {
+                reason_code::UNSUPPORTED_PROTOCOL_VERSION => ConnackCode::UnacceptableProtocolVersion,
+                reason_code::CLIENT_IDENTIFIER_NOT_VALID => ConnackCode::IdentifierRejected,
+                reason_code::SERVER_UNAVAILABLE => ConnackCode::ServerUnavailable,
+                reason_code::BAD_USERNAME_OR_PASSWORD => ConnackCode::BadUsernameOrPassword,
+                reason_code::NOT_AUTHORIZED => ConnackCode::NotAuthorized,
+                reason_code::SERVER_BUSY => ConnackCode::ServerBusy,
+                reason_code::BANNED => ConnackCode::Banned,
+                reason_code::BAD_AUTHENTICATION_METHOD => ConnackCode::BadAuthenticationMethod,
+                other => ConnackCode::Other(other),
+            }
+        } else {
+            match return_code {
+                0x01 => ConnackCode::UnacceptableProtocolVersion,
+                0x02 => ConnackCode::IdentifierRejected,
+                0x03 => ConnackCode::ServerUnavailable,
+                0x04 => ConnackCode::BadUsernameOrPassword,
+                0x05 => ConnackCode::NotAuthorized,
+                other => ConnackCode::Other(other),
+            }
+        };
+        Some(code)
+    }
+}
+
+impl fmt::Display for ConnackCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnackCode::UnacceptableProtocolVersion => write!(f, "unacceptable protocol version"),
+            ConnackCode::IdentifierRejected => write!(f, "identifier rejected"),
+            ConnackCode::ServerUnavailable => write!(f, "server unavailable"),
+            ConnackCode::BadUsernameOrPassword => write!(f, "bad user name or password"),
+            ConnackCode::NotAuthorized => write!(f, "not authorized"),
+            ConnackCode::ServerBusy => write!(f, "server busy"),
+            ConnackCode::Banned => write!(f, "banned"),
+            ConnackCode::BadAuthenticationMethod => write!(f, "bad authentication method"),
+            ConnackCode::Other(code) => write!(f, "return code {:02x}", code),
+        }
+    }
+}
+
+/// MQTT操作失败的原因
+#[derive(Debug)]
+pub enum MqttError {
+    /// 网络、TLS或文件读写失败
+    Io(io::Error),
+    /// 对方发送了格式错误或不符合协议流程的数据包
+    Protocol(String),
+    /// 代理拒绝了连接
+    ConnectionRefused(ConnackCode),
+    /// 当前状态不允许执行该操作
+    InvalidState { operation: &'static str, state: ClientState },
+    /// 没有与代理建立连接
+    NotConnected,
+    /// 等待应答期间连接断开，操作结果未知
+    ConnectionLost,
+    /// 等待代理应答超时，参数为等待的数据包
+    Timeout(&'static str),
+    /// 代理以失败原因码确认了发布（MQTT 5.0）
+    PublishRejected(u8),
+    /// 代理拒绝了订阅：(主题过滤器, SUBACK返回码)
+    SubscriptionRejected(String, u8),
+    /// 代理发送DISCONNECT断开了连接，参数为原因码（MQTT 5.0）
+    DisconnectedByBroker(u8),
+    /// 自动重连达到最大次数仍未成功
+    ReconnectFailed(u32),
+    /// 重连期间缓存的发布请求已达上限
+    BufferFull,
+    /// 所有包标识符都在等待应答，暂时无法发送需要确认的数据包
+    PacketIdsExhausted,
+    /// 参数不合法，例如不支持的协议级别或空的订阅列表
+    InvalidArgument(String),
+    /// 负载无法序列化为JSON
+    Json(serde_json::Error),
+}
+
+impl MqttError {
+    /// 解码数据包时的错误：编解码器以`InvalidData`表示格式错误的数据包
+    pub(crate) fn decode(error: io::Error) -> Self {
+        if error.kind() == io::ErrorKind::InvalidData {
+            MqttError::Protocol(error.to_string())
+        } else {
+            MqttError::Io(error)
+        }
+    }
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Io(e) => write!(f, "I/O error: {}", e),
+            MqttError::Protocol(message) => write!(f, "protocol error: {}", message),
+            MqttError::ConnectionRefused(code) => write!(f, "connection refused by broker: {}", code),
+            MqttError::InvalidState { operation, state } => {
+                write!(f, "cannot {} in {:?} state", operation.to_lowercase(), state)
+            }
+            MqttError::NotConnected => write!(f, "not connected to broker"),
+            MqttError::ConnectionLost => write!(f, "connection to broker lost"),
+            MqttError::Timeout(packet) => write!(f, "timed out waiting for {}", packet),
+            MqttError::PublishRejected(reason) => write!(f, "publish rejected by broker with reason code {:02x}", reason),
+            MqttError::SubscriptionRejected(filter, code) => {
+                write!(f, "subscription to '{}' rejected by broker with return code {:02x}", filter, code)
+            }
+            MqttError::DisconnectedByBroker(reason) => write!(f, "disconnected by broker with reason code {:02x}", reason),
+            MqttError::ReconnectFailed(attempts) => write!(f, "failed to reconnect after {} attempts", attempts),
+            MqttError::BufferFull => write!(f, "publish buffer is full while reconnecting"),
+            MqttError::PacketIdsExhausted => write!(f, "no free packet identifier"),
+            MqttError::InvalidArgument(message) => write!(f, "invalid argument: {}", message),
+            MqttError::Json(e) => write!(f, "JSON error: {}", e),
+        }
+    }
+}
+
+impl Error for MqttError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MqttError::Io(e) => Some(e),
+            MqttError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MqttError {
+    fn from(error: io::Error) -> Self {
+        MqttError::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for MqttError {
+    fn from(error: serde_json::Error) -> Self {
+        MqttError::Json(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MQTT_PROTOCOL_VERSION;
+
+    #[test]
+    fn test_connack_code_by_protocol_version() {
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION, 0x00), None);
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION, 0x04), Some(ConnackCode::BadUsernameOrPassword));
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION, 0x09), Some(ConnackCode::Other(0x09)));
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION_5, 0x00), None);
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION_5, 0x86), Some(ConnackCode::BadUsernameOrPassword));
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION_5, 0x89), Some(ConnackCode::ServerBusy));
+        // 3.1.1的返回码在5.0中没有相同含义
+        assert_eq!(ConnackCode::from_return_code(MQTT_PROTOCOL_VERSION_5, 0x04), Some(ConnackCode::Other(0x04)));
+    }
+
+    #[test]
+    fn test_decode_error_classification() {
+        let malformed = io::Error::new(io::ErrorKind::InvalidData, "invalid PUBLISH QoS 3");
+        assert!(matches!(MqttError::decode(malformed), MqttError::Protocol(message) if message.contains("QoS 3")));
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert!(matches!(MqttError::decode(reset), MqttError::Io(e) if e.kind() == io::ErrorKind::ConnectionReset));
+    }
+
+    #[test]
+    fn test_display() {
+        let error = MqttError::InvalidState { operation: "Subscribe", state: ClientState::Disconnected };
+        assert_eq!(error.to_string(), "cannot subscribe in Disconnected state");
+        let error = MqttError::ConnectionRefused(ConnackCode::NotAuthorized);
+        assert_eq!(error.to_string(), "connection refused by broker: not authorized");
+    }
+}
@@ -0,0 +1,248 @@
+/// QoS 1/2 在途消息跟踪
+/// 负责包标识符分配、确认流程状态和超时重传，服务端和客户端共用
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::packet::{AckPacket, Packet, PublishPacket};
+use crate::protocol::QoS;
+
+/// 未确认消息的默认重传间隔
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 在途消息所处的确认阶段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflightStage {
+    /// QoS 1：已发送PUBLISH，等待PUBACK
+    AwaitingPuback,
+    /// QoS 2：已发送PUBLISH，等待PUBREC
+    AwaitingPubrec,
+    /// QoS 2：已发送PUBREL，等待PUBCOMP
+    AwaitingPubcomp,
+}
+
+#[derive(Debug, Clone)]
+pub struct InflightMessage {
+    pub publish: PublishPacket,
+    pub stage: InflightStage,
+    pub sent_at: Instant,
+}
+
+/// 发送方向的在途消息窗口
+#[derive(Debug)]
+pub struct InflightWindow {
+    next_id: u16,
+    messages: BTreeMap<u16, InflightMessage>,
+}
+
+impl InflightWindow {
+    pub fn new() -> Self {
+        InflightWindow {
+            next_id: 1,
+            messages: BTreeMap::new(),
+        }
+    }
+
+    /// 分配一个当前未被占用的包标识符（1..=65535循环使用），全部被占用时返回None
+    pub fn next_packet_id(&mut self) -> Option<u16> {
+        for _ in 0..u16::MAX {
+            let id = self.next_id;
+            self.next_id = if self.next_id == u16::MAX { 1 } else { self.next_id + 1 };
+            if !self.messages.contains_key(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// 为QoS > 0的PUBLISH分配包标识符并开始跟踪，返回待发送的数据包；没有空闲的标识符时返回None
+    pub fn start(&mut self, mut publish: PublishPacket) -> Option<PublishPacket> {
+        let id = self.next_packet_id()?;
+        publish.packet_id = Some(id);
+        let stage = match publish.qos {
+            QoS::ExactlyOnce => InflightStage::AwaitingPubrec,
+            _ => InflightStage::AwaitingPuback,
+        };
+        self.messages.insert(id, InflightMessage {
+            publish: publish.clone(),
+            stage,
+            sent_at: Instant::now(),
+        });
+        Some(publish)
+    }
+
+    /// 收到PUBACK，QoS 1流程结束
+    pub fn on_puback(&mut self, packet_id: u16) -> Option<PublishPacket> {
+        self.complete(packet_id, InflightStage::AwaitingPuback)
+    }
+
+    /// 收到PUBREC，进入等待PUBCOMP阶段；返回应回复的PUBREL
+    pub fn on_pubrec(&mut self, packet_id: u16) -> Option<Packet> {
+        let message = self.messages.get_mut(&packet_id)?;
+        match message.stage {
+            InflightStage::AwaitingPubrec | InflightStage::AwaitingPubcomp => {
+                message.stage = InflightStage::AwaitingPubcomp;
+                message.sent_at = Instant::now();
+                Some(Packet::Pubrel(AckPacket::new(packet_id)))
+            }
+            InflightStage::AwaitingPuback => None,
+        }
+    }
+
+    /// 收到PUBCOMP，QoS 2流程结束
+    pub fn on_pubcomp(&mut self, packet_id: u16) -> Option<PublishPacket> {
+        self.complete(packet_id, InflightStage::AwaitingPubcomp)
+    }
+
+    /// 对方以失败原因码（MQTT 5.0，0x80及以上）确认时，消息流程直接结束
+    pub fn abandon(&mut self, packet_id: u16) -> Option<PublishPacket> {
+        self.messages.remove(&packet_id).map(|message| message.publish)
+    }
+
+    fn complete(&mut self, packet_id: u16, expected: InflightStage) -> Option<PublishPacket> {
+        match self.messages.get(&packet_id) {
+            Some(message) if message.stage == expected => {
+                self.messages.remove(&packet_id).map(|message| message.publish)
+            }
+            _ => None,
+        }
+    }
+
+    /// 包标识符是否仍在途
+    pub fn contains(&self, packet_id: u16) -> bool {
+        self.messages.contains_key(&packet_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// 对方尚未收到的PUBLISH：等待PUBACK或PUBREC的消息，按包标识符排序
+    pub fn unreceived(&self) -> impl Iterator<Item = &PublishPacket> {
+        self.messages
+            .values()
+            .filter(|message| message.stage != InflightStage::AwaitingPubcomp)
+            .map(|message| &message.publish)
+    }
+
+    /// 收集超过重传间隔仍未确认的消息，返回需要重发的数据包
+    ///
+    /// PUBLISH重发时设置DUP标志；已收到PUBREC的消息重发PUBREL。
+    pub fn retransmissions(&mut self, retry_interval: Duration) -> Vec<Packet> {
+        let now = Instant::now();
+        let mut packets = Vec::new();
+        for (id, message) in self.messages.iter_mut() {
+            if now.duration_since(message.sent_at) < retry_interval {
+                continue;
+            }
+            message.sent_at = now;
+            match message.stage {
+                InflightStage::AwaitingPubcomp => packets.push(Packet::Pubrel(AckPacket::new(*id))),
+                _ => {
+                    message.publish.dup = true;
+                    packets.push(Packet::Publish(message.publish.clone()));
+                }
+            }
+        }
+        packets
+    }
+}
+
+impl Default for InflightWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::properties::Properties;
+    use bytes::Bytes;
+
+    fn publish(qos: QoS) -> PublishPacket {
+        PublishPacket {
+            dup: false,
+            qos,
+            retain: false,
+            topic: "test/topic".to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: Bytes::from_static(b"payload"),
+        }
+    }
+
+    #[test]
+    fn test_packet_id_allocation_skips_inflight_ids() {
+        let mut window = InflightWindow::new();
+        let first = window.start(publish(QoS::AtLeastOnce)).unwrap().packet_id.unwrap();
+        assert_eq!(first, 1);
+
+        // 绕回后跳过仍在途的标识符1
+        window.next_id = u16::MAX;
+        assert_eq!(window.next_packet_id(), Some(u16::MAX));
+        assert_eq!(window.next_packet_id(), Some(2));
+    }
+
+    #[test]
+    fn test_packet_id_allocation_fails_when_all_ids_inflight() {
+        let mut window = InflightWindow::new();
+        for _ in 0..u16::MAX {
+            assert!(window.start(publish(QoS::AtLeastOnce)).is_some());
+        }
+        assert_eq!(window.next_packet_id(), None);
+        assert!(window.start(publish(QoS::AtLeastOnce)).is_none());
+
+        // 释放一个标识符后可以再次分配
+        window.on_puback(42);
+        assert_eq!(window.next_packet_id(), Some(42));
+    }
+
+    #[test]
+    fn test_qos1_flow() {
+        let mut window = InflightWindow::new();
+        let id = window.start(publish(QoS::AtLeastOnce)).unwrap().packet_id.unwrap();
+        assert!(window.contains(id));
+        // QoS 1不接受PUBCOMP
+        assert!(window.on_pubcomp(id).is_none());
+        assert!(window.on_puback(id).is_some());
+        assert!(window.is_empty());
+    }
+
+    #[test]
+    fn test_qos2_flow() {
+        let mut window = InflightWindow::new();
+        let id = window.start(publish(QoS::ExactlyOnce)).unwrap().packet_id.unwrap();
+        assert!(window.on_puback(id).is_none());
+        assert_eq!(window.on_pubrec(id), Some(Packet::Pubrel(AckPacket::new(id))));
+        assert!(window.contains(id));
+        assert!(window.on_pubcomp(id).is_some());
+        assert!(window.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retransmissions_set_dup_and_resend_pubrel() {
+        let mut window = InflightWindow::new();
+        let qos1 = window.start(publish(QoS::AtLeastOnce)).unwrap().packet_id.unwrap();
+        let qos2 = window.start(publish(QoS::ExactlyOnce)).unwrap().packet_id.unwrap();
+        window.on_pubrec(qos2);
+
+        // 未超时前不重传
+        assert!(window.retransmissions(DEFAULT_RETRY_INTERVAL).is_empty());
+
+        tokio::time::advance(DEFAULT_RETRY_INTERVAL).await;
+        let packets = window.retransmissions(DEFAULT_RETRY_INTERVAL);
+        assert_eq!(packets.len(), 2);
+        match &packets[0] {
+            Packet::Publish(publish) => {
+                assert!(publish.dup);
+                assert_eq!(publish.packet_id, Some(qos1));
+            }
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+        assert_eq!(packets[1], Packet::Pubrel(AckPacket::new(qos2)));
+    }
+}
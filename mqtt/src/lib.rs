@@ -0,0 +1,25 @@
+/// MQTT协议实现库
+/// 服务端与客户端共享同一套协议定义和编解码器
+pub mod protocol;
+pub mod error;
+pub mod properties;
+pub mod packet;
+pub mod codec;
+pub mod inflight;
+pub mod topic;
+pub mod session;
+pub mod shared;
+pub mod stats;
+pub mod storage;
+pub mod auth;
+pub mod acl;
+pub mod transport;
+pub mod tls;
+pub mod websocket;
+pub mod options;
+pub mod reconnect;
+pub mod client;
+pub mod server;
+pub mod admin;
+pub mod config;
+pub mod patterns;
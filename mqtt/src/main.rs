@@ -1,75 +1,233 @@
 use std::error::Error;
+use std::path::PathBuf;
 
-mod protocol;
-mod client;
-mod packet;
-mod server;
-mod patterns;
+use clap::{Args, Parser, Subcommand};
+use mqtt::{client, patterns};
+use mqtt::config::{AuthBackend, BrokerConfig, ConfigError};
+use mqtt::options::MqttOptions;
+use mqtt::protocol::QoS;
+
+#[derive(Parser)]
+#[command(name = "mqtt", version, about = "MQTT 3.1.1/5.0 broker and demo client")]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// 命令只在启动时解析一次，不必为变体大小装箱
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Run the MQTT broker
+    Server(ServerArgs),
+    /// Run the demo client against a broker
+    Client(ClientArgs),
+}
+
+/// 代理的命令行参数，设置的参数覆盖配置文件中的对应项
+#[derive(Args)]
+struct ServerArgs {
+    /// TOML configuration file
+    #[arg(short, long, value_name = "FILE", env = "MQTT_CONFIG")]
+    config: Option<PathBuf>,
+    /// MQTT over TCP listen address [default: 127.0.0.1:1883]
+    #[arg(long, value_name = "ADDR")]
+    bind: Option<String>,
+    /// MQTT over WebSocket listen address
+    #[arg(long, value_name = "ADDR")]
+    ws_bind: Option<String>,
+    /// WebSocket upgrade path [default: /mqtt]
+    #[arg(long, value_name = "PATH")]
+    ws_path: Option<String>,
+    /// Admin HTTP server (/metrics, /healthz, REST API) listen address; unauthenticated, bind to a trusted interface only
+    #[arg(long, value_name = "ADDR", env = "MQTT_ADMIN_ADDR")]
+    admin_bind: Option<String>,
+    /// TLS listen address [default: 127.0.0.1:8883]
+    #[arg(long, value_name = "ADDR")]
+    tls_bind: Option<String>,
+    /// PEM certificate chain; enables TLS together with --tls-key
+    #[arg(long, value_name = "FILE", env = "MQTT_TLS_CERT")]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key
+    #[arg(long, value_name = "FILE", env = "MQTT_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// CA used to verify client certificates presented over TLS
+    #[arg(long, value_name = "FILE", env = "MQTT_TLS_CLIENT_CA")]
+    tls_client_ca: Option<PathBuf>,
+    /// Reject TLS clients without a certificate signed by --tls-client-ca
+    #[arg(long)]
+    tls_require_client_cert: bool,
+    /// Use the client certificate CN as the username, skipping password authentication
+    #[arg(long)]
+    tls_identity_as_username: bool,
+    /// bcrypt password file; enables password authentication
+    #[arg(long, value_name = "FILE")]
+    password_file: Option<PathBuf>,
+    /// ACL file restricting publish and subscribe
+    #[arg(long, value_name = "FILE")]
+    acl_file: Option<PathBuf>,
+    /// Maximum accepted packet size in bytes
+    #[arg(long, value_name = "BYTES")]
+    max_packet_size: Option<usize>,
+    /// Maximum unacknowledged QoS 1/2 messages per connection
+    #[arg(long, value_name = "N")]
+    max_inflight: Option<u16>,
+    /// Maximum number of connected clients
+    #[arg(long, value_name = "N")]
+    max_connections: Option<usize>,
+    /// Append-only log for retained messages and persistent sessions
+    #[arg(long, value_name = "FILE", env = "MQTT_STORAGE_FILE")]
+    storage_file: Option<PathBuf>,
+    /// Log filter such as "info" or "info,mqtt::server=debug"
+    #[arg(long, value_name = "FILTER")]
+    log_level: Option<String>,
+    /// Validate the configuration and exit
+    #[arg(long)]
+    check: bool,
+}
+
+impl ServerArgs {
+    /// 加载配置文件（未指定时使用默认配置），应用命令行参数后检查
+    fn load_config(&self) -> Result<BrokerConfig, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => BrokerConfig::from_file(path)?,
+            None => BrokerConfig::default(),
+        };
+        self.apply(&mut config);
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply(&self, config: &mut BrokerConfig) {
+        let set = |target: &mut String, value: &Option<String>| {
+            if let Some(value) = value {
+                *target = value.clone();
+            }
+        };
+        set(&mut config.log_level, &self.log_level);
+        set(&mut config.listeners.tcp, &self.bind);
+        set(&mut config.listeners.websocket_path, &self.ws_path);
+        set(&mut config.tls.bind, &self.tls_bind);
+        config.listeners.websocket = self.ws_bind.clone().or(config.listeners.websocket.take());
+        config.listeners.admin = self.admin_bind.clone().or(config.listeners.admin.take());
+        config.tls.cert = self.tls_cert.clone().or(config.tls.cert.take());
+        config.tls.key = self.tls_key.clone().or(config.tls.key.take());
+        config.tls.client_ca = self.tls_client_ca.clone().or(config.tls.client_ca.take());
+        // 开关只在给出时覆盖配置文件
+        config.tls.require_client_cert |= self.tls_require_client_cert;
+        config.tls.identity_as_username |= self.tls_identity_as_username;
+        if let Some(path) = &self.password_file {
+            config.auth.backend = AuthBackend::PasswordFile;
+            config.auth.password_file = Some(path.clone());
+        }
+        config.acl.file = self.acl_file.clone().or(config.acl.file.take());
+        config.limits.max_packet_size = self.max_packet_size.unwrap_or(config.limits.max_packet_size);
+        config.limits.max_inflight = self.max_inflight.unwrap_or(config.limits.max_inflight);
+        config.limits.max_connections = self.max_connections.or(config.limits.max_connections);
+        config.persistence.path = self.storage_file.clone().or(config.persistence.path.take());
+    }
+}
+
+#[derive(Args)]
+struct ClientArgs {
+    /// Broker address
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:1883")]
+    host: String,
+    /// Log filter
+    #[arg(long, value_name = "FILTER", default_value = "info")]
+    log_level: String,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    // 初始化日志系统
-    env_logger::init();
-    
-    log::info!("Rust Algorithmic Network Protocol - MQTT Implementation");
-    
-    // 启动服务端和客户端的选项
-    let args: Vec<String> = std::env::args().collect();
-    
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "server" => {
-                start_server().await?;
-            },
-            "client" => {
-                start_client().await?;
-            },
-            _ => {
-                log::warn!("Usage: {} [server|client]", args[0]);
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Server(args)) => {
+            // 配置错误在初始化日志之前直接输出到标准错误
+            let config = match args.load_config() {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    std::process::exit(2);
+                }
+            };
+            if args.check {
+                println!("configuration OK");
+                return Ok(());
+            }
+            init_logging(&config.log_level);
+            log::info!("Rust Algorithmic Network Protocol - MQTT Implementation");
+            if let Err(e) = start_server(&config).await {
+                log::error!("Broker failed: {}", e);
+                eprintln!("error: {}", e);
+                std::process::exit(1);
             }
         }
-    } else {
-        log::info!("Usage: {} [server|client]", args[0]);
-        log::info!("Starting client by default...");
-        start_client().await?;
+        Some(Command::Client(args)) => {
+            init_logging(&args.log_level);
+            start_client(&args.host).await?;
+        }
+        None => {
+            init_logging("info");
+            log::info!("No command given, starting the demo client (see --help)");
+            start_client("127.0.0.1:1883").await?;
+        }
     }
-    
     Ok(())
 }
 
-async fn start_server() -> Result<(), Box<dyn Error>> {
-    log::info!("Starting MQTT broker on port 1883...");
-    server::run_broker("127.0.0.1:1883").await?;
+/// 初始化日志系统，设置了RUST_LOG时以环境变量为准
+fn init_logging(filter: &str) {
+    env_logger::Builder::new().parse_filters(filter).parse_env("RUST_LOG").init();
+}
+
+async fn start_server(config: &BrokerConfig) -> Result<(), Box<dyn Error>> {
+    log::info!("Starting MQTT broker on {}...", config.listeners.tcp);
+    let mut broker = config.build().await?;
+    broker.run().await?;
     Ok(())
 }
 
-async fn start_client() -> Result<(), Box<dyn Error>> {
+async fn start_client(host: &str) -> Result<(), Box<dyn Error>> {
     log::info!("Starting MQTT client...");
     
     // 示例：创建MQTT客户端并连接到测试服务器
-    let mut client = client::MqttClient::new("test-client".to_string());
+    let options = MqttOptions::new("test-client")
+        .keep_alive(std::time::Duration::from_secs(30))
+        .last_will("status/test-client", "offline", QoS::AtLeastOnce, true)
+        .connect_timeout(std::time::Duration::from_secs(5));
+    let mut client = client::MqttClient::new(options);
     
     // 注册消息回调
     client.on_message("test/topic".to_string(), |topic, message| {
-        log::info!("Received message on {}: {}", topic, message);
+        log::info!("Received message on {}: {}", topic, String::from_utf8_lossy(&message));
     }).await;
     
     // 使用命令模式执行操作
-    let connect_cmd = patterns::ConnectCommand::new("127.0.0.1:1883".to_string());
+    let connect_cmd = patterns::ConnectCommand::new(host.to_string());
     client.execute_command(&connect_cmd).await?;
     
-    let subscribe_cmd = patterns::SubscribeCommand::new("test/topic".to_string());
+    let subscribe_cmd = patterns::SubscribeCommand::new("test/topic".to_string()).with_qos(QoS::AtLeastOnce);
     client.execute_command(&subscribe_cmd).await?;
     
     let publish_cmd = patterns::PublishCommand::new("test/topic".to_string(), "Hello, MQTT with Command Pattern!".to_string());
     client.execute_command(&publish_cmd).await?;
     
-    // 启动消息监听循环（在实际应用中，这里可能需要在单独的任务中运行）
-    // client.start_listening().await?;
+    // QoS 1发布会等待代理返回PUBACK
+    let qos1_cmd = patterns::PublishCommand::new("test/topic".to_string(), "Hello, MQTT with QoS 1!".to_string()).with_qos(QoS::AtLeastOnce);
+    client.execute_command(&qos1_cmd).await?;
     
-    // 演示断开连接
-    let disconnect_cmd = patterns::DisconnectCommand::new();
-    client.execute_command(&disconnect_cmd).await?;
+    // 通过句柄在其他任务中发布，同时在当前任务中监听消息，最后由句柄断开连接
+    let handle = client.handle();
+    let publisher = tokio::spawn(async move {
+        handle.publish("test/topic".to_string(), "Hello from a client handle!".to_string(), QoS::AtLeastOnce).await?;
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        handle.disconnect().await
+    });
+    client.start_listening().await?;
+    if let Err(e) = publisher.await? {
+        log::error!("Client handle failed: {}", e);
+    }
     
     log::info!("MQTT client demo completed");
     Ok(())
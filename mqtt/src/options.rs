@@ -0,0 +1,302 @@
+/// 客户端连接选项
+/// 以构建器方式设置客户端标识符、认证信息、保活、会话、遗嘱、超时和传输参数，传给`MqttClient::new`；
+/// 连接前按协议规范检查选项，不合法时`connect`返回`MqttError::InvalidArgument`
+use std::time::Duration;
+use bytes::Bytes;
+use crate::error::MqttError;
+use crate::packet::LastWill;
+use crate::properties::Properties;
+use crate::protocol::{QoS, DEFAULT_KEEP_ALIVE, MAX_FIELD_LEN, MAX_REMAINING_LENGTH, MQTT_PROTOCOL_VERSION_5};
+use crate::tls::TlsClientOptions;
+use crate::topic::valid_topic_name;
+
+/// 建立连接（TCP、TLS和CONNECT/CONNACK握手）的默认超时时间
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 默认最多同时等待确认的QoS 1/2发布数
+pub const DEFAULT_INFLIGHT_WINDOW: u16 = 100;
+/// 协议规定代理必须接受的客户端标识符最大长度（字节）
+pub const MAX_PORTABLE_CLIENT_ID_LEN: usize = 23;
+
+/// 客户端连接选项
+///
+/// 只有客户端标识符是必需的，其余选项都有默认值；设置在下次连接时生效。
+#[derive(Debug, Clone)]
+pub struct MqttOptions {
+    pub(crate) client_id: String,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    // 保活时间，超过该时间没有发送数据包时发送PINGREQ；0表示关闭保活
+    pub(crate) keep_alive: Duration,
+    // 是否请求清除会话，为false时代理在断开后保留订阅和离线消息
+    pub(crate) clean_session: bool,
+    // 客户端未发送DISCONNECT就断开时代理发布的遗嘱消息
+    pub(crate) will: Option<LastWill>,
+    pub(crate) connect_timeout: Duration,
+    // 允许接收的最大包长度（剩余长度）
+    pub(crate) max_packet_size: usize,
+    // 同时等待确认的QoS 1/2发布数上限，超出的发布排队到有确认完成后再发送
+    pub(crate) inflight_window: u16,
+    // 设置后通过TLS连接代理
+    pub(crate) tls: Option<TlsClientOptions>,
+    // 是否只允许所有代理都必须接受的客户端标识符
+    strict_client_id: bool,
+}
+
+impl MqttOptions {
+    /// 以MQTT 5.0连接时可以使用空标识符，由代理分配
+    pub fn new(client_id: impl Into<String>) -> Self {
+        MqttOptions {
+            client_id: client_id.into(),
+            username: None,
+            password: None,
+            keep_alive: Duration::from_secs(DEFAULT_KEEP_ALIVE.into()),
+            clean_session: true,
+            will: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            max_packet_size: MAX_REMAINING_LENGTH,
+            inflight_window: DEFAULT_INFLIGHT_WINDOW,
+            tls: None,
+            strict_client_id: false,
+        }
+    }
+
+    /// 连接时使用的用户名和密码
+    pub fn credentials(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
+        self.username = Some(username.into());
+        self.password = Some(password.into());
+        self
+    }
+
+    /// 保活时间（按秒取整，不足1秒按1秒计，最长65535秒）；`Duration::ZERO`关闭保活
+    ///
+    /// 空闲超过保活时间时自动发送PINGREQ，再过一个保活时间仍未收到PINGRESP则视为连接断开。
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive_secs(keep_alive);
+        self
+    }
+
+    /// 是否清除会话，默认为true
+    ///
+    /// 设为false时代理会按客户端标识符保留订阅，并缓存离线期间的QoS 1/2消息。
+    pub fn clean_session(mut self, clean_session: bool) -> Self {
+        self.clean_session = clean_session;
+        self
+    }
+
+    /// 遗嘱消息：客户端未发送DISCONNECT就断开连接时，代理将该消息发布到指定主题
+    pub fn last_will(mut self, topic: impl Into<String>, message: impl Into<Bytes>, qos: QoS, retain: bool) -> Self {
+        self.will = Some(LastWill {
+            topic: topic.into(),
+            message: message.into(),
+            qos,
+            retain,
+            properties: Properties::default(),
+        });
+        self
+    }
+
+    /// 建立连接的超时时间，包括TCP连接、TLS握手和等待CONNACK，自动重连时同样适用
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// 允许接收的最大包长度，收到更大的数据包时断开连接；MQTT 5.0中同时通过CONNECT告知代理
+    pub fn max_packet_size(mut self, max_packet_size: usize) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// 同时等待确认的QoS 1/2发布数上限；MQTT 5.0代理在CONNACK中指定了更小的接收上限时以代理为准
+    pub fn inflight_window(mut self, inflight_window: u16) -> Self {
+        self.inflight_window = inflight_window;
+        self
+    }
+
+    /// 通过TLS连接代理
+    pub fn tls(mut self, tls: TlsClientOptions) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// 只允许1到23个字节、由数字和大小写字母组成的客户端标识符，这是所有代理都必须接受的范围
+    pub fn strict_client_id(mut self, strict: bool) -> Self {
+        self.strict_client_id = strict;
+        self
+    }
+
+    pub fn client_id(&self) -> &str {
+        &self.client_id
+    }
+
+    pub fn get_keep_alive(&self) -> Duration {
+        self.keep_alive
+    }
+
+    pub fn get_clean_session(&self) -> bool {
+        self.clean_session
+    }
+
+    pub fn get_connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    pub fn get_max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub fn get_inflight_window(&self) -> u16 {
+        self.inflight_window
+    }
+
+    /// 按协议规范检查选项，返回遇到的第一个错误
+    pub fn validate(&self, protocol_version: u8) -> Result<(), MqttError> {
+        validate_client_id(&self.client_id, self.strict_client_id)?;
+        // 3.1.1只允许在清除会话时使用空标识符
+        if self.client_id.is_empty() && !self.clean_session && protocol_version != MQTT_PROTOCOL_VERSION_5 {
+            return Err(invalid("empty client identifier requires a clean session"));
+        }
+        if let Some(will) = &self.will {
+            if !valid_topic_name(&will.topic) {
+                return Err(invalid(format!("invalid will topic '{}'", will.topic)));
+            }
+            if will.message.len() > MAX_FIELD_LEN {
+                return Err(invalid(format!("will message longer than {} bytes", MAX_FIELD_LEN)));
+            }
+        }
+        let too_long = |value: &Option<String>| value.as_ref().is_some_and(|value| value.len() > MAX_FIELD_LEN);
+        if too_long(&self.username) || too_long(&self.password) {
+            return Err(invalid(format!("user name and password must not be longer than {} bytes", MAX_FIELD_LEN)));
+        }
+        if self.max_packet_size == 0 || self.max_packet_size > MAX_REMAINING_LENGTH {
+            return Err(invalid(format!("max packet size must be between 1 and {}", MAX_REMAINING_LENGTH)));
+        }
+        if self.inflight_window == 0 {
+            return Err(invalid("inflight window must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl From<String> for MqttOptions {
+    fn from(client_id: String) -> Self {
+        MqttOptions::new(client_id)
+    }
+}
+
+impl From<&str> for MqttOptions {
+    fn from(client_id: &str) -> Self {
+        MqttOptions::new(client_id)
+    }
+}
+
+/// 检查客户端标识符
+///
+/// 标识符是UTF-8字符串，不能超过65535字节，也不能包含U+0000等控制字符；
+/// `strict`时只允许1到23个字节的数字和大小写字母。
+pub fn validate_client_id(client_id: &str, strict: bool) -> Result<(), MqttError> {
+    if client_id.len() > MAX_FIELD_LEN {
+        return Err(invalid(format!("client identifier longer than {} bytes", MAX_FIELD_LEN)));
+    }
+    if client_id.chars().any(char::is_control) {
+        return Err(invalid("client identifier contains control characters"));
+    }
+    if strict {
+        if client_id.is_empty() || client_id.len() > MAX_PORTABLE_CLIENT_ID_LEN {
+            return Err(invalid(format!(
+                "client identifier must be 1 to {} bytes long", MAX_PORTABLE_CLIENT_ID_LEN
+            )));
+        }
+        if !client_id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(invalid("client identifier may only contain 0-9, a-z and A-Z"));
+        }
+    }
+    Ok(())
+}
+
+/// 把保活时间换算为CONNECT中的整秒数；非零的时间至少为1秒，避免被取整为0而关闭保活
+pub(crate) fn keep_alive_secs(keep_alive: Duration) -> Duration {
+    let secs = keep_alive.as_secs().min(u16::MAX.into());
+    if secs == 0 && !keep_alive.is_zero() {
+        return Duration::from_secs(1);
+    }
+    Duration::from_secs(secs)
+}
+
+fn invalid(message: impl Into<String>) -> MqttError {
+    MqttError::InvalidArgument(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::MQTT_PROTOCOL_VERSION;
+
+    #[test]
+    fn test_builder_defaults_and_setters() {
+        let options = MqttOptions::new("sensor1");
+        assert_eq!(options.client_id(), "sensor1");
+        assert_eq!(options.get_keep_alive(), Duration::from_secs(60));
+        assert!(options.get_clean_session());
+        assert_eq!(options.get_connect_timeout(), DEFAULT_CONNECT_TIMEOUT);
+        assert_eq!(options.get_inflight_window(), DEFAULT_INFLIGHT_WINDOW);
+
+        let options = options
+            .credentials("user", "secret")
+            .keep_alive(Duration::from_millis(90_500))
+            .clean_session(false)
+            .last_will("status/sensor1", "offline", QoS::AtLeastOnce, true)
+            .connect_timeout(Duration::from_secs(5))
+            .max_packet_size(4096)
+            .inflight_window(10);
+        assert_eq!(options.username.as_deref(), Some("user"));
+        assert_eq!(options.password.as_deref(), Some("secret"));
+        assert_eq!(options.get_keep_alive(), Duration::from_secs(90));
+        assert!(!options.get_clean_session());
+        assert_eq!(options.will.as_ref().map(|will| &will.message[..]), Some(&b"offline"[..]));
+        assert_eq!(options.get_connect_timeout(), Duration::from_secs(5));
+        assert_eq!(options.get_max_packet_size(), 4096);
+        assert_eq!(options.get_inflight_window(), 10);
+        assert!(options.validate(MQTT_PROTOCOL_VERSION).is_ok());
+        assert_eq!(MqttOptions::new("x").keep_alive(Duration::from_secs(100_000)).get_keep_alive().as_secs(), 65535);
+        // 不足1秒的保活时间不会变成0而关闭保活
+        assert_eq!(MqttOptions::new("x").keep_alive(Duration::from_millis(500)).get_keep_alive(), Duration::from_secs(1));
+        assert_eq!(MqttOptions::new("x").keep_alive(Duration::ZERO).get_keep_alive(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_validate_client_id() {
+        assert!(validate_client_id("sensor-1/kitchen", false).is_ok());
+        assert!(validate_client_id("", false).is_ok());
+        assert!(validate_client_id("bad\0id", false).is_err());
+        assert!(validate_client_id("bad\nid", false).is_err());
+        assert!(validate_client_id(&"a".repeat(65536), false).is_err());
+
+        assert!(validate_client_id("Sensor01", true).is_ok());
+        assert!(validate_client_id(&"a".repeat(23), true).is_ok());
+        assert!(validate_client_id(&"a".repeat(24), true).is_err());
+        assert!(validate_client_id("", true).is_err());
+        assert!(validate_client_id("sensor-1", true).is_err());
+        assert!(validate_client_id("датчик", true).is_err());
+    }
+
+    #[test]
+    fn test_validate_options() {
+        // 空标识符在3.1.1中要求清除会话，5.0中由代理分配
+        let options = MqttOptions::new("").clean_session(false);
+        assert!(matches!(options.validate(MQTT_PROTOCOL_VERSION), Err(MqttError::InvalidArgument(_))));
+        assert!(options.validate(MQTT_PROTOCOL_VERSION_5).is_ok());
+        assert!(MqttOptions::new("").validate(MQTT_PROTOCOL_VERSION).is_ok());
+
+        assert!(MqttOptions::new("c").strict_client_id(true).validate(MQTT_PROTOCOL_VERSION).is_ok());
+        assert!(MqttOptions::new("c-1").strict_client_id(true).validate(MQTT_PROTOCOL_VERSION).is_err());
+        assert!(MqttOptions::new("c").last_will("status/#", "x", QoS::AtMostOnce, false).validate(MQTT_PROTOCOL_VERSION).is_err());
+        assert!(MqttOptions::new("c").max_packet_size(0).validate(MQTT_PROTOCOL_VERSION).is_err());
+        // 长度前缀只有两个字节
+        let long = "a".repeat(MAX_FIELD_LEN + 1);
+        assert!(MqttOptions::new("c").last_will("status", long.clone(), QoS::AtMostOnce, false).validate(MQTT_PROTOCOL_VERSION).is_err());
+        assert!(MqttOptions::new("c").last_will(long.clone(), "x", QoS::AtMostOnce, false).validate(MQTT_PROTOCOL_VERSION).is_err());
+        assert!(MqttOptions::new("c").credentials("user", long).validate(MQTT_PROTOCOL_VERSION).is_err());
+        assert!(MqttOptions::new("c").inflight_window(0).validate(MQTT_PROTOCOL_VERSION).is_err());
+    }
+}
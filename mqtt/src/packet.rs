@@ -1,16 +1,497 @@
 /// MQTT数据包结构和处理
-use bytes::{BytesMut, BufMut};
+/// 同时支持3.1.1（协议级别4）和5.0（协议级别5）：
+/// 5.0的数据包在可变头部中携带属性，确认包携带原因码
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use crate::properties::Properties;
 use crate::protocol::*;
 
-#[derive(Debug)]
+/// MQTT控制包，覆盖协议定义的全部15种包类型（AUTH仅用于MQTT 5.0）
+// CONNECT携带连接属性和遗嘱属性，明显大于其他包，但每个连接只出现一次
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Packet {
+    Connect(ConnectPacket),
+    Connack(ConnackPacket),
+    Publish(PublishPacket),
+    Puback(AckPacket),
+    Pubrec(AckPacket),
+    Pubrel(AckPacket),
+    Pubcomp(AckPacket),
+    Subscribe(SubscribePacket),
+    Suback(SubackPacket),
+    Unsubscribe(UnsubscribePacket),
+    Unsuback(UnsubackPacket),
+    Pingreq,
+    Pingresp,
+    Disconnect(DisconnectPacket),
+    Auth(AuthPacket),
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct ConnectPacket {
     pub protocol_name: String,
     pub protocol_version: u8,
     pub connect_flags: u8,
     pub keep_alive: u16,
     pub client_id: String,
+    pub will: Option<LastWill>,
     pub username: Option<String>,
     pub password: Option<String>,
+    pub properties: Properties,
+}
+
+/// 遗嘱消息，客户端非正常断开时由代理代为发布
+#[derive(Debug, Clone, PartialEq)]
+pub struct LastWill {
+    pub topic: String,
+    pub message: Bytes,
+    pub qos: QoS,
+    pub retain: bool,
+    /// 遗嘱属性（MQTT 5.0），发布遗嘱时随消息一起转发
+    pub properties: Properties,
+}
+
+/// CONNACK包
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnackPacket {
+    pub session_present: bool,
+    /// 3.1.1的返回码，或5.0的原因码
+    pub return_code: u8,
+    pub properties: Properties,
+}
+
+/// PUBLISH包
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublishPacket {
+    pub dup: bool,
+    pub qos: QoS,
+    pub retain: bool,
+    pub topic: String,
+    /// 仅在QoS > 0时存在
+    pub packet_id: Option<u16>,
+    pub properties: Properties,
+    pub payload: Bytes,
+}
+
+/// PUBACK、PUBREC、PUBREL和PUBCOMP包
+#[derive(Debug, Clone, PartialEq)]
+pub struct AckPacket {
+    pub packet_id: u16,
+    /// 原因码（MQTT 5.0），3.1.1中始终为0
+    pub reason_code: u8,
+    pub properties: Properties,
+}
+
+/// SUBSCRIBE包
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscribePacket {
+    pub packet_id: u16,
+    /// 主题过滤器及其请求的QoS
+    pub topics: Vec<(String, QoS)>,
+    pub properties: Properties,
+}
+
+/// SUBACK包
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubackPacket {
+    pub packet_id: u16,
+    /// 每个主题过滤器对应的返回码（0x00-0x02为授予的QoS，0x80及以上为失败）
+    pub return_codes: Vec<u8>,
+    pub properties: Properties,
+}
+
+/// UNSUBSCRIBE包
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsubscribePacket {
+    pub packet_id: u16,
+    pub topics: Vec<String>,
+    pub properties: Properties,
+}
+
+/// UNSUBACK包
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnsubackPacket {
+    pub packet_id: u16,
+    /// 每个主题过滤器对应的原因码（MQTT 5.0），3.1.1中为空
+    pub reason_codes: Vec<u8>,
+    pub properties: Properties,
+}
+
+/// DISCONNECT包，5.0中客户端和代理都可以发送
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DisconnectPacket {
+    pub reason_code: u8,
+    pub properties: Properties,
+}
+
+/// AUTH包（MQTT 5.0），用于增强认证的质询/响应
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuthPacket {
+    pub reason_code: u8,
+    pub properties: Properties,
+}
+
+impl AckPacket {
+    /// 成功的确认
+    pub fn new(packet_id: u16) -> Self {
+        Self::with_reason(packet_id, reason_code::SUCCESS)
+    }
+
+    /// 带原因码的确认，3.1.1连接上原因码不会被编码
+    pub fn with_reason(packet_id: u16, reason_code: u8) -> Self {
+        AckPacket {
+            packet_id,
+            reason_code,
+            properties: Properties::default(),
+        }
+    }
+
+    fn encode_body(&self, body: &mut BytesMut, v5: bool) {
+        body.put_u16(self.packet_id);
+        encode_reason(body, self.reason_code, &self.properties, v5);
+    }
+
+    fn decode_body(body: &mut Bytes, v5: bool) -> io::Result<Self> {
+        let packet_id = read_u16(body)?;
+        let (reason_code, properties) = decode_reason(body, v5)?;
+        Ok(AckPacket { packet_id, reason_code, properties })
+    }
+}
+
+impl DisconnectPacket {
+    /// 带原因码的DISCONNECT
+    pub fn with_reason(reason_code: u8) -> Self {
+        DisconnectPacket {
+            reason_code,
+            properties: Properties::default(),
+        }
+    }
+}
+
+impl PublishPacket {
+    /// 按UTF-8解析负载，负载不是合法的UTF-8时返回错误
+    pub fn payload_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(&self.payload)
+    }
+
+    /// 将负载按JSON反序列化
+    pub fn payload_json<T: serde::de::DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.payload)
+    }
+}
+
+impl Packet {
+    /// 获取包类型
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Packet::Connect(_) => PacketType::CONNECT,
+            Packet::Connack(_) => PacketType::CONNACK,
+            Packet::Publish(_) => PacketType::PUBLISH,
+            Packet::Puback(_) => PacketType::PUBACK,
+            Packet::Pubrec(_) => PacketType::PUBREC,
+            Packet::Pubrel(_) => PacketType::PUBREL,
+            Packet::Pubcomp(_) => PacketType::PUBCOMP,
+            Packet::Subscribe(_) => PacketType::SUBSCRIBE,
+            Packet::Suback(_) => PacketType::SUBACK,
+            Packet::Unsubscribe(_) => PacketType::UNSUBSCRIBE,
+            Packet::Unsuback(_) => PacketType::UNSUBACK,
+            Packet::Pingreq => PacketType::PINGREQ,
+            Packet::Pingresp => PacketType::PINGRESP,
+            Packet::Disconnect(_) => PacketType::DISCONNECT,
+            Packet::Auth(_) => PacketType::AUTH,
+        }
+    }
+
+    /// 按MQTT 3.1.1格式将数据包编码到缓冲区（固定头部 + 剩余长度 + 可变头部 + 载荷）
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        self.encode_with_version(buffer, MQTT_PROTOCOL_VERSION);
+    }
+
+    /// 按指定的协议级别编码数据包
+    ///
+    /// CONNECT总是按其自身的`protocol_version`编码；3.1.1中不存在的属性和原因码被忽略。
+    pub fn encode_with_version(&self, buffer: &mut BytesMut, protocol_version: u8) {
+        let v5 = protocol_version == MQTT_PROTOCOL_VERSION_5;
+        let mut body = BytesMut::new();
+        let flags = match self {
+            Packet::Connect(connect) => {
+                connect.encode_body(&mut body);
+                0
+            }
+            Packet::Connack(connack) => {
+                body.put_u8(connack.session_present as u8);
+                body.put_u8(connack.return_code);
+                if v5 {
+                    connack.properties.encode(&mut body);
+                }
+                0
+            }
+            Packet::Publish(publish) => {
+                write_string(&mut body, &publish.topic);
+                if publish.qos != QoS::AtMostOnce {
+                    body.put_u16(publish.packet_id.unwrap_or(0));
+                }
+                if v5 {
+                    publish.properties.encode(&mut body);
+                }
+                body.extend_from_slice(&publish.payload);
+                (publish.dup as u8) << 3 | (publish.qos as u8) << 1 | publish.retain as u8
+            }
+            Packet::Puback(ack) | Packet::Pubrec(ack) | Packet::Pubcomp(ack) => {
+                ack.encode_body(&mut body, v5);
+                0
+            }
+            Packet::Pubrel(ack) => {
+                ack.encode_body(&mut body, v5);
+                0b0010
+            }
+            Packet::Subscribe(subscribe) => {
+                body.put_u16(subscribe.packet_id);
+                if v5 {
+                    subscribe.properties.encode(&mut body);
+                }
+                for (topic, qos) in &subscribe.topics {
+                    write_string(&mut body, topic);
+                    body.put_u8(*qos as u8);
+                }
+                0b0010
+            }
+            Packet::Suback(suback) => {
+                body.put_u16(suback.packet_id);
+                if v5 {
+                    suback.properties.encode(&mut body);
+                }
+                body.extend_from_slice(&suback.return_codes);
+                0
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                body.put_u16(unsubscribe.packet_id);
+                if v5 {
+                    unsubscribe.properties.encode(&mut body);
+                }
+                for topic in &unsubscribe.topics {
+                    write_string(&mut body, topic);
+                }
+                0b0010
+            }
+            Packet::Unsuback(unsuback) => {
+                body.put_u16(unsuback.packet_id);
+                if v5 {
+                    unsuback.properties.encode(&mut body);
+                    body.extend_from_slice(&unsuback.reason_codes);
+                }
+                0
+            }
+            Packet::Disconnect(disconnect) => {
+                encode_reason(&mut body, disconnect.reason_code, &disconnect.properties, v5);
+                0
+            }
+            // AUTH只存在于5.0
+            Packet::Auth(auth) => {
+                encode_reason(&mut body, auth.reason_code, &auth.properties, true);
+                0
+            }
+            Packet::Pingreq | Packet::Pingresp => 0,
+        };
+
+        buffer.put_u8((self.packet_type() as u8) << 4 | flags);
+        encode_remaining_length(buffer, body.len());
+        buffer.extend_from_slice(&body);
+    }
+
+    /// 将数据包编码为独立的字节缓冲区
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        self.encode(&mut buffer);
+        buffer
+    }
+
+    /// 检查带2字节长度前缀的字符串和二进制字段，编码前调用
+    ///
+    /// 超过65535字节的字段编码后长度前缀会回绕，产生损坏的数据包。
+    pub fn check_field_lengths(&self) -> io::Result<()> {
+        match self {
+            Packet::Connect(connect) => connect.check_field_lengths(),
+            Packet::Publish(publish) => {
+                check_field_len("topic name", publish.topic.len())?;
+                publish.properties.check_field_lengths()
+            }
+            Packet::Subscribe(subscribe) => {
+                for (topic, _) in &subscribe.topics {
+                    check_field_len("topic filter", topic.len())?;
+                }
+                subscribe.properties.check_field_lengths()
+            }
+            Packet::Unsubscribe(unsubscribe) => {
+                for topic in &unsubscribe.topics {
+                    check_field_len("topic filter", topic.len())?;
+                }
+                unsubscribe.properties.check_field_lengths()
+            }
+            Packet::Connack(ConnackPacket { properties, .. })
+            | Packet::Puback(AckPacket { properties, .. })
+            | Packet::Pubrec(AckPacket { properties, .. })
+            | Packet::Pubrel(AckPacket { properties, .. })
+            | Packet::Pubcomp(AckPacket { properties, .. })
+            | Packet::Suback(SubackPacket { properties, .. })
+            | Packet::Unsuback(UnsubackPacket { properties, .. })
+            | Packet::Disconnect(DisconnectPacket { properties, .. })
+            | Packet::Auth(AuthPacket { properties, .. }) => properties.check_field_lengths(),
+            Packet::Pingreq | Packet::Pingresp => Ok(()),
+        }
+    }
+
+    /// 按MQTT 3.1.1格式解码数据包，参数为固定头部第一个字节和完整的包体（不含固定头部）
+    pub fn decode(header: u8, body: Bytes) -> io::Result<Packet> {
+        Self::decode_with_version(header, body, MQTT_PROTOCOL_VERSION)
+    }
+
+    /// 按指定的协议级别解码数据包；CONNECT总是按其自身声明的协议级别解码
+    pub fn decode_with_version(header: u8, mut body: Bytes, protocol_version: u8) -> io::Result<Packet> {
+        let v5 = protocol_version == MQTT_PROTOCOL_VERSION_5;
+        let packet_type = PacketType::from_u8(header >> 4)
+            .ok_or_else(|| malformed(format!("unknown packet type {}", header >> 4)))?;
+        let flags = header & 0x0F;
+
+        // 除PUBLISH外，固定头部的标志位是协议规定的固定值
+        let expected_flags = match packet_type {
+            PacketType::PUBLISH => flags,
+            PacketType::PUBREL | PacketType::SUBSCRIBE | PacketType::UNSUBSCRIBE => 0b0010,
+            _ => 0,
+        };
+        if flags != expected_flags {
+            return Err(malformed(format!("invalid flags {:04b} for {:?}", flags, packet_type)));
+        }
+
+        let packet = match packet_type {
+            PacketType::CONNECT => Packet::Connect(ConnectPacket::decode_body(&mut body)?),
+            PacketType::CONNACK => {
+                let ack_flags = read_u8(&mut body)?;
+                let return_code = read_u8(&mut body)?;
+                let properties = if v5 { Properties::decode(&mut body)? } else { Properties::default() };
+                Packet::Connack(ConnackPacket {
+                    session_present: ack_flags & 0x01 != 0,
+                    return_code,
+                    properties,
+                })
+            }
+            PacketType::PUBLISH => {
+                let qos = QoS::from_u8((flags >> 1) & 0b11)
+                    .ok_or_else(|| malformed("invalid PUBLISH QoS 3"))?;
+                let topic = read_string(&mut body)?;
+                let packet_id = if qos != QoS::AtMostOnce {
+                    Some(read_u16(&mut body)?)
+                } else {
+                    None
+                };
+                let properties = if v5 { Properties::decode(&mut body)? } else { Properties::default() };
+                Packet::Publish(PublishPacket {
+                    dup: flags & 0b1000 != 0,
+                    qos,
+                    retain: flags & 0b0001 != 0,
+                    topic,
+                    packet_id,
+                    properties,
+                    payload: body.split_to(body.len()),
+                })
+            }
+            PacketType::PUBACK => Packet::Puback(AckPacket::decode_body(&mut body, v5)?),
+            PacketType::PUBREC => Packet::Pubrec(AckPacket::decode_body(&mut body, v5)?),
+            PacketType::PUBREL => Packet::Pubrel(AckPacket::decode_body(&mut body, v5)?),
+            PacketType::PUBCOMP => Packet::Pubcomp(AckPacket::decode_body(&mut body, v5)?),
+            PacketType::SUBSCRIBE => {
+                let packet_id = read_u16(&mut body)?;
+                let properties = if v5 { Properties::decode(&mut body)? } else { Properties::default() };
+                let mut topics = Vec::new();
+                while body.has_remaining() {
+                    let topic = read_string(&mut body)?;
+                    // 5.0的订阅选项中高两位保留；No Local、Retain As Published和Retain Handling暂不支持，按默认值处理
+                    let options = read_u8(&mut body)?;
+                    let reserved = if v5 { 0b1100_0000 } else { 0b1111_1100 };
+                    if options & reserved != 0 {
+                        return Err(malformed("reserved subscription option bits are set"));
+                    }
+                    let qos = QoS::from_u8(options & 0b11)
+                        .ok_or_else(|| malformed("invalid requested QoS in SUBSCRIBE"))?;
+                    topics.push((topic, qos));
+                }
+                if topics.is_empty() {
+                    return Err(malformed("SUBSCRIBE without topic filters"));
+                }
+                Packet::Subscribe(SubscribePacket { packet_id, topics, properties })
+            }
+            PacketType::SUBACK => {
+                let packet_id = read_u16(&mut body)?;
+                let properties = if v5 { Properties::decode(&mut body)? } else { Properties::default() };
+                let return_codes = body.split_to(body.len()).to_vec();
+                Packet::Suback(SubackPacket { packet_id, return_codes, properties })
+            }
+            PacketType::UNSUBSCRIBE => {
+                let packet_id = read_u16(&mut body)?;
+                let properties = if v5 { Properties::decode(&mut body)? } else { Properties::default() };
+                let mut topics = Vec::new();
+                while body.has_remaining() {
+                    topics.push(read_string(&mut body)?);
+                }
+                if topics.is_empty() {
+                    return Err(malformed("UNSUBSCRIBE without topic filters"));
+                }
+                Packet::Unsubscribe(UnsubscribePacket { packet_id, topics, properties })
+            }
+            PacketType::UNSUBACK => {
+                let packet_id = read_u16(&mut body)?;
+                let (properties, reason_codes) = if v5 {
+                    (Properties::decode(&mut body)?, body.split_to(body.len()).to_vec())
+                } else {
+                    (Properties::default(), Vec::new())
+                };
+                Packet::Unsuback(UnsubackPacket { packet_id, reason_codes, properties })
+            }
+            PacketType::PINGREQ => Packet::Pingreq,
+            PacketType::PINGRESP => Packet::Pingresp,
+            PacketType::DISCONNECT => {
+                let (reason_code, properties) = decode_reason(&mut body, v5)?;
+                Packet::Disconnect(DisconnectPacket { reason_code, properties })
+            }
+            PacketType::AUTH => {
+                if !v5 {
+                    return Err(malformed("AUTH is only valid in MQTT 5.0"));
+                }
+                let (reason_code, properties) = decode_reason(&mut body, true)?;
+                Packet::Auth(AuthPacket { reason_code, properties })
+            }
+        };
+
+        if body.has_remaining() {
+            return Err(malformed(format!("{} trailing bytes in {:?}", body.remaining(), packet_type)));
+        }
+
+        Ok(packet)
+    }
+}
+
+/// 编码确认包、DISCONNECT和AUTH中的原因码和属性
+///
+/// 原因码为0且没有属性时两者都可以省略；3.1.1中两者都不存在。
+fn encode_reason(body: &mut BytesMut, reason_code: u8, properties: &Properties, v5: bool) {
+    if !v5 || (reason_code == reason_code::SUCCESS && properties.is_empty()) {
+        return;
+    }
+    body.put_u8(reason_code);
+    if !properties.is_empty() {
+        properties.encode(body);
+    }
+}
+
+/// 解码可省略的原因码和属性，省略时原因码为0
+fn decode_reason(body: &mut Bytes, v5: bool) -> io::Result<(u8, Properties)> {
+    if !v5 || !body.has_remaining() {
+        return Ok((reason_code::SUCCESS, Properties::default()));
+    }
+    let reason_code = read_u8(body)?;
+    let properties = if body.has_remaining() { Properties::decode(body)? } else { Properties::default() };
+    Ok((reason_code, properties))
 }
 
 impl ConnectPacket {
@@ -19,151 +500,377 @@ impl ConnectPacket {
             protocol_name: MQTT_PROTOCOL_NAME.to_string(),
             protocol_version: MQTT_PROTOCOL_VERSION,
             connect_flags: CLEAN_SESSION, // 默认只设置CLEAN_SESSION
-            keep_alive: 60, // 默认60秒
+            keep_alive: DEFAULT_KEEP_ALIVE,
             client_id,
+            will: None,
             username: None,
             password: None,
+            properties: Properties::default(),
         }
     }
 
+    /// 是否使用MQTT 5.0
+    fn is_v5(&self) -> bool {
+        self.protocol_version == MQTT_PROTOCOL_VERSION_5
+    }
+
     /// 将ConnectPacket编码为字节流
     pub fn encode(&self) -> BytesMut {
         let mut buffer = BytesMut::new();
-        
+
         // 固定头部 - 控制包类型和标志
         let header = (PacketType::CONNECT as u8) << 4;
         buffer.put_u8(header);
-        
+
         // 计算剩余长度（可变头部+载荷）
         let remaining_length = self.calculate_remaining_length();
         self.encode_remaining_length(&mut buffer, remaining_length);
-        
+
+        self.encode_body(&mut buffer);
+
+        buffer
+    }
+
+    /// 编码可变头部和载荷
+    fn encode_body(&self, buffer: &mut BytesMut) {
         // 可变头部
         // 协议名长度和协议名
-        buffer.put_u16(self.protocol_name.len() as u16);
-        buffer.extend_from_slice(self.protocol_name.as_bytes());
-        
+        write_string(buffer, &self.protocol_name);
+
         // 协议版本
         buffer.put_u8(self.protocol_version);
-        
+
         // 连接标志
-        buffer.put_u8(self.connect_flags);
-        
+        buffer.put_u8(self.effective_flags());
+
         // 保持连接时间
         buffer.put_u16(self.keep_alive);
-        
+
+        // 连接属性（MQTT 5.0）
+        if self.is_v5() {
+            self.properties.encode(buffer);
+        }
+
         // 载荷 - 客户端标识符
-        buffer.put_u16(self.client_id.len() as u16);
-        buffer.extend_from_slice(self.client_id.as_bytes());
-        
+        write_string(buffer, &self.client_id);
+
+        // 遗嘱主题和遗嘱消息（如果存在）
+        if let Some(ref will) = self.will {
+            if self.is_v5() {
+                will.properties.encode(buffer);
+            }
+            write_string(buffer, &will.topic);
+            buffer.put_u16(will.message.len() as u16);
+            buffer.extend_from_slice(&will.message);
+        }
+
         // 用户名和密码（如果存在）
         if let Some(ref username) = self.username {
-            buffer.put_u16(username.len() as u16);
-            buffer.extend_from_slice(username.as_bytes());
+            write_string(buffer, username);
         }
-        
+
         if let Some(ref password) = self.password {
-            buffer.put_u16(password.len() as u16);
-            buffer.extend_from_slice(password.as_bytes());
+            write_string(buffer, password);
         }
-        
-        buffer
     }
-    
-    /// 计算剩余长度
-    fn calculate_remaining_length(&self) -> usize {
-        // 协议名长度(2字节) + 协议名 + 协议版本(1字节) + 连接标志(1字节) + 
-        // 保持连接时间(2字节) + 客户端ID长度(2字节) + 客户端ID +
-        // 用户名长度(2字节) + 用户名(如果有) +
-        // 密码长度(2字节) + 密码(如果有)
-        
-        let mut length = 2 + self.protocol_name.len() + 1 + 1 + 2 + 2 + self.client_id.len();
-        
-        if let Some(ref username) = self.username {
-            length += 2 + username.len();
+
+    /// 计算实际编码的连接标志（遗嘱、用户名和密码标志位根据字段是否存在自动设置）
+    fn effective_flags(&self) -> u8 {
+        let mut flags = self.connect_flags & !(USERNAME_FLAG | PASSWORD_FLAG | WILL_FLAG | WILL_QOS_MASK | WILL_RETAIN);
+        if let Some(ref will) = self.will {
+            flags |= WILL_FLAG | (will.qos as u8) << 3;
+            if will.retain {
+                flags |= WILL_RETAIN;
+            }
         }
-        
-        if let Some(ref password) = self.password {
-            length += 2 + password.len();
+        if self.username.is_some() {
+            flags |= USERNAME_FLAG;
+        }
+        if self.password.is_some() {
+            flags |= PASSWORD_FLAG;
+        }
+        flags
+    }
+
+    /// 检查带2字节长度前缀的字段，见[`Packet::check_field_lengths`]
+    pub fn check_field_lengths(&self) -> io::Result<()> {
+        check_field_len("protocol name", self.protocol_name.len())?;
+        check_field_len("client identifier", self.client_id.len())?;
+        if let Some(ref will) = self.will {
+            check_field_len("will topic", will.topic.len())?;
+            check_field_len("will message", will.message.len())?;
+            will.properties.check_field_lengths()?;
         }
-        
-        length
+        check_field_len("user name", self.username.as_ref().map_or(0, String::len))?;
+        check_field_len("password", self.password.as_ref().map_or(0, String::len))?;
+        self.properties.check_field_lengths()
+    }
+
+    /// 解码CONNECT包的可变头部和载荷
+    fn decode_body(body: &mut Bytes) -> io::Result<Self> {
+        let protocol_name = read_string(body)?;
+        let protocol_version = read_u8(body)?;
+        let connect_flags = read_u8(body)?;
+        if connect_flags & 0x01 != 0 {
+            return Err(malformed("reserved CONNECT flag is set"));
+        }
+        let keep_alive = read_u16(body)?;
+        let v5 = protocol_version == MQTT_PROTOCOL_VERSION_5;
+        let properties = if v5 { Properties::decode(body)? } else { Properties::default() };
+        let client_id = read_string(body)?;
+
+        let will = if connect_flags & WILL_FLAG != 0 {
+            let qos = QoS::from_u8((connect_flags & WILL_QOS_MASK) >> 3)
+                .ok_or_else(|| malformed("invalid will QoS 3"))?;
+            let properties = if v5 { Properties::decode(body)? } else { Properties::default() };
+            Some(LastWill {
+                topic: read_string(body)?,
+                message: read_binary(body)?,
+                qos,
+                retain: connect_flags & WILL_RETAIN != 0,
+                properties,
+            })
+        } else {
+            // 未设置遗嘱标志时，遗嘱QoS和遗嘱保留标志必须为0
+            if connect_flags & (WILL_QOS_MASK | WILL_RETAIN) != 0 {
+                return Err(malformed("will QoS or retain set without will flag"));
+            }
+            None
+        };
+
+        let username = if connect_flags & USERNAME_FLAG != 0 {
+            Some(read_string(body)?)
+        } else {
+            None
+        };
+        let password = if connect_flags & PASSWORD_FLAG != 0 {
+            Some(read_string(body)?)
+        } else {
+            None
+        };
+
+        Ok(ConnectPacket {
+            protocol_name,
+            protocol_version,
+            connect_flags,
+            keep_alive,
+            client_id,
+            will,
+            username,
+            password,
+            properties,
+        })
     }
-    
+
+    /// 是否请求清除会话
+    pub fn clean_session(&self) -> bool {
+        self.connect_flags & CLEAN_SESSION != 0
+    }
+
+    /// 计算剩余长度（可变头部和载荷的编码长度）
+    fn calculate_remaining_length(&self) -> usize {
+        let mut body = BytesMut::new();
+        self.encode_body(&mut body);
+        body.len()
+    }
+
     /// 编码剩余长度（使用可变长度编码）
     fn encode_remaining_length(&self, buffer: &mut BytesMut, length: usize) {
-        let mut remaining_length = length;
-        loop {
-            let mut digit = remaining_length % 128;
-            remaining_length /= 128;
-            if remaining_length > 0 {
-                digit |= 128;
-            }
-            buffer.put_u8(digit as u8);
-            if remaining_length == 0 {
-                break;
-            }
+        encode_remaining_length(buffer, length);
+    }
+}
+
+/// 编码剩余长度（使用可变长度编码）
+pub fn encode_remaining_length(buffer: &mut BytesMut, length: usize) {
+    let mut remaining_length = length;
+    loop {
+        let mut digit = remaining_length % 128;
+        remaining_length /= 128;
+        if remaining_length > 0 {
+            digit |= 128;
         }
+        buffer.put_u8(digit as u8);
+        if remaining_length == 0 {
+            break;
+        }
+    }
+}
+
+/// 解码剩余长度
+///
+/// `data` 从剩余长度字段的第一个字节开始。数据不足时返回 `Ok(None)`，
+/// 成功时返回 `(剩余长度, 长度字段占用的字节数)`。
+pub fn decode_remaining_length(data: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let mut value = 0usize;
+    let mut multiplier = 1usize;
+    for (i, byte) in data.iter().enumerate() {
+        if i >= 4 {
+            return Err(malformed("remaining length exceeds 4 bytes"));
+        }
+        value += (*byte as usize & 127) * multiplier;
+        if byte & 128 == 0 {
+            return Ok(Some((value, i + 1)));
+        }
+        multiplier *= 128;
+    }
+    if data.len() >= 4 {
+        return Err(malformed("remaining length exceeds 4 bytes"));
     }
+    Ok(None)
+}
+
+/// 构造格式错误的数据包错误
+pub(crate) fn malformed(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed packet: {}", message.into()))
+}
+
+pub(crate) fn read_u8(body: &mut Bytes) -> io::Result<u8> {
+    if body.remaining() < 1 {
+        return Err(malformed("unexpected end of packet"));
+    }
+    Ok(body.get_u8())
+}
+
+pub(crate) fn read_u16(body: &mut Bytes) -> io::Result<u16> {
+    if body.remaining() < 2 {
+        return Err(malformed("unexpected end of packet"));
+    }
+    Ok(body.get_u16())
+}
+
+/// 读取变长整数（与剩余长度的编码方式相同，最多4字节）
+pub(crate) fn read_variable_int(body: &mut Bytes) -> io::Result<u32> {
+    let mut value = 0u32;
+    for i in 0..4 {
+        let byte = read_u8(body)?;
+        value |= ((byte & 127) as u32) << (7 * i);
+        if byte & 128 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(malformed("variable byte integer exceeds 4 bytes"))
+}
+
+/// 读取带2字节长度前缀的二进制数据
+pub(crate) fn read_binary(body: &mut Bytes) -> io::Result<Bytes> {
+    let len = read_u16(body)? as usize;
+    if body.remaining() < len {
+        return Err(malformed("length prefix exceeds packet size"));
+    }
+    Ok(body.split_to(len))
+}
+
+/// 读取带2字节长度前缀的UTF-8字符串
+pub(crate) fn read_string(body: &mut Bytes) -> io::Result<String> {
+    let data = read_binary(body)?;
+    String::from_utf8(data.to_vec()).map_err(|_| malformed("invalid UTF-8 string"))
+}
+
+/// 检查带2字节长度前缀的字段长度，`field`用于错误信息
+pub(crate) fn check_field_len(field: &str, len: usize) -> io::Result<()> {
+    if len > MAX_FIELD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} longer than {} bytes", field, MAX_FIELD_LEN),
+        ));
+    }
+    Ok(())
+}
+
+/// 写入带2字节长度前缀的UTF-8字符串，调用方需先用`check_field_len`检查长度
+pub(crate) fn write_string(buffer: &mut BytesMut, value: &str) {
+    buffer.put_u16(value.len() as u16);
+    buffer.extend_from_slice(value.as_bytes());
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// 编码后再解码，确保得到相同的数据包
+    fn roundtrip(packet: Packet) {
+        let mut encoded = packet.to_bytes();
+        let header = encoded[0];
+        let (length, used) = decode_remaining_length(&encoded[1..]).unwrap().unwrap();
+        let _ = encoded.split_to(1 + used);
+        assert_eq!(encoded.len(), length);
+        let decoded = Packet::decode(header, encoded.freeze()).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
     #[test]
     fn test_connect_packet_encoding() {
         let packet = ConnectPacket::new("test-client".to_string());
         let encoded = packet.encode();
-        
+
         // 验证基本结构
         assert!(!encoded.is_empty());
         // 第一个字节应该是CONNECT包类型
         assert_eq!(encoded[0], (PacketType::CONNECT as u8) << 4);
     }
-    
+
     #[test]
     fn test_connect_packet_with_username() {
         let mut packet = ConnectPacket::new("test-client".to_string());
         packet.username = Some("testuser".to_string());
         let encoded = packet.encode();
-        
+
         // 验证基本结构
         assert!(!encoded.is_empty());
         // 第一个字节应该是CONNECT包类型
         assert_eq!(encoded[0], (PacketType::CONNECT as u8) << 4);
     }
-    
+
     #[test]
     fn test_connect_packet_with_password() {
         let mut packet = ConnectPacket::new("test-client".to_string());
         packet.username = Some("testuser".to_string());
         packet.password = Some("testpass".to_string());
         let encoded = packet.encode();
-        
+
         // 验证基本结构
         assert!(!encoded.is_empty());
         // 第一个字节应该是CONNECT包类型
         assert_eq!(encoded[0], (PacketType::CONNECT as u8) << 4);
     }
-    
+
+    #[test]
+    fn test_connect_packet_with_will() {
+        let mut packet = ConnectPacket::new("test-client".to_string());
+        packet.will = Some(LastWill {
+            topic: "status".to_string(),
+            message: Bytes::from_static(b"gone"),
+            qos: QoS::ExactlyOnce,
+            retain: false,
+            properties: Properties::default(),
+        });
+        let encoded = packet.encode();
+
+        // 剩余长度包含遗嘱字段，连接标志包含遗嘱标志和遗嘱QoS
+        assert_eq!(encoded.len(), 2 + packet.calculate_remaining_length());
+        let flags = encoded[2 + 2 + MQTT_PROTOCOL_NAME.len() + 1];
+        assert_eq!(flags & WILL_FLAG, WILL_FLAG);
+        assert_eq!((flags & WILL_QOS_MASK) >> 3, QoS::ExactlyOnce as u8);
+        assert_eq!(flags & WILL_RETAIN, 0);
+    }
+
     #[test]
     fn test_encode_remaining_length() {
         let packet = ConnectPacket::new("test-client".to_string());
-        
+
         // 测试小长度值
         let mut buffer = BytesMut::new();
         packet.encode_remaining_length(&mut buffer, 127);
         assert_eq!(buffer.len(), 1);
         assert_eq!(buffer[0], 127);
-        
+
         // 测试需要多个字节的长度值
         let mut buffer = BytesMut::new();
         packet.encode_remaining_length(&mut buffer, 128);
         assert_eq!(buffer.len(), 2);
         assert_eq!(buffer[0], 128 | 128); // 继续位 + 0
         assert_eq!(buffer[1], 1); // 1
-        
+
         // 测试更大的长度值
         let mut buffer = BytesMut::new();
         packet.encode_remaining_length(&mut buffer, 16383);
@@ -171,15 +878,223 @@ mod tests {
         assert_eq!(buffer[0], 128 | 127); // 继续位 + 127
         assert_eq!(buffer[1], 127); // 127
     }
-    
+
     #[test]
     fn test_calculate_remaining_length() {
         let packet = ConnectPacket::new("test-client".to_string());
         let length = packet.calculate_remaining_length();
-        
+
         // 验证长度计算是否合理
         assert!(length > 0);
         // 对于基本的client-id，应该包含协议名、版本、标志、keep_alive、client_id等字段
         assert!(length > 20);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decode_remaining_length() {
+        for length in [0, 127, 128, 16383, 16384, 2_097_151, 2_097_152, MAX_REMAINING_LENGTH] {
+            let mut buffer = BytesMut::new();
+            encode_remaining_length(&mut buffer, length);
+            assert_eq!(decode_remaining_length(&buffer).unwrap(), Some((length, buffer.len())));
+            // 数据不完整时需要等待更多字节
+            assert_eq!(decode_remaining_length(&buffer[..buffer.len() - 1]).unwrap(), None);
+        }
+
+        // 超过4个字节的长度字段是非法的
+        assert!(decode_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF, 0x7F]).is_err());
+    }
+
+    #[test]
+    fn test_roundtrip_all_packet_types() {
+        let mut connect = ConnectPacket::new("test-client".to_string());
+        connect.username = Some("testuser".to_string());
+        connect.password = Some("testpass".to_string());
+        connect.connect_flags = CLEAN_SESSION | USERNAME_FLAG | PASSWORD_FLAG;
+
+        roundtrip(Packet::Connect(connect.clone()));
+        connect.will = Some(LastWill {
+            topic: "status/test-client".to_string(),
+            message: Bytes::from_static(b"offline"),
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            properties: Properties::default(),
+        });
+        connect.connect_flags |= WILL_FLAG | WILL_RETAIN | (QoS::AtLeastOnce as u8) << 3;
+        roundtrip(Packet::Connect(connect));
+        roundtrip(Packet::Connack(ConnackPacket { session_present: true, return_code: 0, properties: Properties::default() }));
+        roundtrip(Packet::Publish(PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic: "test/topic".to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: Bytes::from_static(b"Hello, MQTT!"),
+        }));
+        roundtrip(Packet::Publish(PublishPacket {
+            dup: true,
+            qos: QoS::ExactlyOnce,
+            retain: false,
+            topic: "test/topic".to_string(),
+            packet_id: Some(42),
+            properties: Properties::default(),
+            payload: Bytes::from(vec![0u8; 300]),
+        }));
+        roundtrip(Packet::Puback(AckPacket::new(1)));
+        roundtrip(Packet::Pubrec(AckPacket::new(2)));
+        roundtrip(Packet::Pubrel(AckPacket::new(3)));
+        roundtrip(Packet::Pubcomp(AckPacket::new(4)));
+        roundtrip(Packet::Subscribe(SubscribePacket {
+            packet_id: 5,
+            topics: vec![("a/b".to_string(), QoS::AtMostOnce), ("c/#".to_string(), QoS::ExactlyOnce)],
+            properties: Properties::default(),
+        }));
+        roundtrip(Packet::Suback(SubackPacket {
+            packet_id: 5,
+            return_codes: vec![0x00, 0x80],
+            properties: Properties::default(),
+        }));
+        roundtrip(Packet::Unsubscribe(UnsubscribePacket {
+            packet_id: 6,
+            topics: vec!["a/b".to_string(), "c/#".to_string()],
+            properties: Properties::default(),
+        }));
+        roundtrip(Packet::Unsuback(UnsubackPacket {
+            packet_id: 6,
+            reason_codes: Vec::new(),
+            properties: Properties::default(),
+        }));
+        roundtrip(Packet::Pingreq);
+        roundtrip(Packet::Pingresp);
+        roundtrip(Packet::Disconnect(DisconnectPacket::default()));
+    }
+
+    /// 按MQTT 5.0编码后再解码
+    fn roundtrip_v5(packet: Packet) {
+        let mut encoded = BytesMut::new();
+        packet.encode_with_version(&mut encoded, MQTT_PROTOCOL_VERSION_5);
+        let header = encoded[0];
+        let (length, used) = decode_remaining_length(&encoded[1..]).unwrap().unwrap();
+        let _ = encoded.split_to(1 + used);
+        assert_eq!(encoded.len(), length);
+        let decoded = Packet::decode_with_version(header, encoded.freeze(), MQTT_PROTOCOL_VERSION_5).unwrap();
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn test_roundtrip_v5_packets() {
+        let mut connect = ConnectPacket::new("v5-client".to_string());
+        connect.protocol_version = MQTT_PROTOCOL_VERSION_5;
+        connect.properties.session_expiry_interval = Some(300);
+        connect.properties.topic_alias_maximum = Some(10);
+        connect.will = Some(LastWill {
+            topic: "status/v5-client".to_string(),
+            message: Bytes::from_static(b"offline"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Properties {
+                will_delay_interval: Some(5),
+                ..Properties::default()
+            },
+        });
+        connect.connect_flags |= WILL_FLAG;
+        // CONNECT总是按自身的协议级别编码，与连接当前的协议级别无关
+        roundtrip(Packet::Connect(connect.clone()));
+        roundtrip_v5(Packet::Connect(connect));
+
+        let mut connack = ConnackPacket {
+            session_present: false,
+            return_code: reason_code::SUCCESS,
+            properties: Properties::default(),
+        };
+        connack.properties.assigned_client_identifier = Some("auto-1".to_string());
+        roundtrip_v5(Packet::Connack(connack));
+
+        let mut publish = PublishPacket {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: "rpc/request".to_string(),
+            packet_id: Some(7),
+            properties: Properties::default(),
+            payload: Bytes::from_static(b"ping"),
+        };
+        publish.properties.response_topic = Some("rpc/response".to_string());
+        publish.properties.correlation_data = Some(Bytes::from_static(b"\x01\x02"));
+        publish.properties.message_expiry_interval = Some(60);
+        publish.properties.user_properties.push(("trace".to_string(), "abc".to_string()));
+        roundtrip_v5(Packet::Publish(publish));
+
+        roundtrip_v5(Packet::Puback(AckPacket::new(1)));
+        roundtrip_v5(Packet::Puback(AckPacket::with_reason(2, reason_code::NOT_AUTHORIZED)));
+        roundtrip_v5(Packet::Pubrel(AckPacket::with_reason(3, reason_code::PACKET_IDENTIFIER_NOT_FOUND)));
+        roundtrip_v5(Packet::Subscribe(SubscribePacket {
+            packet_id: 4,
+            topics: vec![("a/+".to_string(), QoS::AtLeastOnce)],
+            properties: Properties {
+                subscription_identifiers: vec![9],
+                ..Properties::default()
+            },
+        }));
+        roundtrip_v5(Packet::Suback(SubackPacket {
+            packet_id: 4,
+            return_codes: vec![0x01, reason_code::TOPIC_FILTER_INVALID],
+            properties: Properties::default(),
+        }));
+        roundtrip_v5(Packet::Unsuback(UnsubackPacket {
+            packet_id: 5,
+            reason_codes: vec![reason_code::SUCCESS, reason_code::NO_SUBSCRIPTION_EXISTED],
+            properties: Properties::default(),
+        }));
+        roundtrip_v5(Packet::Disconnect(DisconnectPacket::default()));
+        roundtrip_v5(Packet::Disconnect(DisconnectPacket::with_reason(reason_code::DISCONNECT_WITH_WILL)));
+        let mut auth = AuthPacket {
+            reason_code: reason_code::CONTINUE_AUTHENTICATION,
+            properties: Properties::default(),
+        };
+        auth.properties.authentication_method = Some("SCRAM-SHA-1".to_string());
+        auth.properties.authentication_data = Some(Bytes::from_static(b"challenge"));
+        roundtrip_v5(Packet::Auth(auth));
+
+        // 原因码为0且没有属性时省略，确认包只有2字节的包标识符
+        let mut encoded = BytesMut::new();
+        Packet::Puback(AckPacket::new(1)).encode_with_version(&mut encoded, MQTT_PROTOCOL_VERSION_5);
+        assert_eq!(&encoded[..], &[0x40, 0x02, 0x00, 0x01]);
+        // 3.1.1中AUTH包非法
+        assert!(Packet::decode(0xF0, Bytes::new()).is_err());
+    }
+
+    #[test]
+    fn test_publish_payload_helpers() {
+        let mut publish = PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "sensors/temp".to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: Bytes::from_static(br#"{"value": 21.5}"#),
+        };
+        assert_eq!(publish.payload_str().unwrap(), r#"{"value": 21.5}"#);
+        let value: serde_json::Value = publish.payload_json().unwrap();
+        assert_eq!(value["value"], 21.5);
+
+        // 二进制负载原样保留，按文本解析时报错而不是替换字符
+        publish.payload = Bytes::from_static(&[0xA1, 0x00, 0xFF]);
+        assert!(publish.payload_str().is_err());
+        assert!(publish.payload_json::<serde_json::Value>().is_err());
+        roundtrip(Packet::Publish(publish));
+    }
+
+    #[test]
+    fn test_decode_rejects_malformed_packets() {
+        // SUBSCRIBE的固定头部标志必须为0010
+        assert!(Packet::decode(0x80, Bytes::from_static(&[0x00, 0x01, 0x00, 0x01, b'a', 0x00])).is_err());
+        // PUBLISH的QoS不能为3
+        assert!(Packet::decode(0x36, Bytes::from_static(&[0x00, 0x01, b'a', 0x00, 0x01])).is_err());
+        // 字符串长度超出包体
+        assert!(Packet::decode(0x30, Bytes::from_static(&[0x00, 0x09, b'a'])).is_err());
+        // PINGREQ不应带有包体
+        assert!(Packet::decode(0xC0, Bytes::from_static(&[0x00])).is_err());
+    }
+}
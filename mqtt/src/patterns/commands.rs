@@ -1,13 +1,15 @@
 /// 命令模式实现模块
 /// 将MQTT操作封装为命令对象
-
-use std::error::Error;
+use bytes::Bytes;
+use serde::Serialize;
 use crate::client::MqttClient;
+use crate::error::MqttError;
+use crate::protocol::QoS;
 
 /// 命令 trait，定义了所有MQTT命令的通用接口
 #[async_trait::async_trait]
 pub trait Command {
-    async fn execute(&self, client: &mut MqttClient) -> Result<(), Box<dyn Error>>;
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError>;
     fn get_name(&self) -> &'static str;
 }
 
@@ -24,7 +26,7 @@ impl ConnectCommand {
 
 #[async_trait::async_trait]
 impl Command for ConnectCommand {
-    async fn execute(&self, client: &mut MqttClient) -> Result<(), Box<dyn Error>> {
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError> {
         client.connect(&self.addr).await
     }
     
@@ -34,6 +36,7 @@ impl Command for ConnectCommand {
 }
 
 /// 断开连接命令
+#[derive(Default)]
 pub struct DisconnectCommand;
 
 impl DisconnectCommand {
@@ -44,7 +47,7 @@ impl DisconnectCommand {
 
 #[async_trait::async_trait]
 impl Command for DisconnectCommand {
-    async fn execute(&self, client: &mut MqttClient) -> Result<(), Box<dyn Error>> {
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError> {
         client.disconnect().await
     }
     
@@ -56,19 +59,32 @@ impl Command for DisconnectCommand {
 /// 发布命令
 pub struct PublishCommand {
     topic: String,
-    message: String,
+    payload: Bytes,
+    qos: QoS,
 }
 
 impl PublishCommand {
-    pub fn new(topic: String, message: String) -> Self {
-        PublishCommand { topic, message }
+    /// 负载按原样发送，可以是文本或任意二进制数据
+    pub fn new(topic: String, payload: impl Into<Bytes>) -> Self {
+        PublishCommand { topic, payload: payload.into(), qos: QoS::AtMostOnce }
+    }
+
+    /// 发布序列化为JSON的值
+    pub fn json<T: Serialize + ?Sized>(topic: String, value: &T) -> serde_json::Result<Self> {
+        Ok(Self::new(topic, serde_json::to_vec(value)?))
+    }
+
+    /// 指定发布的QoS等级
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
     }
 }
 
 #[async_trait::async_trait]
 impl Command for PublishCommand {
-    async fn execute(&self, client: &mut MqttClient) -> Result<(), Box<dyn Error>> {
-        client.publish(self.topic.clone(), self.message.clone()).await
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError> {
+        client.publish(self.topic.clone(), self.payload.clone(), self.qos).await
     }
     
     fn get_name(&self) -> &'static str {
@@ -79,18 +95,25 @@ impl Command for PublishCommand {
 /// 订阅命令
 pub struct SubscribeCommand {
     topic: String,
+    qos: QoS,
 }
 
 impl SubscribeCommand {
     pub fn new(topic: String) -> Self {
-        SubscribeCommand { topic }
+        SubscribeCommand { topic, qos: QoS::AtMostOnce }
+    }
+
+    /// 指定请求的QoS等级
+    pub fn with_qos(mut self, qos: QoS) -> Self {
+        self.qos = qos;
+        self
     }
 }
 
 #[async_trait::async_trait]
 impl Command for SubscribeCommand {
-    async fn execute(&self, client: &mut MqttClient) -> Result<(), Box<dyn Error>> {
-        client.subscribe(self.topic.clone()).await
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError> {
+        client.subscribe(self.topic.clone(), self.qos).await
     }
     
     fn get_name(&self) -> &'static str {
@@ -98,6 +121,28 @@ impl Command for SubscribeCommand {
     }
 }
 
+/// 取消订阅命令
+pub struct UnsubscribeCommand {
+    topic: String,
+}
+
+impl UnsubscribeCommand {
+    pub fn new(topic: String) -> Self {
+        UnsubscribeCommand { topic }
+    }
+}
+
+#[async_trait::async_trait]
+impl Command for UnsubscribeCommand {
+    async fn execute(&self, client: &mut MqttClient) -> Result<(), MqttError> {
+        client.unsubscribe(self.topic.clone()).await
+    }
+    
+    fn get_name(&self) -> &'static str {
+        "Unsubscribe"
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,5 +157,17 @@ mod tests {
         
         let subscribe_cmd = SubscribeCommand::new("test/topic".to_string());
         assert_eq!(subscribe_cmd.get_name(), "Subscribe");
+        
+        let unsubscribe_cmd = UnsubscribeCommand::new("test/topic".to_string());
+        assert_eq!(unsubscribe_cmd.get_name(), "Unsubscribe");
+        
+        let qos_cmd = PublishCommand::new("test/topic".to_string(), "message".to_string()).with_qos(QoS::ExactlyOnce);
+        assert_eq!(qos_cmd.qos, QoS::ExactlyOnce);
+        
+        let binary_cmd = PublishCommand::new("test/topic".to_string(), vec![0x00, 0xFF]);
+        assert_eq!(&binary_cmd.payload[..], &[0x00, 0xFF]);
+        
+        let json_cmd = PublishCommand::json("test/topic".to_string(), &serde_json::json!({ "value": 21.5 })).unwrap();
+        assert_eq!(&json_cmd.payload[..], br#"{"value":21.5}"#);
     }
 }
\ No newline at end of file
@@ -1,9 +1,8 @@
 /// 设计模式实现模块
 /// 聚合所有设计模式实现
-
 pub mod commands;
 pub mod states;
 
 // 重新导出公共类型
-pub use commands::{Command, ConnectCommand, PublishCommand, SubscribeCommand, DisconnectCommand};
-pub use states::{State, ClientState, DisconnectedState, ConnectingState, ConnectedState, DisconnectingState};
\ No newline at end of file
+pub use commands::{Command, ConnectCommand, PublishCommand, SubscribeCommand, UnsubscribeCommand, DisconnectCommand};
+pub use states::{State, ClientState, DisconnectedState, ConnectingState, ConnectedState, ReconnectingState, DisconnectingState};
\ No newline at end of file
@@ -1,5 +1,5 @@
-/// 状态模式实现模块
-/// 管理MQTT客户端的不同状态
+//! 状态模式实现模块
+//! 管理MQTT客户端的不同状态
 
 /// 客户端状态枚举
 #[derive(Debug, Clone, PartialEq)]
@@ -7,6 +7,7 @@ pub enum ClientState {
     Disconnected,
     Connecting,
     Connected,
+    Reconnecting,
     Disconnecting,
 }
 
@@ -67,13 +68,36 @@ impl State for ConnectedState {
     }
     
     fn can_execute_command(&self, command_name: &str) -> bool {
-        command_name == "Publish" || command_name == "Subscribe" || command_name == "Disconnect"
+        command_name == "Publish" || command_name == "Subscribe" || command_name == "Unsubscribe" || command_name == "Disconnect"
     }
     
     fn transition_to(&self, target_state: ClientState) -> Option<Box<dyn State + Send>> {
         match target_state {
             ClientState::Disconnecting => Some(Box::new(DisconnectingState)),
             ClientState::Disconnected => Some(Box::new(DisconnectedState)),
+            ClientState::Reconnecting => Some(Box::new(ReconnectingState)),
+            _ => None,
+        }
+    }
+}
+
+/// 重连中状态：连接意外断开，正在按重连策略重新连接
+pub struct ReconnectingState;
+
+impl State for ReconnectingState {
+    fn get_state(&self) -> ClientState {
+        ClientState::Reconnecting
+    }
+    
+    fn can_execute_command(&self, command_name: &str) -> bool {
+        // 发布请求缓存到重新连接后发送，断开连接则放弃重连
+        command_name == "Publish" || command_name == "Disconnect"
+    }
+    
+    fn transition_to(&self, target_state: ClientState) -> Option<Box<dyn State + Send>> {
+        match target_state {
+            ClientState::Connected => Some(Box::new(ConnectedState)),
+            ClientState::Disconnected => Some(Box::new(DisconnectedState)),
             _ => None,
         }
     }
@@ -131,6 +155,7 @@ mod tests {
         // 已连接状态下可以执行发布和订阅命令
         assert!(connected.can_execute_command("Publish"));
         assert!(connected.can_execute_command("Subscribe"));
+        assert!(connected.can_execute_command("Unsubscribe"));
         assert!(!connected.can_execute_command("Connect")); // 已连接时不能再次连接
     }
     
@@ -154,6 +179,16 @@ mod tests {
         assert!(connected.transition_to(ClientState::Disconnecting).is_some());
         assert!(connected.transition_to(ClientState::Disconnected).is_some());
         assert!(connected.transition_to(ClientState::Connecting).is_none());
+        assert!(connected.transition_to(ClientState::Reconnecting).is_some());
+        
+        // 测试重连中状态的转换和可执行的命令
+        let reconnecting = ReconnectingState;
+        assert!(reconnecting.transition_to(ClientState::Connected).is_some());
+        assert!(reconnecting.transition_to(ClientState::Disconnected).is_some());
+        assert!(reconnecting.transition_to(ClientState::Connecting).is_none());
+        assert!(reconnecting.can_execute_command("Publish"));
+        assert!(reconnecting.can_execute_command("Disconnect"));
+        assert!(!reconnecting.can_execute_command("Subscribe"));
         
         // 测试断开连接中状态的转换
         assert!(disconnecting.transition_to(ClientState::Disconnected).is_some());
@@ -0,0 +1,285 @@
+/// MQTT 5.0属性
+/// 可变头部中的属性以可变长度整数表示的总长度开头，随后是若干(标识符, 值)对；
+/// 3.1.1的数据包不携带属性，编解码时整体跳过
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use crate::packet::{check_field_len, encode_remaining_length, malformed, read_binary, read_string, read_u16, read_u8, read_variable_int, write_string};
+
+// 属性标识符
+const PAYLOAD_FORMAT_INDICATOR: u8 = 0x01;
+const MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+const CONTENT_TYPE: u8 = 0x03;
+const RESPONSE_TOPIC: u8 = 0x08;
+const CORRELATION_DATA: u8 = 0x09;
+const SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+const SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+const ASSIGNED_CLIENT_IDENTIFIER: u8 = 0x12;
+const SERVER_KEEP_ALIVE: u8 = 0x13;
+const AUTHENTICATION_METHOD: u8 = 0x15;
+const AUTHENTICATION_DATA: u8 = 0x16;
+const REQUEST_PROBLEM_INFORMATION: u8 = 0x17;
+const WILL_DELAY_INTERVAL: u8 = 0x18;
+const REQUEST_RESPONSE_INFORMATION: u8 = 0x19;
+const RESPONSE_INFORMATION: u8 = 0x1A;
+const SERVER_REFERENCE: u8 = 0x1C;
+const REASON_STRING: u8 = 0x1F;
+const RECEIVE_MAXIMUM: u8 = 0x21;
+const TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+const TOPIC_ALIAS: u8 = 0x23;
+const MAXIMUM_QOS: u8 = 0x24;
+const RETAIN_AVAILABLE: u8 = 0x25;
+const USER_PROPERTY: u8 = 0x26;
+const MAXIMUM_PACKET_SIZE: u8 = 0x27;
+const WILDCARD_SUBSCRIPTION_AVAILABLE: u8 = 0x28;
+const SUBSCRIPTION_IDENTIFIER_AVAILABLE: u8 = 0x29;
+const SHARED_SUBSCRIPTION_AVAILABLE: u8 = 0x2A;
+
+/// 数据包属性，所有包类型共用；未出现的属性为None
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Properties {
+    pub payload_format_indicator: Option<u8>,
+    /// 消息过期时间（秒）
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    /// 请求/响应模式中响应消息应发布到的主题
+    pub response_topic: Option<String>,
+    /// 请求/响应模式中用于关联请求和响应的数据
+    pub correlation_data: Option<Bytes>,
+    /// 消息匹配的订阅标识符，可以出现多次
+    pub subscription_identifiers: Vec<u32>,
+    /// 会话过期时间（秒），0xFFFFFFFF表示永不过期
+    pub session_expiry_interval: Option<u32>,
+    pub assigned_client_identifier: Option<String>,
+    pub server_keep_alive: Option<u16>,
+    pub authentication_method: Option<String>,
+    pub authentication_data: Option<Bytes>,
+    pub request_problem_information: Option<u8>,
+    pub will_delay_interval: Option<u32>,
+    pub request_response_information: Option<u8>,
+    pub response_information: Option<String>,
+    pub server_reference: Option<String>,
+    pub reason_string: Option<String>,
+    pub receive_maximum: Option<u16>,
+    pub topic_alias_maximum: Option<u16>,
+    pub topic_alias: Option<u16>,
+    pub maximum_qos: Option<u8>,
+    pub retain_available: Option<u8>,
+    /// 用户属性，按出现顺序保存，同名属性可以出现多次
+    pub user_properties: Vec<(String, String)>,
+    pub maximum_packet_size: Option<u32>,
+    pub wildcard_subscription_available: Option<u8>,
+    pub subscription_identifier_available: Option<u8>,
+    pub shared_subscription_available: Option<u8>,
+}
+
+impl Properties {
+    /// 是否没有任何属性
+    pub fn is_empty(&self) -> bool {
+        *self == Properties::default()
+    }
+
+    /// 检查带2字节长度前缀的字符串和二进制属性，见[`Packet::check_field_lengths`](crate::packet::Packet::check_field_lengths)
+    pub fn check_field_lengths(&self) -> io::Result<()> {
+        let len = |value: &Option<String>| value.as_ref().map_or(0, String::len);
+        let binary_len = |value: &Option<Bytes>| value.as_ref().map_or(0, Bytes::len);
+        check_field_len("content type", len(&self.content_type))?;
+        check_field_len("response topic", len(&self.response_topic))?;
+        check_field_len("correlation data", binary_len(&self.correlation_data))?;
+        check_field_len("assigned client identifier", len(&self.assigned_client_identifier))?;
+        check_field_len("authentication method", len(&self.authentication_method))?;
+        check_field_len("authentication data", binary_len(&self.authentication_data))?;
+        check_field_len("response information", len(&self.response_information))?;
+        check_field_len("server reference", len(&self.server_reference))?;
+        check_field_len("reason string", len(&self.reason_string))?;
+        for (name, value) in &self.user_properties {
+            check_field_len("user property", name.len().max(value.len()))?;
+        }
+        Ok(())
+    }
+
+    /// 编码属性长度和全部属性
+    pub fn encode(&self, buffer: &mut BytesMut) {
+        let mut body = BytesMut::new();
+        put_u8(&mut body, PAYLOAD_FORMAT_INDICATOR, self.payload_format_indicator);
+        put_u32(&mut body, MESSAGE_EXPIRY_INTERVAL, self.message_expiry_interval);
+        put_string(&mut body, CONTENT_TYPE, &self.content_type);
+        put_string(&mut body, RESPONSE_TOPIC, &self.response_topic);
+        put_binary(&mut body, CORRELATION_DATA, &self.correlation_data);
+        for identifier in &self.subscription_identifiers {
+            body.put_u8(SUBSCRIPTION_IDENTIFIER);
+            encode_remaining_length(&mut body, *identifier as usize);
+        }
+        put_u32(&mut body, SESSION_EXPIRY_INTERVAL, self.session_expiry_interval);
+        put_string(&mut body, ASSIGNED_CLIENT_IDENTIFIER, &self.assigned_client_identifier);
+        put_u16(&mut body, SERVER_KEEP_ALIVE, self.server_keep_alive);
+        put_string(&mut body, AUTHENTICATION_METHOD, &self.authentication_method);
+        put_binary(&mut body, AUTHENTICATION_DATA, &self.authentication_data);
+        put_u8(&mut body, REQUEST_PROBLEM_INFORMATION, self.request_problem_information);
+        put_u32(&mut body, WILL_DELAY_INTERVAL, self.will_delay_interval);
+        put_u8(&mut body, REQUEST_RESPONSE_INFORMATION, self.request_response_information);
+        put_string(&mut body, RESPONSE_INFORMATION, &self.response_information);
+        put_string(&mut body, SERVER_REFERENCE, &self.server_reference);
+        put_string(&mut body, REASON_STRING, &self.reason_string);
+        put_u16(&mut body, RECEIVE_MAXIMUM, self.receive_maximum);
+        put_u16(&mut body, TOPIC_ALIAS_MAXIMUM, self.topic_alias_maximum);
+        put_u16(&mut body, TOPIC_ALIAS, self.topic_alias);
+        put_u8(&mut body, MAXIMUM_QOS, self.maximum_qos);
+        put_u8(&mut body, RETAIN_AVAILABLE, self.retain_available);
+        for (name, value) in &self.user_properties {
+            body.put_u8(USER_PROPERTY);
+            write_string(&mut body, name);
+            write_string(&mut body, value);
+        }
+        put_u32(&mut body, MAXIMUM_PACKET_SIZE, self.maximum_packet_size);
+        put_u8(&mut body, WILDCARD_SUBSCRIPTION_AVAILABLE, self.wildcard_subscription_available);
+        put_u8(&mut body, SUBSCRIPTION_IDENTIFIER_AVAILABLE, self.subscription_identifier_available);
+        put_u8(&mut body, SHARED_SUBSCRIPTION_AVAILABLE, self.shared_subscription_available);
+
+        encode_remaining_length(buffer, body.len());
+        buffer.extend_from_slice(&body);
+    }
+
+    /// 解码属性长度和全部属性
+    ///
+    /// 除用户属性和订阅标识符外，同一属性出现多次属于协议错误。
+    pub fn decode(body: &mut Bytes) -> io::Result<Self> {
+        let length = read_variable_int(body)? as usize;
+        if body.remaining() < length {
+            return Err(malformed("property length exceeds packet size"));
+        }
+        let mut data = body.split_to(length);
+        let mut properties = Properties::default();
+        while data.has_remaining() {
+            let identifier = read_u8(&mut data)?;
+            let p = &mut properties;
+            match identifier {
+                PAYLOAD_FORMAT_INDICATOR => set(&mut p.payload_format_indicator, read_u8(&mut data)?, identifier)?,
+                MESSAGE_EXPIRY_INTERVAL => set(&mut p.message_expiry_interval, read_u32(&mut data)?, identifier)?,
+                CONTENT_TYPE => set(&mut p.content_type, read_string(&mut data)?, identifier)?,
+                RESPONSE_TOPIC => set(&mut p.response_topic, read_string(&mut data)?, identifier)?,
+                CORRELATION_DATA => set(&mut p.correlation_data, read_binary(&mut data)?, identifier)?,
+                SUBSCRIPTION_IDENTIFIER => {
+                    let value = read_variable_int(&mut data)?;
+                    if value == 0 {
+                        return Err(malformed("subscription identifier must not be 0"));
+                    }
+                    p.subscription_identifiers.push(value);
+                }
+                SESSION_EXPIRY_INTERVAL => set(&mut p.session_expiry_interval, read_u32(&mut data)?, identifier)?,
+                ASSIGNED_CLIENT_IDENTIFIER => set(&mut p.assigned_client_identifier, read_string(&mut data)?, identifier)?,
+                SERVER_KEEP_ALIVE => set(&mut p.server_keep_alive, read_u16(&mut data)?, identifier)?,
+                AUTHENTICATION_METHOD => set(&mut p.authentication_method, read_string(&mut data)?, identifier)?,
+                AUTHENTICATION_DATA => set(&mut p.authentication_data, read_binary(&mut data)?, identifier)?,
+                REQUEST_PROBLEM_INFORMATION => set(&mut p.request_problem_information, read_u8(&mut data)?, identifier)?,
+                WILL_DELAY_INTERVAL => set(&mut p.will_delay_interval, read_u32(&mut data)?, identifier)?,
+                REQUEST_RESPONSE_INFORMATION => set(&mut p.request_response_information, read_u8(&mut data)?, identifier)?,
+                RESPONSE_INFORMATION => set(&mut p.response_information, read_string(&mut data)?, identifier)?,
+                SERVER_REFERENCE => set(&mut p.server_reference, read_string(&mut data)?, identifier)?,
+                REASON_STRING => set(&mut p.reason_string, read_string(&mut data)?, identifier)?,
+                RECEIVE_MAXIMUM => set(&mut p.receive_maximum, read_u16(&mut data)?, identifier)?,
+                TOPIC_ALIAS_MAXIMUM => set(&mut p.topic_alias_maximum, read_u16(&mut data)?, identifier)?,
+                TOPIC_ALIAS => set(&mut p.topic_alias, read_u16(&mut data)?, identifier)?,
+                MAXIMUM_QOS => set(&mut p.maximum_qos, read_u8(&mut data)?, identifier)?,
+                RETAIN_AVAILABLE => set(&mut p.retain_available, read_u8(&mut data)?, identifier)?,
+                USER_PROPERTY => {
+                    let name = read_string(&mut data)?;
+                    let value = read_string(&mut data)?;
+                    p.user_properties.push((name, value));
+                }
+                MAXIMUM_PACKET_SIZE => set(&mut p.maximum_packet_size, read_u32(&mut data)?, identifier)?,
+                WILDCARD_SUBSCRIPTION_AVAILABLE => set(&mut p.wildcard_subscription_available, read_u8(&mut data)?, identifier)?,
+                SUBSCRIPTION_IDENTIFIER_AVAILABLE => set(&mut p.subscription_identifier_available, read_u8(&mut data)?, identifier)?,
+                SHARED_SUBSCRIPTION_AVAILABLE => set(&mut p.shared_subscription_available, read_u8(&mut data)?, identifier)?,
+                other => return Err(malformed(format!("unknown property identifier 0x{:02x}", other))),
+            }
+        }
+        Ok(properties)
+    }
+}
+
+fn set<T>(slot: &mut Option<T>, value: T, identifier: u8) -> io::Result<()> {
+    if slot.is_some() {
+        return Err(malformed(format!("duplicate property 0x{:02x}", identifier)));
+    }
+    *slot = Some(value);
+    Ok(())
+}
+
+fn read_u32(body: &mut Bytes) -> io::Result<u32> {
+    if body.remaining() < 4 {
+        return Err(malformed("unexpected end of packet"));
+    }
+    Ok(body.get_u32())
+}
+
+fn put_u8(buffer: &mut BytesMut, identifier: u8, value: Option<u8>) {
+    if let Some(value) = value {
+        buffer.put_u8(identifier);
+        buffer.put_u8(value);
+    }
+}
+
+fn put_u16(buffer: &mut BytesMut, identifier: u8, value: Option<u16>) {
+    if let Some(value) = value {
+        buffer.put_u8(identifier);
+        buffer.put_u16(value);
+    }
+}
+
+fn put_u32(buffer: &mut BytesMut, identifier: u8, value: Option<u32>) {
+    if let Some(value) = value {
+        buffer.put_u8(identifier);
+        buffer.put_u32(value);
+    }
+}
+
+fn put_string(buffer: &mut BytesMut, identifier: u8, value: &Option<String>) {
+    if let Some(value) = value {
+        buffer.put_u8(identifier);
+        write_string(buffer, value);
+    }
+}
+
+fn put_binary(buffer: &mut BytesMut, identifier: u8, value: &Option<Bytes>) {
+    if let Some(value) = value {
+        buffer.put_u8(identifier);
+        buffer.put_u16(value.len() as u16);
+        buffer.extend_from_slice(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties_roundtrip() {
+        let properties = Properties {
+            message_expiry_interval: Some(60),
+            response_topic: Some("replies/c1".to_string()),
+            correlation_data: Some(Bytes::from_static(b"req-1")),
+            subscription_identifiers: vec![1, 300],
+            topic_alias: Some(3),
+            user_properties: vec![("k".to_string(), "v1".to_string()), ("k".to_string(), "v2".to_string())],
+            ..Properties::default()
+        };
+        let mut buffer = BytesMut::new();
+        properties.encode(&mut buffer);
+        let mut encoded = buffer.freeze();
+        assert_eq!(Properties::decode(&mut encoded).unwrap(), properties);
+        assert!(encoded.is_empty());
+
+        // 空属性只占一个字节的长度字段
+        let mut buffer = BytesMut::new();
+        Properties::default().encode(&mut buffer);
+        assert_eq!(&buffer[..], &[0x00]);
+    }
+
+    #[test]
+    fn test_decode_rejects_duplicate_and_unknown_properties() {
+        let mut duplicate = Bytes::from_static(&[0x06, TOPIC_ALIAS, 0x00, 0x01, TOPIC_ALIAS, 0x00, 0x02]);
+        assert!(Properties::decode(&mut duplicate).is_err());
+        let mut unknown = Bytes::from_static(&[0x02, 0x7F, 0x00]);
+        assert!(Properties::decode(&mut unknown).is_err());
+    }
+}
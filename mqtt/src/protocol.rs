@@ -1,6 +1,8 @@
 /// MQTT协议常量和类型定义
 pub const MQTT_PROTOCOL_NAME: &str = "MQTT";
 pub const MQTT_PROTOCOL_VERSION: u8 = 4; // MQTT 3.1.1
+pub const MQTT_PROTOCOL_VERSION_5: u8 = 5; // MQTT 5.0
+pub const DEFAULT_KEEP_ALIVE: u16 = 60; // 默认保活时间（秒）
 
 // 连接标志位
 pub const USERNAME_FLAG: u8 = 0b10000000;
@@ -10,7 +12,20 @@ pub const WILL_QOS_MASK: u8 = 0b00011000;
 pub const WILL_FLAG: u8 = 0b00000100;
 pub const CLEAN_SESSION: u8 = 0b00000010;
 
+// SUBACK中表示订阅失败的返回码
+pub const SUBACK_FAILURE: u8 = 0x80;
+
+// 会话过期间隔取最大值时会话永不过期（MQTT 5.0）
+pub const SESSION_EXPIRY_NEVER: u32 = u32::MAX;
+
+// 剩余长度字段允许的最大值（4字节可变长度编码）
+pub const MAX_REMAINING_LENGTH: usize = 268_435_455;
+
+// UTF-8字符串和二进制数据的长度前缀为两个字节
+pub const MAX_FIELD_LEN: usize = u16::MAX as usize;
+
 // MQTT控制包类型
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PacketType {
     CONNECT = 1,
@@ -27,6 +42,7 @@ pub enum PacketType {
     PINGREQ = 12,
     PINGRESP = 13,
     DISCONNECT = 14,
+    AUTH = 15,
 }
 
 impl PacketType {
@@ -46,6 +62,56 @@ impl PacketType {
             12 => Some(PacketType::PINGREQ),
             13 => Some(PacketType::PINGRESP),
             14 => Some(PacketType::DISCONNECT),
+            15 => Some(PacketType::AUTH),
+            _ => None,
+        }
+    }
+}
+
+/// MQTT 5.0原因码（CONNACK、各类确认包、DISCONNECT和AUTH共用）
+pub mod reason_code {
+    pub const SUCCESS: u8 = 0x00;
+    pub const DISCONNECT_WITH_WILL: u8 = 0x04;
+    pub const NO_MATCHING_SUBSCRIBERS: u8 = 0x10;
+    pub const NO_SUBSCRIPTION_EXISTED: u8 = 0x11;
+    pub const CONTINUE_AUTHENTICATION: u8 = 0x18;
+    pub const REAUTHENTICATE: u8 = 0x19;
+    pub const UNSPECIFIED_ERROR: u8 = 0x80;
+    pub const MALFORMED_PACKET: u8 = 0x81;
+    pub const PROTOCOL_ERROR: u8 = 0x82;
+    pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+    pub const CLIENT_IDENTIFIER_NOT_VALID: u8 = 0x85;
+    pub const BAD_USERNAME_OR_PASSWORD: u8 = 0x86;
+    pub const NOT_AUTHORIZED: u8 = 0x87;
+    pub const SERVER_UNAVAILABLE: u8 = 0x88;
+    pub const SERVER_BUSY: u8 = 0x89;
+    pub const BANNED: u8 = 0x8A;
+    pub const BAD_AUTHENTICATION_METHOD: u8 = 0x8C;
+    pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+    pub const SESSION_TAKEN_OVER: u8 = 0x8E;
+    pub const TOPIC_FILTER_INVALID: u8 = 0x8F;
+    pub const TOPIC_NAME_INVALID: u8 = 0x90;
+    pub const PACKET_IDENTIFIER_NOT_FOUND: u8 = 0x92;
+    pub const TOPIC_ALIAS_INVALID: u8 = 0x94;
+    pub const PACKET_TOO_LARGE: u8 = 0x95;
+    pub const QUOTA_EXCEEDED: u8 = 0x97;
+    pub const ADMINISTRATIVE_ACTION: u8 = 0x98;
+}
+
+/// 服务质量等级
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum QoS {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+impl QoS {
+    pub fn from_u8(value: u8) -> Option<QoS> {
+        match value {
+            0 => Some(QoS::AtMostOnce),
+            1 => Some(QoS::AtLeastOnce),
+            2 => Some(QoS::ExactlyOnce),
             _ => None,
         }
     }
@@ -63,8 +129,18 @@ mod tests {
         assert_eq!(PacketType::from_u8(8), Some(PacketType::SUBSCRIBE));
         assert_eq!(PacketType::from_u8(9), Some(PacketType::SUBACK));
         assert_eq!(PacketType::from_u8(14), Some(PacketType::DISCONNECT));
+        assert_eq!(PacketType::from_u8(15), Some(PacketType::AUTH));
         assert_eq!(PacketType::from_u8(99), None);
     }
+
+    #[test]
+    fn test_qos_from_u8() {
+        assert_eq!(QoS::from_u8(0), Some(QoS::AtMostOnce));
+        assert_eq!(QoS::from_u8(1), Some(QoS::AtLeastOnce));
+        assert_eq!(QoS::from_u8(2), Some(QoS::ExactlyOnce));
+        assert_eq!(QoS::from_u8(3), None);
+        assert!(QoS::AtMostOnce < QoS::ExactlyOnce);
+    }
     
     #[test]
     fn test_protocol_constants() {
@@ -0,0 +1,126 @@
+/// 客户端自动重连策略
+/// 连接断开后按指数退避等待再重新连接，等待时间加入随机抖动，避免大量客户端同时重连
+use std::time::Duration;
+use rand::Rng;
+
+/// 第一次重连前的默认等待时间
+pub const DEFAULT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// 默认的最长等待时间
+pub const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(60);
+/// 默认的抖动比例：实际等待时间在计算值的±20%内随机
+pub const DEFAULT_JITTER: f64 = 0.2;
+/// 重连期间默认最多缓存的发布请求数
+pub const DEFAULT_BUFFER_CAPACITY: usize = 100;
+
+/// 自动重连策略
+///
+/// 第n次重连前等待`initial_delay * 2^(n-1)`，不超过`max_delay`；
+/// 超过`max_attempts`次仍未连接成功时放弃，事件循环结束。
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    initial_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    // None表示不限次数
+    max_attempts: Option<u32>,
+    // 重连期间缓存的发布请求上限，超过时发布直接失败
+    pub(crate) buffer_capacity: usize,
+}
+
+impl ReconnectPolicy {
+    pub fn new() -> Self {
+        ReconnectPolicy {
+            initial_delay: DEFAULT_INITIAL_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            jitter: DEFAULT_JITTER,
+            max_attempts: None,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+        }
+    }
+
+    /// 第一次重连前的等待时间
+    pub fn initial_delay(mut self, initial_delay: Duration) -> Self {
+        self.initial_delay = initial_delay;
+        self
+    }
+
+    /// 等待时间的上限
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// 抖动比例，取值0.0到1.0；0表示不加抖动
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// 连续重连失败的最大次数
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// 重连期间最多缓存的发布请求数
+    pub fn buffer_capacity(mut self, buffer_capacity: usize) -> Self {
+        self.buffer_capacity = buffer_capacity;
+        self
+    }
+
+    /// 第`attempt`次重连（从1开始）是否仍在允许的次数内
+    pub fn allows(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max_attempts| attempt <= max_attempts)
+    }
+
+    /// 第`attempt`次重连（从1开始）前的等待时间
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self.initial_delay.saturating_mul(1 << exponent).min(self.max_delay);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        let factor = rand::thread_rng().gen_range(1.0 - self.jitter..=1.0 + self.jitter);
+        delay.mul_f64(factor).min(self.max_delay)
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_grows_exponentially_up_to_max() {
+        let policy = ReconnectPolicy::new()
+            .initial_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_secs(1))
+            .jitter(0.0);
+        let delays: Vec<_> = (1..=6).map(|attempt| policy.delay(attempt).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        // 次数很大时不溢出
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_jitter_stays_within_bounds() {
+        let policy = ReconnectPolicy::new().initial_delay(Duration::from_secs(10)).jitter(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        assert!(ReconnectPolicy::new().allows(u32::MAX));
+        let policy = ReconnectPolicy::new().max_attempts(3);
+        assert!(policy.allows(3));
+        assert!(!policy.allows(4));
+    }
+}
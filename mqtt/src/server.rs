@@ -1,39 +1,483 @@
 /// MQTT服务端实现
-use tokio::net::{TcpListener, TcpStream};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::sync::broadcast;
+/// 同一监听端口同时服务MQTT 3.1.1和5.0客户端，协议级别由每个连接的CONNECT决定；
+/// `$share/<组名>/<过滤器>`共享订阅的消息在组内成员之间分摊；
+/// 运行统计定期发布到`$SYS/broker/...`主题，也可以通过管理HTTP服务以Prometheus格式采集；
+/// 保留消息和持久会话写入`Storage`，使用文件存储时代理重启后恢复
+use tokio::net::TcpListener;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
+use futures::{SinkExt, StreamExt};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use std::error::Error;
-use rand;
+use std::time::Duration;
+use crate::acl::{Access, Acl};
+use crate::admin::{self, ClientInfo, RetainedInfo, SubscriptionInfo};
+use crate::auth::{AllowAll, AuthResult, Authenticator, EnhancedAuth};
+use crate::codec::MqttCodec;
+use crate::error::MqttError;
+use crate::inflight::{InflightWindow, DEFAULT_RETRY_INTERVAL};
+use crate::packet::{
+    AckPacket, AuthPacket, ConnackPacket, ConnectPacket, DisconnectPacket, LastWill, Packet, PublishPacket, SubackPacket,
+    UnsubackPacket,
+};
+use crate::properties::Properties;
+use crate::protocol::{
+    reason_code, QoS, MAX_REMAINING_LENGTH, MQTT_PROTOCOL_VERSION, MQTT_PROTOCOL_VERSION_5, SESSION_EXPIRY_NEVER, SUBACK_FAILURE,
+};
+use crate::session::{Message, Session, SessionState};
+use crate::shared::{self, ShareStrategy, SharedGroup};
+use crate::stats::{BrokerStats, CountingTransport, DropReason, Gauges, DEFAULT_SYS_INTERVAL, SYS_PREFIX};
+use crate::storage::{self, MemoryStorage, Record, Storage, StoredMessage};
+use crate::tls::{self, TlsServerOptions};
+use crate::topic::{self, valid_topic_filter, valid_topic_name, TopicTrie};
+use crate::transport::Transport;
+use crate::websocket::{UpgradeCheck, WsTransport};
 
 // 存储订阅信息的类型
-type Subscriptions = Arc<Mutex<HashMap<String, Vec<String>>>>;
+type Subscriptions = Mutex<SubscriptionTable>;
+
+// 保留消息：主题名 -> 该主题最后一条保留消息
+type RetainedMessages = Mutex<HashMap<String, Message>>;
+
+// 客户端会话：客户端标识符 -> 会话
+type Sessions = Mutex<HashMap<String, Session>>;
+
+// 带MQTT编解码器的客户端连接，底层可以是TCP、TLS或WebSocket
+type ClientFramed<S> = Framed<S, MqttCodec>;
+
+/// 每个在线会话的消息队列默认容量
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1000;
+
+/// 代理在CONNACK中向MQTT 5.0客户端声明的主题别名最大值
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 64;
+
+// TLS和WebSocket握手以及等待CONNECT的超时时间，防止未完成握手的连接一直占用资源；
+// 保活检查和连接数上限都在收到CONNECT之后才生效
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Block策略下发布方等待订阅者队列的最长时间；发布方的连接任务在等待期间不处理自己的队列，
+// 订阅自己发布的主题或互相订阅的客户端不能无限等待
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// 在线会话的消息队列已满时的处理策略，配置文件中写作`drop_newest`、`disconnect`或`block`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// 丢弃发给该客户端的新消息，其他订阅者不受影响
+    #[default]
+    DropNewest,
+    /// 断开该客户端；持久会话之后的QoS 1/2消息进入离线队列
+    Disconnect,
+    /// 发布方等待队列腾出空间，慢速订阅者会拖慢发布方；
+    /// 一次发布等待所有已满队列的总时间不超过5秒，超时仍未放入的消息以及发给发布方自己的消息被丢弃
+    Block,
+}
+
+/// 订阅表
+///
+/// 普通订阅：主题过滤器 -> (客户端ID -> 授予的QoS)；
+/// 共享订阅：主题过滤器 -> (共享组名 -> 组成员)，过滤器为去掉`$share/<组名>/`前缀后的部分。
+#[derive(Default)]
+struct SubscriptionTable {
+    clients: TopicTrie<HashMap<String, QoS>>,
+    shared: TopicTrie<HashMap<String, SharedGroup>>,
+    // 普通订阅和共享订阅的总数
+    count: usize,
+}
+
+impl SubscriptionTable {
+    /// 添加订阅，`filter`可以是共享订阅过滤器
+    fn subscribe(&mut self, client_id: &str, filter: &str, qos: QoS) {
+        let added = match shared::parse(filter) {
+            Some((group, filter)) => self
+                .shared
+                .entry(filter)
+                .entry(group.to_string())
+                .or_default()
+                .subscribe(client_id, qos),
+            None => self.clients.entry(filter).insert(client_id.to_string(), qos).is_none(),
+        };
+        if added {
+            self.count += 1;
+        }
+    }
+
+    /// 删除客户端在某个过滤器上的订阅，过滤器或共享组没有订阅者时一并删除
+    fn unsubscribe(&mut self, client_id: &str, filter: &str) {
+        if let Some((group, filter)) = shared::parse(filter) {
+            let now_empty = match self.shared.get_mut(filter) {
+                Some(groups) => {
+                    if let Some(members) = groups.get_mut(group) {
+                        if members.unsubscribe(client_id) {
+                            self.count -= 1;
+                        }
+                        if members.is_empty() {
+                            groups.remove(group);
+                        }
+                    }
+                    groups.is_empty()
+                }
+                None => false,
+            };
+            if now_empty {
+                self.shared.remove(filter);
+            }
+            return;
+        }
+        let now_empty = match self.clients.get_mut(filter) {
+            Some(clients) => {
+                if clients.remove(client_id).is_some() {
+                    self.count -= 1;
+                }
+                clients.is_empty()
+            }
+            None => false,
+        };
+        if now_empty {
+            self.clients.remove(filter);
+        }
+    }
+}
+
+/// 所有客户端连接共享的代理状态
+///
+/// 需要同时持有多把锁时，按 subscriptions -> sessions 的顺序加锁。
+pub(crate) struct BrokerState {
+    subscriptions: Subscriptions,
+    retained: RetainedMessages,
+    sessions: Sessions,
+    // 在线会话消息队列的容量和队列满时的策略
+    queue_capacity: usize,
+    backpressure: Backpressure,
+    // 接收数据包的最大剩余长度，每个连接未确认的QoS 1/2消息上限，以及在线连接数上限
+    max_packet_size: usize,
+    max_inflight: u16,
+    max_connections: usize,
+    active_connections: AtomicUsize,
+    // 为每个网络连接分配唯一编号，用于区分同一会话的新旧连接
+    next_connection_id: AtomicU64,
+    // 处理CONNECT时校验用户名和密码
+    authenticator: Arc<dyn Authenticator>,
+    // 发布和订阅的主题访问控制
+    acl: Acl,
+    // 共享订阅在组内选择接收者的策略
+    share_strategy: ShareStrategy,
+    // 运行统计，以及发布到$SYS主题的间隔（0表示不发布）
+    pub(crate) stats: Arc<BrokerStats>,
+    sys_interval: Duration,
+    // 保留消息和持久会话的持久化存储
+    storage: Arc<dyn Storage>,
+}
+
+impl BrokerState {
+    fn new() -> Self {
+        BrokerState {
+            subscriptions: Mutex::new(SubscriptionTable::default()),
+            retained: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            backpressure: Backpressure::default(),
+            max_packet_size: MAX_REMAINING_LENGTH,
+            max_inflight: u16::MAX,
+            max_connections: usize::MAX,
+            active_connections: AtomicUsize::new(0),
+            next_connection_id: AtomicU64::new(1),
+            authenticator: Arc::new(AllowAll),
+            acl: Acl::allow_all(),
+            share_strategy: ShareStrategy::default(),
+            stats: Arc::new(BrokerStats::new()),
+            sys_interval: DEFAULT_SYS_INTERVAL,
+            storage: Arc::new(MemoryStorage::new()),
+        }
+    }
+
+    /// 把状态变更写入存储；写入失败只记录日志，不影响消息处理
+    fn persist(&self, record: Record) {
+        if let Err(e) = self.storage.apply(record) {
+            log::error!("Failed to write broker state to storage: {}", e);
+        }
+    }
+
+    /// 在线客户端及其订阅和待确认消息数，按客户端标识符排序
+    pub(crate) async fn connected_clients(&self) -> Vec<ClientInfo> {
+        let sessions = self.sessions.lock().await;
+        let mut clients: Vec<ClientInfo> = sessions
+            .iter()
+            .filter_map(|(client_id, session)| {
+                let inflight = session.inflight()?;
+                let mut subscriptions: Vec<SubscriptionInfo> = session
+                    .subscriptions
+                    .iter()
+                    .map(|(filter, qos)| SubscriptionInfo { filter: filter.clone(), qos: *qos as u8 })
+                    .collect();
+                subscriptions.sort_by(|a, b| a.filter.cmp(&b.filter));
+                Some(ClientInfo { client_id: client_id.clone(), subscriptions, inflight })
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client_id.cmp(&b.client_id));
+        clients
+    }
+
+    /// 未过期的保留消息，按主题排序
+    pub(crate) async fn retained_topics(&self) -> Vec<RetainedInfo> {
+        let store = self.retained.lock().await;
+        let mut retained: Vec<RetainedInfo> = store
+            .values()
+            .filter(|message| !message.is_expired())
+            .map(|message| RetainedInfo {
+                topic: message.publish.topic.clone(),
+                qos: message.publish.qos as u8,
+                payload_size: message.publish.payload.len(),
+            })
+            .collect();
+        retained.sort_by(|a, b| a.topic.cmp(&b.topic));
+        retained
+    }
+
+    /// 断开在线客户端，返回客户端是否在线；会话按其过期间隔保留，遗嘱照常发布
+    pub(crate) async fn disconnect_client(&self, client_id: &str) -> bool {
+        let mut sessions = self.sessions.lock().await;
+        match sessions.get_mut(client_id) {
+            Some(session) if session.is_connected() => {
+                session.close_connection(reason_code::ADMINISTRATIVE_ACTION);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 由代理自身发布一条消息，与客户端发布的消息走相同的路由
+    pub(crate) async fn publish(&self, publish: PublishPacket) {
+        publish_message(publish, None, self).await;
+    }
+
+    /// 读取在线客户端、订阅、保留消息和消息队列的当前数值
+    pub(crate) async fn gauges(&self) -> Gauges {
+        let subscriptions = self.subscriptions.lock().await.count;
+        let mut gauges = Gauges { subscriptions, ..Gauges::default() };
+        for session in self.sessions.lock().await.values() {
+            match session.outbox() {
+                Some(outbox) => {
+                    gauges.clients_connected += 1;
+                    gauges.queued_online += outbox.max_capacity() - outbox.capacity();
+                }
+                None => gauges.queued_offline += session.queued.len(),
+            }
+        }
+        gauges.retained = self.retained.lock().await.len();
+        gauges
+    }
+}
+
+/// TLS监听端口
+struct TlsListener {
+    listener: TcpListener,
+    acceptor: TlsAcceptor,
+    // 以客户端证书的CN作为用户名
+    use_identity_as_username: bool,
+}
+
+/// WebSocket监听端口
+struct WsListener {
+    listener: TcpListener,
+    // 接受升级请求的路径
+    path: String,
+}
 
 pub struct MqttBroker {
     listener: TcpListener,
-    subscriptions: Subscriptions,
-    // 使用broadcast来发送消息给所有订阅者
-    tx: broadcast::Sender<(String, String)>,
+    tls: Option<TlsListener>,
+    websocket: Option<WsListener>,
+    // 管理HTTP服务（/metrics、/healthz）的监听端口
+    admin: Option<TcpListener>,
+    state: Arc<BrokerState>,
 }
 
 impl MqttBroker {
-    pub async fn new(addr: &str) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(addr: &str) -> Result<Self, MqttError> {
         let listener = TcpListener::bind(addr).await?;
-        let subscriptions: Subscriptions = Arc::new(Mutex::new(HashMap::new()));
-        let (tx, _rx) = broadcast::channel(100);
+        let state = Arc::new(BrokerState::new());
         
         Ok(MqttBroker {
             listener,
-            subscriptions,
-            tx,
+            tls: None,
+            websocket: None,
+            admin: None,
+            state,
         })
     }
 
+    /// 在`addr`上额外监听TLS连接，MQTT over TLS的标准端口为8883
+    ///
+    /// 必须在`run`之前调用，证书或私钥无法加载时返回错误。
+    pub async fn enable_tls(&mut self, addr: &str, options: TlsServerOptions) -> Result<(), MqttError> {
+        let acceptor = options.acceptor()?;
+        let listener = TcpListener::bind(addr).await?;
+        self.tls = Some(TlsListener {
+            listener,
+            acceptor,
+            use_identity_as_username: options.identity_as_username(),
+        });
+        Ok(())
+    }
+
+    /// 在`addr`上额外接受MQTT over WebSocket连接，升级请求的路径必须为`path`
+    ///
+    /// 必须在`run`之前调用。
+    pub async fn enable_websocket(&mut self, addr: &str, path: &str) -> Result<(), MqttError> {
+        let listener = TcpListener::bind(addr).await?;
+        self.websocket = Some(WsListener {
+            listener,
+            path: path.to_string(),
+        });
+        Ok(())
+    }
+
+    /// 在`addr`上启动管理HTTP服务，提供Prometheus格式的`/metrics`和`/healthz`
+    ///
+    /// 管理服务没有认证，可以断开客户端和发布消息，`addr`应当是只有运维能访问的接口（如127.0.0.1）。
+    /// 必须在`run`之前调用。
+    pub async fn enable_admin(&mut self, addr: &str) -> Result<(), MqttError> {
+        self.admin = Some(TcpListener::bind(addr).await?);
+        Ok(())
+    }
+
+    /// 设置认证器，默认接受所有连接
+    ///
+    /// 必须在`run`之前调用。
+    pub fn set_authenticator(&mut self, authenticator: impl Authenticator + 'static) {
+        self.state_mut().authenticator = Arc::new(authenticator);
+    }
+
+    /// 设置主题访问控制列表，默认允许所有发布和订阅
+    ///
+    /// 必须在`run`之前调用。
+    pub fn set_acl(&mut self, acl: Acl) {
+        self.state_mut().acl = acl;
+    }
+
+    /// 设置每个在线会话的消息队列容量，必须在`run`之前调用
+    pub fn set_queue_capacity(&mut self, capacity: usize) {
+        self.state_mut().queue_capacity = capacity.max(1);
+    }
+
+    /// 设置消息队列已满时的处理策略，必须在`run`之前调用
+    pub fn set_backpressure(&mut self, backpressure: Backpressure) {
+        self.state_mut().backpressure = backpressure;
+    }
+
+    /// 设置接收数据包的最大长度（剩余长度），超出时关闭连接；必须在`run`之前调用
+    pub fn set_max_packet_size(&mut self, max_packet_size: usize) {
+        self.state_mut().max_packet_size = max_packet_size.min(MAX_REMAINING_LENGTH);
+    }
+
+    /// 设置每个连接上已发送未确认的QoS 1/2消息上限，达到上限后新消息在队列中等待
+    ///
+    /// 5.0客户端在CONNECT中声明的接收最大值更小时以客户端为准。必须在`run`之前调用。
+    pub fn set_max_inflight(&mut self, max_inflight: u16) {
+        self.state_mut().max_inflight = max_inflight.max(1);
+    }
+
+    /// 设置在线连接数上限，超出时以“服务不可用”拒绝CONNECT；必须在`run`之前调用
+    pub fn set_max_connections(&mut self, max_connections: usize) {
+        self.state_mut().max_connections = max_connections;
+    }
+
+    /// 设置共享订阅在组内选择接收者的策略，默认轮询，必须在`run`之前调用
+    pub fn set_share_strategy(&mut self, strategy: ShareStrategy) {
+        self.state_mut().share_strategy = strategy;
+    }
+
+    /// 设置发布`$SYS`系统主题的间隔，默认10秒，0表示不发布；必须在`run`之前调用
+    pub fn set_sys_interval(&mut self, interval: Duration) {
+        self.state_mut().sys_interval = interval;
+    }
+
+    /// 设置持久化存储并恢复其中保存的保留消息和持久会话，默认使用只在进程内保存的`MemoryStorage`
+    ///
+    /// 必须在`run`之前调用。已经过期的会话和消息在恢复时丢弃。
+    pub fn set_storage(&mut self, storage: impl Storage + 'static) -> Result<(), MqttError> {
+        let stored = storage.load()?;
+        let state = self.state_mut();
+        let retained = state.retained.get_mut();
+        for (topic, message) in &stored.retained {
+            match message.to_message() {
+                Ok(Some(message)) => {
+                    retained.insert(topic.clone(), message);
+                }
+                Ok(None) => storage.apply(Record::RemoveRetained { topic: topic.clone() })?,
+                Err(e) => log::warn!("Dropping unreadable retained message on topic '{}': {}", topic, e),
+            }
+        }
+        let subs = state.subscriptions.get_mut();
+        let sessions = state.sessions.get_mut();
+        for (client_id, stored_session) in &stored.sessions {
+            let Some(session) = stored_session.restore() else {
+                storage.apply(Record::RemoveSession { client_id: client_id.clone() })?;
+                continue;
+            };
+            for (filter, qos) in &session.subscriptions {
+                subs.subscribe(client_id, filter, *qos);
+            }
+            sessions.insert(client_id.clone(), session);
+        }
+        log::info!("Restored {} retained messages and {} sessions from storage", retained.len(), sessions.len());
+        state.storage = Arc::new(storage);
+        Ok(())
+    }
+
+    fn state_mut(&mut self) -> &mut BrokerState {
+        Arc::get_mut(&mut self.state).expect("broker settings must be changed before run()")
+    }
+
+    /// 获取实际监听的地址
+    pub fn local_addr(&self) -> std::io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// 获取TLS监听的实际地址，没有启用TLS时返回None
+    pub fn tls_local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.tls.as_ref().and_then(|tls| tls.listener.local_addr().ok())
+    }
+
+    /// 获取WebSocket监听的实际地址，没有启用WebSocket时返回None
+    pub fn ws_local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.websocket.as_ref().and_then(|ws| ws.listener.local_addr().ok())
+    }
+
+    /// 获取管理HTTP服务的实际地址，没有启用时返回None
+    pub fn admin_local_addr(&self) -> Option<std::net::SocketAddr> {
+        self.admin.as_ref().and_then(|admin| admin.local_addr().ok())
+    }
+
     /// 运行MQTT代理服务器
-    pub async fn run(&mut self) -> Result<(), Box<dyn Error>> {
+    pub async fn run(&mut self) -> Result<(), MqttError> {
         log::info!("MQTT Broker listening on {}", self.listener.local_addr()?);
+        if let Some(tls) = self.tls.take() {
+            log::info!("MQTT Broker listening for TLS on {}", tls.listener.local_addr()?);
+            tokio::spawn(run_tls_listener(tls, self.state.clone()));
+        }
+        if let Some(websocket) = self.websocket.take() {
+            log::info!("MQTT Broker listening for WebSocket on {}{}", websocket.listener.local_addr()?, websocket.path);
+            tokio::spawn(run_ws_listener(websocket, self.state.clone()));
+        }
+        if let Some(admin) = self.admin.take() {
+            log::info!("Admin HTTP server listening on {}", admin.local_addr()?);
+            tokio::spawn(admin::run_admin_listener(admin, self.state.clone()));
+        }
+        if !self.state.sys_interval.is_zero() {
+            tokio::spawn(publish_sys_topics(self.state.clone()));
+        }
+        // 从存储中恢复的离线会话按各自的过期时刻删除
+        for (client_id, session) in self.state.sessions.lock().await.iter() {
+            if let Some(expires_at) = session.expires_at {
+                tokio::spawn(expire_session(self.state.clone(), client_id.clone(), expires_at));
+            }
+        }
         
         loop {
             match self.listener.accept().await {
@@ -41,12 +485,10 @@ impl MqttBroker {
                     log::info!("New client connected: {}", addr);
                     
                     // 为每个客户端创建一个处理任务
-                    let subscriptions = self.subscriptions.clone();
-                    let tx = self.tx.clone();
-                    let rx = tx.subscribe();
+                    let state = self.state.clone();
                     
                     tokio::spawn(async move {
-                        if let Err(e) = handle_client(socket, subscriptions, tx, rx).await {
+                        if let Err(e) = handle_client(socket, state, None).await {
                             log::error!("Error handling client: {}", e);
                         }
                     });
@@ -57,250 +499,2281 @@ impl MqttBroker {
     }
 }
 
+/// 接受TLS连接，握手完成后与普通TCP连接走相同的处理流程
+async fn run_tls_listener(tls: TlsListener, state: Arc<BrokerState>) {
+    loop {
+        let (socket, addr) = match tls.listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Error accepting TLS connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = tls.acceptor.clone();
+        let use_identity_as_username = tls.use_identity_as_username;
+        let state = state.clone();
+        
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::warn!("TLS handshake with {} failed: {}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("TLS handshake with {} timed out", addr);
+                    return;
+                }
+            };
+            log::info!("New TLS client connected: {}", addr);
+            
+            let identity = if use_identity_as_username {
+                tls::peer_common_name(&stream)
+            } else {
+                None
+            };
+            if let Err(e) = handle_client(stream, state, identity).await {
+                log::error!("Error handling client: {}", e);
+            }
+        });
+    }
+}
+
+/// 接受WebSocket连接，升级完成后与普通TCP连接走相同的处理流程
+async fn run_ws_listener(websocket: WsListener, state: Arc<BrokerState>) {
+    let path: Arc<str> = Arc::from(websocket.path);
+    loop {
+        let (socket, addr) = match websocket.listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                log::error!("Error accepting WebSocket connection: {}", e);
+                continue;
+            }
+        };
+        let path = path.clone();
+        let state = state.clone();
+        
+        tokio::spawn(async move {
+            let upgrade = tokio_tungstenite::accept_hdr_async(socket, UpgradeCheck::new(path));
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, upgrade).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    log::warn!("WebSocket upgrade from {} failed: {}", addr, e);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("WebSocket upgrade from {} timed out", addr);
+                    return;
+                }
+            };
+            log::info!("New WebSocket client connected: {}", addr);
+            
+            if let Err(e) = handle_client(WsTransport::new(stream), state, None).await {
+                log::error!("Error handling client: {}", e);
+            }
+        });
+    }
+}
+
+/// CONNECT被接受后建立的连接信息
+struct Connection {
+    client_id: String,
+    // CONNECT中的用户名或客户端证书的CN，用于ACL中的%u替换
+    username: Option<String>,
+    connection_id: u64,
+    // CONNECT中的协议级别，决定是否使用原因码和属性
+    protocol_version: u8,
+    session: SessionState,
+    // 路由给该连接的消息
+    outbox: mpsc::Receiver<Message>,
+    // 会话被新连接接管或被代理断开时触发，携带DISCONNECT原因码
+    close: oneshot::Receiver<u8>,
+    // CONNECT中协商的保活时间，0表示关闭保活检测
+    keep_alive: Duration,
+    // 增强认证使用的认证方法（MQTT 5.0），重新认证时必须相同
+    auth_method: Option<String>,
+    // 该连接上允许同时未确认的QoS 1/2消息数
+    receive_maximum: usize,
+}
+
+/// 占用一个在线连接名额，释放时归还
+struct ConnectionPermit<'a>(&'a AtomicUsize);
+
+impl<'a> ConnectionPermit<'a> {
+    fn acquire(state: &'a BrokerState) -> Option<Self> {
+        state
+            .active_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| (active < state.max_connections).then_some(active + 1))
+            .ok()
+            .map(|_| ConnectionPermit(&state.active_connections))
+    }
+}
+
+impl Drop for ConnectionPermit<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
 /// 处理单个客户端连接
-async fn handle_client(
-    mut socket: TcpStream,
-    subscriptions: Subscriptions,
-    tx: broadcast::Sender<(String, String)>,
-    mut rx: broadcast::Receiver<(String, String)>,
-) -> Result<(), Box<dyn Error>> {
-    let mut buf = [0; 1024];
-    let mut client_id = String::new();
+///
+/// `identity`为TLS客户端证书提供的用户名，设置时跳过认证器。
+async fn handle_client<S: Transport>(
+    socket: S,
+    state: Arc<BrokerState>,
+    identity: Option<String>,
+) -> Result<(), MqttError> {
+    let socket = CountingTransport::new(socket, state.stats.clone());
+    let mut framed = Framed::new(socket, MqttCodec::with_max_packet_size(state.max_packet_size));
+    
+    // 客户端发送的第一个数据包必须是CONNECT
+    let Ok(first) = tokio::time::timeout(HANDSHAKE_TIMEOUT, framed.next()).await else {
+        log::warn!("No CONNECT received within {:?}, closing connection", HANDSHAKE_TIMEOUT);
+        return Ok(());
+    };
+    let connect = match first {
+        Some(Ok(Packet::Connect(connect))) => connect,
+        Some(Ok(other)) => {
+            log::error!("Expected CONNECT, got {:?}, closing connection", other.packet_type());
+            return Ok(());
+        }
+        Some(Err(e)) => return Err(MqttError::decode(e)),
+        None => return Ok(()),
+    };
+    // CONNECT中携带的遗嘱消息，收到DISCONNECT时清除
+    let mut will = connect.will.clone();
+    if will.as_ref().is_some_and(|will| !valid_topic_name(&will.topic)) {
+        // MQTT 5.0用CONNACK原因码告知客户端；3.1.1没有对应的返回码，直接关闭连接
+        if connect.protocol_version == MQTT_PROTOCOL_VERSION_5 {
+            log::warn!("Rejecting CONNECT from '{}': invalid will topic", connect.client_id);
+            reject_connect(&mut framed, reason_code::TOPIC_NAME_INVALID).await?;
+            return Ok(());
+        }
+        return Err(MqttError::Protocol("invalid will topic in CONNECT".to_string()));
+    }
+    let Some(_permit) = ConnectionPermit::acquire(&state) else {
+        log::warn!("Rejecting CONNECT from '{}': connection limit of {} reached", connect.client_id, state.max_connections);
+        let code = if connect.protocol_version == MQTT_PROTOCOL_VERSION_5 { reason_code::SERVER_BUSY } else { 0x03 }; // 返回码: 3表示服务不可用
+        reject_connect(&mut framed, code).await?;
+        return Ok(());
+    };
+    
+    let Some(mut connection) = handle_connect(&mut framed, &state, &connect, identity).await? else {
+        return Ok(());
+    };
+    log::info!("Client connected with ID: {}", connection.client_id);
+    
+    let result = client_loop(&mut framed, &mut connection, &mut will, &state).await;
+    
+    // 客户端未发送DISCONNECT就断开（连接关闭、读写错误、协议错误），发布遗嘱消息
+    if let Some(will) = will {
+        if state.acl.check(Access::Publish, &connection.client_id, connection.username.as_deref(), &will.topic) {
+            log::info!("Publishing will message of {} to topic '{}'", connection.client_id, will.topic);
+            publish_will(will, &state).await;
+        } else {
+            log::warn!("Client {} is not authorized to publish its will to '{}'", connection.client_id, will.topic);
+        }
+    }
+    close_session(&state, connection).await;
+    
+    result
+}
+
+/// 以原因码断开连接；只有MQTT 5.0允许代理发送DISCONNECT，3.1.1中直接关闭连接
+async fn send_disconnect<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    protocol_version: u8,
+    reason: u8,
+) -> Result<(), MqttError> {
+    if protocol_version == MQTT_PROTOCOL_VERSION_5 {
+        framed.send(Packet::Disconnect(DisconnectPacket::with_reason(reason))).await?;
+    }
+    Ok(())
+}
+
+/// 构造携带认证方法和认证数据的AUTH包
+fn auth_packet(reason_code: u8, method: &str, data: Option<bytes::Bytes>) -> Packet {
+    let properties = Properties {
+        authentication_method: Some(method.to_string()),
+        authentication_data: data,
+        ..Properties::default()
+    };
+    Packet::Auth(AuthPacket { reason_code, properties })
+}
+
+/// 客户端连接的消息处理循环
+async fn client_loop<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    connection: &mut Connection,
+    will: &mut Option<LastWill>,
+    state: &BrokerState,
+) -> Result<(), MqttError> {
+    let Connection { client_id, username, protocol_version, session, outbox, close, keep_alive, auth_method, receive_maximum, .. } = connection;
+    let receive_maximum = *receive_maximum;
+    let client_id = client_id.as_str();
+    let username = username.as_deref();
+    let protocol_version = *protocol_version;
+    let v5 = protocol_version == MQTT_PROTOCOL_VERSION_5;
+    // 客户端在该连接上建立的主题别名（MQTT 5.0）
+    let mut topic_aliases: HashMap<u16, String> = HashMap::new();
+    let mut retry_timer = tokio::time::interval(DEFAULT_RETRY_INTERVAL);
+    
+    // 超过1.5倍保活时间没有收到任何数据包，视为客户端已失联
+    let idle_timeout = *keep_alive * 3 / 2;
+    let idle = tokio::time::sleep(idle_timeout);
+    tokio::pin!(idle);
+    
+    // 恢复持久会话：先重发未完成确认的消息，离线期间缓存的消息随后在循环中投递
+    for packet in session.inflight.retransmissions(Duration::ZERO) {
+        framed.send(packet).await?;
+    }
     
     loop {
+        // 在途窗口有空位时投递排队的消息（离线缓存和窗口已满时的保留消息）
+        send_queued(framed, session, receive_maximum, &state.stats).await?;
+        // 共享订阅按各连接的待确认消息数选择接收者
+        session.report_unacked();
         tokio::select! {
             // 处理来自客户端的消息
-            result = socket.read(&mut buf) => {
+            result = framed.next() => {
                 match result {
-                    Ok(0) => {
+                    None => {
                         // 客户端断开连接
                         log::info!("Client disconnected");
                         return Ok(());
                     }
-                    Ok(n) => {
-                        // 解析MQTT包
-                        if let Some(packet_type) = parse_packet_type(buf[0]) {
-                            match packet_type {
-                                1 => {
-                                    // CONNECT包
-                                    client_id = handle_connect(&mut socket, &buf[..n]).await?;
-                                    log::info!("Client connected with ID: {}", client_id);
-                                },
-                                3 => {
-                                    // PUBLISH包
-                                    if let Some((topic, message)) = parse_publish(&buf[..n]) {
-                                        log::info!("Publishing message to topic '{}': {}", topic, message);
-                                        // 广播消息给所有订阅者
-                                        let _ = tx.send((topic.clone(), message.clone()));
+                    Some(Ok(packet)) => {
+                        idle.as_mut().reset(tokio::time::Instant::now() + idle_timeout);
+                        match packet {
+                            Packet::Connect(_) => {
+                                // 重复发送CONNECT属于协议错误
+                                log::error!("Second CONNECT from {}, closing connection", client_id);
+                                return Ok(());
+                            },
+                            Packet::Publish(mut publish) => {
+                                state.stats.message_received(publish.qos);
+                                // 主题别名只在该连接内有效，路由之前换回完整的主题名
+                                if let Some(alias) = publish.properties.topic_alias.take() {
+                                    if alias == 0 || alias > TOPIC_ALIAS_MAXIMUM {
+                                        log::error!("Invalid topic alias {} from {}, closing connection", alias, client_id);
+                                        send_disconnect(framed, protocol_version, reason_code::TOPIC_ALIAS_INVALID).await?;
+                                        return Ok(());
                                     }
-                                },
-                                8 => {
-                                    // SUBSCRIBE包
-                                    if let Some(topic) = parse_subscribe(&buf[..n]) {
-                                        log::info!("Client subscribed to topic: {}", topic);
-                                        // 添加订阅
-                                        let mut subs = subscriptions.lock().await;
-                                        if let Some(clients) = subs.get_mut(&topic) {
-                                            if !clients.contains(&client_id) {
-                                                clients.push(client_id.clone());
+                                    if publish.topic.is_empty() {
+                                        let Some(topic) = topic_aliases.get(&alias) else {
+                                            log::error!("Unknown topic alias {} from {}, closing connection", alias, client_id);
+                                            send_disconnect(framed, protocol_version, reason_code::PROTOCOL_ERROR).await?;
+                                            return Ok(());
+                                        };
+                                        publish.topic = topic.clone();
+                                    } else {
+                                        topic_aliases.insert(alias, publish.topic.clone());
+                                    }
+                                }
+                                // 发布的主题名不能包含通配符
+                                if !valid_topic_name(&publish.topic) {
+                                    log::error!("Invalid topic name '{}' from {}, closing connection", publish.topic, client_id);
+                                    send_disconnect(framed, protocol_version, reason_code::TOPIC_NAME_INVALID).await?;
+                                    return Ok(());
+                                }
+                                // 订阅标识符由代理按订阅设置，客户端发布时不能携带
+                                publish.properties.subscription_identifiers.clear();
+                                log::info!("Publishing message to topic '{}': {} bytes, QoS {:?}", 
+                                    publish.topic, publish.payload.len(), publish.qos);
+                                // 未授权的发布不转发给订阅者；3.1.1中照常确认，5.0中以0x87确认。
+                                // $SYS主题只能由代理发布
+                                let authorized = !publish.topic.starts_with(SYS_PREFIX)
+                                    && state.acl.check(Access::Publish, client_id, username, &publish.topic);
+                                if !authorized {
+                                    log::warn!("Client {} is not authorized to publish to '{}', dropping message", client_id, publish.topic);
+                                    state.stats.message_dropped(DropReason::Unauthorized);
+                                }
+                                let reason = if authorized { reason_code::SUCCESS } else { reason_code::NOT_AUTHORIZED };
+                                match (publish.qos, publish.packet_id) {
+                                    (QoS::AtLeastOnce, Some(packet_id)) => {
+                                        if authorized {
+                                            publish_message(publish, Some(client_id), state).await;
+                                        }
+                                        framed.send(Packet::Puback(AckPacket::with_reason(packet_id, reason))).await?;
+                                    }
+                                    (QoS::ExactlyOnce, Some(packet_id)) => {
+                                        // 同一包标识符在收到PUBREL之前只转发一次；5.0中被拒绝的消息不会再收到PUBREL，无需记录
+                                        let track = authorized || !v5;
+                                        if track && !session.incoming_qos2.insert(packet_id) {
+                                            log::debug!("Duplicate QoS 2 PUBLISH from {}, packet_id: {}", client_id, packet_id);
+                                        } else if authorized {
+                                            publish_message(publish, Some(client_id), state).await;
+                                        }
+                                        framed.send(Packet::Pubrec(AckPacket::with_reason(packet_id, reason))).await?;
+                                    }
+                                    _ => {
+                                        if authorized {
+                                            publish_message(publish, Some(client_id), state).await;
+                                        }
+                                    }
+                                }
+                            },
+                            Packet::Pubrel(ack) => {
+                                let reason = if session.incoming_qos2.remove(&ack.packet_id) {
+                                    reason_code::SUCCESS
+                                } else {
+                                    reason_code::PACKET_IDENTIFIER_NOT_FOUND
+                                };
+                                framed.send(Packet::Pubcomp(AckPacket::with_reason(ack.packet_id, reason))).await?;
+                            },
+                            Packet::Puback(ack) => {
+                                if session.inflight.on_puback(ack.packet_id).is_none() {
+                                    log::warn!("Unexpected PUBACK from {}, packet_id: {}", client_id, ack.packet_id);
+                                }
+                            },
+                            Packet::Pubrec(ack) if ack.reason_code >= reason_code::UNSPECIFIED_ERROR => {
+                                // 接收方拒绝了消息，QoS 2流程到此结束，不再发送PUBREL
+                                log::warn!("Client {} rejected packet_id {} with reason 0x{:02X}", client_id, ack.packet_id, ack.reason_code);
+                                session.inflight.abandon(ack.packet_id);
+                            },
+                            Packet::Pubrec(ack) => {
+                                match session.inflight.on_pubrec(ack.packet_id) {
+                                    Some(pubrel) => framed.send(pubrel).await?,
+                                    None => log::warn!("Unexpected PUBREC from {}, packet_id: {}", client_id, ack.packet_id),
+                                }
+                            },
+                            Packet::Pubcomp(ack) => {
+                                if session.inflight.on_pubcomp(ack.packet_id).is_none() {
+                                    log::warn!("Unexpected PUBCOMP from {}, packet_id: {}", client_id, ack.packet_id);
+                                }
+                            },
+                            Packet::Subscribe(subscribe) => {
+                                // 非法或未授权的主题过滤器返回0x80（5.0中为具体的原因码），其余过滤器照常订阅
+                                let failure = |reason: u8| if v5 { reason } else { SUBACK_FAILURE };
+                                let mut accepted = Vec::new();
+                                let mut return_codes = Vec::with_capacity(subscribe.topics.len());
+                                for (topic, qos) in &subscribe.topics {
+                                    let valid = if shared::is_shared(topic) {
+                                        shared::parse(topic).is_some()
+                                    } else {
+                                        valid_topic_filter(topic)
+                                    };
+                                    // 共享订阅按实际匹配的过滤器检查权限
+                                    let filter = shared::subscription_filter(topic);
+                                    if !valid {
+                                        log::warn!("Client {} sent invalid topic filter '{}'", client_id, topic);
+                                        return_codes.push(failure(reason_code::TOPIC_FILTER_INVALID));
+                                    } else if !state.acl.check(Access::Subscribe, client_id, username, filter) {
+                                        log::warn!("Client {} is not authorized to subscribe to '{}'", client_id, topic);
+                                        return_codes.push(failure(reason_code::NOT_AUTHORIZED));
+                                    } else {
+                                        return_codes.push(*qos as u8);
+                                        accepted.push((topic.clone(), *qos));
+                                    }
+                                }
+                                
+                                // 添加订阅，同时记录到会话中以便断线后保留
+                                {
+                                    let mut subs = state.subscriptions.lock().await;
+                                    let mut sessions = state.sessions.lock().await;
+                                    let mut client_session = sessions.get_mut(client_id);
+                                    for (topic, qos) in &accepted {
+                                        log::info!("Client subscribed to topic: {} with QoS {:?}", topic, qos);
+                                        subs.subscribe(client_id, topic, *qos);
+                                        if let Some(client_session) = client_session.as_mut() {
+                                            client_session.subscriptions.insert(topic.clone(), *qos);
+                                            if client_session.expiry_interval != 0 {
+                                                state.persist(Record::Subscribe {
+                                                    client_id: client_id.to_string(),
+                                                    filter: topic.clone(),
+                                                    qos: *qos as u8,
+                                                });
                                             }
-                                        } else {
-                                            subs.insert(topic, vec![client_id.clone()]);
                                         }
-                                        
-                                        // 发送SUBACK响应
-                                        if let Err(e) = send_suback(&mut socket, &buf[..n]).await {
-                                            log::error!("Error sending SUBACK: {}", e);
+                                    }
+                                }
+                                
+                                // 发送SUBACK响应
+                                if let Err(e) = send_suback(framed, subscribe.packet_id, return_codes).await {
+                                    log::error!("Error sending SUBACK: {}", e);
+                                }
+                                
+                                // 向新订阅发送匹配的保留消息，共享订阅不接收保留消息
+                                accepted.retain(|(topic, _)| !shared::is_shared(topic));
+                                let matched = matching_retained(&state.retained, &accepted).await;
+                                // 与其他消息一样经过在途窗口，窗口已满时排队等待确认
+                                for (mut message, granted) in matched {
+                                    message.publish.qos = message.publish.qos.min(granted);
+                                    session.queued.push_back(message);
+                                }
+                            },
+                            Packet::Unsubscribe(unsubscribe) => {
+                                let mut reason_codes = Vec::with_capacity(unsubscribe.topics.len());
+                                {
+                                    let mut subs = state.subscriptions.lock().await;
+                                    let mut sessions = state.sessions.lock().await;
+                                    let mut client_session = sessions.get_mut(client_id);
+                                    for topic in &unsubscribe.topics {
+                                        log::info!("Client unsubscribed from topic: {}", topic);
+                                        subs.unsubscribe(client_id, topic);
+                                        let existed = client_session
+                                            .as_mut()
+                                            .is_some_and(|client_session| client_session.subscriptions.remove(topic).is_some());
+                                        if existed && client_session.as_ref().is_some_and(|s| s.expiry_interval != 0) {
+                                            state.persist(Record::Unsubscribe { client_id: client_id.to_string(), filter: topic.clone() });
                                         }
+                                        reason_codes.push(if existed {
+                                            reason_code::SUCCESS
+                                        } else {
+                                            reason_code::NO_SUBSCRIPTION_EXISTED
+                                        });
+                                    }
+                                }
+                                framed.send(Packet::Unsuback(UnsubackPacket {
+                                    packet_id: unsubscribe.packet_id,
+                                    reason_codes,
+                                    properties: Properties::default(),
+                                })).await?;
+                            },
+                            Packet::Pingreq => {
+                                framed.send(Packet::Pingresp).await?;
+                            },
+                            Packet::Disconnect(disconnect) => {
+                                log::info!("Client {} sent DISCONNECT", client_id);
+                                if let Some(expiry_interval) = disconnect.properties.session_expiry_interval {
+                                    // CONNECT中会话过期间隔为0时，DISCONNECT不能再把它改为非0值
+                                    if session.expiry_interval == 0 && expiry_interval != 0 {
+                                        log::error!("Client {} set a session expiry interval on DISCONNECT, closing connection", client_id);
+                                        send_disconnect(framed, protocol_version, reason_code::PROTOCOL_ERROR).await?;
+                                        return Ok(());
                                     }
-                                },
-                                _ => {
-                                    log::warn!("Unhandled packet type: {}", packet_type);
+                                    session.expiry_interval = expiry_interval;
                                 }
+                                // 正常断开，丢弃遗嘱消息；5.0客户端可以要求仍然发布遗嘱
+                                if disconnect.reason_code != reason_code::DISCONNECT_WITH_WILL {
+                                    *will = None;
+                                }
+                                return Ok(());
+                            },
+                            Packet::Auth(auth) => {
+                                // 重新认证必须沿用CONNECT中的认证方法
+                                let method = match (auth.properties.authentication_method.as_deref(), auth_method.as_deref()) {
+                                    (Some(method), Some(expected)) if method == expected => method,
+                                    _ => {
+                                        log::error!("Unexpected AUTH from {}, closing connection", client_id);
+                                        send_disconnect(framed, protocol_version, reason_code::PROTOCOL_ERROR).await?;
+                                        return Ok(());
+                                    }
+                                };
+                                let data = auth.properties.authentication_data.as_deref();
+                                match state.authenticator.authenticate_enhanced(client_id, method, data).await {
+                                    EnhancedAuth::Continue(challenge) => {
+                                        framed.send(auth_packet(reason_code::CONTINUE_AUTHENTICATION, method, Some(challenge))).await?;
+                                    }
+                                    EnhancedAuth::Success(data) => {
+                                        log::info!("Client {} re-authenticated", client_id);
+                                        framed.send(auth_packet(reason_code::SUCCESS, method, data)).await?;
+                                    }
+                                    EnhancedAuth::Failure(reason) => {
+                                        log::warn!("Re-authentication of client {} failed: 0x{:02X}", client_id, reason);
+                                        send_disconnect(framed, protocol_version, reason).await?;
+                                        return Ok(());
+                                    }
+                                }
+                            },
+                            other => {
+                                log::warn!("Unhandled packet type: {:?}", other.packet_type());
                             }
                         }
                     }
-                    Err(e) => {
+                    Some(Err(e)) => {
                         log::error!("Error reading from socket: {}", e);
-                        return Err(e.into());
+                        return Err(MqttError::decode(e));
                     }
                 }
             }
             
-            // 处理来自其他客户端的消息广播
-            result = rx.recv() => {
-                match result {
-                    Ok((topic, message)) => {
-                        // 检查此客户端是否订阅了该主题
-                        let subs = subscriptions.lock().await;
-                        if let Some(clients) = subs.get(&topic) {
-                            if clients.contains(&client_id) {
-                                // 发送消息给客户端
-                                if let Err(e) = send_publish(&mut socket, &topic, &message).await {
-                                    log::error!("Error sending publish message: {}", e);
-                                }
-                            }
+            // 发送路由给该客户端的消息（QoS已按订阅降级），未确认的消息达到上限或还有排队的消息时暂停
+            Some(message) = outbox.recv(), if session.queued.is_empty() && session.inflight.len() < receive_maximum => {
+                let Some(publish) = message.into_publish() else {
+                    log::debug!("Dropping expired message for client {}", client_id);
+                    state.stats.message_dropped(DropReason::Expired);
+                    continue;
+                };
+                let qos = publish.qos;
+                send_publish(framed, &mut session.inflight, &publish, qos, false, &state.stats).await?;
+            }
+            
+            // 重传超时未确认的QoS 1/2消息；MQTT 5.0只允许在恢复会话时重发
+            _ = retry_timer.tick(), if !v5 => {
+                for packet in session.inflight.retransmissions(DEFAULT_RETRY_INTERVAL) {
+                    log::warn!("Retransmitting {:?} to {}", packet.packet_type(), client_id);
+                    framed.send(packet).await?;
+                }
+            }
+            
+            // 保活超时，关闭连接（保留遗嘱消息，由调用方发布）
+            _ = &mut idle, if !idle_timeout.is_zero() => {
+                log::warn!("Client {} exceeded keep-alive of {:?}, closing connection", client_id, keep_alive);
+                send_disconnect(framed, protocol_version, reason_code::KEEP_ALIVE_TIMEOUT).await?;
+                return Ok(());
+            }
+            
+            // 同一客户端标识符建立了新连接，或代理主动断开
+            reason = &mut *close => {
+                log::info!("Closing connection of client {} on broker request", client_id);
+                let reason = reason.unwrap_or(reason_code::UNSPECIFIED_ERROR);
+                send_disconnect(framed, protocol_version, reason).await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// 处理CONNECT包：确定客户端标识符，建立或恢复会话并回复CONNACK
+///
+/// 连接被拒绝时返回None。
+async fn handle_connect<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    state: &Arc<BrokerState>,
+    connect: &ConnectPacket,
+    identity: Option<String>,
+) -> Result<Option<Connection>, MqttError> {
+    log::debug!("Received CONNECT from '{}', keep_alive: {}, protocol level {}", connect.client_id, connect.keep_alive, connect.protocol_version);
+    let v5 = connect.protocol_version == MQTT_PROTOCOL_VERSION_5;
+    if connect.protocol_version != MQTT_PROTOCOL_VERSION && !v5 {
+        log::error!("Rejecting CONNECT with unsupported protocol level {}", connect.protocol_version);
+        reject_connect(framed, 0x01).await?; // 返回码: 1表示不支持的协议版本
+        return Ok(None);
+    }
+    let clean_start = connect.clean_session();
+    // 3.1.1的清除会话随连接删除、持久会话永不过期；5.0由CONNECT中的会话过期间隔决定
+    let expiry_interval = if v5 {
+        connect.properties.session_expiry_interval.unwrap_or(0)
+    } else if clean_start {
+        0
+    } else {
+        SESSION_EXPIRY_NEVER
+    };
+    let mut connack_properties = Properties::default();
+    
+    let client_id = if !connect.client_id.is_empty() {
+        connect.client_id.clone()
+    } else if clean_start || v5 {
+        // 客户端标识符为空时由代理分配，5.0中通过CONNACK告知客户端
+        let client_id = "client-".to_string() + &rand::random::<u32>().to_string();
+        if v5 {
+            connack_properties.assigned_client_identifier = Some(client_id.clone());
+        }
+        client_id
+    } else {
+        // 3.1.1的持久会话必须提供客户端标识符
+        log::error!("Rejecting CONNECT with empty client ID and clean_session = false");
+        reject_connect(framed, 0x02).await?; // 返回码: 2表示标识符被拒绝
+        return Ok(None);
+    };
+    
+    let auth_method = connect.properties.authentication_method.clone().filter(|_| v5);
+    let username = match (identity, auth_method.as_deref()) {
+        // 客户端证书已经由TLS层校验，证书中的CN直接作为用户名
+        (Some(identity), _) => {
+            log::debug!("Client {} identified by certificate as '{}'", client_id, identity);
+            Some(identity)
+        }
+        (None, Some(method)) => {
+            let data = connect.properties.authentication_data.clone();
+            match enhanced_auth(framed, state, &client_id, method, data).await? {
+                Ok(data) => {
+                    connack_properties.authentication_method = Some(method.to_string());
+                    connack_properties.authentication_data = data;
+                }
+                Err(reason) => {
+                    log::warn!("Enhanced authentication of client {} failed: 0x{:02X}", client_id, reason);
+                    state.stats.auth_failed();
+                    reject_connect(framed, reason).await?;
+                    return Ok(None);
+                }
+            }
+            connect.username.clone()
+        }
+        (None, None) => {
+            let auth = state.authenticator
+                .authenticate(&client_id, connect.username.as_deref(), connect.password.as_deref().map(str::as_bytes))
+                .await;
+            if auth != AuthResult::Accepted {
+                log::warn!("Authentication failed for client {} (username: {:?}): {:?}", client_id, connect.username, auth);
+                state.stats.auth_failed();
+                let code = if v5 { auth.reason_code() } else { auth.return_code() };
+                reject_connect(framed, code).await?;
+                return Ok(None);
+            }
+            connect.username.clone()
+        }
+    };
+    
+    let connection_id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let (outbox_tx, outbox) = mpsc::channel(state.queue_capacity);
+    let (session_present, session, close) = loop {
+        let released = {
+            let mut subs = state.subscriptions.lock().await;
+            let mut sessions = state.sessions.lock().await;
+            match sessions.get_mut(&client_id) {
+                // 会话仍绑定在旧连接上，等旧连接关闭并归还会话状态后再接管
+                Some(existing) if existing.is_connected() => existing.take_over(),
+                existing => {
+                    // 清除会话：丢弃之前保存的会话及其订阅；已过期的会话同样丢弃
+                    if clean_start || existing.is_some_and(|existing| existing.is_expired()) {
+                        if let Some(old) = sessions.remove(&client_id) {
+                            remove_subscriptions(&mut subs, &client_id, &old);
+                            state.persist(Record::RemoveSession { client_id: client_id.clone() });
                         }
                     }
-                    Err(broadcast::error::RecvError::Closed) => {
-                        // 发送者关闭
-                        break;
-                    }
-                    Err(broadcast::error::RecvError::Lagged(_)) => {
-                        // 消息滞后
-                        log::warn!("Warning: Lagged messages");
+                    let session_present = sessions.contains_key(&client_id);
+                    let entry = sessions.entry(client_id.clone()).or_insert_with(|| Session::new(expiry_interval));
+                    entry.expiry_interval = expiry_interval;
+                    let (session, close) = entry.attach(connection_id, outbox_tx.clone());
+                    // 离线队列已交给新连接；过期间隔为0的会话不再保存
+                    if expiry_interval != 0 {
+                        state.persist(Record::SetSession { client_id: client_id.clone(), expiry_interval, expires_at: None });
+                        state.persist(Record::SetMessages { client_id: client_id.clone(), messages: Vec::new() });
+                    } else if session_present {
+                        state.persist(Record::RemoveSession { client_id: client_id.clone() });
                     }
+                    break (session_present, session, close);
                 }
             }
-        }
-        // 添加一个短暂的延迟，防止过于频繁的循环
-        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        };
+        let _ = released.await;
+    };
+    
+    if v5 {
+        connack_properties.topic_alias_maximum = Some(TOPIC_ALIAS_MAXIMUM);
     }
+    let connack = Packet::Connack(ConnackPacket {
+        session_present,
+        return_code: 0x00, // 返回码: 0表示连接接受
+        properties: connack_properties,
+    });
+    framed.send(connack).await?;
+    state.stats.connection_accepted();
     
-    Ok(())
+    Ok(Some(Connection {
+        client_id,
+        username,
+        connection_id,
+        protocol_version: connect.protocol_version,
+        session,
+        outbox,
+        close,
+        keep_alive: Duration::from_secs(connect.keep_alive.into()),
+        auth_method,
+        receive_maximum: connect
+            .properties
+            .receive_maximum
+            .filter(|_| v5)
+            .map_or(state.max_inflight, |maximum| maximum.clamp(1, state.max_inflight))
+            .into(),
+    }))
 }
 
-/// 解析包类型
-fn parse_packet_type(first_byte: u8) -> Option<u8> {
-    Some(first_byte >> 4)
+/// 以返回码（5.0中为原因码）拒绝连接
+async fn reject_connect<S: Transport>(framed: &mut ClientFramed<S>, return_code: u8) -> Result<(), MqttError> {
+    framed.send(Packet::Connack(ConnackPacket {
+        session_present: false,
+        return_code,
+        properties: Properties::default(),
+    })).await?;
+    Ok(())
 }
 
-/// 处理CONNECT包
-async fn handle_connect(socket: &mut TcpStream, _data: &[u8]) -> Result<String, Box<dyn Error>> {
-    // 简化的CONNECT处理，实际实现应该解析完整的CONNECT包
-    // 这里我们只发送一个CONNACK响应
-    
-    // CONNACK包: 固定头部(2字节) + 剩余长度(1字节) + 连接确认标志(1字节) + 返回码(1字节)
-    let connack = [
-        0x20, // CONNACK包类型
-        0x02, // 剩余长度
-        0x00, // 连接确认标志
-        0x00, // 返回码: 0表示连接接受
-    ];
-    
-    socket.write_all(&connack).await?;
-    
-    // 返回一个简单的客户端ID
-    Ok("client-".to_string() + &rand::random::<u32>().to_string())
+/// CONNECT阶段的增强认证（MQTT 5.0）：与客户端交换AUTH包，直到认证器给出结果
+///
+/// 成功时返回随CONNACK发送的认证数据，失败时返回CONNACK原因码。
+async fn enhanced_auth<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    state: &BrokerState,
+    client_id: &str,
+    method: &str,
+    mut data: Option<bytes::Bytes>,
+) -> Result<Result<Option<bytes::Bytes>, u8>, MqttError> {
+    loop {
+        match state.authenticator.authenticate_enhanced(client_id, method, data.as_deref()).await {
+            EnhancedAuth::Success(data) => return Ok(Ok(data)),
+            EnhancedAuth::Failure(reason) => return Ok(Err(reason)),
+            EnhancedAuth::Continue(challenge) => {
+                framed.send(auth_packet(reason_code::CONTINUE_AUTHENTICATION, method, Some(challenge))).await?;
+            }
+        }
+        let Ok(response) = tokio::time::timeout(HANDSHAKE_TIMEOUT, framed.next()).await else {
+            return Err(MqttError::Timeout("AUTH"));
+        };
+        data = match response {
+            Some(Ok(Packet::Auth(auth)))
+                if auth.reason_code == reason_code::CONTINUE_AUTHENTICATION
+                    && auth.properties.authentication_method.as_deref() == Some(method) =>
+            {
+                auth.properties.authentication_data
+            }
+            Some(Ok(other)) => {
+                log::error!("Expected AUTH from {}, got {:?}", client_id, other.packet_type());
+                return Ok(Err(reason_code::PROTOCOL_ERROR));
+            }
+            Some(Err(e)) => return Err(MqttError::decode(e)),
+            None => return Err(MqttError::ConnectionLost),
+        };
+    }
 }
 
-/// 解析PUBLISH包
-fn parse_publish(data: &[u8]) -> Option<(String, String)> {
-    if data.len() < 5 {
-        return None;
-    }
+/// 连接结束时处理会话：会话归还状态后按过期间隔保留，过期间隔为0的会话连同订阅一起删除
+async fn close_session(state: &Arc<BrokerState>, connection: Connection) {
+    let Connection { client_id, connection_id, mut session, mut outbox, .. } = connection;
+    let mut subs = state.subscriptions.lock().await;
+    let mut sessions = state.sessions.lock().await;
     
-    // 解析剩余长度（简化处理，假设长度<128）
-    let remaining_length = data[1] as usize;
-    if data.len() < 2 + remaining_length {
-        return None;
+    // 已路由但尚未发送的QoS 1/2消息放回会话队列；持有sessions锁时不会再有新消息路由进来
+    outbox.close();
+    while let Ok(message) = outbox.try_recv() {
+        if message.publish.qos != QoS::AtMostOnce {
+            session.queued.push_back(message);
+        }
     }
     
-    // 解析主题长度
-    let topic_len = ((data[2] as u16) << 8 | (data[3] as u16)) as usize;
-    
-    // 检查缓冲区长度
-    if data.len() < 4 + topic_len {
-        return None;
+    let Some(client_session) = sessions.get_mut(&client_id) else {
+        return;
+    };
+    // 会话已被新连接接管
+    let was_persistent = client_session.expiry_interval != 0;
+    if !client_session.detach(connection_id, session) {
+        return;
     }
-    
-    // 解析主题
-    let topic = String::from_utf8_lossy(&data[4..4 + topic_len]).to_string();
-    
-    // 解析消息内容
-    let message_start = 4 + topic_len;
-    if message_start >= data.len() {
-        return None;
+    if client_session.expiry_interval == 0 {
+        if let Some(removed) = sessions.remove(&client_id) {
+            remove_subscriptions(&mut subs, &client_id, &removed);
+        }
+        // 客户端在DISCONNECT中把过期间隔改为了0
+        if was_persistent {
+            state.persist(Record::RemoveSession { client_id });
+        }
+        return;
     }
     
-    let message = String::from_utf8_lossy(&data[message_start..]).to_string();
-    
-    Some((topic, message))
+    // 保存离线状态：客户端尚未收到的在途消息排在离线队列之前，恢复后作为新消息重新投递
+    let messages = client_session
+        .inflight
+        .unreceived()
+        .map(|publish| StoredMessage::from_message(&Message::new(publish.clone())))
+        .chain(client_session.queued.iter().map(StoredMessage::from_message))
+        .collect();
+    state.persist(Record::SetSession {
+        client_id: client_id.clone(),
+        expiry_interval: client_session.expiry_interval,
+        expires_at: client_session.expires_at.map(storage::to_timestamp),
+    });
+    state.persist(Record::SetMessages { client_id: client_id.clone(), messages });
+    if let Some(expires_at) = client_session.expires_at {
+        tokio::spawn(expire_session(state.clone(), client_id, expires_at));
+    }
 }
 
-/// 解析SUBSCRIBE包
-fn parse_subscribe(data: &[u8]) -> Option<String> {
-    if data.len() < 6 {
-        return None;
+/// 会话过期时删除仍然离线的会话；期间重新连接过的会话不受影响
+async fn expire_session(state: Arc<BrokerState>, client_id: String, expires_at: tokio::time::Instant) {
+    tokio::time::sleep_until(expires_at).await;
+    let mut subs = state.subscriptions.lock().await;
+    let mut sessions = state.sessions.lock().await;
+    if sessions.get(&client_id).is_some_and(Session::is_expired) {
+        log::info!("Session of client {} expired", client_id);
+        if let Some(removed) = sessions.remove(&client_id) {
+            remove_subscriptions(&mut subs, &client_id, &removed);
+            state.persist(Record::RemoveSession { client_id });
+        }
     }
-    
-    // 解析剩余长度（简化处理，假设长度<128）
-    let remaining_length = data[1] as usize;
-    if data.len() < 2 + remaining_length {
-        return None;
+}
+
+/// 从订阅表中删除会话的全部订阅
+fn remove_subscriptions(subs: &mut SubscriptionTable, client_id: &str, session: &Session) {
+    for filter in session.subscriptions.keys() {
+        subs.unsubscribe(client_id, filter);
     }
-    
-    // 解析主题长度（跳过包标识符2字节）
-    let topic_len = ((data[4] as u16) << 8 | (data[5] as u16)) as usize;
-    
-    // 检查缓冲区长度
-    if data.len() < 6 + topic_len {
-        return None;
+}
+
+/// 处理客户端发布的消息：更新保留消息，并路由给订阅了该主题的会话
+async fn publish_message(publish: PublishPacket, publisher: Option<&str>, state: &BrokerState) {
+    let message = Message::new(publish);
+    let publish = &message.publish;
+    if publish.retain {
+        // $SYS主题由代理定期重新发布，不需要持久化
+        let durable = !publish.topic.starts_with(SYS_PREFIX);
+        let mut store = state.retained.lock().await;
+        if publish.payload.is_empty() {
+            // 零长度的保留消息用于清除该主题的保留消息
+            log::info!("Clearing retained message on topic '{}'", publish.topic);
+            if store.remove(&publish.topic).is_some() && durable {
+                state.persist(Record::RemoveRetained { topic: publish.topic.clone() });
+            }
+        } else {
+            store.insert(publish.topic.clone(), message.clone());
+            if durable {
+                state.persist(Record::SetRetained {
+                    topic: publish.topic.clone(),
+                    message: StoredMessage::from_message(&message),
+                });
+            }
+        }
     }
     
-    // 解析主题
-    let topic = String::from_utf8_lossy(&data[6..6 + topic_len]).to_string();
-    
-    Some(topic)
+    route_message(&message, publisher, state).await;
 }
 
-/// 发送PUBLISH包给客户端（简化版）
-async fn send_publish(socket: &mut TcpStream, topic: &str, message: &str) -> Result<(), Box<dyn Error>> {
-    // 构造一个简单的PUBLISH响应包
-    let topic_bytes = topic.as_bytes();
-    let message_bytes = message.as_bytes();
-    
-    // 固定头部 + 剩余长度 + 主题长度 + 主题 + 消息
-    let mut response = Vec::new();
-    response.push(0x30); // PUBLISH包类型 (PUBLISH)
-    
-    // 计算剩余长度（简化计算）
-    let remaining_length = 2 + topic_bytes.len() + message_bytes.len();
-    response.push(remaining_length as u8);
-    
-    // 主题长度（网络字节序）
-    response.push((topic_bytes.len() >> 8) as u8);
-    response.push(topic_bytes.len() as u8);
-    
-    // 主题
-    response.extend_from_slice(topic_bytes);
-    
-    // 消息
-    response.extend_from_slice(message_bytes);
+/// 将消息投递给所有匹配的会话
+///
+/// 在线会话的消息进入其连接的队列，离线的持久会话缓存QoS 1/2消息。
+/// 同一客户端有多个重叠订阅时只投递一次，使用其中最大的授予QoS。
+/// 每个匹配的共享组另外选出一个成员投递一份，与该成员的普通订阅互不影响。
+/// 消息属性（用户属性、响应主题、关联数据等）原样转发给5.0订阅者。
+/// `publisher`为发布该消息的客户端，代理自己发布的消息（遗嘱、`$SYS`、管理接口）为None。
+async fn route_message(message: &Message, publisher: Option<&str>, state: &BrokerState) {
+    let publish = &message.publish;
+    // 队列已满且策略为Block的消息，释放锁之后再等待发送
+    let mut blocked = Vec::new();
+    {
+        let subs = state.subscriptions.lock().await;
+        let mut granted: HashMap<&str, QoS> = HashMap::new();
+        for clients in subs.clients.matches(&publish.topic) {
+            for (client_id, qos) in clients {
+                let entry = granted.entry(client_id.as_str()).or_insert(*qos);
+                *entry = (*entry).max(*qos);
+            }
+        }
+        let groups = subs.shared.matches(&publish.topic);
+        if granted.is_empty() && groups.is_empty() {
+            return;
+        }
+        
+        let mut sessions = state.sessions.lock().await;
+        let mut recipients: Vec<(&str, QoS)> = granted.into_iter().collect();
+        for group in groups.into_iter().flat_map(HashMap::values) {
+            let inflight = |client_id: &str| sessions.get(client_id).and_then(Session::inflight);
+            recipients.extend(group.pick(state.share_strategy, inflight));
+        }
+        for (client_id, granted) in recipients {
+            let Some(session) = sessions.get_mut(client_id) else {
+                continue;
+            };
+            let outgoing = Message {
+                publish: PublishPacket {
+                    dup: false,
+                    qos: publish.qos.min(granted),
+                    retain: false,
+                    topic: publish.topic.clone(),
+                    packet_id: None,
+                    properties: publish.properties.clone(),
+                    payload: publish.payload.clone(),
+                },
+                expires_at: message.expires_at,
+            };
+            
+            let Some(outbox) = session.outbox() else {
+                // QoS 0消息不为离线客户端缓存
+                if outgoing.publish.qos != QoS::AtMostOnce {
+                    log::debug!("Queueing message on topic '{}' for offline client {}", publish.topic, client_id);
+                    state.persist(Record::PushMessage {
+                        client_id: client_id.to_string(),
+                        message: StoredMessage::from_message(&outgoing),
+                    });
+                    session.enqueue(outgoing);
+                }
+                continue;
+            };
+            match outbox.try_send(outgoing) {
+                Ok(()) => {}
+                // 连接正在关闭，剩余消息由close_session放回会话
+                Err(TrySendError::Closed(_)) => {}
+                Err(TrySendError::Full(outgoing)) => match state.backpressure {
+                    Backpressure::DropNewest => {
+                        log::warn!("Queue of client {} is full, dropping message on topic '{}'", client_id, publish.topic);
+                        state.stats.message_dropped(DropReason::QueueFull);
+                    }
+                    Backpressure::Disconnect => {
+                        log::warn!("Queue of client {} is full, disconnecting slow client", client_id);
+                        session.close_connection(reason_code::QUOTA_EXCEEDED);
+                        // 持久会话断开后会保留这条消息，清除会话在断开时整体丢弃
+                        if outgoing.publish.qos != QoS::AtMostOnce {
+                            session.enqueue(outgoing);
+                        } else {
+                            state.stats.message_dropped(DropReason::QueueFull);
+                        }
+                    }
+                    // 发布方的连接任务正在处理这条发布，等待它自己的队列永远不会有结果
+                    Backpressure::Block if publisher == Some(client_id) => {
+                        log::warn!("Queue of client {} is full, dropping its own message on topic '{}'", client_id, publish.topic);
+                        state.stats.message_dropped(DropReason::QueueFull);
+                    }
+                    Backpressure::Block => blocked.push((outbox.clone(), outgoing)),
+                },
+            }
+        }
+    }
     
-    socket.write_all(&response).await?;
+    // 所有已满的队列共用一个截止时间，慢速订阅者再多，发布方也最多等待BLOCK_TIMEOUT
+    let deadline = tokio::time::Instant::now() + BLOCK_TIMEOUT;
+    for (outbox, outgoing) in blocked {
+        if tokio::time::timeout_at(deadline, outbox.send(outgoing)).await.is_err() {
+            log::warn!("Queue of subscriber still full after {:?}, dropping message", BLOCK_TIMEOUT);
+            state.stats.message_dropped(DropReason::QueueFull);
+        }
+    }
+}
+
+/// 发布客户端的遗嘱消息
+async fn publish_will(will: LastWill, state: &BrokerState) {
+    // 遗嘱属性随消息转发，遗嘱延迟间隔只在CONNECT中有意义
+    let mut properties = will.properties;
+    properties.will_delay_interval = None;
+    let publish = PublishPacket {
+        dup: false,
+        qos: will.qos,
+        retain: will.retain,
+        topic: will.topic,
+        packet_id: None,
+        properties,
+        payload: will.message,
+    };
+    publish_message(publish, None, state).await;
+}
+
+/// 查找与订阅过滤器匹配的保留消息
+///
+/// 同一条保留消息被多个过滤器匹配时只发送一次，使用其中最大的授予QoS。
+async fn matching_retained(retained: &RetainedMessages, filters: &[(String, QoS)]) -> Vec<(Message, QoS)> {
+    let mut store = retained.lock().await;
+    // 顺便清理已过期的保留消息
+    store.retain(|_, message| !message.is_expired());
+    let mut matched: HashMap<&str, (&Message, QoS)> = HashMap::new();
+    for (filter, granted) in filters {
+        for message in store.values().filter(|message| topic::matches(filter, &message.publish.topic)) {
+            let entry = matched.entry(&message.publish.topic).or_insert((message, *granted));
+            entry.1 = entry.1.max(*granted);
+        }
+    }
+    matched.into_values().map(|(message, granted)| (message.clone(), granted)).collect()
+}
+
+/// 按顺序发送排队的消息，直到在途窗口达到接收上限
+///
+/// 排队的消息QoS已按订阅降级，只有因新订阅而发送的保留消息带有RETAIN标志。
+async fn send_queued<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    session: &mut SessionState,
+    receive_maximum: usize,
+    stats: &BrokerStats,
+) -> Result<(), MqttError> {
+    while session.inflight.len() < receive_maximum {
+        let Some(message) = session.queued.pop_front() else { break };
+        // 排队期间已过期的消息不再投递
+        let Some(publish) = message.into_publish() else {
+            stats.message_dropped(DropReason::Expired);
+            continue;
+        };
+        let (qos, retain) = (publish.qos, publish.retain);
+        send_publish(framed, &mut session.inflight, &publish, qos, retain, stats).await?;
+    }
     Ok(())
 }
 
-/// 发送SUBACK包给客户端
-async fn send_suback(socket: &mut TcpStream, subscribe_packet: &[u8]) -> Result<(), Box<dyn Error>> {
-    // SUBACK包: 固定头部(1字节) + 剩余长度(1字节) + 包标识符(2字节) + 返回码(1字节)
-    let mut response = Vec::new();
-    
-    // 固定头部 - SUBACK包类型
-    response.push(0x90); // SUBACK包类型
-    
-    // 剩余长度 - 包标识符(2) + 返回码(1) = 3
-    response.push(0x03);
-    
-    // 包标识符 - 从SUBSCRIBE包中复制
-    if subscribe_packet.len() >= 4 {
-        response.push(subscribe_packet[2]); // 包标识符高字节
-        response.push(subscribe_packet[3]); // 包标识符低字节
-    } else {
-        response.push(0x00); // 默认包标识符高字节
-        response.push(0x01); // 默认包标识符低字节
+/// 发送PUBLISH包给客户端
+///
+/// 实际QoS取发布QoS与订阅授予QoS中的较小者，QoS > 0的消息进入在途窗口等待确认。
+/// 只有因新订阅而发送的保留消息才设置RETAIN标志。
+async fn send_publish<S: Transport>(
+    framed: &mut ClientFramed<S>,
+    inflight: &mut InflightWindow,
+    publish: &PublishPacket,
+    granted: QoS,
+    retain: bool,
+    stats: &BrokerStats,
+) -> Result<(), MqttError> {
+    let mut outgoing = PublishPacket {
+        dup: false,
+        qos: publish.qos.min(granted),
+        retain,
+        topic: publish.topic.clone(),
+        packet_id: None,
+        properties: publish.properties.clone(),
+        payload: publish.payload.clone(),
+    };
+    if outgoing.qos != QoS::AtMostOnce {
+        // 调用方按接收上限（不超过65535）控制在途消息数，总有空闲的包标识符
+        let Some(started) = inflight.start(outgoing) else {
+            log::warn!("No free packet identifier, dropping message on topic '{}'", publish.topic);
+            stats.message_dropped(DropReason::QueueFull);
+            return Ok(());
+        };
+        outgoing = started;
     }
     
-    // 返回码 - 0x00表示QoS 0成功
-    response.push(0x00);
+    let qos = outgoing.qos;
+    framed.send(Packet::Publish(outgoing)).await?;
+    stats.message_sent(qos);
+    Ok(())
+}
+
+/// 按`sys_interval`定期把运行统计发布到`$SYS`主题，作为保留消息供新订阅者立即读取
+async fn publish_sys_topics(state: Arc<BrokerState>) {
+    let mut interval = tokio::time::interval(state.sys_interval);
+    loop {
+        interval.tick().await;
+        for (topic, value) in state.stats.sys_topics(state.gauges().await) {
+            let publish = PublishPacket {
+                dup: false,
+                qos: QoS::AtMostOnce,
+                retain: true,
+                topic: topic.to_string(),
+                packet_id: None,
+                properties: Properties::default(),
+                payload: value.into(),
+            };
+            publish_message(publish, None, &state).await;
+        }
+    }
+}
+
+/// 发送SUBACK包给客户端
+///
+/// 每个主题过滤器一个返回码：授予的QoS，或0x80（5.0中为具体的原因码）表示订阅失败。
+async fn send_suback<S: Transport>(framed: &mut ClientFramed<S>, packet_id: u16, return_codes: Vec<u8>) -> Result<(), MqttError> {
+    let suback = Packet::Suback(SubackPacket {
+        packet_id,
+        return_codes,
+        properties: Properties::default(),
+    });
     
-    socket.write_all(&response).await?;
+    framed.send(suback).await?;
     Ok(())
 }
 
 /// 启动MQTT代理
-pub async fn run_broker(addr: &str) -> Result<(), Box<dyn Error>> {
+pub async fn run_broker(addr: &str) -> Result<(), MqttError> {
     let mut broker = MqttBroker::new(addr).await?;
     broker.run().await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::SubscribePacket;
+    use crate::storage::FileStorage;
+    use tokio::net::TcpStream;
+
+    // 测试通过TCP连接代理
+    type ClientFramed = super::ClientFramed<TcpStream>;
+
+    /// 在随机端口上启动代理，返回监听地址
+    async fn start_broker() -> String {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+        addr
+    }
+
+    /// 建立原始MQTT连接并完成CONNECT握手
+    async fn raw_connect(addr: &str, client_id: &str) -> ClientFramed {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        framed.send(Packet::Connect(ConnectPacket::new(client_id.to_string()))).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => assert_eq!(connack.return_code, 0),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+        framed
+    }
+
+    async fn subscribe(framed: &mut ClientFramed, topic: &str, qos: QoS) -> Vec<u8> {
+        framed.send(Packet::Subscribe(SubscribePacket {
+            packet_id: 1,
+            topics: vec![(topic.to_string(), qos)],
+            properties: Properties::default(),
+        })).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Suback(suback))) => suback.return_codes,
+            other => panic!("expected SUBACK, got {:?}", other),
+        }
+    }
+
+    fn publish(topic: &str, qos: QoS, packet_id: Option<u16>) -> Packet {
+        Packet::Publish(PublishPacket {
+            dup: false,
+            qos,
+            retain: false,
+            topic: topic.to_string(),
+            packet_id,
+            properties: Properties::default(),
+            payload: bytes::Bytes::from_static(b"hello"),
+        })
+    }
+
+    fn retained_publish(topic: &str, payload: &'static [u8]) -> Packet {
+        Packet::Publish(PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            topic: topic.to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: bytes::Bytes::from_static(payload),
+        })
+    }
+
+    async fn next_publish(framed: &mut ClientFramed) -> PublishPacket {
+        match framed.next().await {
+            Some(Ok(Packet::Publish(p))) => p,
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_qos1_publish_is_acked_and_delivered() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        assert_eq!(subscribe(&mut subscriber, "test/qos1", QoS::AtLeastOnce).await, vec![0x01]);
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("test/qos1", QoS::AtLeastOnce, Some(7))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(7)));
+
+        let delivered = match subscriber.next().await {
+            Some(Ok(Packet::Publish(p))) => p,
+            other => panic!("expected PUBLISH, got {:?}", other),
+        };
+        assert_eq!(delivered.qos, QoS::AtLeastOnce);
+        subscriber.send(Packet::Puback(AckPacket::new(delivered.packet_id.unwrap()))).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_qos2_exactly_once_flow() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        assert_eq!(subscribe(&mut subscriber, "test/qos2", QoS::ExactlyOnce).await, vec![0x02]);
+
+        // 发布方重复发送同一个QoS 2消息，订阅方只应收到一次
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("test/qos2", QoS::ExactlyOnce, Some(9))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Pubrec(AckPacket::new(9)));
+        publisher.send(publish("test/qos2", QoS::ExactlyOnce, Some(9))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Pubrec(AckPacket::new(9)));
+        publisher.send(Packet::Pubrel(AckPacket::new(9))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Pubcomp(AckPacket::new(9)));
+
+        let delivered = match subscriber.next().await {
+            Some(Ok(Packet::Publish(p))) => p,
+            other => panic!("expected PUBLISH, got {:?}", other),
+        };
+        assert_eq!(delivered.qos, QoS::ExactlyOnce);
+        let packet_id = delivered.packet_id.unwrap();
+        subscriber.send(Packet::Pubrec(AckPacket::new(packet_id))).await.unwrap();
+        assert_eq!(subscriber.next().await.unwrap().unwrap(), Packet::Pubrel(AckPacket::new(packet_id)));
+        subscriber.send(Packet::Pubcomp(AckPacket::new(packet_id))).await.unwrap();
+
+        // 重复的PUBLISH没有被再次转发
+        publisher.send(publish("test/qos2", QoS::AtMostOnce, None)).await.unwrap();
+        match subscriber.next().await {
+            Some(Ok(Packet::Publish(p))) => assert_eq!(p.qos, QoS::AtMostOnce),
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_qos_is_downgraded_to_granted_qos() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "test/downgrade", QoS::AtMostOnce).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("test/downgrade", QoS::AtLeastOnce, Some(1))).await.unwrap();
+
+        match subscriber.next().await {
+            Some(Ok(Packet::Publish(p))) => {
+                assert_eq!(p.qos, QoS::AtMostOnce);
+                assert_eq!(p.packet_id, None);
+            }
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_subscriptions_route_messages() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "sensors/+/temp", QoS::AtMostOnce).await;
+        subscribe(&mut subscriber, "home/#", QoS::AtMostOnce).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("sensors/kitchen/humidity", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("sensors/kitchen/temp", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("home/livingroom/light", QoS::AtMostOnce, None)).await.unwrap();
+
+        // 不匹配的主题被跳过，匹配的两条按发布顺序到达
+        for expected in ["sensors/kitchen/temp", "home/livingroom/light"] {
+            match subscriber.next().await {
+                Some(Ok(Packet::Publish(p))) => assert_eq!(p.topic, expected),
+                other => panic!("expected PUBLISH, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retained_message_replayed_to_late_wildcard_subscriber() {
+        let addr = start_broker().await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(retained_publish("sensors/kitchen/temp", b"21.5")).await.unwrap();
+        publisher.send(retained_publish("sensors/garage/temp", b"15.0")).await.unwrap();
+        // 用一次订阅往返确保保留消息已经被代理处理
+        subscribe(&mut publisher, "sync", QoS::AtMostOnce).await;
+
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "sensors/kitchen/+", QoS::AtLeastOnce).await;
+        let retained = next_publish(&mut subscriber).await;
+        assert_eq!(retained.topic, "sensors/kitchen/temp");
+        assert_eq!(&retained.payload[..], b"21.5");
+        assert!(retained.retain);
+        // 保留消息以原始QoS 0投递
+        assert_eq!(retained.qos, QoS::AtMostOnce);
+
+        // 正常转发给已有订阅者的消息不带RETAIN标志
+        publisher.send(retained_publish("sensors/kitchen/temp", b"22.0")).await.unwrap();
+        let live = next_publish(&mut subscriber).await;
+        assert_eq!(&live.payload[..], b"22.0");
+        assert!(!live.retain);
+    }
+
+    #[tokio::test]
+    async fn test_binary_payload_delivered_unchanged_between_clients() {
+        let addr = start_broker().await;
+        let mut subscriber = crate::client::MqttClient::new("binary-sub".to_string());
+        subscriber.connect(&addr).await.unwrap();
+        let mut messages = subscriber.messages();
+        subscriber.subscribe("sensors/raw".to_string(), QoS::AtLeastOnce).await.unwrap();
+
+        // 非UTF-8的负载（例如CBOR编码的传感器数据）逐字节保留
+        let payload = bytes::Bytes::from_static(&[0xA1, 0x61, 0x74, 0xF9, 0x4D, 0x60, 0x00, 0xFF]);
+        let mut publisher = crate::client::MqttClient::new("binary-pub".to_string());
+        publisher.connect(&addr).await.unwrap();
+        publisher.publish("sensors/raw".to_string(), payload.clone(), QoS::AtLeastOnce).await.unwrap();
+
+        let message = messages.next().await.unwrap();
+        assert_eq!(message.payload, payload);
+        assert!(message.payload_str().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_empty_retained_payload_clears_retained_message() {
+        let addr = start_broker().await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(retained_publish("status/device1", b"online")).await.unwrap();
+        publisher.send(retained_publish("status/device1", b"")).await.unwrap();
+        subscribe(&mut publisher, "sync", QoS::AtMostOnce).await;
+
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "status/#", QoS::AtMostOnce).await;
+
+        // 没有保留消息，第一条收到的是之后的实时消息
+        publisher.send(publish("status/device2", QoS::AtMostOnce, None)).await.unwrap();
+        let live = next_publish(&mut subscriber).await;
+        assert_eq!(live.topic, "status/device2");
+        assert!(!live.retain);
+    }
+
+    /// 建立携带遗嘱消息的原始MQTT连接
+    async fn connect_with_will(addr: &str, client_id: &str, will_topic: &str) -> ClientFramed {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new(client_id.to_string());
+        connect.will = Some(LastWill {
+            topic: will_topic.to_string(),
+            message: bytes::Bytes::from_static(b"offline"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Properties::default(),
+        });
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Packet::Connack(_)))));
+        framed
+    }
+
+    #[tokio::test]
+    async fn test_will_published_on_unexpected_disconnect() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "status/#", QoS::AtMostOnce).await;
+
+        // 直接关闭连接，不发送DISCONNECT
+        let device = connect_with_will(&addr, "device", "status/device").await;
+        drop(device);
+
+        let will = next_publish(&mut subscriber).await;
+        assert_eq!(will.topic, "status/device");
+        assert_eq!(&will.payload[..], b"offline");
+    }
+
+    #[tokio::test]
+    async fn test_will_discarded_on_disconnect() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "status/#", QoS::AtMostOnce).await;
+
+        let mut device = connect_with_will(&addr, "device", "status/device").await;
+        device.send(Packet::Disconnect(DisconnectPacket::default())).await.unwrap();
+        // 等待代理关闭连接，确认DISCONNECT已被处理
+        assert!(device.next().await.is_none());
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("status/other", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut subscriber).await.topic, "status/other");
+    }
+
+    /// 建立clean_session = false的原始MQTT连接，返回CONNACK中的session_present
+    async fn persistent_connect(addr: &str, client_id: &str) -> (ClientFramed, bool) {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new(client_id.to_string());
+        connect.connect_flags &= !crate::protocol::CLEAN_SESSION;
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => {
+                assert_eq!(connack.return_code, 0);
+                (framed, connack.session_present)
+            }
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+    }
+
+    /// 发送DISCONNECT并等待代理关闭连接
+    async fn disconnect(mut framed: ClientFramed) {
+        framed.send(Packet::Disconnect(DisconnectPacket::default())).await.unwrap();
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_persistent_session_queues_messages_while_offline() {
+        let addr = start_broker().await;
+        let (mut device, session_present) = persistent_connect(&addr, "device").await;
+        assert!(!session_present);
+        subscribe(&mut device, "cmd/device", QoS::AtLeastOnce).await;
+        disconnect(device).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("cmd/device", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(1)));
+        // QoS 0消息不为离线会话缓存
+        publisher.send(publish("cmd/device", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("cmd/device", QoS::ExactlyOnce, Some(2))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Pubrec(AckPacket::new(2)));
+
+        // 重连后恢复会话，收到离线期间的消息（QoS 2按授予的QoS 1投递）
+        let (mut device, session_present) = persistent_connect(&addr, "device").await;
+        assert!(session_present);
+        for _ in 0..2 {
+            let queued = next_publish(&mut device).await;
+            assert_eq!(queued.topic, "cmd/device");
+            assert_eq!(queued.qos, QoS::AtLeastOnce);
+            device.send(Packet::Puback(AckPacket::new(queued.packet_id.unwrap()))).await.unwrap();
+        }
+
+        // 订阅在会话中保留，无需重新订阅即可收到实时消息
+        publisher.send(publish("cmd/device", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut device).await.qos, QoS::AtMostOnce);
+    }
+
+    /// 使用文件存储启动代理，返回监听地址和可以中止代理的任务句柄；不发布$SYS主题，以便停止后所有任务都能退出
+    async fn start_broker_with_storage(storage: Arc<FileStorage>) -> (String, tokio::task::JoinHandle<()>) {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_sys_interval(Duration::ZERO);
+        broker.set_storage(storage).unwrap();
+        let addr = broker.local_addr().unwrap().to_string();
+        (addr, tokio::spawn(async move { broker.run().await.unwrap() }))
+    }
+
+    /// 中止代理并等待所有连接任务释放存储，丢弃存储时后台线程写完剩余的记录
+    async fn stop_broker(broker: tokio::task::JoinHandle<()>, mut storage: Arc<FileStorage>) {
+        broker.abort();
+        assert!(broker.await.unwrap_err().is_cancelled());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+        loop {
+            match Arc::try_unwrap(storage) {
+                Ok(storage) => return drop(storage),
+                Err(shared) => storage = shared,
+            }
+            assert!(tokio::time::Instant::now() < deadline, "broker tasks still hold the storage");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_file_storage_restores_state_after_restart() {
+        let log = crate::storage::tests::TempLog::new("broker");
+        let storage = Arc::new(FileStorage::open(log.path()).unwrap());
+        let (addr, broker) = start_broker_with_storage(storage.clone()).await;
+        let (mut device, _) = persistent_connect(&addr, "device").await;
+        subscribe(&mut device, "cmd/#", QoS::AtLeastOnce).await;
+        subscribe(&mut device, "tmp", QoS::AtLeastOnce).await;
+        device.send(Packet::Unsubscribe(crate::packet::UnsubscribePacket {
+            packet_id: 9,
+            topics: vec!["tmp".to_string()],
+            properties: Properties::default(),
+        })).await.unwrap();
+        assert!(matches!(device.next().await, Some(Ok(Packet::Unsuback(_)))));
+        disconnect(device).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(retained_publish("status/hub", b"online")).await.unwrap();
+        publisher.send(publish("cmd/reboot", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(1)));
+
+        // 停止代理后用新的代理实例重新打开同一个日志文件，相当于进程重启
+        drop(publisher);
+        stop_broker(broker, storage).await;
+        let storage = Arc::new(FileStorage::open(log.path()).unwrap());
+        let (addr, _broker) = start_broker_with_storage(storage).await;
+        let (mut device, session_present) = persistent_connect(&addr, "device").await;
+        assert!(session_present);
+        let queued = next_publish(&mut device).await;
+        assert_eq!(queued.topic, "cmd/reboot");
+        assert_eq!(queued.qos, QoS::AtLeastOnce);
+        device.send(Packet::Puback(AckPacket::new(queued.packet_id.unwrap()))).await.unwrap();
+
+        // 订阅已恢复，取消过的订阅不再生效
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("tmp", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("cmd/live", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut device).await.topic, "cmd/live");
+
+        let mut monitor = raw_connect(&addr, "monitor").await;
+        subscribe(&mut monitor, "status/#", QoS::AtMostOnce).await;
+        let retained = next_publish(&mut monitor).await;
+        assert_eq!(retained.topic, "status/hub");
+        assert_eq!(&retained.payload[..], b"online");
+        assert!(retained.retain);
+    }
+
+    #[tokio::test]
+    async fn test_unacked_message_redelivered_with_dup_after_reconnect() {
+        let addr = start_broker().await;
+        let (mut device, _) = persistent_connect(&addr, "device").await;
+        subscribe(&mut device, "cmd/device", QoS::AtLeastOnce).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("cmd/device", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        let first = next_publish(&mut device).await;
+        // 不确认就断开
+        drop(device);
+
+        let (mut device, session_present) = persistent_connect(&addr, "device").await;
+        assert!(session_present);
+        let redelivered = next_publish(&mut device).await;
+        assert!(redelivered.dup);
+        assert_eq!(redelivered.packet_id, first.packet_id);
+    }
+
+    #[tokio::test]
+    async fn test_clean_session_discards_previous_session() {
+        let addr = start_broker().await;
+        let (mut device, _) = persistent_connect(&addr, "device").await;
+        subscribe(&mut device, "cmd/device", QoS::AtLeastOnce).await;
+        disconnect(device).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("cmd/device", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(1)));
+
+        // 以clean_session = true连接，之前的订阅和缓存消息都被丢弃
+        let mut device = raw_connect(&addr, "device").await;
+        subscribe(&mut device, "other", QoS::AtMostOnce).await;
+        publisher.send(publish("cmd/device", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("other", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut device).await.topic, "other");
+        disconnect(device).await;
+
+        let (_device, session_present) = persistent_connect(&addr, "device").await;
+        assert!(!session_present);
+    }
+
+    #[tokio::test]
+    async fn test_empty_client_id_rejected_for_persistent_session() {
+        let addr = start_broker().await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new(String::new());
+        connect.connect_flags &= !crate::protocol::CLEAN_SESSION;
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => assert_eq!(connack.return_code, 0x02),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_new_connection_takes_over_session() {
+        let addr = start_broker().await;
+        let (mut old, _) = persistent_connect(&addr, "device").await;
+        subscribe(&mut old, "cmd/device", QoS::AtLeastOnce).await;
+
+        // 同一客户端标识符的新连接接管会话，旧连接被关闭
+        let (mut new, session_present) = persistent_connect(&addr, "device").await;
+        assert!(session_present);
+        assert!(old.next().await.is_none());
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("cmd/device", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut new).await.topic, "cmd/device");
+    }
+
+    /// 建立指定保活时间的原始MQTT连接，携带遗嘱消息
+    async fn connect_with_keep_alive(addr: &str, client_id: &str, keep_alive: u16) -> ClientFramed {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new(client_id.to_string());
+        connect.keep_alive = keep_alive;
+        connect.will = Some(LastWill {
+            topic: format!("status/{}", client_id),
+            message: bytes::Bytes::from_static(b"offline"),
+            qos: QoS::AtMostOnce,
+            retain: false,
+            properties: Properties::default(),
+        });
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Packet::Connack(_)))));
+        framed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_idle_connection_closed_after_keep_alive_timeout() {
+        let addr = start_broker().await;
+        let mut subscriber = connect_with_keep_alive(&addr, "sub", 0).await;
+        subscribe(&mut subscriber, "status/#", QoS::AtMostOnce).await;
+
+        let start = tokio::time::Instant::now();
+        let mut device = connect_with_keep_alive(&addr, "device", 10).await;
+        assert!(device.next().await.is_none());
+        // 1.5倍保活时间之前不会关闭
+        assert!(start.elapsed() >= Duration::from_secs(15));
+
+        // 保活超时属于异常断开，发布遗嘱消息
+        let will = next_publish(&mut subscriber).await;
+        assert_eq!(will.topic, "status/device");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_connection_without_connect_closed_after_handshake_timeout() {
+        let addr = start_broker().await;
+        let start = tokio::time::Instant::now();
+        let mut silent = Framed::new(TcpStream::connect(&addr).await.unwrap(), MqttCodec::new());
+        assert!(silent.next().await.is_none());
+        assert!(start.elapsed() >= HANDSHAKE_TIMEOUT);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pingreq_keeps_connection_alive() {
+        let addr = start_broker().await;
+        let mut device = connect_with_keep_alive(&addr, "device", 10).await;
+
+        for _ in 0..5 {
+            tokio::time::sleep(Duration::from_secs(10)).await;
+            device.send(Packet::Pingreq).await.unwrap();
+            assert_eq!(device.next().await.unwrap().unwrap(), Packet::Pingresp);
+        }
+        // 总时长已远超1.5倍保活时间，连接仍然可用
+        assert_eq!(subscribe(&mut device, "still/alive", QoS::AtMostOnce).await, vec![0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_multi_filter_subscribe_returns_per_filter_codes() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscriber.send(Packet::Subscribe(SubscribePacket {
+            packet_id: 3,
+            topics: vec![
+                ("a/+".to_string(), QoS::AtLeastOnce),
+                ("a/#/b".to_string(), QoS::AtMostOnce),
+                ("b".to_string(), QoS::ExactlyOnce),
+            ],
+            properties: Properties::default(),
+        })).await.unwrap();
+        match subscriber.next().await {
+            Some(Ok(Packet::Suback(suback))) => {
+                assert_eq!(suback.packet_id, 3);
+                assert_eq!(suback.return_codes, vec![0x01, SUBACK_FAILURE, 0x02]);
+            }
+            other => panic!("expected SUBACK, got {:?}", other),
+        }
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("a/x", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("b", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut subscriber).await.topic, "a/x");
+        assert_eq!(next_publish(&mut subscriber).await.topic, "b");
+    }
+
+    #[tokio::test]
+    async fn test_unsubscribe_stops_delivery() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "news/#", QoS::AtMostOnce).await;
+        subscribe(&mut subscriber, "weather", QoS::AtMostOnce).await;
+
+        subscriber.send(Packet::Unsubscribe(crate::packet::UnsubscribePacket {
+            packet_id: 5,
+            topics: vec!["news/#".to_string()],
+            properties: Properties::default(),
+        })).await.unwrap();
+        assert!(matches!(subscriber.next().await, Some(Ok(Packet::Unsuback(unsuback))) if unsuback.packet_id == 5));
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("news/today", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("weather", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut subscriber).await.topic, "weather");
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription_round_robin() {
+        let addr = start_broker().await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(retained_publish("jobs/retained", b"old")).await.unwrap();
+
+        let mut worker_a = raw_connect(&addr, "worker-a").await;
+        let mut worker_b = raw_connect(&addr, "worker-b").await;
+        let mut monitor = raw_connect(&addr, "monitor").await;
+        assert_eq!(subscribe(&mut worker_a, "$share/workers/jobs/#", QoS::AtMostOnce).await, vec![0x00]);
+        assert_eq!(subscribe(&mut worker_b, "$share/workers/jobs/#", QoS::AtMostOnce).await, vec![0x00]);
+        subscribe(&mut worker_b, "done", QoS::AtMostOnce).await;
+        // 普通订阅者不受共享组影响，收到保留消息和全部消息
+        subscribe(&mut monitor, "jobs/#", QoS::AtMostOnce).await;
+        assert_eq!(next_publish(&mut monitor).await.topic, "jobs/retained");
+        assert_eq!(subscribe(&mut monitor, "$share/workers", QoS::AtMostOnce).await, vec![SUBACK_FAILURE]);
+
+        for job in 1..=4 {
+            publisher.send(publish(&format!("jobs/{}", job), QoS::AtMostOnce, None)).await.unwrap();
+        }
+        // 共享订阅不回放保留消息，两个成员轮流接收
+        assert_eq!(next_publish(&mut worker_a).await.topic, "jobs/1");
+        assert_eq!(next_publish(&mut worker_b).await.topic, "jobs/2");
+        assert_eq!(next_publish(&mut worker_a).await.topic, "jobs/3");
+        assert_eq!(next_publish(&mut worker_b).await.topic, "jobs/4");
+        for job in 1..=4 {
+            assert_eq!(next_publish(&mut monitor).await.topic, format!("jobs/{}", job));
+        }
+
+        // 退出共享组后不再分到消息
+        worker_b.send(Packet::Unsubscribe(crate::packet::UnsubscribePacket {
+            packet_id: 9,
+            topics: vec!["$share/workers/jobs/#".to_string()],
+            properties: Properties::default(),
+        })).await.unwrap();
+        assert!(matches!(worker_b.next().await, Some(Ok(Packet::Unsuback(_)))));
+        publisher.send(publish("jobs/5", QoS::AtMostOnce, None)).await.unwrap();
+        publisher.send(publish("done", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut worker_a).await.topic, "jobs/5");
+        assert_eq!(next_publish(&mut worker_b).await.topic, "done");
+    }
+
+    #[tokio::test]
+    async fn test_shared_subscription_least_inflight() {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_share_strategy(ShareStrategy::LeastInflight);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        // worker-a不确认收到的消息，之后的消息都分给空闲的worker-b
+        let mut worker_a = raw_connect(&addr, "worker-a").await;
+        let mut worker_b = raw_connect(&addr, "worker-b").await;
+        subscribe(&mut worker_a, "$share/workers/jobs/+", QoS::AtLeastOnce).await;
+        subscribe(&mut worker_b, "$share/workers/jobs/+", QoS::AtLeastOnce).await;
+
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("jobs/1", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(next_publish(&mut worker_a).await.topic, "jobs/1");
+        for job in 2..=4 {
+            publisher.send(publish(&format!("jobs/{}", job), QoS::AtLeastOnce, Some(job))).await.unwrap();
+            let delivered = next_publish(&mut worker_b).await;
+            assert_eq!(delivered.topic, format!("jobs/{}", job));
+            worker_b.send(Packet::Puback(AckPacket::new(delivered.packet_id.unwrap()))).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sys_topics_published_periodically() {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_sys_interval(Duration::from_millis(50));
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        let mut monitor = raw_connect(&addr, "monitor").await;
+        subscribe(&mut monitor, "$SYS/broker/#", QoS::AtMostOnce).await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("data", QoS::AtMostOnce, None)).await.unwrap();
+        // 客户端不能伪造系统主题
+        let (mut intruder, _) = connect_v5(&addr, "intruder", |_| {}).await;
+        intruder.send(publish("$SYS/broker/uptime", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(intruder.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::with_reason(1, reason_code::NOT_AUTHORIZED)));
+
+        // 统计值在下一次发布时反映上面的活动
+        let mut values: HashMap<String, String> = HashMap::new();
+        let expected = [
+            ("$SYS/broker/clients/connected", "3"),
+            ("$SYS/broker/messages/received", "2"),
+            ("$SYS/broker/subscriptions/count", "1"),
+        ];
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !expected.iter().all(|(topic, value)| values.get(*topic).map(String::as_str) == Some(*value)) {
+                let publish = next_publish(&mut monitor).await;
+                assert_ne!(String::from_utf8_lossy(&publish.payload), "hello");
+                values.insert(publish.topic, String::from_utf8(publish.payload.to_vec()).unwrap());
+            }
+        }).await.unwrap();
+        assert!(values["$SYS/broker/bytes/received"].parse::<u64>().unwrap() > 0);
+        assert!(values["$SYS/broker/uptime"].ends_with(" seconds"));
+    }
+
+    /// 在随机端口上启动带认证器的代理，返回监听地址
+    async fn start_broker_with_auth(authenticator: impl Authenticator + 'static) -> String {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_authenticator(authenticator);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+        addr
+    }
+
+    /// 发送带用户名和密码的CONNECT，返回CONNACK返回码
+    async fn connect_with_credentials(addr: &str, username: Option<&str>, password: Option<&str>) -> (ClientFramed, u8) {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new("device".to_string());
+        connect.username = username.map(str::to_string);
+        connect.password = password.map(str::to_string);
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => (framed, connack.return_code),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authenticator_decides_connack_return_code() {
+        let mut auth = crate::auth::InMemoryAuthenticator::new();
+        auth.add_user("alice", "secret");
+        let addr = start_broker_with_auth(auth).await;
+
+        let (mut framed, code) = connect_with_credentials(&addr, Some("alice"), Some("wrong")).await;
+        assert_eq!(code, 0x04);
+        // 认证失败后代理关闭连接
+        assert!(framed.next().await.is_none());
+
+        let (_, code) = connect_with_credentials(&addr, None, None).await;
+        assert_eq!(code, 0x05);
+
+        let (mut framed, code) = connect_with_credentials(&addr, Some("alice"), Some("secret")).await;
+        assert_eq!(code, 0x00);
+        assert_eq!(subscribe(&mut framed, "a", QoS::AtMostOnce).await, vec![0x00]);
+    }
+
+    #[tokio::test]
+    async fn test_acl_restricts_publish_and_subscribe() {
+        use crate::acl::{AclRule, Permission};
+
+        let mut acl = Acl::new(Permission::Deny);
+        acl.add_rule(AclRule::allow("devices/%c/#").access(Access::Publish));
+        acl.add_rule(AclRule::allow("devices/#").access(Access::Subscribe).for_user("monitor"));
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_acl(acl);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        // 没有订阅权限的过滤器返回0x80
+        let mut device = raw_connect(&addr, "dev1").await;
+        assert_eq!(subscribe(&mut device, "devices/#", QoS::AtMostOnce).await, vec![SUBACK_FAILURE]);
+
+        let (mut monitor, code) = connect_with_credentials(&addr, Some("monitor"), None).await;
+        assert_eq!(code, 0x00);
+        assert_eq!(subscribe(&mut monitor, "devices/#", QoS::AtMostOnce).await, vec![0x00]);
+
+        // 发布到其他设备的主题被确认但不转发
+        device.send(publish("devices/dev2/temp", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(device.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(1)));
+        device.send(publish("devices/dev1/temp", QoS::AtMostOnce, None)).await.unwrap();
+        assert_eq!(next_publish(&mut monitor).await.topic, "devices/dev1/temp");
+    }
+
+    /// 构造一个在线会话订阅了`slow/topic`、消息队列容量为1的代理状态
+    async fn slow_subscriber_state(backpressure: Backpressure) -> (BrokerState, mpsc::Receiver<Message>, oneshot::Receiver<u8>) {
+        let mut state = BrokerState::new();
+        state.backpressure = backpressure;
+        let (outbox, outbox_rx) = mpsc::channel(1);
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        let (_, close) = session.attach(1, outbox);
+        session.subscriptions.insert("slow/topic".to_string(), QoS::AtLeastOnce);
+        state.sessions.lock().await.insert("slow".to_string(), session);
+        state.subscriptions.lock().await.subscribe("slow", "slow/topic", QoS::AtLeastOnce);
+        (state, outbox_rx, close)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_block_waits_at_most_one_timeout_for_all_slow_subscribers() {
+        let (state, _outbox, _close) = slow_subscriber_state(Backpressure::Block).await;
+        let (outbox, _second_outbox) = mpsc::channel(1);
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        let _second_close = session.attach(2, outbox);
+        state.sessions.lock().await.insert("slow2".to_string(), session);
+        state.subscriptions.lock().await.subscribe("slow2", "slow/topic", QoS::AtLeastOnce);
+        publish_message(slow_message(QoS::AtMostOnce), None, &state).await;
+
+        // 两个订阅者都不读取队列，发布方只等待一个BLOCK_TIMEOUT
+        let start = tokio::time::Instant::now();
+        publish_message(slow_message(QoS::AtMostOnce), None, &state).await;
+        assert_eq!(start.elapsed(), BLOCK_TIMEOUT);
+        assert_eq!(state.stats.messages_dropped(DropReason::QueueFull), 2);
+    }
+
+    fn slow_message(qos: QoS) -> PublishPacket {
+        match publish("slow/topic", qos, None) {
+            Packet::Publish(publish) => publish,
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_drops_newest_message() {
+        let (state, mut outbox, mut close) = slow_subscriber_state(Backpressure::DropNewest).await;
+        publish_message(slow_message(QoS::AtLeastOnce), None, &state).await;
+        publish_message(slow_message(QoS::AtLeastOnce), None, &state).await;
+
+        assert!(outbox.try_recv().is_ok());
+        assert!(outbox.try_recv().is_err());
+        assert!(close.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_disconnects_slow_client_and_keeps_message() {
+        let (state, mut outbox, mut close) = slow_subscriber_state(Backpressure::Disconnect).await;
+        publish_message(slow_message(QoS::AtLeastOnce), None, &state).await;
+        publish_message(slow_message(QoS::AtLeastOnce), None, &state).await;
+
+        assert!(outbox.try_recv().is_ok());
+        assert!(close.try_recv().is_ok());
+        // 溢出的QoS 1消息留在持久会话中，重连后投递
+        assert_eq!(state.sessions.lock().await["slow"].queued.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_blocks_publisher_until_space() {
+        let (state, mut outbox, _close) = slow_subscriber_state(Backpressure::Block).await;
+        let state = Arc::new(state);
+        publish_message(slow_message(QoS::AtMostOnce), None, &state).await;
+
+        let blocked = tokio::spawn({
+            let state = state.clone();
+            async move { publish_message(slow_message(QoS::AtMostOnce), None, &state).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!blocked.is_finished());
+
+        assert!(outbox.recv().await.is_some());
+        blocked.await.unwrap();
+        assert!(outbox.try_recv().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_block_does_not_deadlock_self_subscribed_publisher() {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_queue_capacity(1);
+        broker.set_backpressure(Backpressure::Block);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        // 发布方订阅自己的主题并连续发布，队列已满时它的连接任务不能等待自己
+        let mut client = raw_connect(&addr, "echo").await;
+        subscribe(&mut client, "t", QoS::AtMostOnce).await;
+        for _ in 0..10 {
+            client.send(publish("t", QoS::AtMostOnce, None)).await.unwrap();
+        }
+        client.send(publish("t", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        let acked = async {
+            loop {
+                match client.next().await {
+                    Some(Ok(Packet::Puback(ack))) => break ack.packet_id,
+                    Some(Ok(Packet::Publish(_))) => {}
+                    other => panic!("expected PUBACK, got {:?}", other),
+                }
+            }
+        };
+        // 不需要等到BLOCK_TIMEOUT
+        assert_eq!(tokio::time::timeout(BLOCK_TIMEOUT / 2, acked).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_inflight_and_packet_size_limits() {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_max_connections(2);
+        broker.set_max_inflight(1);
+        broker.set_max_packet_size(64);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "t", QoS::AtLeastOnce).await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+
+        // 超出连接数上限的CONNECT被拒绝
+        let mut rejected = Framed::new(TcpStream::connect(&addr).await.unwrap(), MqttCodec::new());
+        rejected.send(Packet::Connect(ConnectPacket::new("third".to_string()))).await.unwrap();
+        match rejected.next().await {
+            Some(Ok(Packet::Connack(connack))) => assert_eq!(connack.return_code, 0x03),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+
+        // 未确认的消息达到上限后，下一条消息等收到PUBACK之后才发送
+        for packet_id in 1..=2 {
+            publisher.send(publish("t", QoS::AtLeastOnce, Some(packet_id))).await.unwrap();
+            assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(packet_id)));
+        }
+        let first = next_publish(&mut subscriber).await;
+        assert!(tokio::time::timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+        subscriber.send(Packet::Puback(AckPacket::new(first.packet_id.unwrap()))).await.unwrap();
+        assert_eq!(next_publish(&mut subscriber).await.topic, "t");
+
+        // 超过最大长度的数据包导致连接关闭，释放的名额可以给新连接使用
+        let Packet::Publish(mut oversized) = publish("t", QoS::AtMostOnce, None) else { unreachable!() };
+        oversized.payload = bytes::Bytes::from(vec![0u8; 100]);
+        publisher.send(Packet::Publish(oversized)).await.unwrap();
+        assert!(!matches!(publisher.next().await, Some(Ok(_))));
+        raw_connect(&addr, "third").await;
+    }
+
+    /// 建立MQTT 5.0原始连接，`configure`可以修改CONNECT，返回连接和CONNACK
+    async fn connect_v5(addr: &str, client_id: &str, configure: impl FnOnce(&mut ConnectPacket)) -> (ClientFramed, ConnackPacket) {
+        let socket = TcpStream::connect(addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new(client_id.to_string());
+        connect.protocol_version = MQTT_PROTOCOL_VERSION_5;
+        configure(&mut connect);
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => (framed, connack),
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_v5_unacknowledged_publish_not_resent_on_live_connection() {
+        let addr = start_broker().await;
+        let (mut subscriber, _) = connect_v5(&addr, "sub", |connect| connect.keep_alive = 0).await;
+        subscribe(&mut subscriber, "t", QoS::AtLeastOnce).await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        publisher.send(publish("t", QoS::AtLeastOnce, Some(1))).await.unwrap();
+
+        let first = next_publish(&mut subscriber).await;
+        assert!(!first.dup);
+        // 不确认也不会在连接存续期间重发
+        assert!(tokio::time::timeout(DEFAULT_RETRY_INTERVAL * 3, subscriber.next()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_v5_retained_replay_respects_receive_maximum() {
+        let addr = start_broker().await;
+        let mut publisher = raw_connect(&addr, "pub").await;
+        for (packet_id, topic) in [(1, "status/a"), (2, "status/b")] {
+            let Packet::Publish(mut retained) = publish(topic, QoS::AtLeastOnce, Some(packet_id)) else { unreachable!() };
+            retained.retain = true;
+            publisher.send(Packet::Publish(retained)).await.unwrap();
+            assert_eq!(publisher.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::new(packet_id)));
+        }
+
+        // 保留消息同样受接收上限约束，第二条等第一条确认后才发送
+        let (mut subscriber, _) = connect_v5(&addr, "sub", |connect| connect.properties.receive_maximum = Some(1)).await;
+        subscribe(&mut subscriber, "status/+", QoS::AtLeastOnce).await;
+        let first = next_publish(&mut subscriber).await;
+        assert!(first.retain);
+        assert!(tokio::time::timeout(Duration::from_millis(200), subscriber.next()).await.is_err());
+        subscriber.send(Packet::Puback(AckPacket::new(first.packet_id.unwrap()))).await.unwrap();
+        let second = next_publish(&mut subscriber).await;
+        assert!(second.retain);
+        assert_ne!(second.topic, first.topic);
+    }
+
+    #[tokio::test]
+    async fn test_v5_connack_assigns_client_id_and_rejects_unknown_level() {
+        let addr = start_broker().await;
+        let (_framed, connack) = connect_v5(&addr, "", |_| {}).await;
+        assert_eq!(connack.return_code, reason_code::SUCCESS);
+        assert!(connack.properties.assigned_client_identifier.unwrap().starts_with("client-"));
+        assert_eq!(connack.properties.topic_alias_maximum, Some(TOPIC_ALIAS_MAXIMUM));
+
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new("old".to_string());
+        connect.protocol_version = 3;
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Packet::Connack(connack))) if connack.return_code == 0x01));
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v5_properties_forwarded_and_v3_subscriber_on_same_listener() {
+        let addr = start_broker().await;
+        let (mut v5_subscriber, _) = connect_v5(&addr, "v5-sub", |_| {}).await;
+        subscribe(&mut v5_subscriber, "rpc/request", QoS::AtMostOnce).await;
+        let mut v3_subscriber = raw_connect(&addr, "v3-sub").await;
+        subscribe(&mut v3_subscriber, "rpc/request", QoS::AtMostOnce).await;
+
+        let (mut requester, _) = connect_v5(&addr, "requester", |_| {}).await;
+        let mut request = PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "rpc/request".to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: bytes::Bytes::from_static(b"ping"),
+        };
+        request.properties.response_topic = Some("rpc/response/requester".to_string());
+        request.properties.correlation_data = Some(bytes::Bytes::from_static(b"42"));
+        request.properties.user_properties.push(("trace".to_string(), "abc".to_string()));
+        requester.send(Packet::Publish(request.clone())).await.unwrap();
+
+        // 5.0订阅者收到完整的属性，3.1.1订阅者只收到消息本身
+        let delivered = next_publish(&mut v5_subscriber).await;
+        assert_eq!(delivered.properties, request.properties);
+        let delivered = next_publish(&mut v3_subscriber).await;
+        assert_eq!(&delivered.payload[..], b"ping");
+        assert!(delivered.properties.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_v5_topic_alias_resolved_before_routing() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "sensors/#", QoS::AtMostOnce).await;
+
+        let (mut publisher, _) = connect_v5(&addr, "pub", |_| {}).await;
+        let mut aliased = PublishPacket {
+            dup: false,
+            qos: QoS::AtMostOnce,
+            retain: false,
+            topic: "sensors/temp".to_string(),
+            packet_id: None,
+            properties: Properties { topic_alias: Some(1), ..Properties::default() },
+            payload: bytes::Bytes::from_static(b"1"),
+        };
+        publisher.send(Packet::Publish(aliased.clone())).await.unwrap();
+        aliased.topic.clear();
+        publisher.send(Packet::Publish(aliased.clone())).await.unwrap();
+        for _ in 0..2 {
+            assert_eq!(next_publish(&mut subscriber).await.topic, "sensors/temp");
+        }
+
+        // 超过代理声明的最大值的别名导致断开
+        aliased.properties.topic_alias = Some(TOPIC_ALIAS_MAXIMUM + 1);
+        publisher.send(Packet::Publish(aliased)).await.unwrap();
+        match publisher.next().await {
+            Some(Ok(Packet::Disconnect(disconnect))) => assert_eq!(disconnect.reason_code, reason_code::TOPIC_ALIAS_INVALID),
+            other => panic!("expected DISCONNECT, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v5_acks_carry_reason_codes() {
+        use crate::acl::{AclRule, Permission};
+
+        let mut acl = Acl::new(Permission::Deny);
+        acl.add_rule(AclRule::allow("allowed/#"));
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.set_acl(acl);
+        let addr = broker.local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+
+        let (mut client, _) = connect_v5(&addr, "client", |_| {}).await;
+        client.send(publish("denied/topic", QoS::AtLeastOnce, Some(1))).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), Packet::Puback(AckPacket::with_reason(1, reason_code::NOT_AUTHORIZED)));
+        client.send(Packet::Pubrel(AckPacket::new(2))).await.unwrap();
+        assert_eq!(
+            client.next().await.unwrap().unwrap(),
+            Packet::Pubcomp(AckPacket::with_reason(2, reason_code::PACKET_IDENTIFIER_NOT_FOUND))
+        );
+
+        client.send(Packet::Subscribe(SubscribePacket {
+            packet_id: 3,
+            topics: vec![
+                ("allowed/+".to_string(), QoS::AtLeastOnce),
+                ("allowed/#/x".to_string(), QoS::AtMostOnce),
+                ("denied/#".to_string(), QoS::AtMostOnce),
+            ],
+            properties: Properties::default(),
+        })).await.unwrap();
+        match client.next().await {
+            Some(Ok(Packet::Suback(suback))) => assert_eq!(
+                suback.return_codes,
+                vec![0x01, reason_code::TOPIC_FILTER_INVALID, reason_code::NOT_AUTHORIZED]
+            ),
+            other => panic!("expected SUBACK, got {:?}", other),
+        }
+
+        client.send(Packet::Unsubscribe(crate::packet::UnsubscribePacket {
+            packet_id: 4,
+            topics: vec!["allowed/+".to_string(), "never/subscribed".to_string()],
+            properties: Properties::default(),
+        })).await.unwrap();
+        match client.next().await {
+            Some(Ok(Packet::Unsuback(unsuback))) => assert_eq!(
+                unsuback.reason_codes,
+                vec![reason_code::SUCCESS, reason_code::NO_SUBSCRIPTION_EXISTED]
+            ),
+            other => panic!("expected UNSUBACK, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_will_topic_rejected() {
+        let addr = start_broker().await;
+        let invalid_will = |connect: &mut ConnectPacket| {
+            connect.will = Some(LastWill {
+                topic: "status/#".to_string(),
+                message: bytes::Bytes::from_static(b"offline"),
+                qos: QoS::AtMostOnce,
+                retain: false,
+                properties: Properties::default(),
+            });
+        };
+        let (mut framed, connack) = connect_v5(&addr, "device", invalid_will).await;
+        assert_eq!(connack.return_code, reason_code::TOPIC_NAME_INVALID);
+        assert!(framed.next().await.is_none());
+
+        // 3.1.1没有对应的返回码，代理不发送CONNACK直接关闭连接
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new("device".to_string());
+        invalid_will(&mut connect);
+        framed.send(Packet::Connect(connect)).await.unwrap();
+        assert!(framed.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_v5_takeover_and_disconnect_with_will() {
+        let addr = start_broker().await;
+        let mut subscriber = raw_connect(&addr, "sub").await;
+        subscribe(&mut subscriber, "status/#", QoS::AtMostOnce).await;
+
+        let with_will = |connect: &mut ConnectPacket| {
+            connect.will = Some(LastWill {
+                topic: "status/device".to_string(),
+                message: bytes::Bytes::from_static(b"offline"),
+                qos: QoS::AtMostOnce,
+                retain: false,
+                properties: Properties::default(),
+            });
+        };
+        let (mut old, _) = connect_v5(&addr, "device", with_will).await;
+        let (mut new, _) = connect_v5(&addr, "device", with_will).await;
+        match old.next().await {
+            Some(Ok(Packet::Disconnect(disconnect))) => assert_eq!(disconnect.reason_code, reason_code::SESSION_TAKEN_OVER),
+            other => panic!("expected DISCONNECT, got {:?}", other),
+        }
+        // 被接管的连接没有正常断开，发布它的遗嘱
+        assert_eq!(next_publish(&mut subscriber).await.topic, "status/device");
+
+        // 原因码0x04的DISCONNECT同样发布遗嘱
+        new.send(Packet::Disconnect(DisconnectPacket::with_reason(reason_code::DISCONNECT_WITH_WILL))).await.unwrap();
+        assert!(new.next().await.is_none());
+        assert_eq!(next_publish(&mut subscriber).await.topic, "status/device");
+    }
+
+    #[tokio::test]
+    async fn test_v5_session_and_message_expiry() {
+        // 过期依赖真实的套接字往返，暂停的时钟会在等待I/O时跳过定时器，这里使用1秒级的真实间隔
+        let addr = start_broker().await;
+        let persistent = |connect: &mut ConnectPacket| {
+            connect.connect_flags &= !crate::protocol::CLEAN_SESSION;
+            connect.properties.session_expiry_interval = Some(2);
+        };
+        let (mut device, connack) = connect_v5(&addr, "device", persistent).await;
+        assert!(!connack.session_present);
+        subscribe(&mut device, "cmd/device", QoS::AtLeastOnce).await;
+        disconnect(device).await;
+
+        // 离线期间的消息：一条1秒后过期，一条不过期
+        let (mut publisher, _) = connect_v5(&addr, "pub", |_| {}).await;
+        for (packet_id, expiry) in [(1, Some(1)), (2, None)] {
+            let mut message = match publish("cmd/device", QoS::AtLeastOnce, Some(packet_id)) {
+                Packet::Publish(publish) => publish,
+                _ => unreachable!(),
+            };
+            message.properties.message_expiry_interval = expiry;
+            publisher.send(Packet::Publish(message)).await.unwrap();
+            assert!(matches!(publisher.next().await, Some(Ok(Packet::Puback(_)))));
+        }
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        let (mut device, connack) = connect_v5(&addr, "device", persistent).await;
+        assert!(connack.session_present);
+        let queued = next_publish(&mut device).await;
+        assert_eq!(queued.packet_id, Some(1));
+        assert_eq!(queued.properties.message_expiry_interval, None);
+        device.send(Packet::Puback(AckPacket::new(1))).await.unwrap();
+
+        // DISCONNECT把会话过期间隔改为1秒，过期后会话被删除
+        device.send(Packet::Disconnect(DisconnectPacket {
+            reason_code: reason_code::SUCCESS,
+            properties: Properties { session_expiry_interval: Some(1), ..Properties::default() },
+        })).await.unwrap();
+        assert!(device.next().await.is_none());
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        let (_device, connack) = connect_v5(&addr, "device", persistent).await;
+        assert!(!connack.session_present);
+    }
+
+    /// 两轮质询/响应的增强认证：客户端先发送"hello"，再用"response"回应代理的"challenge"
+    struct ChallengeAuthenticator;
+
+    #[async_trait::async_trait]
+    impl Authenticator for ChallengeAuthenticator {
+        async fn authenticate(&self, _client_id: &str, _username: Option<&str>, _password: Option<&[u8]>) -> AuthResult {
+            AuthResult::NotAuthorized
+        }
+
+        async fn authenticate_enhanced(&self, _client_id: &str, method: &str, data: Option<&[u8]>) -> EnhancedAuth {
+            match (method, data) {
+                ("CHALLENGE", Some(b"hello")) => EnhancedAuth::Continue(bytes::Bytes::from_static(b"challenge")),
+                ("CHALLENGE", Some(b"response")) => EnhancedAuth::Success(None),
+                ("CHALLENGE", _) => EnhancedAuth::Failure(reason_code::NOT_AUTHORIZED),
+                _ => EnhancedAuth::Failure(reason_code::BAD_AUTHENTICATION_METHOD),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_v5_enhanced_authentication_and_reauthentication() {
+        let addr = start_broker_with_auth(ChallengeAuthenticator).await;
+        let socket = TcpStream::connect(&addr).await.unwrap();
+        let mut framed = Framed::new(socket, MqttCodec::new());
+        let mut connect = ConnectPacket::new("device".to_string());
+        connect.protocol_version = MQTT_PROTOCOL_VERSION_5;
+        connect.properties.authentication_method = Some("CHALLENGE".to_string());
+        connect.properties.authentication_data = Some(bytes::Bytes::from_static(b"hello"));
+        framed.send(Packet::Connect(connect)).await.unwrap();
+
+        let challenge = auth_packet(reason_code::CONTINUE_AUTHENTICATION, "CHALLENGE", Some(bytes::Bytes::from_static(b"challenge")));
+        assert_eq!(framed.next().await.unwrap().unwrap(), challenge);
+        framed.send(auth_packet(reason_code::CONTINUE_AUTHENTICATION, "CHALLENGE", Some(bytes::Bytes::from_static(b"response")))).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Packet::Connack(connack))) => {
+                assert_eq!(connack.return_code, reason_code::SUCCESS);
+                assert_eq!(connack.properties.authentication_method.as_deref(), Some("CHALLENGE"));
+            }
+            other => panic!("expected CONNACK, got {:?}", other),
+        }
+
+        // 重新认证走同样的质询/响应
+        framed.send(auth_packet(reason_code::REAUTHENTICATE, "CHALLENGE", Some(bytes::Bytes::from_static(b"hello")))).await.unwrap();
+        assert_eq!(framed.next().await.unwrap().unwrap(), challenge);
+        framed.send(auth_packet(reason_code::CONTINUE_AUTHENTICATION, "CHALLENGE", Some(bytes::Bytes::from_static(b"response")))).await.unwrap();
+        assert_eq!(framed.next().await.unwrap().unwrap(), auth_packet(reason_code::SUCCESS, "CHALLENGE", None));
+
+        // 不支持的认证方法
+        let (_, connack) = connect_v5(&addr, "other", |connect| {
+            connect.properties.authentication_method = Some("KERBEROS".to_string());
+        }).await;
+        assert_eq!(connack.return_code, reason_code::BAD_AUTHENTICATION_METHOD);
+    }
+}
@@ -0,0 +1,325 @@
+/// 代理端的客户端会话
+/// 按客户端标识符保存订阅、在途消息和离线消息队列，
+/// 会话在客户端断开后按会话过期间隔继续保留（3.1.1的持久会话永不过期）
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::Instant;
+use crate::inflight::InflightWindow;
+use crate::packet::PublishPacket;
+use crate::protocol::{reason_code, QoS, SESSION_EXPIRY_NEVER};
+
+/// 每个离线会话最多缓存的消息数量，超出时丢弃最早的消息
+pub const MAX_QUEUED_MESSAGES: usize = 1000;
+
+/// 代理中转的消息，记录按消息过期间隔（MQTT 5.0）计算的过期时刻
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub publish: PublishPacket,
+    pub expires_at: Option<Instant>,
+}
+
+impl Message {
+    pub fn new(publish: PublishPacket) -> Self {
+        let expires_at = publish
+            .properties
+            .message_expiry_interval
+            .map(|secs| Instant::now() + Duration::from_secs(secs.into()));
+        Message { publish, expires_at }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// 取出待转发的PUBLISH，消息过期间隔改为剩余的秒数；已过期时返回None
+    pub fn into_publish(self) -> Option<PublishPacket> {
+        let Message { mut publish, expires_at } = self;
+        if let Some(expires_at) = expires_at {
+            let remaining = expires_at.checked_duration_since(Instant::now()).filter(|d| !d.is_zero())?;
+            publish.properties.message_expiry_interval = Some(remaining.as_secs_f64().ceil() as u32);
+        }
+        Some(publish)
+    }
+}
+
+/// 客户端会话
+#[derive(Debug)]
+pub struct Session {
+    /// 连接断开后会话保留的秒数，0表示随连接一起删除
+    pub expiry_interval: u32,
+    /// 会话离线后的过期时刻，在线或永不过期时为None
+    pub expires_at: Option<Instant>,
+    /// 该会话的订阅：主题过滤器 -> 授予的QoS
+    pub subscriptions: HashMap<String, QoS>,
+    /// 会话离线期间缓存的QoS 1/2消息
+    pub queued: VecDeque<Message>,
+    /// 已发送但未完成确认的消息，重连后重发
+    pub inflight: InflightWindow,
+    /// 已收到但尚未收到PUBREL的QoS 2包标识符
+    pub incoming_qos2: HashSet<u16>,
+    /// 当前在线连接，离线时为None
+    connection: Option<Connection>,
+    // 等待接管会话的新连接，在旧连接归还状态后通知
+    released: Option<oneshot::Sender<()>>,
+}
+
+/// 会话当前绑定的网络连接
+#[derive(Debug)]
+struct Connection {
+    id: u64,
+    // 通知连接关闭并携带原因码：同一客户端标识符再次连接，或代理主动断开
+    close: Option<oneshot::Sender<u8>>,
+    // 路由到该连接的消息队列
+    outbox: mpsc::Sender<Message>,
+    // 连接上已发送但未确认的消息数，由连接任务更新
+    unacked: Arc<AtomicUsize>,
+}
+
+/// 连接在线期间从会话中取出的状态，断开时归还
+#[derive(Debug, Default)]
+pub struct SessionState {
+    /// 会话过期间隔，客户端可以在DISCONNECT中修改
+    pub expiry_interval: u32,
+    pub queued: VecDeque<Message>,
+    pub inflight: InflightWindow,
+    pub incoming_qos2: HashSet<u16>,
+    /// 与会话共享的未确认消息计数，连接任务据在途窗口更新，供共享订阅选择接收者
+    pub unacked: Arc<AtomicUsize>,
+}
+
+impl SessionState {
+    /// 用在途窗口的当前大小更新共享的未确认消息计数
+    pub fn report_unacked(&self) {
+        self.unacked.store(self.inflight.len(), Ordering::Relaxed);
+    }
+}
+
+impl Session {
+    pub fn new(expiry_interval: u32) -> Self {
+        Session {
+            expiry_interval,
+            expires_at: None,
+            subscriptions: HashMap::new(),
+            queued: VecDeque::new(),
+            inflight: InflightWindow::new(),
+            incoming_qos2: HashSet::new(),
+            connection: None,
+            released: None,
+        }
+    }
+
+    /// 会话是否有在线连接
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    /// 在线连接的消息队列，离线时为None
+    pub fn outbox(&self) -> Option<&mpsc::Sender<Message>> {
+        self.connection.as_ref().map(|c| &c.outbox)
+    }
+
+    /// 在线连接上待确认的消息数：队列中尚未发送的消息加上已发送未确认的消息，离线时为None
+    pub fn inflight(&self) -> Option<usize> {
+        self.connection.as_ref().map(|c| {
+            let queued = c.outbox.max_capacity() - c.outbox.capacity();
+            queued + c.unacked.load(Ordering::Relaxed)
+        })
+    }
+
+    /// 通知当前在线的连接关闭，`reason`为MQTT 5.0连接上DISCONNECT的原因码
+    pub fn close_connection(&mut self, reason: u8) {
+        if let Some(close) = self.connection.as_mut().and_then(|c| c.close.take()) {
+            let _ = close.send(reason);
+        }
+    }
+
+    /// 通知当前在线的连接关闭，由新连接接管会话
+    ///
+    /// 返回的接收端在旧连接通过`detach`归还会话状态后收到通知，之后新连接才能`attach`。
+    pub fn take_over(&mut self) -> oneshot::Receiver<()> {
+        let (released, released_rx) = oneshot::channel();
+        if let Some(connection) = self.connection.as_ref() {
+            log::info!("Session taken over by a new connection, closing connection {}", connection.id);
+            self.close_connection(reason_code::SESSION_TAKEN_OVER);
+            self.released = Some(released);
+        }
+        released_rx
+    }
+
+    /// 将会话绑定到新的连接，取出离线期间保存的状态
+    ///
+    /// 之后路由给该会话的消息进入`outbox`；返回的接收端在连接需要关闭时收到通知。
+    pub fn attach(&mut self, connection_id: u64, outbox: mpsc::Sender<Message>) -> (SessionState, oneshot::Receiver<u8>) {
+        let (close, close_rx) = oneshot::channel();
+        let unacked = Arc::new(AtomicUsize::new(self.inflight.len()));
+        self.connection = Some(Connection { id: connection_id, close: Some(close), outbox, unacked: unacked.clone() });
+        self.expires_at = None;
+        let state = SessionState {
+            expiry_interval: self.expiry_interval,
+            queued: std::mem::take(&mut self.queued),
+            inflight: std::mem::take(&mut self.inflight),
+            incoming_qos2: std::mem::take(&mut self.incoming_qos2),
+            unacked,
+        };
+        (state, close_rx)
+    }
+
+    /// 连接断开时归还会话状态
+    ///
+    /// 只有仍绑定在该连接上的会话才会被更新；已被新连接接管时旧连接的状态直接丢弃。
+    pub fn detach(&mut self, connection_id: u64, state: SessionState) -> bool {
+        if self.connection.as_ref().map(|c| c.id) != Some(connection_id) {
+            return false;
+        }
+        self.connection = None;
+        self.expiry_interval = state.expiry_interval;
+        self.expires_at = match self.expiry_interval {
+            SESSION_EXPIRY_NEVER => None,
+            secs => Some(Instant::now() + Duration::from_secs(secs.into())),
+        };
+        self.inflight = state.inflight;
+        self.incoming_qos2 = state.incoming_qos2;
+        // 断开期间可能已有新消息入队，保持原有顺序
+        let mut queued = state.queued;
+        queued.append(&mut self.queued);
+        self.queued = queued;
+        if let Some(released) = self.released.take() {
+            let _ = released.send(());
+        }
+        true
+    }
+
+    /// 离线会话是否已经过期
+    pub fn is_expired(&self) -> bool {
+        !self.is_connected() && self.expires_at.is_some_and(|expires_at| expires_at <= Instant::now())
+    }
+
+    /// 为离线会话缓存一条消息
+    pub fn enqueue(&mut self, message: Message) {
+        // 先清理已过期的消息，避免它们占用队列
+        self.queued.retain(|queued| !queued.is_expired());
+        if self.queued.len() >= MAX_QUEUED_MESSAGES {
+            log::warn!("Offline queue full, dropping oldest message");
+            self.queued.pop_front();
+        }
+        self.queued.push_back(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::properties::Properties;
+    use bytes::Bytes;
+
+    fn publish(topic: &str) -> Message {
+        Message::new(PublishPacket {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: false,
+            topic: topic.to_string(),
+            packet_id: None,
+            properties: Properties::default(),
+            payload: Bytes::from_static(b"payload"),
+        })
+    }
+
+    #[test]
+    fn test_attach_takes_queued_state_and_detach_restores_it() {
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        session.enqueue(publish("a"));
+
+        let (outbox, _outbox_rx) = mpsc::channel(1);
+        let (mut state, _close) = session.attach(1, outbox);
+        assert!(session.is_connected());
+        assert!(session.outbox().is_some());
+        assert_eq!(state.queued.len(), 1);
+        assert!(session.queued.is_empty());
+
+        state.queued.clear();
+        state.incoming_qos2.insert(7);
+        assert!(session.detach(1, state));
+        assert!(!session.is_connected());
+        assert!(session.incoming_qos2.contains(&7));
+    }
+
+    #[test]
+    fn test_takeover_waits_for_old_connection_to_detach() {
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        let (outbox, _outbox_rx) = mpsc::channel(1);
+        let (mut old_state, mut old_close) = session.attach(1, outbox.clone());
+        old_state.incoming_qos2.insert(3);
+
+        let mut released = session.take_over();
+        assert_eq!(old_close.try_recv(), Ok(reason_code::SESSION_TAKEN_OVER));
+        assert!(released.try_recv().is_err());
+
+        // 旧连接归还状态后通知新连接，新连接取回旧连接的状态
+        assert!(session.detach(1, old_state));
+        assert!(released.try_recv().is_ok());
+        let (new_state, _new_close) = session.attach(2, outbox);
+        assert!(new_state.incoming_qos2.contains(&3));
+
+        // 不再绑定的连接不能覆盖会话状态
+        assert!(!session.detach(1, SessionState::default()));
+        assert!(session.is_connected());
+    }
+
+    #[test]
+    fn test_inflight_counts_queued_and_unacked_messages() {
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        assert_eq!(session.inflight(), None);
+
+        let (outbox, _outbox_rx) = mpsc::channel(4);
+        let (mut state, _close) = session.attach(1, outbox.clone());
+        assert_eq!(session.inflight(), Some(0));
+        outbox.try_send(publish("a")).unwrap();
+        state.inflight.start(publish("b").publish).unwrap();
+        state.report_unacked();
+        assert_eq!(session.inflight(), Some(2));
+    }
+
+    #[test]
+    fn test_enqueue_drops_oldest_when_full() {
+        let mut session = Session::new(SESSION_EXPIRY_NEVER);
+        for i in 0..=MAX_QUEUED_MESSAGES {
+            session.enqueue(publish(&i.to_string()));
+        }
+        assert_eq!(session.queued.len(), MAX_QUEUED_MESSAGES);
+        assert_eq!(session.queued.front().unwrap().publish.topic, "1");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_expiry_interval_counts_down() {
+        let mut message = publish("a");
+        message.publish.properties.message_expiry_interval = Some(10);
+        let message = Message::new(message.publish);
+
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let forwarded = message.clone().into_publish().unwrap();
+        assert_eq!(forwarded.properties.message_expiry_interval, Some(6));
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert!(message.is_expired());
+        assert!(message.into_publish().is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_session_expires_after_interval_offline() {
+        let mut session = Session::new(30);
+        let (outbox, _outbox_rx) = mpsc::channel(1);
+        let (state, _close) = session.attach(1, outbox.clone());
+        assert!(session.detach(1, state));
+        assert!(!session.is_expired());
+
+        tokio::time::advance(Duration::from_secs(30)).await;
+        assert!(session.is_expired());
+
+        // 重新连接后不再计时
+        let _ = session.attach(2, outbox);
+        assert!(!session.is_expired());
+    }
+}
@@ -0,0 +1,173 @@
+/// 共享订阅
+/// `$share/<组名>/<过滤器>`形式的订阅属于同一个共享组，匹配的消息只投递给组内的一个成员，
+/// 多个消费者实例以此分摊同一主题的流量
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::protocol::QoS;
+use crate::topic::valid_topic_filter;
+
+/// 共享订阅过滤器的前缀
+pub const SHARE_PREFIX: &str = "$share/";
+
+/// 共享组内选择接收者的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ShareStrategy {
+    /// 按订阅顺序轮流投递给在线成员
+    #[default]
+    RoundRobin,
+    /// 投递给待确认消息最少的在线成员，相同时按轮询顺序
+    LeastInflight,
+}
+
+/// 是否为共享订阅过滤器（不检查格式是否合法）
+pub fn is_shared(filter: &str) -> bool {
+    filter.starts_with(SHARE_PREFIX)
+}
+
+/// 把共享订阅过滤器拆分为(组名, 主题过滤器)
+///
+/// 组名不能为空，也不能包含通配符；主题过滤器必须合法。不是合法的共享订阅时返回None。
+pub fn parse(filter: &str) -> Option<(&str, &str)> {
+    let (group, filter) = filter.strip_prefix(SHARE_PREFIX)?.split_once('/')?;
+    if group.is_empty() || group.contains(['+', '#']) || !valid_topic_filter(filter) {
+        return None;
+    }
+    Some((group, filter))
+}
+
+/// 实际匹配主题用的过滤器：共享订阅去掉`$share/<组名>/`前缀，其他过滤器原样返回
+pub fn subscription_filter(filter: &str) -> &str {
+    parse(filter).map_or(filter, |(_, filter)| filter)
+}
+
+/// 一个共享组：同一主题过滤器上使用相同组名的订阅者
+#[derive(Debug, Default)]
+pub struct SharedGroup {
+    // 按订阅顺序排列的成员：客户端ID -> 授予的QoS
+    members: Vec<(String, QoS)>,
+    // 轮询的下一个位置；路由时只持有订阅表的共享引用，因此用原子变量更新
+    next: AtomicUsize,
+}
+
+impl SharedGroup {
+    pub fn new() -> Self {
+        SharedGroup::default()
+    }
+
+    /// 加入共享组，已经是成员时更新授予的QoS；返回是否为新成员
+    pub fn subscribe(&mut self, client_id: &str, qos: QoS) -> bool {
+        match self.members.iter_mut().find(|(member, _)| member == client_id) {
+            Some(member) => {
+                member.1 = qos;
+                false
+            }
+            None => {
+                self.members.push((client_id.to_string(), qos));
+                true
+            }
+        }
+    }
+
+    /// 退出共享组，返回该客户端原来是否是成员
+    pub fn unsubscribe(&mut self, client_id: &str) -> bool {
+        let before = self.members.len();
+        self.members.retain(|(member, _)| member != client_id);
+        before != self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// 为一条消息选择接收者
+    ///
+    /// `inflight`返回在线成员待确认的消息数，离线成员返回None。优先选择在线成员；
+    /// 全部离线时按轮询选择，由持久会话缓存消息。
+    pub fn pick(&self, strategy: ShareStrategy, inflight: impl Fn(&str) -> Option<usize>) -> Option<(&str, QoS)> {
+        let count = self.members.len();
+        if count == 0 {
+            return None;
+        }
+        let start = self.next.load(Ordering::Relaxed) % count;
+        let order = (0..count).map(|offset| (start + offset) % count);
+        let online = order.clone().filter_map(|index| inflight(&self.members[index].0).map(|load| (index, load)));
+        let chosen = match strategy {
+            ShareStrategy::RoundRobin => online.map(|(index, _)| index).next(),
+            // min_by_key在相同时返回第一个，即轮询顺序中最靠前的成员
+            ShareStrategy::LeastInflight => online.min_by_key(|(_, load)| *load).map(|(index, _)| index),
+        }
+        .unwrap_or(start);
+        self.next.store(chosen + 1, Ordering::Relaxed);
+        let (client_id, qos) = &self.members[chosen];
+        Some((client_id, *qos))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_parse_shared_filters() {
+        assert_eq!(parse("$share/workers/jobs/#"), Some(("workers", "jobs/#")));
+        assert_eq!(parse("$share/g/a/+/c"), Some(("g", "a/+/c")));
+        assert_eq!(parse("jobs/#"), None);
+        assert_eq!(parse("$share/workers"), None);
+        assert_eq!(parse("$share//jobs"), None);
+        assert_eq!(parse("$share/w+/jobs"), None);
+        assert_eq!(parse("$share/workers/jobs/#/x"), None);
+        assert_eq!(subscription_filter("$share/workers/jobs/#"), "jobs/#");
+        assert_eq!(subscription_filter("jobs/#"), "jobs/#");
+    }
+
+    fn group(members: &[&str]) -> SharedGroup {
+        let mut group = SharedGroup::new();
+        for member in members {
+            group.subscribe(member, QoS::AtLeastOnce);
+        }
+        group
+    }
+
+    #[test]
+    fn test_round_robin_skips_offline_members() {
+        let group = group(&["a", "b", "c"]);
+        let picks: Vec<&str> = (0..4).map(|_| group.pick(ShareStrategy::RoundRobin, |_| Some(0)).unwrap().0).collect();
+        assert_eq!(picks, vec!["a", "b", "c", "a"]);
+
+        // b离线时在a和c之间轮流
+        let online = |member: &str| (member != "b").then_some(0);
+        let picks: Vec<&str> = (0..3).map(|_| group.pick(ShareStrategy::RoundRobin, online).unwrap().0).collect();
+        assert_eq!(picks, vec!["c", "a", "c"]);
+
+        // 全部离线时仍按轮询选择
+        assert_eq!(group.pick(ShareStrategy::RoundRobin, |_| None).unwrap().0, "a");
+    }
+
+    #[test]
+    fn test_least_inflight_prefers_idle_members() {
+        let group = group(&["a", "b", "c"]);
+        let load = HashMap::from([("a", 5), ("b", 1), ("c", 1)]);
+        let inflight = |member: &str| load.get(member).copied();
+        assert_eq!(group.pick(ShareStrategy::LeastInflight, inflight).unwrap().0, "b");
+        // 负载相同的成员按轮询顺序轮换
+        assert_eq!(group.pick(ShareStrategy::LeastInflight, inflight).unwrap().0, "c");
+        assert_eq!(group.pick(ShareStrategy::LeastInflight, inflight).unwrap().0, "b");
+    }
+
+    #[test]
+    fn test_subscribe_and_unsubscribe_members() {
+        let mut group = group(&["a", "b"]);
+        assert!(!group.subscribe("a", QoS::ExactlyOnce));
+        assert_eq!(group.len(), 2);
+        assert_eq!(group.pick(ShareStrategy::RoundRobin, |_| Some(0)), Some(("a", QoS::ExactlyOnce)));
+        assert!(group.unsubscribe("a"));
+        assert!(!group.unsubscribe("a"));
+        assert!(group.unsubscribe("b"));
+        assert!(group.is_empty());
+        assert_eq!(group.pick(ShareStrategy::RoundRobin, |_| Some(0)), None);
+    }
+}
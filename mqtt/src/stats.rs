@@ -0,0 +1,254 @@
+/// 代理运行统计
+/// 消息和字节计数器在连接处理过程中累加，代理定期把统计值发布到`$SYS/broker/...`主题，
+/// 任何MQTT客户端订阅`$SYS/#`即可监控代理；管理HTTP服务以Prometheus格式导出同一组统计
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Instant;
+use crate::protocol::QoS;
+
+/// 系统主题的前缀，客户端不能向这些主题发布消息
+pub const SYS_PREFIX: &str = "$SYS/";
+
+/// 默认每10秒发布一次系统主题
+pub const DEFAULT_SYS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 消息被代理丢弃的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// 在线会话的消息队列已满
+    QueueFull,
+    /// 消息过期间隔（MQTT 5.0）已过
+    Expired,
+    /// 发布方没有该主题的发布权限
+    Unauthorized,
+}
+
+impl DropReason {
+    pub const ALL: [DropReason; 3] = [DropReason::QueueFull, DropReason::Expired, DropReason::Unauthorized];
+
+    /// 指标标签中使用的名称
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DropReason::QueueFull => "queue_full",
+            DropReason::Expired => "expired",
+            DropReason::Unauthorized => "unauthorized",
+        }
+    }
+}
+
+/// 代理的累计计数器，所有连接共享
+#[derive(Debug)]
+pub struct BrokerStats {
+    started: Instant,
+    // 被接受的CONNECT数
+    connections: AtomicU64,
+    auth_failures: AtomicU64,
+    // 按QoS统计的PUBLISH数，下标为QoS的值
+    messages_received: [AtomicU64; 3],
+    messages_sent: [AtomicU64; 3],
+    // 下标与DropReason::ALL一致
+    messages_dropped: [AtomicU64; 3],
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+}
+
+/// 从代理状态中读取的即时数值
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Gauges {
+    pub clients_connected: usize,
+    pub subscriptions: usize,
+    pub retained: usize,
+    /// 在线会话的消息队列中尚未发送的消息数
+    pub queued_online: usize,
+    /// 离线会话缓存的消息数
+    pub queued_offline: usize,
+}
+
+impl BrokerStats {
+    pub fn new() -> Self {
+        BrokerStats {
+            started: Instant::now(),
+            connections: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            messages_received: Default::default(),
+            messages_sent: Default::default(),
+            messages_dropped: Default::default(),
+            bytes_received: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+        }
+    }
+
+    /// 接受了一个CONNECT
+    pub fn connection_accepted(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 一个CONNECT因认证失败被拒绝
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 收到客户端发布的一条PUBLISH
+    pub fn message_received(&self, qos: QoS) {
+        self.messages_received[qos as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 向客户端发送了一条PUBLISH（不含重传）
+    pub fn message_sent(&self, qos: QoS) {
+        self.messages_sent[qos as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 丢弃了一条消息
+    pub fn message_dropped(&self, reason: DropReason) {
+        self.messages_dropped[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connections(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn auth_failures(&self) -> u64 {
+        self.auth_failures.load(Ordering::Relaxed)
+    }
+
+    /// 收到的PUBLISH总数
+    pub fn messages_received(&self) -> u64 {
+        self.messages_received.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    /// 发出的PUBLISH总数
+    pub fn messages_sent(&self) -> u64 {
+        self.messages_sent.iter().map(|count| count.load(Ordering::Relaxed)).sum()
+    }
+
+    pub fn messages_received_with_qos(&self, qos: QoS) -> u64 {
+        self.messages_received[qos as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn messages_sent_with_qos(&self, qos: QoS) -> u64 {
+        self.messages_sent[qos as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn messages_dropped(&self, reason: DropReason) -> u64 {
+        self.messages_dropped[reason as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// 代理启动以来的运行时间
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// 生成系统主题及其载荷
+    pub fn sys_topics(&self, gauges: Gauges) -> Vec<(&'static str, String)> {
+        vec![
+            ("$SYS/broker/uptime", format!("{} seconds", self.uptime().as_secs())),
+            ("$SYS/broker/clients/connected", gauges.clients_connected.to_string()),
+            ("$SYS/broker/messages/received", self.messages_received().to_string()),
+            ("$SYS/broker/messages/sent", self.messages_sent().to_string()),
+            ("$SYS/broker/bytes/received", self.bytes_received().to_string()),
+            ("$SYS/broker/bytes/sent", self.bytes_sent().to_string()),
+            ("$SYS/broker/subscriptions/count", gauges.subscriptions.to_string()),
+            ("$SYS/broker/retained messages/count", gauges.retained.to_string()),
+        ]
+    }
+}
+
+impl Default for BrokerStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// 统计收发字节数的传输包装，TCP、TLS和WebSocket连接在进入会话处理前套上这一层
+pub struct CountingTransport<S> {
+    inner: S,
+    stats: Arc<BrokerStats>,
+}
+
+impl<S> CountingTransport<S> {
+    pub fn new(inner: S, stats: Arc<BrokerStats>) -> Self {
+        CountingTransport { inner, stats }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CountingTransport<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - before;
+        self.stats.bytes_received.fetch_add(read as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CountingTransport<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.stats.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counting_transport_counts_both_directions() {
+        let stats = Arc::new(BrokerStats::new());
+        let (local, mut remote) = tokio::io::duplex(64);
+        let mut counted = CountingTransport::new(local, stats.clone());
+
+        counted.write_all(b"hello").await.unwrap();
+        remote.write_all(b"abc").await.unwrap();
+        let mut buf = [0u8; 3];
+        counted.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(stats.bytes_sent(), 5);
+        assert_eq!(stats.bytes_received(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sys_topics_report_counters_and_gauges() {
+        let stats = BrokerStats::new();
+        stats.message_received(QoS::AtMostOnce);
+        stats.message_sent(QoS::AtMostOnce);
+        stats.message_sent(QoS::ExactlyOnce);
+        tokio::time::advance(Duration::from_secs(42)).await;
+
+        let gauges = Gauges { clients_connected: 3, subscriptions: 7, retained: 1, ..Gauges::default() };
+        let topics = stats.sys_topics(gauges);
+        let value = |topic: &str| topics.iter().find(|(t, _)| *t == topic).unwrap().1.clone();
+        assert_eq!(value("$SYS/broker/uptime"), "42 seconds");
+        assert_eq!(value("$SYS/broker/clients/connected"), "3");
+        assert_eq!(value("$SYS/broker/messages/received"), "1");
+        assert_eq!(value("$SYS/broker/messages/sent"), "2");
+        assert_eq!(value("$SYS/broker/subscriptions/count"), "7");
+        assert_eq!(value("$SYS/broker/retained messages/count"), "1");
+        assert!(topics.iter().all(|(topic, _)| topic.starts_with(SYS_PREFIX)));
+    }
+}
@@ -0,0 +1,621 @@
+/// 持久化存储
+/// 保存保留消息、持久会话的订阅和离线队列中的QoS 1/2消息，代理重启后从存储中恢复。
+/// 代理把每次状态变更作为一条`Record`交给存储：`MemoryStorage`只在进程内保存，
+/// `FileStorage`在后台线程中把记录追加写入日志文件，日志中的过时记录达到一定数量后重写文件压缩
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use bytes::{Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use crate::packet::{decode_remaining_length, malformed, Packet};
+use crate::protocol::{QoS, MQTT_PROTOCOL_VERSION_5, SESSION_EXPIRY_NEVER};
+use crate::session::{Message, Session, MAX_QUEUED_MESSAGES};
+
+/// 日志至少积累这么多条记录后才考虑压缩
+pub const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// 代理状态的持久化存储
+///
+/// 方法在持有代理内部锁时同步调用，实现不应阻塞；文件IO等耗时操作应交给后台线程，参见`FileStorage`。
+pub trait Storage: Send + Sync {
+    /// 读取已保存的全部状态，代理启动时调用一次
+    fn load(&self) -> io::Result<StoredState>;
+
+    /// 保存一次状态变更
+    fn apply(&self, record: Record) -> io::Result<()>;
+}
+
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn load(&self) -> io::Result<StoredState> {
+        (**self).load()
+    }
+
+    fn apply(&self, record: Record) -> io::Result<()> {
+        (**self).apply(record)
+    }
+}
+
+/// 一次状态变更
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Record {
+    /// 设置主题的保留消息
+    SetRetained { topic: String, message: StoredMessage },
+    /// 清除主题的保留消息
+    RemoveRetained { topic: String },
+    /// 创建或更新持久会话，已有的订阅和消息队列保持不变
+    SetSession { client_id: String, expiry_interval: u32, expires_at: Option<u64> },
+    /// 删除会话及其订阅和消息队列
+    RemoveSession { client_id: String },
+    /// 添加或更新会话的订阅
+    Subscribe { client_id: String, filter: String, qos: u8 },
+    /// 删除会话的订阅
+    Unsubscribe { client_id: String, filter: String },
+    /// 向离线会话的消息队列追加一条消息
+    PushMessage { client_id: String, message: StoredMessage },
+    /// 替换会话的整个消息队列
+    SetMessages { client_id: String, messages: Vec<StoredMessage> },
+}
+
+/// 保存的一条消息
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredMessage {
+    /// 按MQTT 5.0格式编码的PUBLISH包，包含主题、QoS、属性和载荷；日志中以十六进制保存
+    #[serde(with = "hex")]
+    pub packet: Vec<u8>,
+    /// 消息的过期时刻（Unix时间戳，秒），不过期时为None
+    pub expires_at: Option<u64>,
+}
+
+impl StoredMessage {
+    pub fn from_message(message: &Message) -> Self {
+        let mut publish = message.publish.clone();
+        publish.dup = false;
+        publish.packet_id = None;
+        let mut buffer = BytesMut::new();
+        Packet::Publish(publish).encode_with_version(&mut buffer, MQTT_PROTOCOL_VERSION_5);
+        StoredMessage {
+            packet: buffer.to_vec(),
+            expires_at: message.expires_at.map(to_timestamp),
+        }
+    }
+
+    /// 还原为代理中转的消息，已过期时返回`Ok(None)`
+    pub fn to_message(&self) -> io::Result<Option<Message>> {
+        let expires_at = match self.expires_at {
+            Some(timestamp) => match from_timestamp(timestamp) {
+                Some(expires_at) => Some(expires_at),
+                None => return Ok(None),
+            },
+            None => None,
+        };
+        let header = *self.packet.first().ok_or_else(|| malformed("empty stored message"))?;
+        let (length, length_bytes) = decode_remaining_length(&self.packet[1..])?
+            .ok_or_else(|| malformed("truncated stored message"))?;
+        let body = self
+            .packet
+            .get(1 + length_bytes..1 + length_bytes + length)
+            .ok_or_else(|| malformed("truncated stored message"))?;
+        match Packet::decode_with_version(header, Bytes::copy_from_slice(body), MQTT_PROTOCOL_VERSION_5)? {
+            Packet::Publish(mut publish) => {
+                publish.packet_id = None;
+                Ok(Some(Message { publish, expires_at }))
+            }
+            other => Err(malformed(format!("stored message is a {:?}", other.packet_type()))),
+        }
+    }
+}
+
+/// 保存的持久会话
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoredSession {
+    pub expiry_interval: u32,
+    /// 会话离线后的过期时刻（Unix时间戳，秒）；在线或永不过期时为None
+    pub expires_at: Option<u64>,
+    pub subscriptions: BTreeMap<String, QoS>,
+    /// 离线期间缓存的消息，包括断开时尚未被客户端收到的在途消息
+    pub queued: Vec<StoredMessage>,
+}
+
+impl StoredSession {
+    /// 还原为离线会话，已过期时返回None
+    ///
+    /// 没有过期时刻的非永久会话是代理退出时仍在线的会话，从恢复时刻开始计算过期时间。
+    pub fn restore(&self) -> Option<Session> {
+        let mut session = Session::new(self.expiry_interval);
+        session.expires_at = match (self.expires_at, self.expiry_interval) {
+            (Some(timestamp), _) => Some(from_timestamp(timestamp)?),
+            (None, SESSION_EXPIRY_NEVER) => None,
+            (None, secs) => Some(Instant::now() + Duration::from_secs(secs.into())),
+        };
+        session.subscriptions = self.subscriptions.iter().map(|(filter, qos)| (filter.clone(), *qos)).collect::<HashMap<_, _>>();
+        session.queued = self
+            .queued
+            .iter()
+            .filter_map(|message| match message.to_message() {
+                Ok(message) => message,
+                Err(e) => {
+                    log::warn!("Dropping unreadable stored message: {}", e);
+                    None
+                }
+            })
+            .collect::<VecDeque<_>>();
+        Some(session)
+    }
+}
+
+/// 存储中的全部状态
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoredState {
+    /// 主题名 -> 保留消息
+    pub retained: BTreeMap<String, StoredMessage>,
+    /// 客户端标识符 -> 持久会话
+    pub sessions: BTreeMap<String, StoredSession>,
+}
+
+impl StoredState {
+    /// 应用一次状态变更；针对不存在的会话的订阅和消息记录被忽略
+    pub fn apply(&mut self, record: Record) {
+        match record {
+            Record::SetRetained { topic, message } => {
+                self.retained.insert(topic, message);
+            }
+            Record::RemoveRetained { topic } => {
+                self.retained.remove(&topic);
+            }
+            Record::SetSession { client_id, expiry_interval, expires_at } => {
+                let session = self.sessions.entry(client_id).or_default();
+                session.expiry_interval = expiry_interval;
+                session.expires_at = expires_at;
+            }
+            Record::RemoveSession { client_id } => {
+                self.sessions.remove(&client_id);
+            }
+            Record::Subscribe { client_id, filter, qos } => {
+                if let (Some(session), Some(qos)) = (self.sessions.get_mut(&client_id), QoS::from_u8(qos)) {
+                    session.subscriptions.insert(filter, qos);
+                }
+            }
+            Record::Unsubscribe { client_id, filter } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.subscriptions.remove(&filter);
+                }
+            }
+            Record::PushMessage { client_id, message } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    // 与Session::enqueue一致，超出上限时丢弃最早的消息
+                    if session.queued.len() >= MAX_QUEUED_MESSAGES {
+                        session.queued.remove(0);
+                    }
+                    session.queued.push(message);
+                }
+            }
+            Record::SetMessages { client_id, messages } => {
+                if let Some(session) = self.sessions.get_mut(&client_id) {
+                    session.queued = messages;
+                }
+            }
+        }
+    }
+
+    /// 重建当前状态所需的最少记录，用于压缩日志
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = Vec::new();
+        for (topic, message) in &self.retained {
+            records.push(Record::SetRetained { topic: topic.clone(), message: message.clone() });
+        }
+        for (client_id, session) in &self.sessions {
+            records.push(Record::SetSession {
+                client_id: client_id.clone(),
+                expiry_interval: session.expiry_interval,
+                expires_at: session.expires_at,
+            });
+            for (filter, qos) in &session.subscriptions {
+                records.push(Record::Subscribe { client_id: client_id.clone(), filter: filter.clone(), qos: *qos as u8 });
+            }
+            if !session.queued.is_empty() {
+                records.push(Record::SetMessages { client_id: client_id.clone(), messages: session.queued.clone() });
+            }
+        }
+        records
+    }
+}
+
+/// 只保存在进程内的存储，代理默认使用；进程退出后状态丢失
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<StoredState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&self) -> io::Result<StoredState> {
+        Ok(self.state.lock().unwrap().clone())
+    }
+
+    fn apply(&self, record: Record) -> io::Result<()> {
+        self.state.lock().unwrap().apply(record);
+        Ok(())
+    }
+}
+
+/// 基于追加写日志文件的存储
+///
+/// 每条记录以一行JSON追加到文件末尾。打开时重放日志得到当前状态；
+/// 日志记录数超过当前状态所需记录数的两倍（且不少于`COMPACT_MIN_RECORDS`）时，
+/// 把当前状态写入临时文件后原子地替换日志。写入不逐条调用fsync，
+/// 进程崩溃不会丢失已写入的记录，操作系统崩溃可能丢失最后几条记录。
+///
+/// 文件的写入和压缩都在专用的后台线程中进行，`apply`只把记录放入队列后立即返回，
+/// 写入失败时记录日志。`FileStorage`被丢弃时等待后台线程写完队列中的全部记录。
+#[derive(Debug)]
+pub struct FileStorage {
+    path: PathBuf,
+    commands: Option<mpsc::Sender<Command>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+// 交给后台写入线程的操作，按发送顺序执行
+#[derive(Debug)]
+enum Command {
+    Append(Record),
+    Load(mpsc::Sender<StoredState>),
+    Compact(mpsc::Sender<io::Result<()>>),
+    Flush(mpsc::Sender<()>),
+}
+
+#[derive(Debug)]
+struct FileLog {
+    path: PathBuf,
+    file: File,
+    state: StoredState,
+    // 日志文件中的记录数，达到compact_at时压缩
+    records: usize,
+    compact_at: usize,
+}
+
+impl FileStorage {
+    /// 打开日志文件，不存在时创建；已有的日志被重放并压缩
+    ///
+    /// 末尾不完整或损坏的记录（例如写入中途进程退出）及其之后的内容被丢弃。
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut state = StoredState::default();
+        match File::open(&path) {
+            Ok(file) => {
+                for (number, line) in BufReader::new(file).lines().enumerate() {
+                    let line = line?;
+                    match serde_json::from_str::<Record>(&line) {
+                        Ok(record) => state.apply(record),
+                        Err(e) => {
+                            log::warn!("Ignoring storage log {} from line {}: {}", path.display(), number + 1, e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let (file, records) = write_snapshot(&path, &state)?;
+        let mut log = FileLog { path: path.clone(), file, state, records, compact_at: compact_threshold(records) };
+        let (commands, receiver) = mpsc::channel();
+        let writer = thread::Builder::new()
+            .name("mqtt-storage".to_string())
+            .spawn(move || log.run(receiver))?;
+        Ok(FileStorage { path, commands: Some(commands), writer: Some(writer) })
+    }
+
+    /// 日志文件路径
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 立即把日志重写为只包含当前状态的记录
+    pub fn compact(&self) -> io::Result<()> {
+        self.request(Command::Compact)?
+    }
+
+    /// 等待后台线程写完此前交给存储的全部记录
+    pub fn flush(&self) -> io::Result<()> {
+        self.request(Command::Flush)
+    }
+
+    fn send(&self, command: Command) -> io::Result<()> {
+        self.commands
+            .as_ref()
+            .and_then(|commands| commands.send(command).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "storage writer thread has stopped"))
+    }
+
+    // 发送需要回复的操作并等待后台线程执行完
+    fn request<T>(&self, command: impl FnOnce(mpsc::Sender<T>) -> Command) -> io::Result<T> {
+        let (reply, receiver) = mpsc::channel();
+        self.send(command(reply))?;
+        receiver
+            .recv()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "storage writer thread has stopped"))
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&self) -> io::Result<StoredState> {
+        self.request(Command::Load)
+    }
+
+    fn apply(&self, record: Record) -> io::Result<()> {
+        self.send(Command::Append(record))
+    }
+}
+
+impl Drop for FileStorage {
+    fn drop(&mut self) {
+        // 关闭队列后后台线程写完剩余记录即退出
+        self.commands.take();
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::error!("Storage writer thread for {} panicked", self.path.display());
+            }
+        }
+    }
+}
+
+impl FileLog {
+    // 后台写入线程的主循环，队列关闭后返回
+    fn run(&mut self, commands: mpsc::Receiver<Command>) {
+        for command in commands {
+            match command {
+                Command::Append(record) => {
+                    if let Err(e) = self.append(record) {
+                        log::error!("Failed to write storage log {}: {}", self.path.display(), e);
+                    }
+                }
+                Command::Load(reply) => {
+                    let _ = reply.send(self.state.clone());
+                }
+                Command::Compact(reply) => {
+                    let _ = reply.send(self.compact());
+                }
+                Command::Flush(reply) => {
+                    let _ = reply.send(());
+                }
+            }
+        }
+    }
+
+    // 写入失败时仍更新内存中的状态，下一次压缩会把它完整写回日志
+    fn append(&mut self, record: Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.state.apply(record);
+        self.file.write_all(&line)?;
+        self.records += 1;
+        if self.records >= self.compact_at {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self) -> io::Result<()> {
+        let (file, records) = write_snapshot(&self.path, &self.state)?;
+        log::debug!("Compacted storage log {} to {} records", self.path.display(), records);
+        self.file = file;
+        self.records = records;
+        self.compact_at = compact_threshold(records);
+        Ok(())
+    }
+}
+
+// 压缩后日志中有`records`条记录时，下一次压缩的时机
+fn compact_threshold(records: usize) -> usize {
+    (records * 2).max(COMPACT_MIN_RECORDS)
+}
+
+/// 把状态写入临时文件并替换日志，返回以追加方式打开的新日志及其记录数
+fn write_snapshot(path: &Path, state: &StoredState) -> io::Result<(File, usize)> {
+    let records = state.records();
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".compact");
+    let temp_path = PathBuf::from(temp_path);
+    {
+        let mut temp = File::create(&temp_path)?;
+        let mut buffer = Vec::new();
+        for record in &records {
+            serde_json::to_writer(&mut buffer, record)?;
+            buffer.push(b'\n');
+        }
+        temp.write_all(&buffer)?;
+        temp.sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    let file = OpenOptions::new().append(true).open(path)?;
+    Ok((file, records.len()))
+}
+
+/// 把代理内部的过期时刻换算为Unix时间戳（秒，向上取整）
+pub fn to_timestamp(instant: Instant) -> u64 {
+    let deadline = SystemTime::now() + instant.saturating_duration_since(Instant::now());
+    deadline.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs_f64().ceil() as u64)
+}
+
+/// 把Unix时间戳换算为代理内部的时刻，已经过去时返回None
+fn from_timestamp(timestamp: u64) -> Option<Instant> {
+    let deadline = UNIX_EPOCH + Duration::from_secs(timestamp);
+    let remaining = deadline.duration_since(SystemTime::now()).ok().filter(|d| !d.is_zero())?;
+    Some(Instant::now() + remaining)
+}
+
+/// 以十六进制字符串序列化字节
+mod hex {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::packet::PublishPacket;
+    use crate::properties::Properties;
+
+    fn message(topic: &str, payload: &'static [u8]) -> Message {
+        Message::new(PublishPacket {
+            dup: false,
+            qos: QoS::AtLeastOnce,
+            retain: true,
+            topic: topic.to_string(),
+            packet_id: Some(9),
+            properties: Properties::default(),
+            payload: Bytes::from_static(payload),
+        })
+    }
+
+    /// 临时日志文件路径，离开作用域时删除文件（包括断言失败时）
+    pub(crate) struct TempLog(PathBuf);
+
+    impl TempLog {
+        pub(crate) fn new(name: &str) -> Self {
+            TempLog(std::env::temp_dir().join(format!("mqtt-storage-{}-{}-{}.log", name, std::process::id(), rand::random::<u32>())))
+        }
+
+        pub(crate) fn path(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempLog {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_stored_message_round_trip() {
+        let mut original = message("a/b", b"\x00payload");
+        original.publish.properties.message_expiry_interval = Some(60);
+        original.publish.properties.user_properties = vec![("k".to_string(), "v".to_string())];
+        let original = Message::new(original.publish);
+
+        let stored = StoredMessage::from_message(&original);
+        let json = serde_json::to_string(&stored).unwrap();
+        let stored: StoredMessage = serde_json::from_str(&json).unwrap();
+        let restored = stored.to_message().unwrap().unwrap();
+        assert_eq!(restored.publish.topic, "a/b");
+        assert_eq!(&restored.publish.payload[..], b"\x00payload");
+        assert_eq!(restored.publish.qos, QoS::AtLeastOnce);
+        assert_eq!(restored.publish.packet_id, None);
+        assert_eq!(restored.publish.properties.user_properties, original.publish.properties.user_properties);
+        assert!(restored.expires_at.is_some());
+
+        let expired = StoredMessage { expires_at: Some(1), ..stored };
+        assert!(expired.to_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_state_apply_and_rebuild_from_records() {
+        let mut state = StoredState::default();
+        let stored = StoredMessage::from_message(&message("t", b"1"));
+        state.apply(Record::SetRetained { topic: "t".into(), message: stored.clone() });
+        state.apply(Record::SetRetained { topic: "u".into(), message: stored.clone() });
+        state.apply(Record::RemoveRetained { topic: "u".into() });
+        // 会话不存在时订阅被忽略
+        state.apply(Record::Subscribe { client_id: "c".into(), filter: "x".into(), qos: 1 });
+        state.apply(Record::SetSession { client_id: "c".into(), expiry_interval: 60, expires_at: None });
+        state.apply(Record::Subscribe { client_id: "c".into(), filter: "a/#".into(), qos: 1 });
+        state.apply(Record::Subscribe { client_id: "c".into(), filter: "b".into(), qos: 2 });
+        state.apply(Record::Unsubscribe { client_id: "c".into(), filter: "b".into() });
+        state.apply(Record::PushMessage { client_id: "c".into(), message: stored.clone() });
+        state.apply(Record::SetSession { client_id: "d".into(), expiry_interval: 1, expires_at: None });
+        state.apply(Record::RemoveSession { client_id: "d".into() });
+
+        assert_eq!(state.retained.keys().collect::<Vec<_>>(), vec!["t"]);
+        let session = &state.sessions["c"];
+        assert_eq!(session.subscriptions, BTreeMap::from([("a/#".to_string(), QoS::AtLeastOnce)]));
+        assert_eq!(session.queued.len(), 1);
+        assert_eq!(state.sessions.len(), 1);
+
+        let mut rebuilt = StoredState::default();
+        for record in state.records() {
+            rebuilt.apply(record);
+        }
+        assert_eq!(rebuilt, state);
+
+        let restored = session.restore().unwrap();
+        assert_eq!(restored.subscriptions.get("a/#"), Some(&QoS::AtLeastOnce));
+        assert_eq!(restored.queued.len(), 1);
+        // 代理退出时在线的会话从恢复时刻开始计算过期时间
+        assert!(restored.expires_at.is_some());
+        let expired = StoredSession { expires_at: Some(1), ..session.clone() };
+        assert!(expired.restore().is_none());
+    }
+
+    #[test]
+    fn test_file_storage_reloads_and_discards_torn_record() {
+        let log = TempLog::new("reload");
+        let path = log.path();
+        let stored = StoredMessage::from_message(&message("t", b"1"));
+        {
+            let storage = FileStorage::open(path).unwrap();
+            storage.apply(Record::SetRetained { topic: "t".into(), message: stored.clone() }).unwrap();
+            storage.apply(Record::SetSession { client_id: "c".into(), expiry_interval: SESSION_EXPIRY_NEVER, expires_at: None }).unwrap();
+            storage.apply(Record::Subscribe { client_id: "c".into(), filter: "a".into(), qos: 2 }).unwrap();
+        }
+        // 模拟写入中途进程退出留下的半条记录
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(b"{\"op\":\"remove_sess").unwrap();
+        drop(file);
+
+        let storage = FileStorage::open(path).unwrap();
+        let state = storage.load().unwrap();
+        assert_eq!(state.retained.get("t"), Some(&stored));
+        assert_eq!(state.sessions["c"].subscriptions.get("a"), Some(&QoS::ExactlyOnce));
+
+        // 重新打开后可以继续追加
+        storage.apply(Record::RemoveRetained { topic: "t".into() }).unwrap();
+        drop(storage);
+        assert!(FileStorage::open(path).unwrap().load().unwrap().retained.is_empty());
+    }
+
+    #[test]
+    fn test_file_storage_compacts_overwritten_records() {
+        let log = TempLog::new("compact");
+        let path = log.path();
+        let storage = FileStorage::open(path).unwrap();
+        let stored = StoredMessage::from_message(&message("t", b"1"));
+        for _ in 0..COMPACT_MIN_RECORDS + 10 {
+            storage.apply(Record::SetRetained { topic: "t".into(), message: stored.clone() }).unwrap();
+        }
+        storage.flush().unwrap();
+        let lines = fs::read_to_string(storage.path()).unwrap().lines().count();
+        assert!(lines < 20, "log has {} lines after compaction", lines);
+
+        storage.compact().unwrap();
+        assert_eq!(fs::read_to_string(storage.path()).unwrap().lines().count(), 1);
+        assert_eq!(FileStorage::open(path).unwrap().load().unwrap(), storage.load().unwrap());
+    }
+}
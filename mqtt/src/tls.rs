@@ -0,0 +1,315 @@
+/// TLS传输（MQTT over TLS）
+/// 代理端从PEM文件加载证书链和私钥，可选校验客户端证书（双向TLS）；
+/// 客户端使用自定义CA根证书校验代理，可选提供客户端证书
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// MQTT over TLS的默认端口
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+
+/// 代理端TLS监听配置
+#[derive(Debug, Clone)]
+pub struct TlsServerOptions {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    // 校验客户端证书的CA，设置后启用双向TLS
+    client_ca_path: Option<PathBuf>,
+    // 为false时允许客户端不提供证书
+    require_client_cert: bool,
+    use_identity_as_username: bool,
+}
+
+impl TlsServerOptions {
+    /// 使用PEM格式的证书链和私钥文件
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        TlsServerOptions {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            client_ca_path: None,
+            require_client_cert: false,
+            use_identity_as_username: false,
+        }
+    }
+
+    /// 用指定CA校验客户端证书（双向TLS），`required`为false时也接受不提供证书的客户端
+    pub fn client_ca(mut self, ca_path: impl Into<PathBuf>, required: bool) -> Self {
+        self.client_ca_path = Some(ca_path.into());
+        self.require_client_cert = required;
+        self
+    }
+
+    /// 以客户端证书主题中的CN作为用户名
+    ///
+    /// 提供了证书的连接不再经过认证器，CONNECT中的用户名和密码被忽略。
+    pub fn use_identity_as_username(mut self, enabled: bool) -> Self {
+        self.use_identity_as_username = enabled;
+        self
+    }
+
+    pub(crate) fn identity_as_username(&self) -> bool {
+        self.use_identity_as_username
+    }
+
+    /// 加载证书和私钥，创建TLS接受器
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let builder = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?;
+        let builder = match &self.client_ca_path {
+            Some(ca_path) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(load_roots(ca_path)?), provider());
+                let verifier = if self.require_client_cert {
+                    verifier
+                } else {
+                    verifier.allow_unauthenticated()
+                };
+                builder.with_client_cert_verifier(verifier.build().map_err(invalid)?)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(load_certs(&self.cert_path)?, load_private_key(&self.key_path)?)
+            .map_err(invalid)?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// 客户端TLS配置
+#[derive(Debug, Clone)]
+pub struct TlsClientOptions {
+    // 校验代理证书的CA证书（PEM）
+    ca_path: PathBuf,
+    // 双向TLS时提供的客户端证书链和私钥
+    client_cert: Option<(PathBuf, PathBuf)>,
+    // 校验代理证书时使用的名称，默认取连接地址中的主机部分
+    server_name: Option<String>,
+}
+
+impl TlsClientOptions {
+    /// 只信任`ca_path`中的CA证书签发的代理证书
+    pub fn new(ca_path: impl Into<PathBuf>) -> Self {
+        TlsClientOptions {
+            ca_path: ca_path.into(),
+            client_cert: None,
+            server_name: None,
+        }
+    }
+
+    /// 向代理出示客户端证书（双向TLS）
+    pub fn client_cert(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.client_cert = Some((cert_path.into(), key_path.into()));
+        self
+    }
+
+    /// 指定校验代理证书时使用的主机名，用于按IP地址连接的情况
+    pub fn server_name(mut self, server_name: impl Into<String>) -> Self {
+        self.server_name = Some(server_name.into());
+        self
+    }
+
+    /// 在已建立的TCP连接上完成TLS握手
+    pub(crate) async fn connect(&self, stream: TcpStream, addr: &str) -> io::Result<client::TlsStream<TcpStream>> {
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(invalid)?
+            .with_root_certificates(load_roots(&self.ca_path)?);
+        let config = match &self.client_cert {
+            Some((cert_path, key_path)) => builder
+                .with_client_auth_cert(load_certs(cert_path)?, load_private_key(key_path)?)
+                .map_err(invalid)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        let server_name = self.server_name.clone().unwrap_or_else(|| host(addr).to_string());
+        let server_name = ServerName::try_from(server_name).map_err(invalid)?;
+        TlsConnector::from(Arc::new(config)).connect(server_name, stream).await
+    }
+}
+
+/// 取出客户端证书主题中的CN，客户端没有提供证书时返回None
+pub(crate) fn peer_common_name(stream: &server::TlsStream<TcpStream>) -> Option<String> {
+    let (_, connection) = stream.get_ref();
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate.as_ref()).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(common_name.to_string())
+}
+
+/// 去掉地址中的端口，`[::1]:8883`形式的IPv6地址同时去掉方括号
+fn host(addr: &str) -> &str {
+    if let Some(rest) = addr.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    addr.rsplit_once(':').map_or(addr, |(host, _)| host)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(crypto::ring::default_provider())
+}
+
+fn invalid(error: impl Into<Box<dyn Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificate found in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| invalid(format!("no private key found in {}", path.display())))
+}
+
+fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert).map_err(invalid)?;
+    }
+    Ok(roots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::acl::{Acl, AclRule, Permission};
+    use crate::auth::InMemoryAuthenticator;
+    use crate::client::MqttClient;
+    use crate::protocol::{QoS, SUBACK_FAILURE};
+    use crate::server::MqttBroker;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+
+    /// 生成测试用的CA、代理证书和CN为`device-1`的客户端证书，返回所在目录
+    fn generate_pki() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mqtt-tls-{}-{}", std::process::id(), rand::random::<u32>()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::default();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "mqtt test ca");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        let issue = |name: &str, subject_alt_names: Vec<String>, usage: ExtendedKeyUsagePurpose| {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(subject_alt_names).unwrap();
+            params.distinguished_name.push(DnType::CommonName, name);
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+            std::fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            std::fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+        };
+        issue("localhost", vec!["localhost".to_string()], ExtendedKeyUsagePurpose::ServerAuth);
+        issue("device-1", Vec::new(), ExtendedKeyUsagePurpose::ClientAuth);
+        dir
+    }
+
+    /// 启动只有TLS端口对客户端有意义的代理，返回TLS监听地址
+    async fn start_tls_broker(mut broker: MqttBroker, options: TlsServerOptions) -> String {
+        broker.enable_tls("127.0.0.1:0", options).await.unwrap();
+        let addr = broker.tls_local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+        addr
+    }
+
+    #[test]
+    fn test_host_strips_port() {
+        assert_eq!(host("broker.example.com:8883"), "broker.example.com");
+        assert_eq!(host("[::1]:8883"), "::1");
+        assert_eq!(host("localhost"), "localhost");
+    }
+
+    #[tokio::test]
+    async fn test_client_connects_over_tls() {
+        let pki = generate_pki();
+        let broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        let options = TlsServerOptions::new(pki.join("localhost.pem"), pki.join("localhost.key"));
+        let addr = start_tls_broker(broker, options).await;
+
+        let mut client = MqttClient::new("tls-client".to_string());
+        client.set_tls(TlsClientOptions::new(pki.join("ca.pem")).server_name("localhost"));
+        client.connect(&addr).await.unwrap();
+        client.subscribe("tls/test".to_string(), QoS::AtLeastOnce).await.unwrap();
+        client.publish("tls/test".to_string(), "hello".to_string(), QoS::AtLeastOnce).await.unwrap();
+        client.disconnect().await.unwrap();
+
+        // 不信任代理证书的CA时握手失败
+        let mut untrusted = MqttClient::new("untrusted".to_string());
+        untrusted.set_tls(TlsClientOptions::new(pki.join("device-1.pem")).server_name("localhost"));
+        assert!(untrusted.connect(&addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_common_name_used_as_username() {
+        let pki = generate_pki();
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        // 没有任何用户的认证器：只有出示证书的客户端能连接
+        broker.set_authenticator(InMemoryAuthenticator::new());
+        let mut acl = Acl::new(Permission::Deny);
+        acl.add_rule(AclRule::allow("users/%u/#"));
+        broker.set_acl(acl);
+        let options = TlsServerOptions::new(pki.join("localhost.pem"), pki.join("localhost.key"))
+            .client_ca(pki.join("ca.pem"), false)
+            .use_identity_as_username(true);
+        let addr = start_tls_broker(broker, options).await;
+
+        let mut device = MqttClient::new("device".to_string());
+        device.set_tls(
+            TlsClientOptions::new(pki.join("ca.pem"))
+                .server_name("localhost")
+                .client_cert(pki.join("device-1.pem"), pki.join("device-1.key")),
+        );
+        device.connect(&addr).await.unwrap();
+        let codes = device
+            .subscribe_many(&[
+                ("users/device-1/#".to_string(), QoS::AtMostOnce),
+                ("users/device-2/#".to_string(), QoS::AtMostOnce),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(codes, vec![0, SUBACK_FAILURE]);
+        device.disconnect().await.unwrap();
+
+        // 没有证书时回到认证器，匿名连接被拒绝
+        let mut anonymous = MqttClient::new("anonymous".to_string());
+        anonymous.set_tls(TlsClientOptions::new(pki.join("ca.pem")).server_name("localhost"));
+        assert!(anonymous.connect(&addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_required_client_certificate() {
+        let pki = generate_pki();
+        let broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        let options = TlsServerOptions::new(pki.join("localhost.pem"), pki.join("localhost.key"))
+            .client_ca(pki.join("ca.pem"), true);
+        let addr = start_tls_broker(broker, options).await;
+
+        let mut anonymous = MqttClient::new("anonymous".to_string());
+        anonymous.set_tls(TlsClientOptions::new(pki.join("ca.pem")).server_name("localhost"));
+        assert!(anonymous.connect(&addr).await.is_err());
+
+        let mut device = MqttClient::new("device".to_string());
+        device.set_tls(
+            TlsClientOptions::new(pki.join("ca.pem"))
+                .server_name("localhost")
+                .client_cert(pki.join("device-1.pem"), pki.join("device-1.key")),
+        );
+        device.connect(&addr).await.unwrap();
+        device.disconnect().await.unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+/// 主题过滤器匹配
+/// 实现MQTT 3.1.1的通配符语义（`+` 单层、`#` 多层），
+/// 以主题层级为键的前缀树同时服务于代理的消息路由和客户端的回调分发
+use std::collections::HashMap;
+use crate::protocol::MAX_FIELD_LEN;
+
+const SINGLE_LEVEL_WILDCARD: &str = "+";
+const MULTI_LEVEL_WILDCARD: &str = "#";
+
+/// 检查发布用的主题名是否合法（非空、不超过65535字节且不含通配符）
+pub fn valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && topic.len() <= MAX_FIELD_LEN && !topic.contains(['+', '#', '\0'])
+}
+
+/// 检查订阅用的主题过滤器是否合法
+///
+/// `+` 必须独占一个层级；`#` 必须独占一个层级且只能是最后一层。
+pub fn valid_topic_filter(filter: &str) -> bool {
+    if filter.is_empty() || filter.len() > MAX_FIELD_LEN || filter.contains('\0') {
+        return false;
+    }
+    let levels: Vec<&str> = filter.split('/').collect();
+    for (i, level) in levels.iter().enumerate() {
+        if level.contains('#') && (*level != MULTI_LEVEL_WILDCARD || i != levels.len() - 1) {
+            return false;
+        }
+        if level.contains('+') && *level != SINGLE_LEVEL_WILDCARD {
+            return false;
+        }
+    }
+    true
+}
+
+/// 判断单个主题过滤器是否匹配主题名
+pub fn matches(filter: &str, topic: &str) -> bool {
+    // 以$开头的系统主题不会被首层通配符匹配
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter_levels = filter.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (filter_levels.next(), topic_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) => return true,
+            (Some(SINGLE_LEVEL_WILDCARD), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// 判断过滤器`outer`能否覆盖过滤器`inner`，即`inner`能匹配到的主题`outer`都能匹配
+pub fn filter_covers(outer: &str, inner: &str) -> bool {
+    if inner.starts_with('$') && (outer.starts_with('+') || outer.starts_with('#')) {
+        return false;
+    }
+
+    let mut outer_levels = outer.split('/');
+    let mut inner_levels = inner.split('/');
+    loop {
+        match (outer_levels.next(), inner_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) => return true,
+            // 单层通配符不能覆盖多层通配符
+            (Some(SINGLE_LEVEL_WILDCARD), Some(level)) if level != MULTI_LEVEL_WILDCARD => {}
+            (Some(o), Some(i)) if o == i => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// 判断两个过滤器是否有交集，即存在同时被两者匹配的主题
+pub fn filters_overlap(a: &str, b: &str) -> bool {
+    // 以$开头的主题不会被首层通配符匹配
+    let first = |filter: &str| filter.split('/').next().unwrap_or_default().to_string();
+    let (first_a, first_b) = (first(a), first(b));
+    let wildcard = |level: &str| level == SINGLE_LEVEL_WILDCARD || level == MULTI_LEVEL_WILDCARD;
+    if (wildcard(&first_a) && first_b.starts_with('$')) || (wildcard(&first_b) && first_a.starts_with('$')) {
+        return false;
+    }
+
+    let mut a_levels = a.split('/');
+    let mut b_levels = b.split('/');
+    loop {
+        match (a_levels.next(), b_levels.next()) {
+            (Some(MULTI_LEVEL_WILDCARD), _) | (_, Some(MULTI_LEVEL_WILDCARD)) => return true,
+            (Some(SINGLE_LEVEL_WILDCARD), Some(_)) | (Some(_), Some(SINGLE_LEVEL_WILDCARD)) => {}
+            (Some(x), Some(y)) if x == y => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+/// 主题前缀树，每个主题过滤器对应一个值
+#[derive(Debug)]
+pub struct TopicTrie<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    children: HashMap<String, Node<T>>,
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Node {
+            children: HashMap::new(),
+            value: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.value.is_none() && self.children.is_empty()
+    }
+}
+
+impl<T> TopicTrie<T> {
+    pub fn new() -> Self {
+        TopicTrie {
+            root: Node::new(),
+            len: 0,
+        }
+    }
+
+    /// 设置主题过滤器对应的值，返回旧值
+    pub fn insert(&mut self, filter: &str, value: T) -> Option<T> {
+        let old = Self::node_at(&mut self.root, filter).value.replace(value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// 获取主题过滤器对应的值，不存在时插入默认值
+    pub fn entry(&mut self, filter: &str) -> &mut T
+    where
+        T: Default,
+    {
+        let node = Self::node_at(&mut self.root, filter);
+        if node.value.is_none() {
+            self.len += 1;
+        }
+        node.value.get_or_insert_with(T::default)
+    }
+
+    /// 按主题过滤器精确查找
+    pub fn get(&self, filter: &str) -> Option<&T> {
+        let mut node = &self.root;
+        for level in filter.split('/') {
+            node = node.children.get(level)?;
+        }
+        node.value.as_ref()
+    }
+
+    /// 按主题过滤器精确查找（可变）
+    pub fn get_mut(&mut self, filter: &str) -> Option<&mut T> {
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.get_mut(level)?;
+        }
+        node.value.as_mut()
+    }
+
+    /// 删除主题过滤器，并清理不再使用的节点
+    pub fn remove(&mut self, filter: &str) -> Option<T> {
+        let levels: Vec<&str> = filter.split('/').collect();
+        let removed = Self::remove_at(&mut self.root, &levels);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    fn remove_at(node: &mut Node<T>, levels: &[&str]) -> Option<T> {
+        match levels.split_first() {
+            None => node.value.take(),
+            Some((level, rest)) => {
+                let child = node.children.get_mut(*level)?;
+                let removed = Self::remove_at(child, rest);
+                if child.is_empty() {
+                    node.children.remove(*level);
+                }
+                removed
+            }
+        }
+    }
+
+    /// 收集所有匹配给定主题名的过滤器的值
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split('/').collect();
+        // 以$开头的系统主题不会被首层通配符匹配
+        let system_topic = topic.starts_with('$');
+        let mut result = Vec::new();
+        Self::collect(&self.root, &levels, 0, system_topic, &mut result);
+        result
+    }
+
+    fn collect<'a>(node: &'a Node<T>, levels: &[&str], depth: usize, system_topic: bool, result: &mut Vec<&'a T>) {
+        let allow_wildcard = !(depth == 0 && system_topic);
+
+        // "#" 同时匹配父层级本身，例如 "sport/#" 匹配 "sport"
+        if allow_wildcard {
+            if let Some(value) = node.children.get(MULTI_LEVEL_WILDCARD).and_then(|n| n.value.as_ref()) {
+                result.push(value);
+            }
+        }
+
+        let Some(level) = levels.get(depth) else {
+            if let Some(value) = node.value.as_ref() {
+                result.push(value);
+            }
+            return;
+        };
+
+        if let Some(child) = node.children.get(*level) {
+            Self::collect(child, levels, depth + 1, system_topic, result);
+        }
+        if allow_wildcard {
+            if let Some(child) = node.children.get(SINGLE_LEVEL_WILDCARD) {
+                Self::collect(child, levels, depth + 1, system_topic, result);
+            }
+        }
+    }
+
+    /// 过滤器数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn node_at<'a>(root: &'a mut Node<T>, filter: &str) -> &'a mut Node<T> {
+        let mut node = root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.to_string()).or_insert_with(Node::new);
+        }
+        node
+    }
+}
+
+impl<T> Default for TopicTrie<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_topic_filter() {
+        assert!(valid_topic_filter("sport/tennis/player1"));
+        assert!(valid_topic_filter("sport/+/player1"));
+        assert!(valid_topic_filter("sport/#"));
+        assert!(valid_topic_filter("#"));
+        assert!(valid_topic_filter("+"));
+        assert!(valid_topic_filter("/finance"));
+
+        assert!(!valid_topic_filter(""));
+        assert!(!valid_topic_filter("sport/tennis#"));
+        assert!(!valid_topic_filter("sport/#/ranking"));
+        assert!(!valid_topic_filter("sport+"));
+
+        assert!(valid_topic_name("sport/tennis"));
+        assert!(!valid_topic_name("sport/+"));
+        assert!(!valid_topic_name(""));
+        assert!(!valid_topic_name(&"a".repeat(MAX_FIELD_LEN + 1)));
+        assert!(!valid_topic_filter(&"a".repeat(MAX_FIELD_LEN + 1)));
+    }
+
+    #[test]
+    fn test_wildcard_matching() {
+        // 多层通配符
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1"));
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1/ranking"));
+        assert!(matches("sport/tennis/player1/#", "sport/tennis/player1/score/wimbledon"));
+        assert!(matches("sport/#", "sport"));
+        assert!(matches("#", "sport/tennis"));
+        assert!(!matches("sport/tennis/#", "sport/football"));
+
+        // 单层通配符
+        assert!(matches("sport/tennis/+", "sport/tennis/player1"));
+        assert!(!matches("sport/tennis/+", "sport/tennis/player1/ranking"));
+        assert!(!matches("sport/+", "sport"));
+        assert!(matches("sport/+", "sport/"));
+        assert!(matches("+/+", "/finance"));
+        assert!(matches("/+", "/finance"));
+        assert!(!matches("+", "/finance"));
+        assert!(matches("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(!matches("sensors/+/temp", "sensors/kitchen/humidity"));
+    }
+
+    #[test]
+    fn test_system_topics_excluded_from_top_level_wildcards() {
+        assert!(!matches("#", "$SYS/broker/uptime"));
+        assert!(!matches("+/broker/uptime", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/#", "$SYS/broker/uptime"));
+        assert!(matches("$SYS/+/uptime", "$SYS/broker/uptime"));
+    }
+
+    #[test]
+    fn test_filter_covers() {
+        assert!(filter_covers("sensors/#", "sensors/+/temp"));
+        assert!(filter_covers("sensors/#", "sensors/#"));
+        assert!(filter_covers("sensors/+/temp", "sensors/kitchen/temp"));
+        assert!(filter_covers("sensors/+/temp", "sensors/+/temp"));
+        assert!(!filter_covers("sensors/+/temp", "sensors/#"));
+        assert!(!filter_covers("sensors/kitchen/temp", "sensors/+/temp"));
+        assert!(!filter_covers("sensors/+", "sensors/kitchen/temp"));
+        assert!(!filter_covers("#", "$SYS/#"));
+    }
+
+    #[test]
+    fn test_filters_overlap() {
+        assert!(filters_overlap("devices/dev1/secret", "devices/dev1/#"));
+        assert!(filters_overlap("devices/+/secret", "devices/dev1/+"));
+        assert!(filters_overlap("a/#", "a"));
+        assert!(filters_overlap("a/b", "a/b"));
+        assert!(!filters_overlap("a/+", "a"));
+        assert!(!filters_overlap("a/b/c", "a/+"));
+        assert!(!filters_overlap("a/b", "a/c"));
+        assert!(!filters_overlap("#", "$SYS/#"));
+        assert!(filters_overlap("$SYS/+", "$SYS/#"));
+    }
+
+    #[test]
+    fn test_trie_collects_all_matching_filters() {
+        let mut trie = TopicTrie::new();
+        trie.insert("home/#", 1);
+        trie.insert("home/+/temp", 2);
+        trie.insert("home/kitchen/temp", 3);
+        trie.insert("office/#", 4);
+        assert_eq!(trie.len(), 4);
+
+        let mut values: Vec<i32> = trie.matches("home/kitchen/temp").into_iter().copied().collect();
+        values.sort();
+        assert_eq!(values, vec![1, 2, 3]);
+
+        assert_eq!(trie.remove("home/+/temp"), Some(2));
+        assert_eq!(trie.remove("home/+/temp"), None);
+        assert_eq!(trie.len(), 3);
+        let mut values: Vec<i32> = trie.matches("home/kitchen/temp").into_iter().copied().collect();
+        values.sort();
+        assert_eq!(values, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_trie_entry_and_get() {
+        let mut trie: TopicTrie<Vec<&str>> = TopicTrie::new();
+        trie.entry("a/+").push("first");
+        trie.entry("a/+").push("second");
+        assert_eq!(trie.len(), 1);
+        assert_eq!(trie.get("a/+"), Some(&vec!["first", "second"]));
+        assert_eq!(trie.get("a"), None);
+        trie.get_mut("a/+").unwrap().clear();
+        assert_eq!(trie.get("a/+"), Some(&vec![]));
+    }
+}
@@ -0,0 +1,8 @@
+/// 承载MQTT数据包的底层传输
+/// TCP、TLS和WebSocket连接都实现该trait，代理的会话处理逻辑对传输类型泛型
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// 客户端与代理之间的底层传输，例如TCP、TLS或WebSocket连接
+pub trait Transport: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Transport for T {}
@@ -0,0 +1,192 @@
+/// MQTT over WebSocket
+/// 浏览器等无法直接建立TCP连接的客户端通过WebSocket（`mqtt`子协议）连接代理，
+/// MQTT数据包承载在二进制帧中，一个帧可以包含多个数据包，一个数据包也可以跨多个帧
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use bytes::{Buf, Bytes};
+use futures::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// MQTT over WebSocket使用的子协议名
+pub const MQTT_SUBPROTOCOL: &str = "mqtt";
+
+/// WebSocket端点的默认路径
+pub const DEFAULT_WS_PATH: &str = "/mqtt";
+
+/// 把WebSocket连接适配为字节流，使会话处理逻辑与TCP、TLS连接一致
+pub struct WsTransport<S> {
+    inner: WebSocketStream<S>,
+    // 最近收到的二进制帧中尚未读出的部分
+    pending: Bytes,
+}
+
+impl<S> WsTransport<S> {
+    pub fn new(inner: WebSocketStream<S>) -> Self {
+        WsTransport {
+            inner,
+            pending: Bytes::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsTransport<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        while self.pending.is_empty() {
+            match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(Ok(Message::Binary(data))) => self.pending = Bytes::from(data),
+                // MQTT数据包只能放在二进制帧中
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "text frame on MQTT WebSocket")));
+                }
+                // Ping/Pong由tungstenite自动应答
+                Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+                Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(into_io_error(e))),
+            }
+        }
+        let len = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending[..len]);
+        self.pending.advance(len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsTransport<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        ready!(Pin::new(&mut self.inner).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut self.inner).start_send(Message::Binary(buf.to_vec())).map_err(into_io_error)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx).map_err(into_io_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx).map_err(into_io_error)
+    }
+}
+
+fn into_io_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        // 对方已经关闭连接，按连接断开处理
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => io::ErrorKind::BrokenPipe.into(),
+        other => io::Error::new(io::ErrorKind::InvalidData, other),
+    }
+}
+
+/// 校验WebSocket升级请求：路径必须匹配，客户端必须提供`mqtt`子协议
+pub(crate) struct UpgradeCheck {
+    path: Arc<str>,
+}
+
+impl UpgradeCheck {
+    pub(crate) fn new(path: Arc<str>) -> Self {
+        UpgradeCheck { path }
+    }
+}
+
+impl Callback for UpgradeCheck {
+    fn on_request(self, request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
+        if request.uri().path() != &*self.path {
+            return Err(error_response(StatusCode::NOT_FOUND));
+        }
+        let offers_mqtt = request
+            .headers()
+            .get_all("Sec-WebSocket-Protocol")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|protocol| protocol.trim() == MQTT_SUBPROTOCOL);
+        if !offers_mqtt {
+            return Err(error_response(StatusCode::BAD_REQUEST));
+        }
+        response
+            .headers_mut()
+            .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(MQTT_SUBPROTOCOL));
+        Ok(response)
+    }
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut response = ErrorResponse::new(None);
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::MqttCodec;
+    use crate::packet::{ConnectPacket, Packet, PublishPacket, SubscribePacket};
+    use crate::protocol::QoS;
+    use crate::server::MqttBroker;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpStream;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+    use tokio_tungstenite::MaybeTlsStream;
+    use tokio_util::codec::Framed;
+
+    type WsFramed = Framed<WsTransport<MaybeTlsStream<TcpStream>>, MqttCodec>;
+
+    /// 启动同时监听TCP和WebSocket的代理，返回(TCP地址, WebSocket地址)
+    async fn start_broker() -> (String, String) {
+        let mut broker = MqttBroker::new("127.0.0.1:0").await.unwrap();
+        broker.enable_websocket("127.0.0.1:0", DEFAULT_WS_PATH).await.unwrap();
+        let tcp_addr = broker.local_addr().unwrap().to_string();
+        let ws_addr = broker.ws_local_addr().unwrap().to_string();
+        tokio::spawn(async move { broker.run().await.unwrap() });
+        (tcp_addr, ws_addr)
+    }
+
+    async fn ws_connect(ws_addr: &str, path: &str, subprotocol: Option<&'static str>) -> Result<WsFramed, tungstenite::Error> {
+        let mut request = format!("ws://{}{}", ws_addr, path).into_client_request()?;
+        if let Some(subprotocol) = subprotocol {
+            request.headers_mut().insert("Sec-WebSocket-Protocol", HeaderValue::from_static(subprotocol));
+        }
+        let (stream, response) = tokio_tungstenite::connect_async(request).await?;
+        assert_eq!(response.headers().get("Sec-WebSocket-Protocol").unwrap(), MQTT_SUBPROTOCOL);
+        Ok(Framed::new(WsTransport::new(stream), MqttCodec::new()))
+    }
+
+    #[tokio::test]
+    async fn test_websocket_client_exchanges_messages_with_tcp_client() {
+        let (tcp_addr, ws_addr) = start_broker().await;
+
+        let mut ws = ws_connect(&ws_addr, DEFAULT_WS_PATH, Some(MQTT_SUBPROTOCOL)).await.unwrap();
+        ws.send(Packet::Connect(ConnectPacket::new("dashboard".to_string()))).await.unwrap();
+        assert!(matches!(ws.next().await, Some(Ok(Packet::Connack(connack))) if connack.return_code == 0));
+        ws.send(Packet::Subscribe(SubscribePacket {
+            packet_id: 1,
+            topics: vec![("sensors/#".to_string(), QoS::AtMostOnce)],
+            properties: Default::default(),
+        })).await.unwrap();
+        assert!(matches!(ws.next().await, Some(Ok(Packet::Suback(_)))));
+
+        let mut sensor = crate::client::MqttClient::new("sensor".to_string());
+        sensor.connect(&tcp_addr).await.unwrap();
+        sensor.publish("sensors/temp".to_string(), "21.5".to_string(), QoS::AtMostOnce).await.unwrap();
+
+        match ws.next().await {
+            Some(Ok(Packet::Publish(PublishPacket { topic, payload, .. }))) => {
+                assert_eq!(topic, "sensors/temp");
+                assert_eq!(&payload[..], b"21.5");
+            }
+            other => panic!("expected PUBLISH, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_requires_path_and_mqtt_subprotocol() {
+        let (_, ws_addr) = start_broker().await;
+        assert!(ws_connect(&ws_addr, "/other", Some(MQTT_SUBPROTOCOL)).await.is_err());
+        assert!(ws_connect(&ws_addr, DEFAULT_WS_PATH, None).await.is_err());
+    }
+}